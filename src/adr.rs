@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use futuresdr::prelude::*;
+
+use crate::lorawan::MacCommand;
+use crate::region::Region;
+use crate::utils::SpreadingFactor;
+
+/// TX power steps of the TXPower index
+pub const TX_POWER_STEP_DB: f64 = 2.0;
+
+/// Attenuation of a TXPower index relative to the maximum EIRP.
+pub fn tx_power_gain_db(tx_power: u8) -> f64 {
+    -(tx_power as f64) * TX_POWER_STEP_DB
+}
+
+/// SNR needed by the demodulator for a spreading factor.
+pub fn required_snr(sf: SpreadingFactor) -> f64 {
+    match u8::from(sf) {
+        sf @ 5..=12 => -5.0 - 2.5 * (sf as f64 - 6.0),
+        _ => 0.0,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdrConfig {
+    pub region: Region,
+    /// margin kept on top of the required SNR
+    pub installation_margin: f64,
+    /// uplinks needed before the first decision
+    pub history_len: usize,
+    pub min_data_rate: u8,
+    pub max_data_rate: u8,
+    /// highest TXPower index, i.e. the lowest power
+    pub max_tx_power: u8,
+    /// ChMask sent in LinkADRReq
+    pub ch_mask: u16,
+}
+
+impl AdrConfig {
+    /// Data rate and TX power limits of a region, enabling its default channels.
+    pub fn for_region(region: Region) -> Self {
+        let ch_mask = match region.sub_bands() {
+            0 => (1u16 << region.uplink_channels().len()) - 1,
+            _ => 0x00ff,
+        };
+        Self {
+            region,
+            installation_margin: 10.0,
+            history_len: 20,
+            min_data_rate: 0,
+            max_data_rate: region.max_uplink_data_rate(),
+            max_tx_power: region.max_tx_power(),
+            ch_mask,
+        }
+    }
+}
+
+impl Default for AdrConfig {
+    fn default() -> Self {
+        Self::for_region(Region::EU868)
+    }
+}
+
+/// Link settings of a device, as requested by the [`AdrEngine`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdrDecision {
+    pub data_rate: u8,
+    pub tx_power: u8,
+    pub nb_trans: u8,
+}
+
+impl AdrDecision {
+    pub fn spreading_factor(&self, region: Region) -> Option<SpreadingFactor> {
+        region.data_rate(self.data_rate)?.spreading_factor()
+    }
+
+    pub fn gain_db(&self) -> f64 {
+        tx_power_gain_db(self.tx_power)
+    }
+
+    pub fn link_adr_req(&self, ch_mask: u16) -> MacCommand {
+        MacCommand::LinkAdrReq {
+            data_rate: self.data_rate,
+            tx_power: self.tx_power,
+            ch_mask,
+            redundancy: self.nb_trans & 0x0f,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DeviceLink {
+    /// best SNR per frame counter, oldest first
+    history: VecDeque<(u32, f64)>,
+    current: AdrDecision,
+    pending: Option<AdrDecision>,
+}
+
+/// Network side adaptive data rate, following the algorithm of the Semtech reference network server.
+///
+/// The margin of the best SNR of the last uplinks over the SNR required at the current data rate is
+/// spent in steps of 3 dB, first on higher data rates, then on lower TX power. A negative margin raises the
+/// TX power again; lowering the data rate is left to the device's ADR backoff.
+#[derive(Debug, Clone, Default)]
+pub struct AdrEngine {
+    config: AdrConfig,
+    devices: HashMap<u32, DeviceLink>,
+}
+
+impl AdrEngine {
+    pub fn new(config: AdrConfig) -> Self {
+        Self {
+            config,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &AdrConfig {
+        &self.config
+    }
+
+    /// Record an uplink, copies received by several gateways keep the best SNR.
+    pub fn record(&mut self, dev_addr: u32, fcnt: u32, snr: f64, data_rate: u8) {
+        let history_len = self.config.history_len;
+        let device = self.devices.entry(dev_addr).or_insert_with(|| DeviceLink {
+            history: VecDeque::new(),
+            current: AdrDecision {
+                data_rate,
+                tx_power: 0,
+                nb_trans: 1,
+            },
+            pending: None,
+        });
+        if device.current.data_rate != data_rate {
+            // the device changed the data rate on its own, e.g. ADR backoff
+            device.current.data_rate = data_rate;
+            device.history.clear();
+        }
+        match device.history.back_mut() {
+            Some((last, best)) if *last == fcnt => *best = best.max(snr),
+            _ => device.history.push_back((fcnt, snr)),
+        }
+        while device.history.len() > history_len {
+            device.history.pop_front();
+        }
+    }
+
+    /// SNR history of a device, oldest first.
+    pub fn history(&self, dev_addr: u32) -> Vec<f64> {
+        self.devices
+            .get(&dev_addr)
+            .map(|d| d.history.iter().map(|(_, snr)| *snr).collect())
+            .unwrap_or_default()
+    }
+
+    /// New link settings for the device, if the history is complete and they differ from the current ones.
+    ///
+    /// The decision stays pending until [`AdrEngine::acknowledge`] is called.
+    pub fn decide(&mut self, dev_addr: u32) -> Option<AdrDecision> {
+        let config = &self.config;
+        let device = self.devices.get_mut(&dev_addr)?;
+        if device.history.len() < config.history_len {
+            return None;
+        }
+        let snr_max = device
+            .history
+            .iter()
+            .map(|(_, snr)| *snr)
+            .fold(f64::MIN, f64::max);
+        let required = required_snr(device.current.spreading_factor(config.region)?);
+        let margin = snr_max - required - config.installation_margin;
+        let mut steps = (margin / 3.0).floor() as i32;
+
+        let mut decision = device.current;
+        while steps > 0 && decision.data_rate < config.max_data_rate {
+            decision.data_rate += 1;
+            steps -= 1;
+        }
+        while steps > 0 && decision.tx_power < config.max_tx_power {
+            decision.tx_power += 1;
+            steps -= 1;
+        }
+        while steps < 0 && decision.tx_power > 0 {
+            decision.tx_power -= 1;
+            steps += 1;
+        }
+        decision.data_rate = decision.data_rate.max(config.min_data_rate);
+        decision.nb_trans = Self::nb_trans(&device.history);
+
+        if decision == device.current {
+            return None;
+        }
+        debug!(
+            "Adr: {:08X} margin {:.1} dB -> DR{} TXPower {} NbTrans {}",
+            dev_addr, margin, decision.data_rate, decision.tx_power, decision.nb_trans
+        );
+        device.pending = Some(decision);
+        Some(decision)
+    }
+
+    /// Repetitions by the packet loss seen in the frame counter gaps of the history.
+    fn nb_trans(history: &VecDeque<(u32, f64)>) -> u8 {
+        let (Some((first, _)), Some((last, _))) = (history.front(), history.back()) else {
+            return 1;
+        };
+        let expected = last.wrapping_sub(*first) as f64 + 1.0;
+        let loss = 1.0 - history.len() as f64 / expected;
+        match loss {
+            l if l < 0.05 => 1,
+            l if l < 0.10 => 2,
+            _ => 3,
+        }
+    }
+
+    /// Handle the LinkADRAns of the device, the pending decision becomes the current one if all bits are set.
+    pub fn acknowledge(&mut self, dev_addr: u32, answer: &MacCommand) {
+        let MacCommand::LinkAdrAns {
+            power_ack,
+            data_rate_ack,
+            channel_mask_ack,
+        } = answer
+        else {
+            return;
+        };
+        let Some(device) = self.devices.get_mut(&dev_addr) else {
+            return;
+        };
+        let Some(pending) = device.pending.take() else {
+            return;
+        };
+        if *power_ack && *data_rate_ack && *channel_mask_ack {
+            device.current = pending;
+            // SNRs at the old settings do not apply anymore
+            device.history.clear();
+        } else {
+            info!("Adr: {:08X} rejected {:?}", dev_addr, pending);
+        }
+    }
+
+    /// Link settings the device is known to use.
+    pub fn current(&self, dev_addr: u32) -> Option<AdrDecision> {
+        self.devices.get(&dev_addr).map(|d| d.current)
+    }
+}
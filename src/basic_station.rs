@@ -0,0 +1,703 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::anyhow;
+use futures::SinkExt;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use serde_json::json;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+use triggered::Listener;
+use triggered::Trigger;
+
+use futuresdr::channel::mpsc::Receiver;
+use futuresdr::channel::mpsc::Sender;
+use futuresdr::prelude::*;
+
+use crate::decoder::annotated_payload;
+use crate::downlink::Downlink;
+use crate::downlink::DownlinkScheduler;
+use crate::downlink::DownlinkTiming;
+use crate::downlink::ScheduledDownlink;
+use crate::downlink::reception_remainder;
+use crate::downlink::tmst_now;
+use crate::gateway_config::ConcentratorConf;
+use crate::gateway_config::GatewayChannel;
+use crate::lorawan::MacPayload;
+use crate::lorawan::PhyPayload;
+use crate::utils::Bandwidth;
+use crate::utils::CodeRate;
+use crate::utils::SpreadingFactor;
+
+#[derive(Debug, Clone)]
+pub struct BasicStationConfig {
+    /// base URI of the LNS, e.g. `ws://localhost:6090`, `/router-info` is appended for discovery
+    pub lns_uri: String,
+    /// gateway EUI
+    pub router: u64,
+    /// time to wait for the `router_config` when connecting
+    pub router_config_timeout: Duration,
+    /// delay before reconnecting after the connection failed, doubled on every further attempt
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// frequencies (Hz) of the TX chain, the `freq_range` of the `router_config` if `None`
+    pub tx_freq_range: Option<RangeInclusive<u32>>,
+}
+
+impl BasicStationConfig {
+    pub fn new(lns_uri: &str, router: u64) -> Self {
+        Self {
+            lns_uri: lns_uri.trim_end_matches('/').to_string(),
+            router,
+            router_config_timeout: Duration::from_secs(10),
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+            tx_freq_range: None,
+        }
+    }
+}
+
+/// `router_config` message of the LNS.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouterConfig {
+    #[serde(default)]
+    pub region: String,
+    #[serde(default)]
+    pub freq_range: Option<(u32, u32)>,
+    /// data rate table as (SF, BW in kHz, downlink only), unused entries have SF -1, FSK SF 0
+    #[serde(rename = "DRs")]
+    pub data_rates: Vec<(i32, u32, u8)>,
+    #[serde(default)]
+    pub sx1301_conf: Vec<ConcentratorConf>,
+}
+
+impl RouterConfig {
+    fn data_rate(&self, dr: usize) -> Option<(SpreadingFactor, Bandwidth)> {
+        let (sf, bw, _) = *self.data_rates.get(dr)?;
+        let sf = SpreadingFactor::try_from(u8::try_from(sf).ok()?).ok()?;
+        let bw = Bandwidth::try_from(bw * 1000).ok()?;
+        Some((sf, bw))
+    }
+
+    /// index of the uplink data rate with the given modulation
+    fn find_data_rate(&self, sf: u8, bw_khz: u32) -> Option<usize> {
+        self.data_rates
+            .iter()
+            .position(|(s, b, dn_only)| *s == sf as i32 && *b == bw_khz && *dn_only == 0)
+    }
+
+    /// Channels of all concentrators in the configuration.
+    pub fn lora_channels(&self) -> Vec<GatewayChannel> {
+        self.sx1301_conf
+            .iter()
+            .flat_map(|c| c.lora_channels())
+            .collect()
+    }
+}
+
+/// `xx-xx-xx-xx-xx-xx-xx-xx` notation used for EUIs in Basic Station messages
+pub fn format_eui(eui: u64) -> String {
+    eui.to_be_bytes()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Parse an EUI, ignoring `-`, `:` and `.` separators.
+pub fn parse_eui(eui: &str) -> anyhow::Result<u64> {
+    let hex: String = eui.chars().filter(|c| !"-:.".contains(*c)).collect();
+    if hex.len() != 16 {
+        return Err(anyhow!("invalid EUI {eui}"));
+    }
+    u64::from_str_radix(&hex, 16).map_err(|e| anyhow!("invalid EUI {eui}: {e}"))
+}
+
+/// ID6 notation of a router EUI, as expected by the router-info endpoint
+fn format_id6(eui: u64) -> String {
+    (0..4)
+        .rev()
+        .map(|i| format!("{:x}", (eui >> (16 * i)) & 0xffff))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Uplink as received by the SDR, before it is matched against the router config.
+#[derive(Debug, Clone)]
+struct Uplink {
+    payload: Vec<u8>,
+    sf: u8,
+    bw_khz: u32,
+    freq: u32,
+    snr: f64,
+    /// uncalibrated, see [`FrameSync`](crate::FrameSync)
+    rssi: f64,
+    /// end of the reception
+    timestamp_nanos: u64,
+}
+
+impl Uplink {
+    fn from_annotations(m: &HashMap<String, Pmt>) -> anyhow::Result<Self> {
+        let payload = annotated_payload(m).ok_or_else(|| anyhow!("missing or invalid payload"))?;
+        let sf = match m.get("sf") {
+            Some(Pmt::U32(sf)) => *sf as u8,
+            Some(Pmt::Usize(sf)) => *sf as u8,
+            p => return Err(anyhow!("invalid sf {p:?}")),
+        };
+        let bw_khz = match m.get("bw") {
+            Some(Pmt::U32(bw)) => *bw,
+            p => return Err(anyhow!("invalid bw {p:?}")),
+        };
+        let freq = match m.get("freq") {
+            Some(Pmt::F64(f)) if f.is_finite() && *f > 0.0 => *f as u32,
+            p => return Err(anyhow!("invalid freq {p:?}")),
+        };
+        let snr = match m.get("snr") {
+            Some(Pmt::F64(snr)) if snr.is_finite() => *snr,
+            p => return Err(anyhow!("invalid snr {p:?}")),
+        };
+        let rssi = match m.get("rssi") {
+            Some(Pmt::F64(rssi)) if rssi.is_finite() => *rssi,
+            p => return Err(anyhow!("invalid rssi {p:?}")),
+        };
+        let header_start_nanos = match m.get("timestamp") {
+            Some(Pmt::U64(t)) => *t,
+            p => return Err(anyhow!("invalid timestamp {p:?}")),
+        };
+        let remainder = reception_remainder(m, payload.len())
+            .ok_or_else(|| anyhow!("invalid modulation annotations"))?;
+        Ok(Self {
+            payload,
+            sf,
+            bw_khz,
+            freq,
+            snr,
+            rssi,
+            timestamp_nanos: header_start_nanos + remainder.as_nanos() as u64,
+        })
+    }
+
+    /// Basic Station time stamp: session in the upper 16 bit, microsecond counter in the lower 48 bit.
+    ///
+    /// The lower 32 bit equal the `tmst` used by the [`DownlinkScheduler`].
+    fn xtime(&self, session: u16) -> i64 {
+        (((session as u64) << 48) | ((self.timestamp_nanos / 1000) & 0xffff_ffff_ffff)) as i64
+    }
+
+    /// Build the `updf`, `jreq` or `propdf` message for this frame.
+    fn to_message(&self, router_config: &RouterConfig, session: u16) -> anyhow::Result<Value> {
+        let dr = router_config
+            .find_data_rate(self.sf, self.bw_khz)
+            .ok_or_else(|| anyhow!("no data rate for SF{} BW{}", self.sf, self.bw_khz))?;
+        let phy = PhyPayload::parse(&self.payload)?;
+        let mhdr: u8 = phy.mhdr.into();
+        let mic = i32::from_le_bytes(phy.mic);
+        let mut msg = match &phy.payload {
+            MacPayload::JoinRequest(j) => json!({
+                "msgtype": "jreq",
+                "MHdr": mhdr,
+                "JoinEui": format_eui(j.join_eui),
+                "DevEui": format_eui(j.dev_eui),
+                "DevNonce": j.dev_nonce,
+                "MIC": mic,
+            }),
+            MacPayload::Data(d) if phy.mhdr.mtype.is_uplink() => json!({
+                "msgtype": "updf",
+                "MHdr": mhdr,
+                "DevAddr": d.dev_addr as i32,
+                "FCtrl": u8::from(d.fctrl),
+                "FCnt": d.fcnt,
+                "FOpts": to_hex(&d.fopts),
+                "FPort": d.fport.map(i32::from).unwrap_or(-1),
+                "FRMPayload": to_hex(&d.frm_payload),
+                "MIC": mic,
+            }),
+            MacPayload::Proprietary(_) => json!({
+                "msgtype": "propdf",
+                "FRMPayload": to_hex(&self.payload),
+            }),
+            _ => {
+                return Err(anyhow!("not forwarding {:?} frame", phy.mhdr.mtype));
+            }
+        };
+        msg["DR"] = json!(dr);
+        msg["Freq"] = json!(self.freq);
+        msg["upinfo"] = json!({
+            "rctx": 0,
+            "xtime": self.xtime(session),
+            "gpstime": 0,
+            "rssi": self.rssi.round() as i32,
+            "snr": self.snr,
+        });
+        msg["RefTime"] = json!(0.0);
+        Ok(msg)
+    }
+}
+
+enum StationMsg {
+    Uplink(Uplink),
+    TxDone,
+}
+
+/// Forward frames to a LoRa Basics Station LNS.
+#[derive(Block)]
+#[message_inputs(r#in, tx_done)]
+#[null_kernel]
+pub struct BasicStationClient {
+    shutdown_trigger: Trigger,
+    to_station: Sender<StationMsg>,
+    downlink_receiver: Option<Receiver<ScheduledDownlink>>,
+    router_config: watch::Receiver<Option<RouterConfig>>,
+    #[allow(dead_code)]
+    runtime: Runtime,
+}
+
+impl BasicStationClient {
+    /// Connect to the LNS and wait for the first `router_config`.
+    pub fn new(config: BasicStationConfig) -> anyhow::Result<Self> {
+        let (to_station, from_block) = mpsc::channel::<StationMsg>(64);
+        let (downlink_sender, downlink_receiver) = mpsc::channel::<ScheduledDownlink>(16);
+        let (router_config_sender, mut router_config) = watch::channel(None);
+        let (shutdown_trigger, shutdown_signal) = triggered::trigger();
+        let timeout = config.router_config_timeout;
+
+        let runtime = Runtime::new().context("creating tokio runtime")?;
+        runtime.spawn(run_station(
+            config,
+            from_block,
+            downlink_sender,
+            router_config_sender,
+            shutdown_signal,
+        ));
+        runtime
+            .block_on(tokio::time::timeout(
+                timeout,
+                router_config.wait_for(|c| c.is_some()),
+            ))
+            .map_err(|_| anyhow!("no router_config received within {timeout:?}"))??;
+
+        Ok(Self {
+            shutdown_trigger,
+            to_station,
+            downlink_receiver: Some(downlink_receiver),
+            router_config,
+            runtime,
+        })
+    }
+
+    /// latest `router_config` received from the LNS
+    pub fn router_config(&self) -> Option<RouterConfig> {
+        self.router_config.borrow().clone()
+    }
+
+    /// Downlinks accepted from the LNS, to be handed to [`transmit_downlinks`](crate::downlink::transmit_downlinks).
+    pub fn take_downlinks(&mut self) -> Option<Receiver<ScheduledDownlink>> {
+        self.downlink_receiver.take()
+    }
+
+    async fn r#in(
+        &mut self,
+        io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::Finished => {
+                self.shutdown_trigger.trigger();
+                io.finished = true;
+            }
+            Pmt::MapStrPmt(m) => match Uplink::from_annotations(&m) {
+                Ok(uplink) => {
+                    if let Err(e) = self.to_station.try_send(StationMsg::Uplink(uplink)) {
+                        warn!("dropping uplink: {}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!("dropping invalid frame: {}", e);
+                    return Ok(Pmt::InvalidValue);
+                }
+            },
+            _ => return Ok(Pmt::InvalidValue),
+        }
+        Ok(Pmt::Ok)
+    }
+
+    /// burst completion of the downlink [`Transmitter`](crate::Transmitter), answered with `dntxed`
+    async fn tx_done(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        let _ = self.to_station.try_send(StationMsg::TxDone);
+        Ok(Pmt::Ok)
+    }
+}
+
+/// Ask the router-info endpoint for the URI of the muxs the router should connect to.
+async fn discover(config: &BasicStationConfig) -> anyhow::Result<String> {
+    let (mut ws, _) = connect_async(format!("{}/router-info", config.lns_uri)).await?;
+    ws.send(Message::text(
+        json!({ "router": format_id6(config.router) }).to_string(),
+    ))
+    .await?;
+    while let Some(msg) = ws.next().await {
+        if let Message::Text(text) = msg? {
+            let info: Value = serde_json::from_str(&text)?;
+            let _ = ws.close(None).await;
+            if let Some(error) = info.get("error").and_then(Value::as_str) {
+                return Err(anyhow!("router-info: {error}"));
+            }
+            return info
+                .get("uri")
+                .and_then(Value::as_str)
+                .map(String::from)
+                .ok_or_else(|| anyhow!("router-info without uri"));
+        }
+    }
+    Err(anyhow!("router-info closed the connection"))
+}
+
+/// Turn a `dnmsg` into a downlink, trying RX1 before RX2 for class A.
+fn schedule_dnmsg(
+    dnmsg: &Value,
+    router_config: &RouterConfig,
+    scheduler: &mut DownlinkScheduler,
+) -> anyhow::Result<ScheduledDownlink> {
+    let pdu = dnmsg
+        .get("pdu")
+        .and_then(Value::as_str)
+        .and_then(from_hex)
+        .ok_or_else(|| anyhow!("invalid pdu"))?;
+    let downlink = |dr: &str, freq: &str, timing| -> Option<Downlink> {
+        let (sf, bw) = router_config.data_rate(dnmsg.get(dr)?.as_u64()? as usize)?;
+        Some(Downlink {
+            payload: pdu.clone(),
+            freq: dnmsg.get(freq)?.as_u64()? as u32,
+            sf,
+            bw,
+            code_rate: CodeRate::CR_4_5,
+            invert_iq: true,
+            has_crc: false,
+            preamble_len: 8,
+            power: None,
+            timing,
+        })
+    };
+    let now = tmst_now();
+    match dnmsg.get("dC").and_then(Value::as_u64).unwrap_or(0) {
+        0 => {
+            let xtime = dnmsg
+                .get("xtime")
+                .and_then(Value::as_i64)
+                .ok_or_else(|| anyhow!("class A dnmsg without xtime"))?;
+            let rx_delay = dnmsg
+                .get("RxDelay")
+                .and_then(Value::as_u64)
+                .unwrap_or(1)
+                .max(1) as u32;
+            let rx1 = (xtime as u32).wrapping_add(rx_delay * 1_000_000);
+            let rx2 = rx1.wrapping_add(1_000_000);
+            let mut last_error = anyhow!("dnmsg without RX1 or RX2 parameters");
+            for candidate in [
+                downlink("RX1DR", "RX1Freq", DownlinkTiming::Tmst(rx1)),
+                downlink("RX2DR", "RX2Freq", DownlinkTiming::Tmst(rx2)),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                match scheduler.schedule(candidate, now) {
+                    Ok(scheduled) => return Ok(scheduled),
+                    Err(e) => last_error = e.into(),
+                }
+            }
+            Err(last_error)
+        }
+        2 => {
+            let downlink = downlink("RX2DR", "RX2Freq", DownlinkTiming::Immediate)
+                .ok_or_else(|| anyhow!("class C dnmsg without RX2 parameters"))?;
+            Ok(scheduler.schedule(downlink, now)?)
+        }
+        class => Err(anyhow!("device class {class} not supported")),
+    }
+}
+
+/// Keep a connection to the LNS, reconnecting with backoff.
+async fn run_station(
+    config: BasicStationConfig,
+    mut from_block: Receiver<StationMsg>,
+    mut downlink_sender: Sender<ScheduledDownlink>,
+    router_config_sender: watch::Sender<Option<RouterConfig>>,
+    shutdown: Listener,
+) {
+    let mut scheduler = DownlinkScheduler::with_freq_range(config.tx_freq_range.clone());
+    let mut backoff = config.backoff_base;
+    // dntxed messages of scheduled downlinks, in order of their start time
+    let mut pending: VecDeque<(u32, Value)> = VecDeque::new();
+    let mut session: u16 = (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        & 0xffff) as u16;
+
+    loop {
+        let result: anyhow::Result<()> = async {
+            let uri = discover(&config).await?;
+            info!("connecting to muxs {}", uri);
+            let (ws, _) = connect_async(uri.as_str()).await?;
+            let (mut ws_tx, mut ws_rx) = ws.split();
+            ws_tx
+                .send(Message::text(
+                    json!({
+                        "msgtype": "version",
+                        "station": concat!("futuresdr-lora ", env!("CARGO_PKG_VERSION")),
+                        "firmware": null,
+                        "package": null,
+                        "model": "futuresdr-lora",
+                        "protocol": 2,
+                        "features": "",
+                    })
+                    .to_string(),
+                ))
+                .await?;
+            session = session.wrapping_add(1);
+            loop {
+                tokio::select! {
+                    _ = shutdown.clone() => {
+                        let _ = ws_tx.close().await;
+                        return Ok(());
+                    }
+                    msg = from_block.next() => {
+                        let out = match msg {
+                            None => return Ok(()),
+                            Some(StationMsg::Uplink(uplink)) => {
+                                let router_config = router_config_sender.borrow().clone();
+                                match router_config.map(|c| uplink.to_message(&c, session)) {
+                                    Some(Ok(msg)) => Some(msg),
+                                    Some(Err(e)) => {
+                                        warn!("not forwarding frame: {}", e);
+                                        None
+                                    }
+                                    None => None,
+                                }
+                            }
+                            Some(StationMsg::TxDone) => pending.pop_front().map(|(_, dntxed)| dntxed),
+                        };
+                        if let Some(out) = out {
+                            ws_tx.send(Message::text(out.to_string())).await?;
+                        }
+                    }
+                    msg = ws_rx.next() => {
+                        let text = match msg {
+                            None => return Err(anyhow!("connection closed")),
+                            Some(msg) => match msg? {
+                                Message::Text(text) => text,
+                                Message::Close(_) => return Err(anyhow!("connection closed")),
+                                _ => continue,
+                            },
+                        };
+                        let msg: Value = serde_json::from_str(&text)?;
+                        match msg.get("msgtype").and_then(Value::as_str) {
+                            Some("router_config") => {
+                                let router_config: RouterConfig = serde_json::from_value(msg)?;
+                                info!("received router_config for region {}", router_config.region);
+                                if config.tx_freq_range.is_none() {
+                                    let range = router_config.freq_range.map(|(min, max)| min..=max);
+                                    scheduler = DownlinkScheduler::with_freq_range(range);
+                                }
+                                router_config_sender.send_replace(Some(router_config));
+                                backoff = config.backoff_base;
+                            }
+                            Some("dnmsg") => {
+                                let Some(router_config) = router_config_sender.borrow().clone() else {
+                                    warn!("dnmsg before router_config");
+                                    continue;
+                                };
+                                match schedule_dnmsg(&msg, &router_config, &mut scheduler) {
+                                    Ok(scheduled) => {
+                                        let xtime = ((session as i64) << 48)
+                                            | ((tmst_now() as i64) & 0xffff_ffff);
+                                        let dntxed = json!({
+                                            "msgtype": "dntxed",
+                                            "diid": msg.get("diid"),
+                                            "DevEui": msg.get("DevEui"),
+                                            "rctx": msg.get("rctx").cloned().unwrap_or(json!(0)),
+                                            "xtime": xtime,
+                                            "txtime": 0.0,
+                                            "gpstime": 0,
+                                        });
+                                        let start = scheduled.start;
+                                        if downlink_sender.try_send(scheduled).is_err() {
+                                            warn!("no transmitter attached, dropping dnmsg");
+                                            continue;
+                                        }
+                                        let idx = pending
+                                            .iter()
+                                            .position(|(s, _)| (start.wrapping_sub(*s) as i32) < 0)
+                                            .unwrap_or(pending.len());
+                                        pending.insert(idx, (start, dntxed));
+                                    }
+                                    Err(e) => warn!("dropping dnmsg: {}", e),
+                                }
+                            }
+                            Some(other) => debug!("ignoring {} message", other),
+                            None => warn!("message without msgtype: {}", msg),
+                        }
+                    }
+                }
+            }
+        }
+        .await;
+
+        match result {
+            Ok(()) => return,
+            Err(e) => warn!("LNS connection failed: {}", e),
+        }
+        tokio::select! {
+            _ = shutdown.clone() => return,
+            _ = tokio::time::sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(config.backoff_max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downlink::payload_duration;
+
+    fn router_config() -> RouterConfig {
+        serde_json::from_value(json!({
+            "msgtype": "router_config",
+            "region": "EU863",
+            "freq_range": [863000000, 870000000],
+            "DRs": [
+                [12, 125, 0], [11, 125, 0], [10, 125, 0], [9, 125, 0],
+                [8, 125, 0], [7, 125, 0], [7, 250, 0], [0, 0, 0],
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn dnmsg(xtime: u32) -> Value {
+        json!({
+            "msgtype": "dnmsg",
+            "DevEui": "00-00-00-00-00-00-00-01",
+            "dC": 0,
+            "diid": 1,
+            "pdu": "60F17DBE4920020001",
+            "RxDelay": 1,
+            "RX1DR": 5,
+            "RX1Freq": 868100000,
+            "RX2DR": 0,
+            "RX2Freq": 869525000,
+            "xtime": (7i64 << 48) | xtime as i64,
+            "rctx": 0,
+        })
+    }
+
+    #[test]
+    fn dnmsg_rx1() {
+        let mut scheduler = DownlinkScheduler::new();
+        let xtime = tmst_now();
+        let scheduled = schedule_dnmsg(&dnmsg(xtime), &router_config(), &mut scheduler).unwrap();
+        assert_eq!(scheduled.start, xtime.wrapping_add(1_000_000));
+        let downlink = scheduled.downlink;
+        assert_eq!(
+            downlink.payload,
+            [0x60, 0xf1, 0x7d, 0xbe, 0x49, 0x20, 0x02, 0x00, 0x01]
+        );
+        assert_eq!(downlink.freq, 868_100_000);
+        assert_eq!(
+            (downlink.sf, downlink.bw),
+            (SpreadingFactor::SF7, Bandwidth::BW125)
+        );
+        assert!(downlink.invert_iq && !downlink.has_crc);
+    }
+
+    #[test]
+    fn dnmsg_falls_back_to_rx2() {
+        let mut scheduler = DownlinkScheduler::new();
+        // RX1 is due now, too late for the TX chain
+        let xtime = tmst_now().wrapping_sub(1_000_000);
+        let scheduled = schedule_dnmsg(&dnmsg(xtime), &router_config(), &mut scheduler).unwrap();
+        assert_eq!(scheduled.start, xtime.wrapping_add(2_000_000));
+        assert_eq!(scheduled.downlink.freq, 869_525_000);
+        assert_eq!(scheduled.downlink.sf, SpreadingFactor::SF12);
+    }
+
+    #[test]
+    fn dnmsg_outside_tx_range() {
+        let mut scheduler = DownlinkScheduler::with_freq_range(Some(869_400_000..=869_650_000));
+        let mut msg = dnmsg(tmst_now());
+        msg["RX2Freq"] = json!(868_100_000);
+        assert!(schedule_dnmsg(&msg, &router_config(), &mut scheduler).is_err());
+    }
+
+    #[test]
+    fn updf_at_end_of_reception() {
+        let phy = from_hex("40F17DBE4900020001954378762B11FF0D").unwrap();
+        let mut payload = phy.clone();
+        payload.extend_from_slice(&[0xaa, 0xbb]);
+        let header_start = 1_700_000_000_000_000_000u64;
+        let annotations = HashMap::from([
+            ("payload".to_string(), Pmt::Blob(payload)),
+            ("sf".to_string(), Pmt::Usize(7)),
+            ("bw".to_string(), Pmt::U32(125)),
+            ("freq".to_string(), Pmt::F64(868.1e6)),
+            ("snr".to_string(), Pmt::F64(7.5)),
+            ("rssi".to_string(), Pmt::F64(-87.4)),
+            ("timestamp".to_string(), Pmt::U64(header_start)),
+            ("code_rate".to_string(), Pmt::Usize(1)),
+            ("has_crc".to_string(), Pmt::Bool(true)),
+        ]);
+        let uplink = Uplink::from_annotations(&annotations).unwrap();
+        assert_eq!(uplink.payload, phy);
+        let msg = uplink.to_message(&router_config(), 7).unwrap();
+
+        let remainder = payload_duration(
+            SpreadingFactor::SF7,
+            Bandwidth::BW125,
+            CodeRate::CR_4_5,
+            true,
+            phy.len(),
+        );
+        let end_micros = (header_start + remainder.as_nanos() as u64) / 1000;
+        assert_eq!(msg["msgtype"], "updf");
+        assert_eq!(msg["DevAddr"], 0x49be7df1);
+        assert_eq!(msg["FCnt"], 2);
+        assert_eq!(msg["DR"], 5);
+        assert_eq!(msg["Freq"], 868_100_000);
+        assert_eq!(
+            msg["upinfo"]["xtime"],
+            (7i64 << 48) | (end_micros & 0xffff_ffff_ffff) as i64
+        );
+        assert_eq!(msg["upinfo"]["rssi"], -87);
+        assert_eq!(msg["upinfo"]["snr"], 7.5);
+    }
+}
@@ -0,0 +1,150 @@
+//! Minimal LoRa Basics Station LNS stand-in for testing `BasicStationClient`.
+//!
+//! Answers router-info requests, sends an EU868 `router_config` and prints every message of the station.
+use anyhow::Result;
+use clap::Parser;
+use futures::SinkExt;
+use futures::StreamExt;
+use serde_json::Value;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+use tokio_tungstenite::tungstenite::handshake::server::Response;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Listen address
+    #[clap(long, default_value = "127.0.0.1:6090")]
+    bind: String,
+    /// Answer every uplink with a class A downlink carrying this PDU (hex)
+    #[clap(long)]
+    downlink_pdu: Option<String>,
+}
+
+fn router_config() -> Value {
+    let mut sx1301 = json!({
+        "radio_0": { "enable": true, "freq": 867_500_000 },
+        "radio_1": { "enable": true, "freq": 868_500_000 },
+        "chan_Lora_std": { "enable": false, "radio": 1, "if": 0 },
+    });
+    let channels = [
+        (1, -400_000),
+        (1, -200_000),
+        (1, 0),
+        (0, -400_000),
+        (0, -200_000),
+        (0, 0),
+        (0, 200_000),
+        (0, 400_000),
+    ];
+    for (i, (radio, offset)) in channels.iter().enumerate() {
+        sx1301[format!("chan_multiSF_{i}")] =
+            json!({ "enable": true, "radio": radio, "if": offset });
+    }
+    let mut drs = vec![
+        json!([12, 125, 0]),
+        json!([11, 125, 0]),
+        json!([10, 125, 0]),
+        json!([9, 125, 0]),
+        json!([8, 125, 0]),
+        json!([7, 125, 0]),
+        json!([7, 250, 0]),
+        json!([0, 0, 0]),
+    ];
+    drs.resize(16, json!([-1, 0, 0]));
+    json!({
+        "msgtype": "router_config",
+        "NetID": null,
+        "JoinEui": null,
+        "region": "EU863",
+        "hwspec": "sx1301/1",
+        "freq_range": [863_000_000, 870_000_000],
+        "DRs": drs,
+        "sx1301_conf": [sx1301],
+        "nocca": true,
+        "nodc": true,
+        "nodwell": true,
+    })
+}
+
+async fn handle(stream: TcpStream, args: &Args) -> Result<()> {
+    let local = stream.local_addr()?;
+    let mut path = String::new();
+    let mut ws = accept_hdr_async(stream, |req: &Request, resp: Response| {
+        path = req.uri().path().to_string();
+        Ok(resp)
+    })
+    .await?;
+
+    if path == "/router-info" {
+        if let Some(Message::Text(text)) = ws.next().await.transpose()? {
+            let req: Value = serde_json::from_str(&text)?;
+            let router = req.get("router").cloned().unwrap_or(Value::Null);
+            println!("router-info request for {router}");
+            let id = router.as_str().unwrap_or("0").replace(':', "-");
+            let answer = json!({
+                "router": router,
+                "muxs": "muxs-::0",
+                "uri": format!("ws://{local}/router-{id}"),
+            });
+            ws.send(Message::text(answer.to_string())).await?;
+        }
+        return Ok(());
+    }
+
+    println!("station connected on {path}");
+    while let Some(msg) = ws.next().await {
+        let Message::Text(text) = msg? else {
+            continue;
+        };
+        let msg: Value = serde_json::from_str(&text)?;
+        println!("{msg}");
+        match msg.get("msgtype").and_then(Value::as_str) {
+            Some("version") => {
+                ws.send(Message::text(router_config().to_string())).await?;
+            }
+            Some("updf") | Some("jreq") | Some("propdf") => {
+                if let Some(pdu) = &args.downlink_pdu {
+                    let dnmsg = json!({
+                        "msgtype": "dnmsg",
+                        "DevEui": "00-00-00-00-00-00-00-00",
+                        "dC": 0,
+                        "diid": rand::random::<u16>(),
+                        "pdu": pdu,
+                        "RxDelay": 1,
+                        "RX1DR": msg.get("DR"),
+                        "RX1Freq": msg.get("Freq"),
+                        "RX2DR": 0,
+                        "RX2Freq": 869_525_000,
+                        "priority": 0,
+                        "xtime": msg["upinfo"]["xtime"],
+                        "rctx": msg["upinfo"]["rctx"],
+                    });
+                    ws.send(Message::text(dnmsg.to_string())).await?;
+                }
+            }
+            _ => {}
+        }
+    }
+    println!("station disconnected");
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let listener = TcpListener::bind(&args.bind).await?;
+    println!("listening on ws://{}", args.bind);
+    let args: &'static Args = Box::leak(Box::new(args));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, args).await {
+                println!("connection from {peer} failed: {e}");
+            }
+        });
+    }
+}
@@ -3,6 +3,7 @@ use futuresdr::prelude::*;
 use crossbeam_channel::{unbounded};
 use lora::utils::{Channel, Bandwidth, SpreadingFactor};
 use lora::{ChannelProcessor, IqFrame, Node};
+use lora::mac::MacConfig;
 use lora::meshtastic::MeshtasticConfig;
 
 #[tokio::main]
@@ -41,7 +42,7 @@ async fn main() -> Result<()> {
         tx_node_pub,
         55554,
         55555,
- 
+        MacConfig::default(),
     );
     let node2 = Node::new(
         channel,
@@ -56,7 +57,7 @@ async fn main() -> Result<()> {
         tx_node_pub2,
         55556,
         55557,
-
+        MacConfig::default(),
     );
 
     let mut rt = Runtime::new();
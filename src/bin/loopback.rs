@@ -46,6 +46,9 @@ struct Args {
     /// LoRa Code Rate
     #[clap(long, value_enum, default_value_t = CodeRate::CR_4_5)]
     code_rate: CodeRate,
+    /// Inverted IQ (downlink polarity)
+    #[clap(long, default_value_t = false)]
+    invert_iq: bool,
 }
 
 const HAS_CRC: bool = true;
@@ -72,6 +75,7 @@ fn main() -> Result<()> {
         vec![args.sync_word],
         PREAMBLE_LEN,
         PAD,
+        args.invert_iq,
     );
 
     // ==============================================================
@@ -88,6 +92,7 @@ fn main() -> Result<()> {
         None,
         false,
         None,
+        args.invert_iq,
     );
     let fft_demod: FftDemod = FftDemod::new(args.spreading_factor, ldro(args.spreading_factor));
     let gray_mapping: GrayMapping = GrayMapping::new();
@@ -0,0 +1,276 @@
+//! Simulated LoRaWAN class A end device and gateway on a shared channel.
+//!
+//! The gateway forwards to a network server over the Packet Forwarder protocol, e.g. the
+//! `network_server` binary, and transmits its downlinks back to the device.
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use anyhow::anyhow;
+use clap::Parser;
+use crossbeam_channel::unbounded;
+use futuresdr::blocks::MessageAnnotator;
+use futuresdr::prelude::*;
+
+use lora::ChannelProcessor;
+use lora::ChannelPublisher;
+use lora::ChannelSubscriber;
+use lora::Decoder;
+use lora::Deinterleaver;
+use lora::FftDemod;
+use lora::FrameSync;
+use lora::GrayMapping;
+use lora::HammingDecoder;
+use lora::HeaderDecoder;
+use lora::HeaderMode;
+use lora::IqFrame;
+use lora::Node;
+use lora::PacketForwarderClient;
+use lora::Transmitter;
+use lora::default_values::CODE_RATE_LORAWAN;
+use lora::default_values::HAS_CRC;
+use lora::default_values::OVERSAMPLING_TX;
+use lora::default_values::PAD_SYMBOLS;
+use lora::default_values::PREAMBLE_LEN;
+use lora::default_values::SYNC_WORD_PUBLIC;
+use lora::default_values::ldro;
+use lora::downlink::transmit_downlinks;
+use lora::end_device::Activation;
+use lora::end_device::DeviceEvent;
+use lora::end_device::EndDeviceConfig;
+use lora::lorawan_keys::OtaaDevice;
+use lora::lorawan_keys::parse_key;
+use lora::mac::MacConfig;
+use lora::packet_forwarder_client::ForwarderConfig;
+use lora::utils::Bandwidth;
+use lora::utils::Channel;
+use lora::utils::SpreadingFactor;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Network server (Packet Forwarder protocol)
+    #[clap(long, default_value = "127.0.0.1:1700")]
+    server: String,
+    /// DevEUI of an OTAA device (hex)
+    #[clap(long, requires_all = ["join_eui", "app_key"])]
+    dev_eui: Option<String>,
+    /// JoinEUI of the OTAA device (hex)
+    #[clap(long)]
+    join_eui: Option<String>,
+    /// AppKey of the OTAA device (hex)
+    #[clap(long)]
+    app_key: Option<String>,
+    /// DevAddr of an ABP device (hex)
+    #[clap(long, conflicts_with = "dev_eui", requires_all = ["nwk_s_key", "app_s_key"])]
+    dev_addr: Option<String>,
+    /// NwkSKey of the ABP device (hex)
+    #[clap(long)]
+    nwk_s_key: Option<String>,
+    /// AppSKey of the ABP device (hex)
+    #[clap(long)]
+    app_s_key: Option<String>,
+    /// Spreading factor of device and gateway
+    #[clap(long, value_enum, default_value_t = SpreadingFactor::SF7)]
+    spreading_factor: SpreadingFactor,
+    /// Seconds between uplinks
+    #[clap(long, default_value_t = 30)]
+    interval: u64,
+    /// Application port of the uplinks
+    #[clap(long, default_value_t = 1)]
+    fport: u8,
+    /// Send confirmed uplinks
+    #[clap(long)]
+    confirmed: bool,
+    /// Noise standard deviation of the channel
+    #[clap(long, default_value_t = 2e-6)]
+    sigma: f32,
+    /// Distance between device and gateway, sets the path loss of the channel
+    #[clap(long, default_value_t = 25.0)]
+    distance: f32,
+}
+
+const CHANNEL: Channel = Channel::EU868_1;
+const BANDWIDTH: Bandwidth = Bandwidth::BW125;
+const GATEWAY_MAC: &str = "0200.0000.0403.0201";
+
+fn activation(args: &Args) -> Result<Activation> {
+    let hex = |s: &Option<String>| -> Result<u64> {
+        let s = s.as_deref().ok_or_else(|| anyhow!("missing argument"))?;
+        Ok(u64::from_str_radix(s, 16)?)
+    };
+    let key = |s: &Option<String>| parse_key(s.as_deref().unwrap_or_default());
+    if args.dev_eui.is_some() {
+        Ok(Activation::Otaa(OtaaDevice {
+            name: String::from("sim"),
+            dev_eui: hex(&args.dev_eui)?,
+            join_eui: hex(&args.join_eui)?,
+            app_key: key(&args.app_key)?,
+            nwk_key: None,
+        }))
+    } else if args.dev_addr.is_some() {
+        Ok(Activation::Abp {
+            dev_addr: hex(&args.dev_addr)? as u32,
+            nwk_s_key: key(&args.nwk_s_key)?,
+            app_s_key: key(&args.app_s_key)?,
+        })
+    } else {
+        Err(anyhow!("either --dev-eui or --dev-addr is required"))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let sf = args.spreading_factor;
+    let mut config = EndDeviceConfig::new(activation(&args)?);
+    config.data_rate = config
+        .region
+        .find_data_rate(sf, BANDWIDTH)
+        .ok_or_else(|| anyhow!("no {} data rate for {sf:?}", config.region))?;
+
+    let (device_tx, device_tx_sub) = unbounded::<IqFrame>();
+    let (device_rx_pub, device_rx) = unbounded::<IqFrame>();
+    let (gateway_tx, gateway_tx_sub) = unbounded::<IqFrame>();
+    let (gateway_rx_pub, gateway_rx) = unbounded::<IqFrame>();
+
+    let mut device = Node::new(
+        CHANNEL,
+        BANDWIDTH,
+        sf,
+        ldro(sf),
+        SYNC_WORD_PUBLIC as u8,
+        OVERSAMPLING_TX,
+        args.sigma,
+        false,
+        device_rx,
+        device_tx,
+        55560,
+        55561,
+        MacConfig::default(),
+    )?;
+
+    // the device follows ADR, so both ends decode all spreading factors
+    for rx_sf in (7..=12).filter_map(|sf| SpreadingFactor::try_from(sf).ok()) {
+        if rx_sf != sf {
+            device.add_rx_chain(rx_sf, ldro(rx_sf))?;
+        }
+    }
+
+    // gateway: single channel, forwarding to the network server
+    let mut fg = Flowgraph::new();
+    let subscriber = ChannelSubscriber::<DefaultCpuWriter<Complex32>>::new(gateway_rx);
+    let mut packet_forwarder =
+        PacketForwarderClient::new(GATEWAY_MAC, &args.server, ForwarderConfig::default())?;
+    let downlinks = packet_forwarder
+        .take_downlinks()
+        .ok_or_else(|| anyhow!("no downlink receiver"))?;
+    let transmitter: Transmitter = Transmitter::new(
+        CODE_RATE_LORAWAN,
+        HAS_CRC,
+        sf,
+        ldro(sf),
+        false,
+        OVERSAMPLING_TX,
+        vec![SYNC_WORD_PUBLIC],
+        PREAMBLE_LEN,
+        PAD_SYMBOLS,
+        false,
+    );
+    let publisher = ChannelPublisher::<DefaultCpuReader<Complex32>>::new(gateway_tx);
+    connect!(fg,
+        transmitter > publisher;
+        transmitter.tx_done | tx_done.packet_forwarder;
+    );
+    let subscriber = fg.add_block(subscriber);
+    let stream_start_time = SystemTime::now();
+    for sf in (7..=12).filter_map(|sf| SpreadingFactor::try_from(sf).ok()) {
+        let frame_sync: FrameSync = FrameSync::new(
+            CHANNEL,
+            BANDWIDTH,
+            sf,
+            false,
+            vec![vec![SYNC_WORD_PUBLIC]],
+            OVERSAMPLING_TX,
+            None,
+            None,
+            false,
+            Some(stream_start_time),
+            false,
+        );
+        let fft_demod: FftDemod = FftDemod::new(sf, ldro(sf));
+        let gray_mapping: GrayMapping = GrayMapping::new();
+        let deinterleaver: Deinterleaver = Deinterleaver::new(ldro(sf), sf);
+        let hamming_dec: HammingDecoder = HammingDecoder::new();
+        let header_decoder = HeaderDecoder::new(HeaderMode::Explicit, ldro(sf));
+        let decoder = Decoder::new();
+        let tags: HashMap<String, Pmt> = HashMap::from([
+            (String::from("sf"), Pmt::U32(sf.into())),
+            (
+                String::from("bw"),
+                Pmt::U32(Into::<u32>::into(BANDWIDTH) / 1000),
+            ),
+            (String::from("freq"), Pmt::F64(Into::<f64>::into(CHANNEL))),
+        ]);
+        let metadata_tagger = MessageAnnotator::new(tags, None);
+        let subscriber = subscriber.clone();
+        let packet_forwarder = packet_forwarder.clone();
+        connect!(fg,
+            subscriber > frame_sync > fft_demod > gray_mapping > deinterleaver > hamming_dec > header_decoder;
+            header_decoder.frame_info | frame_info.frame_sync;
+            header_decoder | decoder;
+            decoder.out_annotated | metadata_tagger;
+            metadata_tagger | packet_forwarder;
+        );
+    }
+
+    let mut rt = Runtime::new();
+    let (_gateway, gateway_handle) = rt.start_sync(fg)?;
+    let device = device
+        .start_end_device(&mut rt, config)
+        .map_err(|e| anyhow!("{e}"))?;
+
+    let distances = vec![vec![0.1, args.distance], vec![args.distance, 0.1]];
+    let channel = ChannelProcessor::new(
+        vec![device_tx_sub, gateway_tx_sub],
+        vec![device_rx_pub, gateway_rx_pub],
+        distances,
+    );
+    channel.spawn_task();
+    tokio::spawn(transmit_downlinks(
+        gateway_handle,
+        transmitter.into(),
+        None,
+        Into::<usize>::into(BANDWIDTH) * OVERSAMPLING_TX,
+        downlinks,
+    ));
+
+    let mut events = device.subscribe();
+    let mut interval = tokio::time::interval(Duration::from_secs(args.interval));
+    let mut counter: u32 = 0;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                device.send(args.fport, counter.to_be_bytes().to_vec(), args.confirmed);
+                counter += 1;
+            }
+            Some(event) = events.recv() => match event {
+                DeviceEvent::Joined { dev_addr } => println!("joined as {dev_addr:08X}"),
+                DeviceEvent::JoinFailed => println!("no Join-Accept, retrying"),
+                DeviceEvent::TxDone { fcnt } => println!("uplink FCnt {fcnt} sent"),
+                DeviceEvent::Acked { fcnt } => println!("uplink FCnt {fcnt} acknowledged"),
+                DeviceEvent::Unacked { fcnt } => println!("uplink FCnt {fcnt} not acknowledged"),
+                DeviceEvent::Downlink(frame) => println!(
+                    "downlink FCnt {} FPort {:?} payload {:02x?}",
+                    frame.fcnt, frame.fport, frame.payload
+                ),
+                DeviceEvent::LinkAdr { data_rate, tx_power, nb_trans } => {
+                    println!("ADR: DR{data_rate} TXPower {tx_power} NbTrans {nb_trans}")
+                }
+                DeviceEvent::LinkCheck { margin, gw_cnt } => {
+                    println!("link check: margin {margin} dB, {gw_cnt} gateway(s)")
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Simulated Meshtastic mesh with the client API of every node on TCP.
+//!
+//! Connect the Python CLI (`meshtastic --host 127.0.0.1:4403`), the web client or the apps to the nodes and
+//! send messages between them through the simulated channel.
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use anyhow::anyhow;
+use clap::Parser;
+use crossbeam_channel::unbounded;
+use futuresdr::prelude::*;
+
+use lora::ChannelProcessor;
+use lora::IqFrame;
+use lora::Node;
+use lora::mac::MacConfig;
+use lora::meshtastic::MeshtasticConfig;
+use lora::meshtastic_api::API_PORT;
+use lora::meshtastic_api::ApiConfig;
+use lora::meshtastic_pki::PkiKeys;
+use lora::meshtastic_router::RouterConfig;
+use lora::meshtastic_router::RouterEvent;
+use lora::meshtastic_url::ChannelUrl;
+
+const SYNC_WORD: u8 = 0x2b;
+const OVERSAMPLING: usize = 4;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Number of nodes, node i serves the API on the base port + i
+    #[clap(long, default_value_t = 2)]
+    nodes: usize,
+    /// TCP port of the first node
+    #[clap(long, default_value_t = API_PORT)]
+    port: u16,
+    /// channel URL (https://meshtastic.org/e/#...) of all nodes, defaults to LongFast EU
+    #[clap(long)]
+    url: Option<String>,
+    /// Distance between neighbouring nodes
+    #[clap(long, default_value_t = 25.0)]
+    distance: f32,
+    /// Noise standard deviation of the channel
+    #[clap(long, default_value_t = 2e-6)]
+    sigma: f32,
+    /// Directory for the node databases and PKI keys of the nodes
+    #[clap(long)]
+    state_dir: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let url = match &args.url {
+        Some(url) => ChannelUrl::parse(url)?,
+        None => ApiConfig::default_channel_url(MeshtasticConfig::LongFastEu),
+    };
+    let (bandwidth, spreading_factor, _, channel, ldro) = url
+        .to_config()
+        .ok_or_else(|| anyhow!("channel URL without known region and preset"))?;
+
+    let mut nodes = Vec::new();
+    let mut tx_subs = Vec::new();
+    let mut rx_pubs = Vec::new();
+    for i in 0..args.nodes {
+        let (tx_pub, tx_sub) = unbounded::<IqFrame>();
+        let (rx_pub, rx_sub) = unbounded::<IqFrame>();
+        tx_subs.push(tx_sub);
+        rx_pubs.push(rx_pub);
+        let node = Node::new(
+            channel,
+            bandwidth,
+            spreading_factor,
+            ldro,
+            SYNC_WORD,
+            OVERSAMPLING,
+            args.sigma,
+            false,
+            rx_sub,
+            tx_pub,
+            55600 + 2 * i as u16,
+            55601 + 2 * i as u16,
+            MacConfig::default(),
+        )?;
+        nodes.push(node);
+    }
+
+    // nodes on a line, every node only reaches its neighbours directly
+    let n = args.nodes;
+    let mut distances = vec![vec![0.1f32; n]; n];
+    for (i, row) in distances.iter_mut().enumerate() {
+        for (j, d) in row.iter_mut().enumerate() {
+            if i != j {
+                *d = args.distance * i.abs_diff(j) as f32;
+            }
+        }
+    }
+
+    let mut rt = Runtime::new();
+    let mut routers = Vec::new();
+    for (i, node) in nodes.iter_mut().enumerate() {
+        node.start(&mut rt, true).map_err(|e| anyhow!("{e}"))?;
+        let random_num = rand::random::<u32>() | 1;
+        let mut api = match &args.state_dir {
+            Some(dir) => {
+                // the key file keeps the node number across runs
+                let pki_path = dir.join(format!("node{i}_keys.json"));
+                let keys = if pki_path.exists() {
+                    PkiKeys::load(&pki_path)?
+                } else {
+                    PkiKeys::load_or_generate(&pki_path, random_num)?
+                };
+                let mut api = ApiConfig::new(keys.node_num(), url.clone());
+                api.pki = Some(keys);
+                api.pki_path = Some(pki_path);
+                api.node_db = Some(dir.join(format!("node{i}_nodes.json")));
+                api
+            }
+            None => ApiConfig::new(random_num, url.clone()),
+        };
+        let node_num = api.node_num;
+        api.addr = SocketAddr::from(([0, 0, 0, 0], args.port + i as u16));
+        let router = RouterConfig::new(node_num, spreading_factor, bandwidth);
+        routers.push(node.meshtastic_api_create(router, api)?);
+        println!("node {i}: !{node_num:08x} on port {}", args.port + i as u16);
+    }
+
+    ChannelProcessor::new(tx_subs, rx_pubs, distances).spawn_task();
+
+    let mut events = routers[0].subscribe();
+    while let Some(event) = events.recv().await {
+        if let RouterEvent::Received { packet, snr } = event {
+            println!(
+                "node 0: !{:08x} -> !{:08x} (id {:08x}, snr {:?})",
+                packet.sender, packet.dest, packet.packet_id, snr
+            );
+        }
+    }
+    Ok(())
+}
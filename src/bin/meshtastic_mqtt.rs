@@ -0,0 +1,186 @@
+//! Watch and inject Meshtastic MQTT envelopes, e.g. to test `rx_meshtastic_all_channels --mqtt` against a
+//! local broker like mosquitto.
+use std::time::Duration;
+
+use anyhow::Result;
+use base64::prelude::*;
+use clap::Parser;
+use meshtastic::Message;
+use meshtastic::protobufs;
+use meshtastic::protobufs::PortNum;
+use meshtastic::protobufs::mesh_packet;
+use rumqttc::AsyncClient;
+use rumqttc::Event;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::QoS;
+
+use lora::meshtastic::BROADCAST;
+use lora::meshtastic::DEFAULT_HOP_LIMIT;
+use lora::meshtastic::MeshPacket;
+use lora::meshtastic::MeshtasticChannel;
+use lora::meshtastic::MeshtasticEvent;
+use lora::meshtastic::parse_node_num;
+use lora::meshtastic_region::MeshtasticRegion;
+use lora::meshtastic_region::PRESETS;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// MQTT broker (host:port)
+    #[clap(long, default_value = "localhost:1883")]
+    mqtt: String,
+    /// Meshtastic region of the topics
+    #[clap(short, long, value_enum, default_value_t = MeshtasticRegion::EU_868)]
+    meshtastic_region: MeshtasticRegion,
+    /// MQTT root topic, msh/<region> by default
+    #[clap(long)]
+    root_topic: Option<String>,
+    /// channel ID of the topics, the preset name for the unnamed channel
+    #[clap(long, default_value = "LongFast")]
+    channel: String,
+    /// channel key (base64)
+    #[clap(short, long, default_value = "AQ==")]
+    key: String,
+    /// publish this text as downlink envelope instead of printing envelopes
+    #[clap(long)]
+    send: Option<String>,
+    /// sender of the text (!hex, 0xhex or decimal), also used as gateway ID
+    #[clap(long, value_parser = parse_node_num, default_value = "!3a48290e")]
+    from: u32,
+    /// publish the packet encrypted instead of decoded
+    #[clap(long)]
+    encrypted: bool,
+}
+
+/// Decode an encrypted envelope with the channel key, decoded envelopes as they are.
+fn describe(envelope: &protobufs::ServiceEnvelope, chan: &MeshtasticChannel) -> String {
+    let Some(packet) = &envelope.packet else {
+        return "envelope without packet".to_string();
+    };
+    let frame = MeshPacket {
+        dest: packet.to,
+        sender: packet.from,
+        packet_id: packet.id,
+        hop_limit: packet.hop_limit as u8,
+        want_ack: packet.want_ack,
+        via_mqtt: packet.via_mqtt,
+        hop_start: packet.hop_start as u8,
+        channel_hash: packet.channel as u8,
+        next_hop: 0,
+        relay_node: 0,
+        data: Vec::new(),
+    };
+    let data = match &packet.payload_variant {
+        Some(mesh_packet::PayloadVariant::Decoded(data)) => Some(data.clone()),
+        Some(mesh_packet::PayloadVariant::Encrypted(bytes)) => chan.decrypt(&MeshPacket {
+            data: bytes.clone(),
+            ..frame.clone()
+        }),
+        None => None,
+    };
+    match data {
+        Some(data) => {
+            let event = MeshtasticEvent::new(&frame, &envelope.channel_id, &data);
+            format!("{} via {}", event, envelope.gateway_id)
+        }
+        None => format!(
+            "!{:08x} -> !{:08x} (id {:08x}) via {}: not decodable",
+            packet.from, packet.to, packet.id, envelope.gateway_id
+        ),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    // the unnamed channel is published with the preset name, which is also its hash name
+    let name = if PRESETS.iter().any(|p| p.name == args.channel) {
+        ""
+    } else {
+        args.channel.as_str()
+    };
+    let psk = BASE64_STANDARD.decode(args.key.trim())?;
+    let chan = MeshtasticChannel::from_psk(name, &args.channel, &psk)?;
+    let root = args
+        .root_topic
+        .clone()
+        .unwrap_or_else(|| format!("msh/{}", args.meshtastic_region));
+    let gateway_id = format!("!{:08x}", args.from);
+
+    let (host, port) = args
+        .mqtt
+        .rsplit_once(':')
+        .unwrap_or((args.mqtt.as_str(), "1883"));
+    let mut options =
+        MqttOptions::new(format!("meshtastic-mqtt-{gateway_id}"), host, port.parse()?);
+    options.set_keep_alive(Duration::from_secs(30));
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+
+    if let Some(text) = &args.send {
+        let data = protobufs::Data {
+            portnum: PortNum::TextMessageApp as i32,
+            payload: text.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let mut packet = protobufs::MeshPacket {
+            from: args.from,
+            to: BROADCAST,
+            id: rand::random::<u32>() | 1,
+            hop_limit: DEFAULT_HOP_LIMIT as u32,
+            hop_start: DEFAULT_HOP_LIMIT as u32,
+            payload_variant: Some(mesh_packet::PayloadVariant::Decoded(data.clone())),
+            ..Default::default()
+        };
+        if args.encrypted {
+            let mut frame = MeshPacket {
+                dest: packet.to,
+                sender: packet.from,
+                packet_id: packet.id,
+                hop_limit: DEFAULT_HOP_LIMIT,
+                want_ack: false,
+                via_mqtt: false,
+                hop_start: DEFAULT_HOP_LIMIT,
+                channel_hash: 0,
+                next_hop: 0,
+                relay_node: 0,
+                data: Vec::new(),
+            };
+            chan.encrypt(&mut frame, &data);
+            packet.channel = frame.channel_hash as u32;
+            packet.payload_variant = Some(mesh_packet::PayloadVariant::Encrypted(frame.data));
+        }
+        let envelope = protobufs::ServiceEnvelope {
+            packet: Some(packet),
+            channel_id: args.channel.clone(),
+            gateway_id: gateway_id.clone(),
+        };
+        let topic = format!("{root}/2/e/{}/{gateway_id}", args.channel);
+        client
+            .publish(
+                topic.as_str(),
+                QoS::AtLeastOnce,
+                false,
+                envelope.encode_to_vec(),
+            )
+            .await?;
+        // drive the event loop until the broker acknowledged the message
+        loop {
+            if let Event::Incoming(Packet::PubAck(_)) = eventloop.poll().await? {
+                println!("published to {topic}");
+                return Ok(());
+            }
+        }
+    }
+
+    let topic = format!("{root}/2/+/{}/#", args.channel);
+    client.subscribe(topic.as_str(), QoS::AtMostOnce).await?;
+    println!("watching {topic}");
+    loop {
+        if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await? {
+            match protobufs::ServiceEnvelope::decode(&*publish.payload) {
+                Ok(envelope) => println!("{}: {}", publish.topic, describe(&envelope, &chan)),
+                Err(e) => println!("{}: invalid envelope: {e}", publish.topic),
+            }
+        }
+    }
+}
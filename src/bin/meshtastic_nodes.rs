@@ -0,0 +1,84 @@
+use anyhow::Result;
+use anyhow::anyhow;
+use chrono::DateTime;
+use clap::Parser;
+use lora::meshtastic::parse_node_num;
+use lora::meshtastic_nodedb::NodeDb;
+use lora::meshtastic_nodedb::NodeEntry;
+use std::path::PathBuf;
+
+/// List the nodes of a database written by `rx_meshtastic --node-db`.
+#[derive(Parser, Debug)]
+struct Args {
+    /// node database (JSON)
+    db: PathBuf,
+    /// show a single node (!hex, 0xhex or decimal)
+    #[clap(long, value_parser = parse_node_num)]
+    node: Option<u32>,
+    /// only nodes heard within the last seconds
+    #[clap(long)]
+    within: Option<u64>,
+    /// print JSON instead of a table
+    #[clap(long)]
+    json: bool,
+}
+
+fn format_time(secs: u64) -> String {
+    DateTime::from_timestamp(secs as i64, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn print_table(nodes: &[&NodeEntry]) {
+    println!(
+        "{:<10} {:<5} {:<24} {:<20} {:<19} {:>6} {:>4} {:>21}",
+        "ID", "Short", "Name", "Hardware", "Last heard", "SNR", "Hops", "Position"
+    );
+    for node in nodes {
+        let position = node
+            .position
+            .as_ref()
+            .map(|p| format!("{:.5},{:.5}", p.latitude, p.longitude))
+            .unwrap_or_default();
+        println!(
+            "{:<10} {:<5} {:<24} {:<20} {:<19} {:>6} {:>4} {:>21}",
+            node.id,
+            node.short_name.as_deref().unwrap_or(""),
+            node.long_name.as_deref().unwrap_or(""),
+            node.hw_model.as_deref().unwrap_or(""),
+            format_time(node.last_heard),
+            node.last_snr.map(|s| format!("{s:.1}")).unwrap_or_default(),
+            node.hops_away.map(|h| h.to_string()).unwrap_or_default(),
+            position,
+        );
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let db = NodeDb::load(&args.db)?;
+
+    if let Some(num) = args.node {
+        let node = db
+            .get(num)
+            .ok_or_else(|| anyhow!("node !{num:08x} not in database"))?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(node)?);
+        } else {
+            print_table(&[node]);
+        }
+        return Ok(());
+    }
+
+    let nodes = match args.within {
+        Some(seconds) => db.heard_within(seconds),
+        None => db.by_last_heard(),
+    };
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&nodes)?);
+    } else {
+        print_table(&nodes);
+        println!("{} of {} nodes", nodes.len(), db.len());
+    }
+    Ok(())
+}
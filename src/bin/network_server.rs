@@ -0,0 +1,74 @@
+//! Minimal LoRaWAN network server for local end-to-end tests with `rx_all_channels_eu --forward-addr`.
+use anyhow::Result;
+use clap::Parser;
+use lora::adr::AdrConfig;
+use lora::lorawan_keys::KeyStore;
+use lora::network_server::NetworkServer;
+use lora::network_server::NetworkServerConfig;
+use lora::network_server::NsEvent;
+use lora::region::Region;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Listen address
+    #[clap(long, default_value = "0.0.0.0:1700")]
+    bind: String,
+    /// LoRaWAN key file (JSON) with the devices allowed to join or send
+    #[clap(long)]
+    keys: String,
+    /// Time to collect copies of an uplink from several gateways in ms
+    #[clap(long, default_value_t = 200)]
+    dedup_ms: u64,
+    /// Regional plan of the devices
+    #[clap(long, value_enum, default_value_t = Region::EU868)]
+    region: Region,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let config = NetworkServerConfig {
+        bind: args.bind.parse()?,
+        dedup_window: Duration::from_millis(args.dedup_ms),
+        region: args.region,
+        adr: Some(AdrConfig::for_region(args.region)),
+        ..NetworkServerConfig::default()
+    };
+    let mut server = NetworkServer::bind(config, KeyStore::load(&args.keys)?).await?;
+    println!("listening on {}", server.local_addr()?);
+    let mut events = server.subscribe();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            match event {
+                NsEvent::Joined {
+                    device,
+                    dev_eui,
+                    dev_addr,
+                } => println!("{device} ({dev_eui:016X}) joined as {dev_addr:08X}"),
+                NsEvent::Uplink {
+                    device,
+                    fcnt,
+                    fport,
+                    payload,
+                    confirmed,
+                    gateways,
+                    ..
+                } => println!(
+                    "{device}: FCnt {fcnt} FPort {fport:?} payload {payload:02x?}{} via {gateways} gateway(s)",
+                    if confirmed { " (confirmed)" } else { "" }
+                ),
+                NsEvent::TxAck {
+                    gateway,
+                    error: Some(error),
+                } => println!("gateway {gateway:016x} rejected downlink: {error}"),
+                NsEvent::TxAck { .. } => {}
+                NsEvent::Adr { dev_addr, decision } => println!(
+                    "{dev_addr:08X}: LinkADRReq DR{} TXPower {} NbTrans {}",
+                    decision.data_rate, decision.tx_power, decision.nb_trans
+                ),
+            }
+        }
+    });
+    server.run().await
+}
@@ -47,6 +47,9 @@ struct Args {
     /// Oversampling Factor
     #[clap(long, default_value_t = 4)]
     oversampling: usize,
+    /// Receive inverted IQ, e.g., to listen to LoRaWAN downlinks
+    #[clap(long, default_value_t = false)]
+    invert_iq: bool,
 }
 
 const IMPLICIT_HEADER: bool = false;
@@ -74,6 +77,7 @@ fn main() -> Result<()> {
         Some("header_crc_ok"),
         false,
         None,
+        args.invert_iq,
     );
     let fft_demod: FftDemod = FftDemod::new(args.spreading_factor, ldro(args.spreading_factor));
     let gray_mapping: GrayMapping = GrayMapping::new();
@@ -3,30 +3,40 @@ use std::time::SystemTime;
 
 use anyhow::Result;
 use clap::Parser;
-use strum::IntoEnumIterator;
 
-use futuredsp::firdes::remez;
 use futuresdr::blocks::BlobToUdp;
-use futuresdr::blocks::MessageAnnotator;
-use futuresdr::blocks::NullSink;
-use futuresdr::blocks::PfbArbResampler;
-use futuresdr::blocks::PfbChannelizer;
+use futuresdr::blocks::MessagePipe;
 use futuresdr::blocks::seify::Builder;
+use futuresdr::channel::mpsc;
 use futuresdr::prelude::*;
 
-use lora::Decoder;
-use lora::Deinterleaver;
-use lora::FftDemod;
-use lora::FrameSync;
-use lora::GrayMapping;
-use lora::HammingDecoder;
-use lora::HeaderDecoder;
-use lora::HeaderMode;
 use lora::PacketForwarderClient;
-use lora::default_values::ldro;
-use lora::utils::Bandwidth;
-use lora::utils::Channel;
+use lora::Transmitter;
+use lora::basic_station::BasicStationClient;
+use lora::basic_station::BasicStationConfig;
+use lora::basic_station::parse_eui;
+use lora::chirpstack::ChirpstackClient;
+use lora::chirpstack::ChirpstackConfig;
+use lora::default_values::CODE_RATE_LORAWAN;
+use lora::default_values::HAS_CRC;
+use lora::default_values::OVERSAMPLING_TX;
+use lora::default_values::PAD_SYMBOLS;
+use lora::default_values::PREAMBLE_LEN;
+use lora::default_values::SYNC_WORD_PRIVATE;
+use lora::default_values::SYNC_WORD_PUBLIC;
+use lora::downlink::TxSink;
+use lora::downlink::transmit_downlinks;
+use lora::gateway_config::GatewayConfig;
+use lora::lorawan::LorawanFrame;
+use lora::lorawan::LorawanParser;
+use lora::lorawan_keys::DecryptedFrame;
+use lora::lorawan_keys::KeyStore;
+use lora::lorawan_keys::LorawanDecryptor;
+use lora::multi_channel_gateway::MultiChannelGateway;
+use lora::packet_forwarder_client::ForwarderConfig;
+use lora::region::Region;
 use lora::utils::SpreadingFactor;
+use lora::utils::sample_count;
 
 #[derive(Parser, Debug)]
 #[clap(version)]
@@ -43,171 +53,333 @@ struct Args {
     /// Socket Address of the Packet Forwarder Server, or None to simply print the frames to stdout
     #[clap(short, long)]
     forward_addr: Option<String>,
+    /// URI of a LoRa Basics Station LNS (e.g. ws://localhost:6090) to use instead of the Packet Forwarder protocol
+    #[clap(long, conflicts_with = "forward_addr")]
+    lns: Option<String>,
+    /// MQTT broker (host:port) of a ChirpStack network server, events are published as JSON
+    #[clap(long, conflicts_with_all = ["forward_addr", "lns"])]
+    mqtt: Option<String>,
+    /// ChirpStack MQTT topic prefix, e.g. eu868
+    #[clap(long, requires = "mqtt")]
+    mqtt_topic_prefix: Option<String>,
+    /// Print received LoRaWAN frames
+    #[clap(long)]
+    lorawan: bool,
+    /// LoRaWAN key file (JSON) to verify and decrypt frames of own devices, implies --lorawan
+    #[clap(long)]
+    keys: Option<String>,
+    /// Semtech global_conf.json providing gateway ID, server and channel plan
+    #[clap(long)]
+    config: Option<String>,
+    /// Semtech local_conf.json overriding values of the global config
+    #[clap(long, requires = "config")]
+    local_config: Option<String>,
+    /// Seify device args of the TX device, enables downlinks from the network server
+    #[clap(long)]
+    tx_args: Option<String>,
+    /// TX antenna
+    #[clap(long)]
+    tx_antenna: Option<String>,
+    /// TX Gain, used for downlinks at the maximum TX power
+    #[clap(long, default_value_t = 50.0)]
+    tx_gain: f64,
+    /// TX power in dBm reached with the TX gain, lower downlink powers reduce the gain accordingly
+    #[clap(long, default_value_t = 14)]
+    tx_max_power: i64,
+    /// Regional plan providing the channels, if no config or LNS router config gives them
+    #[clap(long, value_enum, default_value_t = Region::EU868)]
+    region: Region,
+    /// Sub-band (1-8) of regions with fixed channel plans like US915
+    #[clap(long)]
+    sub_band: Option<u8>,
+    /// Center frequency of the receiver, by default the middle of the channels
+    #[clap(long, requires = "sample_rate")]
+    center_freq: Option<f64>,
+    /// Sample rate of the receiver, a multiple of the 200kHz channel spacing
+    #[clap(long, requires = "center_freq")]
+    sample_rate: Option<f64>,
 }
 
-const CENTER_FREQ: f64 = 867_900_000.0;
-const NUM_CHANNELS: usize = 8;
-const NUM_CHANNELS_PADDED: usize = 9;
-const CHANNEL_SPACING: usize = 200_000;
-const BANDWIDTH: Bandwidth = Bandwidth::BW125;
-const OVERSAMPLING: usize = 4;
-const CHANNELS: [Channel; NUM_CHANNELS] = [
-    Channel::EU868_8,
-    Channel::EU868_1,
-    Channel::EU868_2,
-    Channel::EU868_3,
-    Channel::EU868_4,
-    Channel::EU868_5,
-    Channel::EU868_6,
-    Channel::EU868_7,
-];
-
-pub fn map_port(i: usize) -> Option<usize> {
-    match i {
-        0 => Some(0),
-        1 => Some(1),
-        2 => Some(2),
-        3 => Some(3),
-        4 => None,
-        5 => Some(4),
-        6 => Some(5),
-        7 => Some(6),
-        8 => Some(7),
-        _ => panic!("wrong port number"),
-    }
+const DEFAULT_GATEWAY_MAC: &str = "0200.0000.0403.0201";
+const TX_SAMPLE_RATE: usize = 125_000 * OVERSAMPLING_TX;
+
+/// channel, spreading factor and SNR of a received frame
+fn describe(annotations: &HashMap<String, Pmt>) -> String {
+    let freq = match annotations.get("freq") {
+        Some(Pmt::F64(f)) => *f / 1e6,
+        _ => 0.0,
+    };
+    let sf = match annotations.get("sf") {
+        Some(Pmt::U32(sf)) => *sf,
+        _ => 0,
+    };
+    let snr = match annotations.get("snr") {
+        Some(Pmt::F64(snr)) => *snr,
+        _ => f64::NAN,
+    };
+    format!("{freq:.1}MHz SF{sf} SNR {snr:.1}dB")
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let rt = Runtime::new();
-    let mut fg = Flowgraph::new();
+    let config = args
+        .config
+        .as_ref()
+        .map(|global| GatewayConfig::load(global, args.local_config.as_ref()))
+        .transpose()?;
 
+    let gateway_eui = match &config {
+        Some(config) => parse_eui(&config.gateway_mac()?)?,
+        None => parse_eui(DEFAULT_GATEWAY_MAC)?,
+    };
+
+    let mut basic_station = match &args.lns {
+        Some(lns) => {
+            let mut bs_config = BasicStationConfig::new(lns, gateway_eui);
+            // without a gateway config, the LNS gives the frequency range in the router_config
+            bs_config.tx_freq_range = config.as_ref().and_then(|c| c.tx_freq_range());
+            Some(BasicStationClient::new(bs_config)?)
+        }
+        None => None,
+    };
+
+    let mut chirpstack = match &args.mqtt {
+        Some(broker) => {
+            let (host, port) = broker.rsplit_once(':').unwrap_or((broker.as_str(), "1883"));
+            let mut cs_config = ChirpstackConfig::new(host, port.parse()?, gateway_eui);
+            cs_config.topic_prefix = args.mqtt_topic_prefix.clone();
+            cs_config.tx_freq_range = config
+                .as_ref()
+                .and_then(|c| c.tx_freq_range())
+                .or(Some(args.region.frequency_range()));
+            Some(ChirpstackClient::new(cs_config)?)
+        }
+        None => None,
+    };
+
+    let channels = match (&config, &basic_station) {
+        (Some(config), _) => config.lora_channels(),
+        (None, Some(bs)) => bs
+            .router_config()
+            .map(|c| c.lora_channels())
+            .unwrap_or_default(),
+        // 8 channel gateway on the first sub-band by default
+        (None, None) => args
+            .region
+            .gateway_channels(Some(args.sub_band.unwrap_or(1))),
+    };
     // streamer start time is relative to function call -> can not be used for precise rx timestamping -> just use the system time when constructing the flowgraph as a reference
     let stream_start_time = SystemTime::now();
+    let mut gateway = MultiChannelGateway::new(channels).stream_start_time(stream_start_time);
+    if let (Some(center_freq), Some(sample_rate)) = (args.center_freq, args.sample_rate) {
+        gateway = gateway.band(center_freq, sample_rate);
+    }
+    let plan = gateway.plan()?;
+
+    let rt = Runtime::new();
+    let mut fg = Flowgraph::new();
+
+    let forwarder_config = ForwarderConfig {
+        tx_freq_range: Some(args.region.frequency_range()),
+        ..Default::default()
+    };
+    let mut packet_forwarder = match (args.forward_addr, &config) {
+        // the Packet Forwarder protocol is only used if no other backhaul is selected
+        _ if basic_station.is_some() || chirpstack.is_some() => None,
+        (Some(addr), config) => {
+            let mac = match config {
+                Some(config) => config.gateway_mac()?,
+                None => DEFAULT_GATEWAY_MAC.to_string(),
+            };
+            Some(PacketForwarderClient::new(&mac, &addr, forwarder_config)?)
+        }
+        (None, Some(config)) => Some(PacketForwarderClient::from_config(
+            config,
+            forwarder_config,
+        )?),
+        (None, None) => None,
+    };
 
-    let packet_forwarder = args
-        .forward_addr
-        .map(|addr| fg.add_block(PacketForwarderClient::new("0200.0000.0403.0201", &addr)));
+    // downlinks are only possible with a server to receive them from and a TX device
+    let downlink_receiver = match (&mut packet_forwarder, &mut basic_station, &mut chirpstack) {
+        (Some(pf), _, _) => pf.take_downlinks(),
+        (None, Some(bs), _) => bs.take_downlinks(),
+        (None, None, Some(cs)) => cs.take_downlinks(),
+        (None, None, None) => None,
+    };
+    let downlinks = match (downlink_receiver, args.tx_args) {
+        (Some(rx), Some(tx_args)) => {
+            let sync_word = match &config {
+                Some(config) if !config.concentrator.lorawan_public => SYNC_WORD_PRIVATE,
+                _ => SYNC_WORD_PUBLIC,
+            };
+            let sink = Builder::new(Some(tx_args))?
+                .sample_rate(TX_SAMPLE_RATE as f64)
+                .frequency(plan.center_freq)
+                .gain(args.tx_gain)
+                .antenna(args.tx_antenna)
+                .min_in_buffer_size(sample_count(
+                    // make sure the sink will not stall on the longest downlink
+                    SpreadingFactor::SF12,
+                    PREAMBLE_LEN,
+                    false,
+                    255,
+                    HAS_CRC,
+                    CODE_RATE_LORAWAN,
+                    OVERSAMPLING_TX,
+                    PAD_SYMBOLS,
+                    true,
+                ))
+                .build_sink()?;
+            let transmitter: Transmitter = Transmitter::new(
+                CODE_RATE_LORAWAN,
+                HAS_CRC,
+                SpreadingFactor::SF12,
+                true,
+                false,
+                OVERSAMPLING_TX,
+                vec![sync_word],
+                PREAMBLE_LEN,
+                PAD_SYMBOLS,
+                false,
+            );
+            connect!(fg, transmitter > inputs[0].sink);
+            let sink = TxSink {
+                block: sink.into(),
+                gain: args.tx_gain,
+                max_power: args.tx_max_power,
+            };
+            Some((rx, transmitter, sink))
+        }
+        _ => None,
+    };
+    let packet_forwarder = packet_forwarder.map(|pf| fg.add_block(pf));
+    let basic_station = basic_station.map(|bs| fg.add_block(bs));
+    let chirpstack = chirpstack.map(|cs| fg.add_block(cs));
+    if let Some((_, transmitter, _)) = &downlinks {
+        let transmitter = transmitter.clone();
+        if let Some(pf) = &packet_forwarder {
+            let packet_forwarder = pf.clone();
+            connect!(fg, transmitter.tx_done | tx_done.packet_forwarder);
+        } else if let Some(bs) = &basic_station {
+            let basic_station = bs.clone();
+            connect!(fg, transmitter.tx_done | tx_done.basic_station);
+        }
+    }
 
     let src = Builder::new(args.args)?
-        .sample_rate((NUM_CHANNELS_PADDED * CHANNEL_SPACING) as f64)
-        .frequency(CENTER_FREQ)
+        .sample_rate(plan.sample_rate)
+        .frequency(plan.center_freq)
         .gain(args.gain)
         .antenna(args.antenna)
         .build_source()?;
 
-    let transition_bw =
-        (CHANNEL_SPACING - Into::<usize>::into(BANDWIDTH)) as f64 / CHANNEL_SPACING as f64;
-    let channelizer_taps: Vec<f32> = remez::low_pass(
-        1.,
-        NUM_CHANNELS_PADDED,
-        0.5 - transition_bw / 2.,
-        0.5 + transition_bw / 2.,
-        0.1,
-        100.,
-        None,
-    )
-    .into_iter()
-    .map(|x| x as f32)
-    .collect();
-    let channelizer: PfbChannelizer =
-        PfbChannelizer::new(NUM_CHANNELS_PADDED, &channelizer_taps, 1.0);
-    // let channelizer = fg.add_block(channelizer);
+    let gateway = gateway.build(&mut fg)?;
+    let channelizer = gateway.channelizer.clone();
     connect!(fg, src.outputs[0] > channelizer);
-    for n_out in 0..NUM_CHANNELS_PADDED {
-        let n_chan = map_port(n_out);
-        if n_chan.is_none() {
-            let null_sink_extra_channel = fg.add_block(NullSink::<Complex32>::new());
-            // map highest channel to null-sink (channel numbering starts at center and wraps around)
-            fg.connect_dyn(
-                &channelizer,
-                format!("out{n_out}"),
-                null_sink_extra_channel,
-                "in",
-            )?;
-            println!("connecting channel {n_out} to NullSink");
-            continue;
-        }
-        let n_chan = n_chan.unwrap();
-
-        let resampler_taps: Vec<f32> = remez::low_pass(
-            1.,
-            5,
-            Into::<f64>::into(BANDWIDTH) / (2.0 * CHANNEL_SPACING as f64),
-            ((Into::<f64>::into(BANDWIDTH) / 2.0)
-                + (CHANNEL_SPACING - Into::<usize>::into(BANDWIDTH)) as f64)
-                / (CHANNEL_SPACING as f64),
-            0.1,
-            100.,
-            None,
-        )
-        .into_iter()
-        .map(|x| x as f32)
-        .collect();
-        let resampler = fg.add_block(PfbArbResampler::new(2.5, &resampler_taps, 5));
-        fg.connect_dyn(&channelizer, format!("out{n_out}"), &resampler, "in")?;
-        let channel = CHANNELS[n_chan];
+    for chain in &gateway.chains {
         println!(
-            "connecting {:.1}MHz chain to channel {}",
-            Into::<f32>::into(channel) / 1.0e6,
-            n_chan
+            "connecting {:.1}MHz FrameSync with spreading factor {}",
+            Into::<f32>::into(chain.channel) / 1.0e6,
+            chain.spreading_factor
         );
-        for sf in SpreadingFactor::iter() {
-            println!(
-                "connecting {:.1}MHz FrameSync with spreading factor {sf}",
-                Into::<f32>::into(channel) / 1.0e6,
-            );
-            let frame_sync: FrameSync = FrameSync::new(
-                channel,
-                BANDWIDTH,
-                sf,
-                false,
-                vec![vec![0x12], vec![0x34]],
-                OVERSAMPLING,
-                None,
-                None,
-                false,
-                Some(stream_start_time),
+        let decoder = chain.decoder.clone();
+        let udp_data = BlobToUdp::new("127.0.0.1:55555");
+        let udp_rftap = BlobToUdp::new("127.0.0.1:55556");
+        connect!(fg,
+            decoder.out | udp_data;
+            decoder.rftap | udp_rftap;
+        );
+        if let Some(ref pf) = packet_forwarder {
+            // counters for the stat reports
+            let packet_forwarder = pf.clone();
+            let frame_sync = chain.frame_sync.clone();
+            let header_decoder = chain.header_decoder.clone();
+            connect!(fg,
+                frame_sync.frame_detected | frame_detected.packet_forwarder;
+                frame_sync.detection_failed | detection_failed.packet_forwarder;
+                header_decoder.frame_info | header.packet_forwarder;
+                decoder.crc_check | crc.packet_forwarder;
             );
-            let frame_sync = fg.add_block(frame_sync);
-            fg.connect_dyn(&resampler, "out", &frame_sync, "in")?;
-            let fft_demod: FftDemod = FftDemod::new(sf, ldro(sf));
-            let gray_mapping: GrayMapping = GrayMapping::new();
-            let deinterleaver: Deinterleaver = Deinterleaver::new(ldro(sf), sf);
-            let hamming_dec: HammingDecoder = HammingDecoder::new();
-            let header_decoder = HeaderDecoder::new(HeaderMode::Explicit, ldro(sf));
-            let decoder = Decoder::new();
-            let udp_data = BlobToUdp::new("127.0.0.1:55555");
-            let udp_rftap = BlobToUdp::new("127.0.0.1:55556");
-
-            let resampler_block_ref = resampler.clone();
+        }
+    }
 
+    let print_lorawan = args.lorawan || args.keys.is_some();
+    let (lorawan_tx, mut lorawan_rx) = mpsc::channel::<Pmt>(64);
+    let merger = gateway.merger.clone();
+    if print_lorawan {
+        let lorawan_parser = LorawanParser::new();
+        connect!(fg, merger | lorawan_parser);
+        if let Some(keys) = &args.keys {
+            // a single decryptor for all chains, so that frame counters are tracked per device
+            let decryptor = LorawanDecryptor::new(KeyStore::load(keys)?);
+            let lorawan_pipe = MessagePipe::new(lorawan_tx.clone());
             connect!(fg,
-                resampler_block_ref > frame_sync > fft_demod > gray_mapping > deinterleaver > hamming_dec > header_decoder;
-                header_decoder.frame_info | frame_info.frame_sync;
-                header_decoder | decoder;
-                decoder.out | udp_data;
-                decoder.rftap | udp_rftap;
+                lorawan_parser | decryptor;
+                decryptor | lorawan_pipe;
             );
-            if let Some(ref pf) = packet_forwarder {
-                let packet_forwarder = pf.clone();
-                let tags: HashMap<String, Pmt> = HashMap::from([
-                    (String::from("sf"), Pmt::U32(sf as u32)),
-                    (
-                        String::from("bw"),
-                        Pmt::U32(Into::<u32>::into(BANDWIDTH) / 1000),
-                    ),
-                    (String::from("freq"), Pmt::F64(Into::<f64>::into(channel))),
-                ]);
-                let metadata_tagger = MessageAnnotator::new(tags, None);
-                connect!(fg, decoder.out_annotated | metadata_tagger);
-                connect!(fg, metadata_tagger | packet_forwarder);
-            }
+        } else {
+            let lorawan_pipe = MessagePipe::new(lorawan_tx.clone());
+            connect!(fg, lorawan_parser | lorawan_pipe);
         }
     }
+    let merger = gateway.merger.clone();
+    if let Some(ref pf) = packet_forwarder {
+        let packet_forwarder = pf.clone();
+        connect!(fg, merger | packet_forwarder);
+    } else if let Some(ref bs) = basic_station {
+        let basic_station = bs.clone();
+        connect!(fg, merger | basic_station);
+    } else if let Some(ref cs) = chirpstack {
+        let chirpstack = cs.clone();
+        connect!(fg, merger | chirpstack);
+    }
+
+    // the pipes hold the remaining senders, so printing ends with the flowgraph
+    drop(lorawan_tx);
 
-    rt.run(fg)?;
+    let (fg, handle) = rt.start_sync(fg)?;
+    rt.block_on(async move {
+        let downlinks = async move {
+            if let Some((downlinks, transmitter, sink)) = downlinks {
+                // runs until the backhaul shuts down
+                transmit_downlinks(
+                    handle,
+                    transmitter.into(),
+                    Some(sink),
+                    TX_SAMPLE_RATE,
+                    downlinks,
+                )
+                .await;
+            }
+        };
+        let lorawan = async move {
+            while let Some(p) = lorawan_rx.next().await {
+                match p {
+                    Pmt::Any(a) => {
+                        if let Some(frame) = a.downcast_ref::<LorawanFrame>() {
+                            println!("{}: {}", describe(&frame.annotations), frame.phy);
+                        } else if let Some(frame) = a.downcast_ref::<DecryptedFrame>() {
+                            println!(
+                                "{}: {} FCnt {} FPort {:?} payload {:02x?} MAC commands {:?}",
+                                describe(&frame.annotations),
+                                frame.device,
+                                frame.fcnt,
+                                frame.fport,
+                                frame.payload,
+                                frame.mac_commands
+                            );
+                        }
+                    }
+                    Pmt::String(event) => println!("{event}"),
+                    _ => {}
+                }
+            }
+        };
+        let (_, _, fg) = futures::join!(downlinks, lorawan, fg);
+        fg
+    })?;
 
     Ok(())
 }
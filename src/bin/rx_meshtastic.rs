@@ -1,5 +1,8 @@
 use anyhow::Result;
+use anyhow::anyhow;
+use base64::prelude::*;
 use clap::Parser;
+use std::path::PathBuf;
 
 use futuredsp::firdes;
 use futuresdr::blocks::MessagePipe;
@@ -18,6 +21,10 @@ use lora::HeaderMode;
 use lora::meshtastic::MeshtasticChannel;
 use lora::meshtastic::MeshtasticChannels;
 use lora::meshtastic::MeshtasticConfig;
+use lora::meshtastic::parse_node_num;
+use lora::meshtastic_nodedb::NodeDb;
+use lora::meshtastic_pki::PkiKeys;
+use lora::meshtastic_url::ChannelUrl;
 use lora::utils::Bandwidth;
 
 const IMPLICIT_HEADER: bool = false;
@@ -34,19 +41,56 @@ struct Args {
     /// RX Gain
     #[clap(short, long, default_value_t = 50.0)]
     gain: f64,
-    /// Meshtastic LoRa Config
-    #[clap(short, long, value_enum, default_value_t = MeshtasticConfig::LongFastEu)]
-    meshtastic_config: MeshtasticConfig,
+    /// Meshtastic LoRa Config, defaults to the one of the channel URL or LONG_FAST_EU
+    #[clap(short, long, value_enum)]
+    meshtastic_config: Option<MeshtasticConfig>,
     /// Meshtastic Channels (Format: <name>:<base64key>,<name>:<base64key>,..)
     #[clap(short, long)]
     channels: Option<String>,
+    /// Print received packets as JSON lines
+    #[clap(long)]
+    json: bool,
+    /// own node number for PKI direct messages (!hex, 0xhex or decimal)
+    #[clap(long, value_parser = parse_node_num, default_value = "!3a48290e")]
+    node_num: u32,
+    /// JSON file with the own key pair and learned public keys, created if missing
+    #[clap(long)]
+    pki_keys: Option<PathBuf>,
+    /// channel URL (https://meshtastic.org/e/#...) with the channels to decode
+    #[clap(long)]
+    url: Option<String>,
+    /// JSON file of the node database, created if missing
+    #[clap(long)]
+    node_db: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
     futuresdr::runtime::init();
     let args = Args::parse();
+    let json = args.json;
+    let url = args.url.as_deref().map(ChannelUrl::parse).transpose()?;
+    let mut node_db = args.node_db.as_deref().map(NodeDb::open).transpose()?;
+    let pki_keys = args.pki_keys.clone();
+    let pki = match &pki_keys {
+        Some(path) => {
+            let keys = PkiKeys::load_or_generate(path, args.node_num)?;
+            info!(
+                "PKI public key of !{:08x}: {}",
+                keys.node_num(),
+                BASE64_STANDARD.encode(keys.key_pair().public_key())
+            );
+            Some(keys)
+        }
+        None => None,
+    };
     info!("args {:?}", &args);
-    let (bandwidth, spreading_factor, _, chan, ldro) = args.meshtastic_config.to_config();
+    let (bandwidth, spreading_factor, _, chan, ldro) = match (args.meshtastic_config, &url) {
+        (Some(config), _) => config.to_config(),
+        (None, Some(url)) => url
+            .to_config()
+            .ok_or_else(|| anyhow!("channel URL without supported LoRa preset"))?,
+        (None, None) => MeshtasticConfig::default().to_config(),
+    };
 
     let mut channels = vec![];
     for chan in args.channels.unwrap_or(String::new()).split(",") {
@@ -56,6 +100,21 @@ fn main() -> Result<()> {
         }
     }
 
+    let mut chans = match &url {
+        Some(url) => url.meshtastic_channels()?,
+        None => {
+            let mut chans = MeshtasticChannels::new();
+            chans.add_channel(MeshtasticChannel::try_new("", "AQ==")?);
+            chans
+        }
+    };
+    for c in &channels {
+        chans.add_channel(MeshtasticChannel::try_new(&c.0, &c.1)?);
+    }
+    if let Some(pki) = pki {
+        chans.set_pki(pki);
+    }
+
     println!("channels: {channels:?}");
 
     let src = Builder::new(args.args)?
@@ -69,7 +128,7 @@ fn main() -> Result<()> {
         Bandwidth::BW62 => 4,
         Bandwidth::BW125 => 2,
         Bandwidth::BW250 => 1,
-        _ => panic!("wrong bandwidth for Meshtastic"),
+        bw => return Err(anyhow!("{bw:?} bandwidth not supported at 1 MS/s")),
     };
     let cutoff = Into::<f64>::into(bandwidth) / 2.0 / 1e6;
     let transition_bw = Into::<f64>::into(bandwidth) / 10.0 / 1e6;
@@ -87,6 +146,7 @@ fn main() -> Result<()> {
         Some("header_crc_ok"),
         false,
         None,
+        false,
     );
     let fft_demod: FftDemod = FftDemod::new(spreading_factor, ldro);
     let gray_mapping: GrayMapping = GrayMapping::new();
@@ -105,21 +165,37 @@ fn main() -> Result<()> {
         fft_demod > gray_mapping > deinterleaver > hamming_dec > header_decoder;
         header_decoder.frame_info | frame_info.frame_sync;
         header_decoder | decoder;
-        decoder | message_pipe;
+        decoder.out_annotated | message_pipe;
     );
 
     let rt = Runtime::new();
     let (_fg, _handle) = rt.start_sync(fg)?;
     rt.block_on(async move {
-        let mut chans = MeshtasticChannels::new();
-        chans.add_channel(MeshtasticChannel::new("", "AQ=="));
-        for c in channels {
-            chans.add_channel(MeshtasticChannel::new(&c.0, &c.1));
-        }
         while let Some(x) = rx_frame.next().await {
             match x {
-                Pmt::Blob(data) => {
-                    chans.decode(&data[..data.len() - 2]);
+                Pmt::MapStrPmt(annotations) => {
+                    let Some(event) = chans.decode_annotated(&annotations) else {
+                        continue;
+                    };
+                    if chans.learn(&event) {
+                        info!("learned public key of !{:08x}", event.from);
+                        if let (Some(path), Some(keys)) = (&pki_keys, chans.pki()) {
+                            if let Err(e) = keys.save(path) {
+                                warn!("failed to save PKI keys: {e}");
+                            }
+                        }
+                    }
+                    if let Some(db) = &mut node_db {
+                        db.update(&event);
+                        if let Err(e) = db.save() {
+                            warn!("failed to save node database: {e}");
+                        }
+                    }
+                    if json {
+                        println!("{}", event.to_json());
+                    } else {
+                        println!("{event}");
+                    }
                 }
                 _ => break,
             }
@@ -1,5 +1,8 @@
 use anyhow::Result;
+use anyhow::anyhow;
+use base64::prelude::*;
 use clap::Parser;
+use std::path::PathBuf;
 
 use futuredsp::firdes;
 use futuresdr::blocks::MessagePipe;
@@ -15,21 +18,59 @@ use lora::GrayMapping;
 use lora::HammingDecoder;
 use lora::HeaderDecoder;
 use lora::HeaderMode;
+use lora::Transmitter;
+use lora::default_values::HAS_CRC;
+use lora::default_values::PREAMBLE_LEN;
 use lora::meshtastic::MeshtasticChannel;
 use lora::meshtastic::MeshtasticChannels;
+use lora::meshtastic::parse_node_num;
+use lora::meshtastic_mqtt::MeshtasticMqttConfig;
+use lora::meshtastic_mqtt::MeshtasticMqttGateway;
+use lora::meshtastic_nodedb::NodeDb;
+use lora::meshtastic_pki::PkiKeys;
+use lora::meshtastic_region::FrequencyPlan;
+use lora::meshtastic_region::MeshtasticRegion;
+use lora::meshtastic_region::PRESETS;
+use lora::meshtastic_region::default_channels;
+use lora::meshtastic_url::ChannelUrl;
 use lora::utils::Bandwidth;
 use lora::utils::Channel;
+use lora::utils::CodeRate;
 use lora::utils::SpreadingFactor;
 
 const IMPLICIT_HEADER: bool = false;
 const OVERSAMPLING: usize = 4;
+const TX_SAMPLE_RATE: u32 = 1_000_000;
+const TX_PAD: usize = 10000;
 
-#[derive(Debug, Clone, clap::ValueEnum, Copy, Default)]
-#[clap(rename_all = "SCREAMING_SNAKE_CASE")]
-enum MeshtasticRegion {
-    #[default]
-    Eu,
-    Us,
+/// Sample rates tried for the source, the lowest covering all channels is used.
+const SAMPLE_RATES: [u32; 6] = [
+    1_000_000, 2_000_000, 4_000_000, 8_000_000, 10_000_000, 20_000_000,
+];
+
+type ChannelConfigs = Vec<(Bandwidth, Channel, Vec<(SpreadingFactor, bool, CodeRate)>)>;
+
+/// Center frequency and the lowest sample rate that covers all channels and decimates to each bandwidth.
+fn receiver_band(configs: &ChannelConfigs) -> Result<(u32, u32)> {
+    let edges = configs.iter().map(|(bandwidth, chan, _)| {
+        let freq = Into::<u32>::into(*chan);
+        let half = Into::<u32>::into(*bandwidth) / 2;
+        (freq - half, freq + half)
+    });
+    let lower = edges.clone().map(|(l, _)| l).min().ok_or_else(|| anyhow!("no channels"))?;
+    let upper = edges.map(|(_, u)| u).max().unwrap();
+    let span = upper - lower;
+    let sample_rate = SAMPLE_RATES
+        .into_iter()
+        .find(|sample_rate| {
+            *sample_rate as f64 * 0.8 >= span as f64
+                && configs.iter().all(|(bandwidth, _, _)| {
+                    let rate = Into::<u32>::into(*bandwidth) * OVERSAMPLING as u32;
+                    sample_rate % rate == 0
+                })
+        })
+        .ok_or_else(|| anyhow!("channels span {} kHz, too wide for one receiver", span / 1000))?;
+    Ok((lower + span / 2, sample_rate))
 }
 
 #[derive(Parser, Debug)]
@@ -43,17 +84,66 @@ struct Args {
     /// RX Gain
     #[clap(short, long, default_value_t = 50.0)]
     gain: f64,
-    /// Meshtastic LoRa Config
-    #[clap(short, long, value_enum, default_value_t = MeshtasticRegion::Eu)]
+    /// Meshtastic region, listens on the default channels of all presets
+    #[clap(short, long, value_enum, default_value_t = MeshtasticRegion::EU_868)]
     meshtastic_region: MeshtasticRegion,
     /// Meshtastic Channels (Format: <name>:<base64key>,<name>:<base64key>,..)
     #[clap(short, long)]
     channels: Option<String>,
+    /// Print received packets as JSON lines
+    #[clap(long)]
+    json: bool,
+    /// own node number for PKI direct messages (!hex, 0xhex or decimal)
+    #[clap(long, value_parser = parse_node_num, default_value = "!3a48290e")]
+    node_num: u32,
+    /// JSON file with the own key pair and learned public keys, created if missing
+    #[clap(long)]
+    pki_keys: Option<PathBuf>,
+    /// channel URL (https://meshtastic.org/e/#...) with the channels to decode
+    #[clap(long)]
+    url: Option<String>,
+    /// JSON file of the node database, created if missing
+    #[clap(long)]
+    node_db: Option<PathBuf>,
+    /// MQTT broker (host:port) to bridge the mesh to, the node number is the gateway ID
+    #[clap(long)]
+    mqtt: Option<String>,
+    /// MQTT root topic, msh/<region> by default
+    #[clap(long, requires = "mqtt")]
+    mqtt_root_topic: Option<String>,
+    /// also publish decrypted packets
+    #[clap(long, requires = "mqtt")]
+    mqtt_decrypted: bool,
+    /// Seify device args of the TX device, enables MQTT downlinks on the primary channel
+    #[clap(long, requires = "mqtt")]
+    tx_args: Option<String>,
+    /// TX antenna
+    #[clap(long)]
+    tx_antenna: Option<String>,
+    /// TX gain
+    #[clap(long, default_value_t = 50.0)]
+    tx_gain: f64,
 }
 
 fn main() -> Result<()> {
     futuresdr::runtime::init();
     let args = Args::parse();
+    let json = args.json;
+    let url = args.url.as_deref().map(ChannelUrl::parse).transpose()?;
+    let mut node_db = args.node_db.as_deref().map(NodeDb::open).transpose()?;
+    let pki_keys = args.pki_keys.clone();
+    let pki = match &pki_keys {
+        Some(path) => {
+            let keys = PkiKeys::load_or_generate(path, args.node_num)?;
+            info!(
+                "PKI public key of !{:08x}: {}",
+                keys.node_num(),
+                BASE64_STANDARD.encode(keys.key_pair().public_key())
+            );
+            Some(keys)
+        }
+        None => None,
+    };
 
     let mut channels = vec![];
     for chan in args.channels.clone().unwrap_or(String::new()).split(",") {
@@ -62,66 +152,114 @@ fn main() -> Result<()> {
             channels.push((vals[0].to_string(), vals[1].to_string()));
         }
     }
-    info!("args {:?}, channel {:?}", &args, &channels);
 
-    let (sample_rate, center_freq, configs) = match args.meshtastic_region {
-        MeshtasticRegion::Eu => (
-            1_000_000,
-            869_300_000,
-            vec![
-                (
-                    Bandwidth::BW250,
-                    Channel::Custom(869_525_000),
-                    vec![
-                        (SpreadingFactor::SF7, false),
-                        (SpreadingFactor::SF8, false),
-                        (SpreadingFactor::SF9, false),
-                        (SpreadingFactor::SF10, false),
-                        (SpreadingFactor::SF11, false),
-                    ],
-                ),
-                (
-                    Bandwidth::BW125,
-                    Channel::Custom(869_587_500),
-                    vec![(SpreadingFactor::SF11, true), (SpreadingFactor::SF12, true)],
-                ),
-                (
-                    Bandwidth::BW62,
-                    Channel::Custom(869_492_500),
-                    vec![(SpreadingFactor::SF12, true)],
-                ),
-            ],
-        ),
-        MeshtasticRegion::Us => (
-            20_000_000,
-            910_000_000,
-            vec![
-                (
-                    Bandwidth::BW250,
-                    Channel::Custom(906_875_000),
-                    vec![
-                        (SpreadingFactor::SF7, false),
-                        (SpreadingFactor::SF8, false),
-                        (SpreadingFactor::SF9, false),
-                        (SpreadingFactor::SF10, false),
-                        (SpreadingFactor::SF11, false),
-                    ],
-                ),
-                (
-                    Bandwidth::BW125,
-                    Channel::Custom(904_437_500),
-                    vec![(SpreadingFactor::SF11, true), (SpreadingFactor::SF12, true)],
-                ),
-                (
-                    Bandwidth::BW62,
-                    Channel::Custom(916_218_750),
-                    vec![(SpreadingFactor::SF12, true)],
-                ),
-            ],
-        ),
+    let mut chans = match &url {
+        Some(url) => url.meshtastic_channels()?,
+        None => {
+            let mut chans = MeshtasticChannels::new();
+            chans.add_channel(MeshtasticChannel::try_new("", "AQ==")?);
+            chans
+        }
     };
+    for c in &channels {
+        chans.add_channel(MeshtasticChannel::try_new(&c.0, &c.1)?);
+    }
+    // the gateway forwards channel traffic only, PKI direct messages of others stay encrypted
+    let gateway_channels = chans.channels().to_vec();
+    if let Some(pki) = pki {
+        chans.set_pki(pki);
+    }
+    info!("args {:?}, channel {:?}", &args, &channels);
+
+    let mut configs = default_channels(args.meshtastic_region);
+    if let Some((bandwidth, spreading_factor, code_rate, chan, ldro)) =
+        url.as_ref().and_then(|url| url.to_config())
+    {
+        // the primary channel of the URL may be on a slot of its own
+        match configs
+            .iter_mut()
+            .find(|(bw, c, _)| *bw == bandwidth && *c == chan)
+        {
+            Some((_, _, chains)) => {
+                if !chains.iter().any(|(sf, _, _)| *sf == spreading_factor) {
+                    chains.push((spreading_factor, ldro, code_rate));
+                }
+            }
+            None => configs.push((bandwidth, chan, vec![(spreading_factor, ldro, code_rate)])),
+        }
+    }
+    let (center_freq, sample_rate) = receiver_band(&configs)?;
+    info!(
+        "receiving {} channels at {} MHz with {} MS/s",
+        configs.len(),
+        center_freq as f64 / 1e6,
+        sample_rate as f64 / 1e6
+    );
 
     let mut fg = Flowgraph::new();
+
+    // downlinks go out on the primary channel of the URL, or the region's LongFast channel
+    let tx_plan = url
+        .as_ref()
+        .and_then(|url| url.frequency_plan())
+        .unwrap_or_else(|| FrequencyPlan::new(args.meshtastic_region, &PRESETS[0]));
+    let mut mqtt_gateway = match &args.mqtt {
+        Some(broker) => {
+            let (host, port) = broker.rsplit_once(':').unwrap_or((broker.as_str(), "1883"));
+            let mut config = MeshtasticMqttConfig::new(
+                host,
+                port.parse()?,
+                args.meshtastic_region,
+                args.node_num,
+            );
+            if let Some(root_topic) = &args.mqtt_root_topic {
+                config.root_topic = root_topic.clone();
+            }
+            config.publish_decrypted = args.mqtt_decrypted;
+            config.downlink = args.tx_args.is_some();
+            config.preset_name = tx_plan.preset.name.to_string();
+            Some(MeshtasticMqttGateway::new(config, gateway_channels)?)
+        }
+        None => None,
+    };
+    let downlinks = match (
+        mqtt_gateway.as_mut().and_then(|g| g.take_downlinks()),
+        args.tx_args,
+    ) {
+        (Some(downlinks), Some(tx_args)) => {
+            let primary_name = url.as_ref().map_or("", |url| url.primary_name());
+            let (bandwidth, spreading_factor, code_rate, chan, ldro) = tx_plan
+                .to_config(primary_name)
+                .ok_or_else(|| anyhow!("no TX channel for {}", tx_plan.preset.name))?;
+            info!(
+                "MQTT downlinks on {} MHz",
+                Into::<u32>::into(chan) as f64 / 1e6
+            );
+            let sink = Builder::new(Some(tx_args))?
+                .sample_rate(TX_SAMPLE_RATE as f64)
+                .frequency(Into::<u32>::into(chan) as f64)
+                .gain(args.tx_gain)
+                .antenna(args.tx_antenna)
+                .build_sink()?;
+            let transmitter: Transmitter = Transmitter::new(
+                code_rate,
+                HAS_CRC,
+                spreading_factor,
+                ldro,
+                IMPLICIT_HEADER,
+                (TX_SAMPLE_RATE / Into::<u32>::into(bandwidth)) as usize,
+                vec![16, 88],
+                PREAMBLE_LEN,
+                TX_PAD,
+                false,
+            );
+            connect!(fg, transmitter > inputs[0].sink);
+            Some((downlinks, BlockId::from(transmitter)))
+        }
+        _ => None,
+    };
+    let mqtt_gateway = mqtt_gateway.map(|g| fg.add_block(g));
+
     let src = Builder::new(args.args)?
         .sample_rate(sample_rate as f64)
         .frequency(center_freq as f64)
@@ -134,21 +272,21 @@ fn main() -> Result<()> {
     connect!(fg, src; message_pipe);
 
     for (bandwidth, chan, chains) in configs.into_iter() {
-        let decimation = sample_rate / Into::<usize>::into(bandwidth) / OVERSAMPLING;
+        let decimation = sample_rate as usize / Into::<usize>::into(bandwidth) / OVERSAMPLING;
         let cutoff = Into::<f64>::into(bandwidth) / 2.0 / sample_rate as f64;
         let transition_bw = cutoff;
         let taps = firdes::kaiser::lowpass(cutoff, transition_bw, 0.05);
         let decimation: XlatingFir = XlatingFir::with_taps(
             taps,
             decimation,
-            (Into::<u32>::into(chan) - center_freq) as f32,
+            (Into::<u32>::into(chan) as i64 - center_freq as i64) as f32,
             sample_rate as f32,
         );
 
         let src = src.clone();
         connect!(fg, src.outputs[0] > decimation);
 
-        for (spreading_factor, ldro) in chains.into_iter() {
+        for (spreading_factor, ldro, _) in chains.into_iter() {
             let decimation = decimation.clone();
             let message_pipe = message_pipe.clone();
             let frame_sync: FrameSync = FrameSync::new(
@@ -162,6 +300,7 @@ fn main() -> Result<()> {
                 Some("header_crc_ok"),
                 false,
                 None,
+                false,
             );
             let fft_demod: FftDemod = FftDemod::new(spreading_factor, ldro);
             let gray_mapping: GrayMapping = GrayMapping::new();
@@ -175,27 +314,61 @@ fn main() -> Result<()> {
                 fft_demod > gray_mapping > deinterleaver > hamming_dec > header_decoder;
                 header_decoder.frame_info | frame_info.frame_sync;
                 header_decoder | decoder;
-                decoder | message_pipe;
+                decoder.out_annotated | message_pipe;
             );
+            if let Some(mqtt_gateway) = &mqtt_gateway {
+                let mqtt_gateway = mqtt_gateway.clone();
+                connect!(fg, decoder.out_annotated | mqtt_gateway);
+            }
         }
     }
 
     let rt = Runtime::new();
-    let (_fg, _handle) = rt.start_sync(fg)?;
+    let (_fg, handle) = rt.start_sync(fg)?;
     rt.block_on(async move {
-        let mut chans = MeshtasticChannels::new();
-        chans.add_channel(MeshtasticChannel::new("", "AQ=="));
-        for c in channels {
-            chans.add_channel(MeshtasticChannel::new(&c.0, &c.1));
-        }
-        while let Some(x) = rx_frame.next().await {
-            match x {
-                Pmt::Blob(data) => {
-                    chans.decode(&data[..data.len() - 2]);
+        // runs until the MQTT gateway shuts down with the flowgraph
+        let downlinks = async move {
+            if let Some((mut downlinks, transmitter)) = downlinks {
+                let mut handle = handle;
+                while let Some(frame) = downlinks.next().await {
+                    if let Err(e) = handle.call(transmitter, "msg", Pmt::Blob(frame)).await {
+                        warn!("could not transmit downlink: {e}");
+                    }
                 }
-                _ => break,
             }
-        }
+        };
+        let frames = async move {
+            while let Some(x) = rx_frame.next().await {
+                match x {
+                    Pmt::MapStrPmt(annotations) => {
+                        let Some(event) = chans.decode_annotated(&annotations) else {
+                            continue;
+                        };
+                        if chans.learn(&event) {
+                            info!("learned public key of !{:08x}", event.from);
+                            if let (Some(path), Some(keys)) = (&pki_keys, chans.pki()) {
+                                if let Err(e) = keys.save(path) {
+                                    warn!("failed to save PKI keys: {e}");
+                                }
+                            }
+                        }
+                        if let Some(db) = &mut node_db {
+                            db.update(&event);
+                            if let Err(e) = db.save() {
+                                warn!("failed to save node database: {e}");
+                            }
+                        }
+                        if json {
+                            println!("{}", event.to_json());
+                        } else {
+                            println!("{event}");
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        };
+        futures::join!(downlinks, frames);
     });
     Ok(())
 }
@@ -52,6 +52,9 @@ struct Args {
     /// LoRa Code Rate
     #[clap(short, long, value_enum, default_value_t = CodeRate::CR_4_5)]
     code_rate: CodeRate,
+    /// Inverted IQ (downlink polarity)
+    #[clap(long, default_value_t = false)]
+    invert_iq: bool,
 }
 const PAD: usize = 0;
 
@@ -89,6 +92,7 @@ fn main() -> Result<()> {
         vec![args.sync_word],
         PREAMBLE_LEN,
         PAD,
+        args.invert_iq,
     );
 
     connect!(fg, transmitter > inputs[0].sink);
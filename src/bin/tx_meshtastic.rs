@@ -1,4 +1,5 @@
 use anyhow::Result;
+use anyhow::anyhow;
 use clap::Parser;
 use futuresdr::blocks::seify::Builder;
 use futuresdr::prelude::*;
@@ -6,9 +7,15 @@ use lora::Transmitter;
 use lora::default_values::HAS_CRC;
 use lora::default_values::IMPLICIT_HEADER;
 use lora::default_values::PREAMBLE_LEN;
+use lora::meshtastic::DEFAULT_HOP_LIMIT;
 use lora::meshtastic::MeshtasticChannel;
 use lora::meshtastic::MeshtasticConfig;
+use lora::meshtastic::MeshtasticEncoder;
+use lora::meshtastic::PacketIds;
+use lora::meshtastic::parse_node_num;
+use lora::meshtastic_url::ChannelUrl;
 use lora::utils::Bandwidth;
+use meshtastic::protobufs::PortNum;
 use std::io::BufRead;
 use std::io::Write;
 
@@ -23,28 +30,92 @@ struct Args {
     /// TX Gain
     #[clap(short, long, default_value_t = 50.0)]
     gain: f64,
-    /// Meshtastic LoRa Config
-    #[clap(short, long, value_enum)]
-    meshtastic_config: MeshtasticConfig,
-    /// meshtastic channel name
-    #[clap(short, long)]
-    name: String,
+    /// Meshtastic LoRa Config, defaults to the one of the channel URL
+    #[clap(short, long, value_enum, required_unless_present = "url")]
+    meshtastic_config: Option<MeshtasticConfig>,
     /// meshtastic channel name
+    #[clap(short, long, required_unless_present = "url")]
+    name: Option<String>,
+    /// meshtastic channel key (base64)
     #[clap(short, long, default_value = "AQ==")]
     key: String,
+    /// own node number (!hex, 0xhex or decimal)
+    #[clap(long, value_parser = parse_node_num, default_value = "!3a48290e")]
+    from: u32,
+    /// destination node number, ^all for broadcast
+    #[clap(long, value_parser = parse_node_num, default_value = "^all")]
+    to: u32,
+    /// hop limit
+    #[clap(long, default_value_t = DEFAULT_HOP_LIMIT)]
+    hop_limit: u8,
+    /// hop start, defaults to the hop limit
+    #[clap(long)]
+    hop_start: Option<u8>,
+    /// request an acknowledgement
+    #[clap(long)]
+    want_ack: bool,
+    /// count packet IDs up from this value instead of using random IDs
+    #[clap(long)]
+    sequential_ids: Option<u32>,
+    /// port number (name like TEXT_MESSAGE_APP or number)
+    #[clap(long, value_parser = parse_portnum, default_value = "TEXT_MESSAGE_APP")]
+    port: PortNum,
+    /// read payloads as hex instead of text
+    #[clap(long)]
+    hex: bool,
+    /// channel URL (https://meshtastic.org/e/#...), sends on its primary channel
+    #[clap(long, conflicts_with_all = ["name", "key"])]
+    url: Option<String>,
 }
+
+fn parse_portnum(s: &str) -> Result<PortNum> {
+    if let Ok(n) = s.parse::<i32>() {
+        return PortNum::try_from(n).map_err(|_| anyhow!("unknown port number {n}"));
+    }
+    PortNum::from_str_name(&s.to_uppercase()).ok_or_else(|| anyhow!("unknown port {s}"))
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let hex: String = s.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    if let Some(c) = hex.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid hex digit {c:?}"));
+    }
+    if hex.len() % 2 != 0 {
+        return Err(anyhow!("odd number of hex digits"));
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?))
+        .collect()
+}
+
 const PAD: usize = 10000;
 
 fn main() -> Result<()> {
     let args = Args::parse();
     info!("args {:?}", &args);
-    let (bandwidth, spreading_factor, code_rate, chan, ldro) = args.meshtastic_config.to_config();
+    let url = args.url.as_deref().map(ChannelUrl::parse).transpose()?;
+    let channel = match &url {
+        Some(url) => url.channels()?.remove(0),
+        None => MeshtasticChannel::try_new(args.name.as_deref().unwrap_or_default(), &args.key)?,
+    };
+    let name = channel.name().to_string();
+    let (bandwidth, spreading_factor, code_rate, chan, ldro) = match (args.meshtastic_config, &url) {
+        (Some(config), _) => {
+            info!("channel URL: {}", ChannelUrl::from_channels([&channel], config));
+            config.to_config()
+        }
+        (None, Some(url)) => url
+            .to_config()
+            .ok_or_else(|| anyhow!("channel URL without supported LoRa preset"))?,
+        (None, None) => unreachable!("clap requires a LoRa config or URL"),
+    };
 
     let interpolation = match bandwidth {
         Bandwidth::BW62 => 16,
         Bandwidth::BW125 => 8,
         Bandwidth::BW250 => 4,
-        _ => panic!("wrong bandwidth for Meshtastic"),
+        bw => return Err(anyhow!("{bw:?} bandwidth not supported at 1 MS/s")),
     };
 
     let mut fg = Flowgraph::new();
@@ -66,6 +137,7 @@ fn main() -> Result<()> {
         vec![16, 88],
         PREAMBLE_LEN,
         PAD,
+        false,
     );
 
     connect!(fg, transmitter > inputs[0].sink);
@@ -74,17 +146,39 @@ fn main() -> Result<()> {
     let rt = Runtime::new();
     let (_fg, handle) = rt.start_sync(fg)?;
 
-    let channel = MeshtasticChannel::new(&args.name, &args.key);
+    let mut encoder = MeshtasticEncoder::new(args.from)
+        .dest(args.to)
+        .hop_limit(args.hop_limit)
+        .want_ack(args.want_ack);
+    if let Some(hop_start) = args.hop_start {
+        encoder = encoder.hop_start(hop_start);
+    }
+    if let Some(start) = args.sequential_ids {
+        encoder = encoder.packet_ids(PacketIds::Sequential(start));
+    }
     loop {
         let msg = {
             let i = std::io::stdin().lock();
             let mut o = std::io::stdout().lock();
-            write!(o, "{}: ", &args.name)?;
+            write!(o, "{}: ", &name)?;
             o.flush()?;
             let mut iterator = i.lines();
             iterator.next().unwrap()?
         };
-        let data = channel.encode(msg);
+        let payload = if args.hex {
+            match parse_hex(&msg) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("invalid payload: {e}");
+                    continue;
+                }
+            }
+        } else {
+            msg.into_bytes()
+        };
+        let packet = encoder.payload(&channel, args.port, payload);
+        info!("sending packet {:08x} to {:08x}", packet.packet_id, packet.dest);
+        let data = packet.to_bytes();
         let mut handle = handle.clone();
 
         rt.block_on(async move {
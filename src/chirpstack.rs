@@ -0,0 +1,598 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::anyhow;
+use base64::prelude::*;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use rumqttc::AsyncClient;
+use rumqttc::Event;
+use rumqttc::LastWill;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::QoS;
+use serde_json::Value;
+use serde_json::json;
+use tokio::runtime::Runtime;
+use triggered::Listener;
+use triggered::Trigger;
+
+use futuresdr::channel::mpsc::Receiver;
+use futuresdr::channel::mpsc::Sender;
+use futuresdr::prelude::*;
+
+use crate::decoder::annotated_payload;
+use crate::downlink::Downlink;
+use crate::downlink::DownlinkError;
+use crate::downlink::DownlinkScheduler;
+use crate::downlink::DownlinkTiming;
+use crate::downlink::ScheduledDownlink;
+use crate::downlink::reception_remainder;
+use crate::downlink::tmst_now;
+use crate::utils::Bandwidth;
+use crate::utils::CodeRate;
+use crate::utils::SpreadingFactor;
+
+/// Connection settings of the ChirpStack MQTT gateway backend.
+///
+/// Events and commands are JSON encoded, so the ChirpStack region has to be configured with
+/// `[regions.gateway.backend.mqtt] json=true`.
+#[derive(Debug, Clone)]
+pub struct ChirpstackConfig {
+    pub host: String,
+    pub port: u16,
+    pub gateway_id: u64,
+    /// prefix of all topics, e.g. `eu868` for the default ChirpStack v4 region setup
+    pub topic_prefix: Option<String>,
+    pub credentials: Option<(String, String)>,
+    pub keep_alive: Duration,
+    /// delay before reconnecting after the connection failed, doubled on every further attempt
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// frequencies (Hz) of the TX chain, downlinks outside are rejected with `TX_FREQ`
+    pub tx_freq_range: Option<RangeInclusive<u32>>,
+}
+
+impl ChirpstackConfig {
+    pub fn new(host: &str, port: u16, gateway_id: u64) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            gateway_id,
+            topic_prefix: None,
+            credentials: None,
+            keep_alive: Duration::from_secs(30),
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+            tx_freq_range: None,
+        }
+    }
+
+    fn gateway_id(&self) -> String {
+        format!("{:016x}", self.gateway_id)
+    }
+
+    /// `[<prefix>/]gateway/<id>/<suffix>`
+    fn topic(&self, suffix: &str) -> String {
+        match &self.topic_prefix {
+            Some(prefix) => format!("{}/gateway/{}/{}", prefix, self.gateway_id(), suffix),
+            None => format!("gateway/{}/{}", self.gateway_id(), suffix),
+        }
+    }
+
+    fn conn_state(&self, online: bool) -> Vec<u8> {
+        json!({
+            "gatewayId": self.gateway_id(),
+            "state": if online { "ONLINE" } else { "OFFLINE" },
+        })
+        .to_string()
+        .into_bytes()
+    }
+}
+
+fn code_rate_name(code_rate: usize) -> Option<&'static str> {
+    match code_rate {
+        1 => Some("CR_4_5"),
+        2 => Some("CR_4_6"),
+        3 => Some("CR_4_7"),
+        4 => Some("CR_4_8"),
+        _ => None,
+    }
+}
+
+fn parse_code_rate(name: &str) -> Option<CodeRate> {
+    match name {
+        "CR_4_5" => Some(CodeRate::CR_4_5),
+        "CR_4_6" => Some(CodeRate::CR_4_6),
+        "CR_4_7" => Some(CodeRate::CR_4_7),
+        "CR_4_8" => Some(CodeRate::CR_4_8),
+        _ => None,
+    }
+}
+
+/// Build an `UplinkFrame` event from the annotations of a decoded frame.
+///
+/// The `context` carries the 32 bit microsecond counter at the end of the frame, which is echoed
+/// back in delayed downlinks.
+fn uplink_frame(
+    m: &HashMap<String, Pmt>,
+    gateway_id: &str,
+    uplink_id: u32,
+) -> anyhow::Result<Value> {
+    let payload = annotated_payload(m).ok_or_else(|| anyhow!("missing or invalid payload"))?;
+    let sf = match m.get("sf") {
+        Some(Pmt::U32(sf)) => *sf as usize,
+        Some(Pmt::Usize(sf)) => *sf,
+        p => return Err(anyhow!("invalid sf {p:?}")),
+    };
+    let bw = match m.get("bw") {
+        Some(Pmt::U32(bw)) => *bw * 1000,
+        p => return Err(anyhow!("invalid bw {p:?}")),
+    };
+    let freq = match m.get("freq") {
+        Some(Pmt::F64(f)) if f.is_finite() && *f > 0.0 => *f as u32,
+        p => return Err(anyhow!("invalid freq {p:?}")),
+    };
+    let code_rate = match m.get("code_rate") {
+        Some(Pmt::Usize(cr)) => code_rate_name(*cr),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("invalid code_rate {:?}", m.get("code_rate")))?;
+    let crc_status = match m.get("has_crc") {
+        Some(Pmt::Bool(true)) => "CRC_OK",
+        Some(Pmt::Bool(false)) => "NO_CRC",
+        p => return Err(anyhow!("invalid has_crc {p:?}")),
+    };
+    let snr = match m.get("snr") {
+        Some(Pmt::F64(snr)) if snr.is_finite() => *snr,
+        p => return Err(anyhow!("invalid snr {p:?}")),
+    };
+    let rssi = match m.get("rssi") {
+        Some(Pmt::F64(rssi)) if rssi.is_finite() => *rssi,
+        p => return Err(anyhow!("invalid rssi {p:?}")),
+    };
+    let timestamp_nanos = match m.get("timestamp") {
+        Some(Pmt::U64(t)) => *t,
+        p => return Err(anyhow!("invalid timestamp {p:?}")),
+    };
+    // like a concentrator, the counter is latched when the reception ends
+    let remainder = reception_remainder(m, payload.len())
+        .ok_or_else(|| anyhow!("invalid modulation annotations"))?;
+    let tmst = ((timestamp_nanos + remainder.as_nanos() as u64) / 1000) as u32;
+
+    Ok(json!({
+        "phyPayload": BASE64_STANDARD.encode(&payload),
+        "txInfo": {
+            "frequency": freq,
+            "modulation": {
+                "lora": {
+                    "bandwidth": bw,
+                    "spreadingFactor": sf,
+                    "codeRate": code_rate,
+                },
+            },
+        },
+        "rxInfo": {
+            "gatewayId": gateway_id,
+            "uplinkId": uplink_id,
+            "gwTime": DateTime::from_timestamp_nanos(timestamp_nanos as i64)
+                .to_rfc3339_opts(SecondsFormat::Nanos, true),
+            "rssi": rssi.round() as i32,
+            "snr": snr,
+            "channel": 0,
+            "context": BASE64_STANDARD.encode(tmst.to_be_bytes()),
+            "crcStatus": crc_status,
+        },
+    }))
+}
+
+/// Parse a protobuf JSON duration like `1s` or `1.500s`.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let secs: f64 = duration.strip_suffix('s')?.parse().ok()?;
+    (secs.is_finite() && secs >= 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+/// Turn a `DownlinkFrameItem` into a downlink, timed relative to the uplink given by its context.
+fn parse_item(item: &Value) -> Result<Downlink, DownlinkError> {
+    let unsupported = |what: &str| DownlinkError::Unsupported(what.to_string());
+
+    let payload = item
+        .get("phyPayload")
+        .and_then(Value::as_str)
+        .and_then(|p| BASE64_STANDARD.decode(p).ok())
+        .ok_or_else(|| unsupported("phyPayload"))?;
+    let tx_info = item.get("txInfo").ok_or_else(|| unsupported("txInfo"))?;
+    let lora = tx_info
+        .pointer("/modulation/lora")
+        .ok_or_else(|| unsupported("modulation"))?;
+    let sf = lora
+        .get("spreadingFactor")
+        .and_then(Value::as_u64)
+        .and_then(|sf| SpreadingFactor::try_from(sf as u8).ok())
+        .ok_or_else(|| unsupported("spreadingFactor"))?;
+    let bw = lora
+        .get("bandwidth")
+        .and_then(Value::as_u64)
+        .and_then(|bw| Bandwidth::try_from(bw as u32).ok())
+        .ok_or_else(|| unsupported("bandwidth"))?;
+    let code_rate = match lora.get("codeRate").and_then(Value::as_str) {
+        Some(name) => parse_code_rate(name).ok_or_else(|| unsupported("codeRate"))?,
+        None => CodeRate::CR_4_5,
+    };
+    let freq = tx_info
+        .get("frequency")
+        .and_then(Value::as_u64)
+        .ok_or(DownlinkError::TxFreq)? as u32;
+
+    let timing = tx_info.get("timing").ok_or_else(|| unsupported("timing"))?;
+    let timing = if timing.get("immediately").is_some() {
+        DownlinkTiming::Immediate
+    } else if let Some(delay) = timing.pointer("/delay/delay") {
+        let delay = delay
+            .as_str()
+            .and_then(parse_duration)
+            .ok_or_else(|| unsupported("delay"))?;
+        let context = tx_info
+            .get("context")
+            .and_then(Value::as_str)
+            .and_then(|c| BASE64_STANDARD.decode(c).ok())
+            .and_then(|c| <[u8; 4]>::try_from(c).ok())
+            .ok_or_else(|| unsupported("context"))?;
+        DownlinkTiming::Tmst(u32::from_be_bytes(context).wrapping_add(delay.as_micros() as u32))
+    } else if timing.get("gpsEpoch").is_some() {
+        return Err(DownlinkError::GpsUnlocked);
+    } else {
+        return Err(unsupported("timing"));
+    };
+
+    Ok(Downlink {
+        payload,
+        freq,
+        sf,
+        bw,
+        code_rate,
+        invert_iq: lora
+            .get("polarizationInversion")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+        has_crc: false,
+        preamble_len: lora
+            .get("preamble")
+            .and_then(Value::as_u64)
+            .filter(|p| *p > 0)
+            .map(|p| p as usize)
+            .unwrap_or(8),
+        power: tx_info.get("power").and_then(Value::as_i64),
+        timing,
+    })
+}
+
+/// `TxAckStatus` reported for a rejected downlink
+fn ack_status(e: &DownlinkError) -> &'static str {
+    match e {
+        DownlinkError::TooLate => "TOO_LATE",
+        DownlinkError::TooEarly => "TOO_EARLY",
+        DownlinkError::Collision => "COLLISION_PACKET",
+        DownlinkError::TxFreq => "TX_FREQ",
+        DownlinkError::GpsUnlocked => "GPS_UNLOCKED",
+        DownlinkError::Unsupported(_) => "INTERNAL_ERROR",
+    }
+}
+
+/// Schedule the first item of a `DownlinkFrame` that fits and build the `DownlinkTxAck`.
+fn handle_command_down(
+    frame: &Value,
+    gateway_id: &str,
+    scheduler: &mut DownlinkScheduler,
+    downlink_sender: &mut Sender<ScheduledDownlink>,
+) -> Value {
+    let items = frame
+        .get("items")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let mut statuses = Vec::with_capacity(items.len());
+    let mut sent = false;
+    for item in &items {
+        if sent {
+            statuses.push("IGNORED");
+            continue;
+        }
+        let status = match parse_item(item).and_then(|d| scheduler.schedule(d, tmst_now())) {
+            Ok(scheduled) => {
+                if downlink_sender.try_send(scheduled).is_ok() {
+                    sent = true;
+                    "OK"
+                } else {
+                    warn!("no transmitter attached, dropping downlink");
+                    "INTERNAL_ERROR"
+                }
+            }
+            Err(e) => {
+                warn!("downlink item rejected: {}", e);
+                ack_status(&e)
+            }
+        };
+        statuses.push(status);
+    }
+    json!({
+        "gatewayId": gateway_id,
+        "downlinkId": frame.get("downlinkId").cloned().unwrap_or(json!(0)),
+        "items": statuses
+            .into_iter()
+            .map(|status| json!({ "status": status }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Publish frames as ChirpStack gateway events on an MQTT broker and receive downlink commands.
+#[derive(Block)]
+#[message_inputs(r#in)]
+#[null_kernel]
+pub struct ChirpstackClient {
+    shutdown_trigger: Trigger,
+    to_mqtt: Sender<Value>,
+    downlink_receiver: Option<Receiver<ScheduledDownlink>>,
+    gateway_id: String,
+    #[allow(dead_code)]
+    runtime: Runtime,
+}
+
+impl ChirpstackClient {
+    pub fn new(config: ChirpstackConfig) -> anyhow::Result<Self> {
+        let (to_mqtt, from_block) = mpsc::channel::<Value>(64);
+        let (downlink_sender, downlink_receiver) = mpsc::channel::<ScheduledDownlink>(16);
+        let (shutdown_trigger, shutdown_signal) = triggered::trigger();
+        let gateway_id = config.gateway_id();
+
+        let runtime = Runtime::new().context("creating tokio runtime")?;
+        runtime.spawn(run_mqtt(
+            config,
+            from_block,
+            downlink_sender,
+            shutdown_signal,
+        ));
+
+        Ok(Self {
+            shutdown_trigger,
+            to_mqtt,
+            downlink_receiver: Some(downlink_receiver),
+            gateway_id,
+            runtime,
+        })
+    }
+
+    /// Downlinks received on `command/down`, to be handed to [`transmit_downlinks`](crate::downlink::transmit_downlinks).
+    pub fn take_downlinks(&mut self) -> Option<Receiver<ScheduledDownlink>> {
+        self.downlink_receiver.take()
+    }
+
+    async fn r#in(
+        &mut self,
+        io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::Finished => {
+                self.shutdown_trigger.trigger();
+                io.finished = true;
+            }
+            Pmt::MapStrPmt(m) => match uplink_frame(&m, &self.gateway_id, rand::random()) {
+                Ok(frame) => {
+                    if let Err(e) = self.to_mqtt.try_send(frame) {
+                        warn!("dropping uplink: {}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!("dropping invalid frame: {}", e);
+                    return Ok(Pmt::InvalidValue);
+                }
+            },
+            _ => return Ok(Pmt::InvalidValue),
+        }
+        Ok(Pmt::Ok)
+    }
+}
+
+/// Drive the MQTT connection, publishing uplinks and handling downlink commands.
+async fn run_mqtt(
+    config: ChirpstackConfig,
+    mut from_block: Receiver<Value>,
+    mut downlink_sender: Sender<ScheduledDownlink>,
+    shutdown: Listener,
+) {
+    let gateway_id = config.gateway_id();
+    let up_topic = config.topic("event/up");
+    let ack_topic = config.topic("event/ack");
+    let down_topic = config.topic("command/down");
+    let state_topic = config.topic("state/conn");
+
+    let mut options = MqttOptions::new(
+        format!("futuresdr-lora-{gateway_id}"),
+        config.host.as_str(),
+        config.port,
+    );
+    options.set_keep_alive(config.keep_alive);
+    options.set_last_will(LastWill::new(
+        state_topic.as_str(),
+        config.conn_state(false),
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some((user, password)) = &config.credentials {
+        options.set_credentials(user.as_str(), password.as_str());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+    let mut scheduler = DownlinkScheduler::with_freq_range(config.tx_freq_range.clone());
+    let mut backoff = config.backoff_base;
+    loop {
+        tokio::select! {
+            _ = shutdown.clone() => {
+                let _ = client.try_publish(state_topic.as_str(), QoS::AtLeastOnce, true, config.conn_state(false));
+                let _ = client.try_disconnect();
+                return;
+            }
+            frame = from_block.next() => {
+                let Some(frame) = frame else {
+                    return;
+                };
+                if let Err(e) = client.try_publish(up_topic.as_str(), QoS::AtMostOnce, false, frame.to_string()) {
+                    warn!("dropping uplink: {}", e);
+                }
+            }
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("connected to MQTT broker {}:{}", config.host, config.port);
+                    backoff = config.backoff_base;
+                    if let Err(e) = client.try_subscribe(down_topic.as_str(), QoS::AtLeastOnce) {
+                        warn!("could not subscribe to {}: {}", down_topic, e);
+                    }
+                    let _ = client.try_publish(state_topic.as_str(), QoS::AtLeastOnce, true, config.conn_state(true));
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == down_topic => {
+                    let frame: Value = match serde_json::from_slice(&publish.payload) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            warn!("invalid downlink command: {}", e);
+                            continue;
+                        }
+                    };
+                    let ack = handle_command_down(&frame, &gateway_id, &mut scheduler, &mut downlink_sender);
+                    if let Err(e) = client.try_publish(ack_topic.as_str(), QoS::AtMostOnce, false, ack.to_string()) {
+                        warn!("could not send downlink ack: {}", e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // the event loop reconnects on the next poll
+                    warn!("MQTT connection failed: {}", e);
+                    tokio::select! {
+                        _ = shutdown.clone() => return,
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                    backoff = (backoff * 2).min(config.backoff_max);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    use crate::downlink::payload_duration;
+
+    fn item(context: u32, delay: &str, frequency: u32) -> Value {
+        json!({
+            "phyPayload": "YPF9vkkgAgAB",
+            "txInfo": {
+                "frequency": frequency,
+                "power": 14,
+                "modulation": {
+                    "lora": {
+                        "bandwidth": 125000,
+                        "spreadingFactor": 7,
+                        "codeRate": "CR_4_5",
+                        "polarizationInversion": true,
+                    },
+                },
+                "timing": { "delay": { "delay": delay } },
+                "context": BASE64_STANDARD.encode(context.to_be_bytes()),
+            },
+        })
+    }
+
+    #[test]
+    fn parse_delayed_item() {
+        let downlink = parse_item(&item(u32::MAX - 999, "1.500s", 868_100_000)).unwrap();
+        assert_eq!(
+            downlink.payload,
+            [0x60, 0xf1, 0x7d, 0xbe, 0x49, 0x20, 0x02, 0x00, 0x01]
+        );
+        assert_eq!(downlink.timing, DownlinkTiming::Tmst(1_499_000));
+        assert_eq!(
+            (downlink.sf, downlink.bw),
+            (SpreadingFactor::SF7, Bandwidth::BW125)
+        );
+        assert!(downlink.invert_iq);
+        assert_eq!(downlink.power, Some(14));
+        assert_eq!(parse_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_duration("-1s"), None);
+    }
+
+    #[test]
+    fn command_down_acks() {
+        let mut scheduler = DownlinkScheduler::with_freq_range(Some(863_000_000..=870_000_000));
+        let (mut sender, mut receiver) = mpsc::channel::<ScheduledDownlink>(4);
+        let now = tmst_now();
+        let frame = json!({
+            "downlinkId": 42,
+            "items": [
+                // RX1 is already over, RX2 is outside the TX range, then a valid RX2
+                item(now.wrapping_sub(2_000_000), "1s", 868_100_000),
+                item(now, "2s", 923_300_000),
+                item(now, "2s", 869_525_000),
+                item(now, "3s", 869_525_000),
+            ],
+        });
+        let ack = handle_command_down(&frame, "0011223344556677", &mut scheduler, &mut sender);
+        assert_eq!(
+            ack,
+            json!({
+                "gatewayId": "0011223344556677",
+                "downlinkId": 42,
+                "items": [
+                    { "status": "TOO_LATE" },
+                    { "status": "TX_FREQ" },
+                    { "status": "OK" },
+                    { "status": "IGNORED" },
+                ],
+            })
+        );
+        let scheduled = futures::executor::block_on(receiver.next()).unwrap();
+        assert_eq!(scheduled.start, now.wrapping_add(2_000_000));
+    }
+
+    #[test]
+    fn uplink_context_at_end_of_reception() {
+        let phy = BASE64_STANDARD.decode("QPF9vkkAAgABlUN4disR/w0=").unwrap();
+        let header_start = 1_700_000_000_000_000_000u64;
+        let annotations = HashMap::from([
+            ("payload".to_string(), Pmt::Blob(phy.clone())),
+            ("sf".to_string(), Pmt::Usize(7)),
+            ("bw".to_string(), Pmt::U32(125)),
+            ("freq".to_string(), Pmt::F64(868.1e6)),
+            ("snr".to_string(), Pmt::F64(7.5)),
+            ("rssi".to_string(), Pmt::F64(-87.6)),
+            ("timestamp".to_string(), Pmt::U64(header_start)),
+            ("code_rate".to_string(), Pmt::Usize(1)),
+            ("has_crc".to_string(), Pmt::Bool(false)),
+        ]);
+        let frame = uplink_frame(&annotations, "0011223344556677", 1).unwrap();
+
+        let remainder = payload_duration(
+            SpreadingFactor::SF7,
+            Bandwidth::BW125,
+            CodeRate::CR_4_5,
+            false,
+            phy.len(),
+        );
+        let tmst = ((header_start + remainder.as_nanos() as u64) / 1000) as u32;
+        assert_eq!(frame["phyPayload"], "QPF9vkkAAgABlUN4disR/w0=");
+        assert_eq!(frame["txInfo"]["frequency"], 868_100_000);
+        assert_eq!(frame["txInfo"]["modulation"]["lora"]["codeRate"], "CR_4_5");
+        assert_eq!(
+            frame["rxInfo"]["context"],
+            BASE64_STANDARD.encode(tmst.to_be_bytes())
+        );
+        assert_eq!(frame["rxInfo"]["rssi"], -88);
+        assert_eq!(frame["rxInfo"]["crcStatus"], "NO_CRC");
+    }
+}
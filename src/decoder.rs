@@ -4,6 +4,18 @@ use std::collections::HashMap;
 use crate::Frame;
 use crate::utils::*;
 use crate::kiss_driver::*;
+
+/// Payload of an `out_annotated` message, without the two CRC bytes that are included when `has_crc` is set.
+pub fn annotated_payload(annotations: &HashMap<String, Pmt>) -> Option<Vec<u8>> {
+    let Some(Pmt::Blob(payload)) = annotations.get("payload") else {
+        return None;
+    };
+    match annotations.get("has_crc") {
+        Some(Pmt::Bool(true)) => Some(payload[..payload.len().checked_sub(2)?].to_vec()),
+        _ => Some(payload.clone()),
+    }
+}
+
 #[derive(Block)]
 #[message_inputs(r#in)]
 #[message_outputs(out, out_annotated, kiss, crc_check)]
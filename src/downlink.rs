@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use base64::prelude::*;
+use futuresdr::async_io::Timer;
+use futuresdr::channel::mpsc;
+use futuresdr::prelude::*;
+use futuresdr::runtime::BlockId;
+use futuresdr::runtime::FlowgraphHandle;
+use serde_json::Value;
+
+use crate::default_values::PREAMBLE_LEN;
+use crate::default_values::ldro;
+use crate::utils::Bandwidth;
+use crate::utils::CodeRate;
+use crate::utils::SpreadingFactor;
+
+/// time needed by the TX chain between handing a frame to the [`Transmitter`](crate::Transmitter) and the first sample on air
+pub const TX_LEAD: Duration = Duration::from_millis(50);
+/// downlinks scheduled further in the future are rejected as too early
+pub const MAX_ADVANCE: Duration = Duration::from_secs(30);
+
+/// Current value of the gateway's 32 bit microsecond counter.
+///
+/// Uplinks are timestamped with the unix time in microseconds, truncated to 32 bit (see
+/// [`PacketForwarderClient`](crate::PacketForwarderClient)), so downlink `tmst` values refer to the same clock.
+pub fn tmst_now() -> u32 {
+    (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros()) as u32
+}
+
+/// Duration of one LoRa symbol.
+pub fn symbol_duration(sf: SpreadingFactor, bw: Bandwidth) -> Duration {
+    Duration::from_secs_f64((1 << Into::<usize>::into(sf)) as f64 / Into::<f64>::into(bw))
+}
+
+/// Duration of the explicit header and payload symbols of a LoRa frame, i.e. the time on air after the
+/// preamble and sync word, as given in the Semtech SX127x data sheet.
+pub fn payload_duration(
+    sf: SpreadingFactor,
+    bw: Bandwidth,
+    code_rate: CodeRate,
+    has_crc: bool,
+    len: usize,
+) -> Duration {
+    let de = if ldro(sf) { 1. } else { 0. };
+    let sf_f = Into::<f64>::into(sf);
+    let payload_symbols = 8.
+        + (((8 * len) as f64 - 4. * sf_f + 28. + if has_crc { 16. } else { 0. })
+            / (4. * (sf_f - 2. * de)))
+            .ceil()
+            .max(0.)
+            * (Into::<u8>::into(code_rate) as f64 + 4.);
+    symbol_duration(sf, bw).mul_f64(payload_symbols)
+}
+
+/// Time on air from the annotated header start of a decoded frame to its end.
+///
+/// Concentrators latch the timestamp of a frame when the reception ends and RX1 downlinks are scheduled
+/// relative to it, while the receive chains annotate the start of the header.
+pub fn reception_remainder(
+    annotations: &HashMap<String, Pmt>,
+    payload_len: usize,
+) -> Option<Duration> {
+    let sf = match annotations.get("sf")? {
+        Pmt::U32(sf) => *sf as u8,
+        Pmt::Usize(sf) => *sf as u8,
+        _ => return None,
+    };
+    let (Some(Pmt::U32(bw)), Some(Pmt::Usize(code_rate)), Some(Pmt::Bool(has_crc))) = (
+        annotations.get("bw"),
+        annotations.get("code_rate"),
+        annotations.get("has_crc"),
+    ) else {
+        return None;
+    };
+    Some(payload_duration(
+        SpreadingFactor::try_from(sf).ok()?,
+        Bandwidth::try_from(bw * 1000).ok()?,
+        CodeRate::try_from(*code_rate as u8).ok()?,
+        *has_crc,
+        payload_len,
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownlinkTiming {
+    Immediate,
+    Tmst(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownlinkError {
+    TooLate,
+    TooEarly,
+    Collision,
+    TxFreq,
+    GpsUnlocked,
+    Unsupported(String),
+}
+
+impl Display for DownlinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLate => write!(f, "too late"),
+            Self::TooEarly => write!(f, "too early"),
+            Self::Collision => write!(f, "collision with scheduled downlink"),
+            Self::TxFreq => write!(f, "frequency not supported"),
+            Self::GpsUnlocked => write!(f, "GPS timed downlinks are not supported"),
+            Self::Unsupported(reason) => write!(f, "unsupported downlink: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DownlinkError {}
+
+#[derive(Debug, Clone)]
+pub struct Downlink {
+    pub payload: Vec<u8>,
+    /// center frequency in Hz
+    pub freq: u32,
+    pub sf: SpreadingFactor,
+    pub bw: Bandwidth,
+    pub code_rate: CodeRate,
+    pub invert_iq: bool,
+    pub has_crc: bool,
+    pub preamble_len: usize,
+    /// requested TX power in dBm, applied through the gain of the [`TxSink`]
+    pub power: Option<i64>,
+    pub timing: DownlinkTiming,
+}
+
+fn parse_datr(datr: &str) -> Option<(SpreadingFactor, Bandwidth)> {
+    let (sf, bw) = datr.strip_prefix("SF")?.split_once("BW")?;
+    let sf = SpreadingFactor::try_from(sf.parse::<u8>().ok()?).ok()?;
+    let bw = match bw {
+        "62" | "62.5" => Bandwidth::BW62,
+        "125" => Bandwidth::BW125,
+        "250" => Bandwidth::BW250,
+        "500" => Bandwidth::BW500,
+        _ => return None,
+    };
+    Some((sf, bw))
+}
+
+fn parse_codr(codr: &str) -> Option<CodeRate> {
+    match codr {
+        "4/5" => Some(CodeRate::CR_4_5),
+        "4/6" | "2/3" => Some(CodeRate::CR_4_6),
+        "4/7" => Some(CodeRate::CR_4_7),
+        "4/8" | "2/4" | "1/2" => Some(CodeRate::CR_4_8),
+        _ => None,
+    }
+}
+
+impl Downlink {
+    /// Parse a GWMP `txpk` JSON object.
+    pub fn from_txpk(txpk: &Value) -> Result<Self, DownlinkError> {
+        let unsupported = |what: &str| DownlinkError::Unsupported(what.to_string());
+
+        if let Some(modu) = txpk.get("modu").and_then(Value::as_str) {
+            if modu != "LORA" {
+                return Err(DownlinkError::Unsupported(format!("modulation {modu}")));
+            }
+        }
+        let timing = if txpk.get("imme").and_then(Value::as_bool).unwrap_or(false) {
+            DownlinkTiming::Immediate
+        } else if let Some(tmst) = txpk.get("tmst").and_then(Value::as_u64) {
+            DownlinkTiming::Tmst(tmst as u32)
+        } else {
+            return Err(DownlinkError::GpsUnlocked);
+        };
+        let freq = txpk
+            .get("freq")
+            .and_then(Value::as_f64)
+            .ok_or(DownlinkError::TxFreq)?;
+        let (sf, bw) = txpk
+            .get("datr")
+            .and_then(Value::as_str)
+            .and_then(parse_datr)
+            .ok_or_else(|| unsupported("datr"))?;
+        let code_rate = txpk
+            .get("codr")
+            .and_then(Value::as_str)
+            .map(|c| parse_codr(c).ok_or_else(|| unsupported("codr")))
+            .transpose()?
+            .unwrap_or(CodeRate::CR_4_5);
+        let payload = txpk
+            .get("data")
+            .and_then(Value::as_str)
+            .and_then(|d| BASE64_STANDARD.decode(d).ok())
+            .ok_or_else(|| unsupported("data"))?;
+        if let Some(size) = txpk.get("size").and_then(Value::as_u64) {
+            if size as usize != payload.len() {
+                warn!(
+                    "txpk size {} does not match payload length {}",
+                    size,
+                    payload.len()
+                );
+            }
+        }
+
+        Ok(Self {
+            payload,
+            freq: (freq * 1e6).round() as u32,
+            sf,
+            bw,
+            code_rate,
+            invert_iq: txpk.get("ipol").and_then(Value::as_bool).unwrap_or(false),
+            has_crc: !txpk.get("ncrc").and_then(Value::as_bool).unwrap_or(false),
+            preamble_len: txpk
+                .get("prea")
+                .and_then(Value::as_u64)
+                .map(|p| p as usize)
+                .unwrap_or(PREAMBLE_LEN),
+            power: txpk.get("powe").and_then(Value::as_i64),
+            timing,
+        })
+    }
+
+    /// LoRa time on air as given in the Semtech SX127x data sheet
+    pub fn time_on_air(&self) -> Duration {
+        let t_sym = symbol_duration(self.sf, self.bw);
+        t_sym.mul_f64(self.preamble_len as f64 + 4.25)
+            + payload_duration(
+                self.sf,
+                self.bw,
+                self.code_rate,
+                self.has_crc,
+                self.payload.len(),
+            )
+    }
+
+    /// Message understood by the [`Transmitter`](crate::Transmitter) for a given TX sample rate.
+    pub fn to_pmt(&self, sample_rate: usize) -> Pmt {
+        let oversampling = (sample_rate / Into::<usize>::into(self.bw)).max(1);
+        Pmt::MapStrPmt(HashMap::from([
+            (String::from("payload"), Pmt::Blob(self.payload.clone())),
+            (String::from("sf"), Pmt::Usize(self.sf.into())),
+            (String::from("code_rate"), Pmt::Usize(self.code_rate.into())),
+            (String::from("has_crc"), Pmt::Bool(self.has_crc)),
+            (String::from("ldro"), Pmt::Bool(ldro(self.sf))),
+            (String::from("invert_iq"), Pmt::Bool(self.invert_iq)),
+            (String::from("preamble_len"), Pmt::Usize(self.preamble_len)),
+            (String::from("oversampling"), Pmt::Usize(oversampling)),
+        ]))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledDownlink {
+    pub downlink: Downlink,
+    /// counter value at which the first sample should be on air
+    pub start: u32,
+}
+
+/// Admission control for downlinks, mirroring the checks of the Semtech just-in-time queue.
+#[derive(Default)]
+pub struct DownlinkScheduler {
+    /// airtime of accepted downlinks as (start, end) counter values
+    scheduled: Vec<(u32, u32)>,
+    /// frequencies (Hz) the TX chain can send on, any if `None`
+    freq_range: Option<RangeInclusive<u32>>,
+}
+
+impl DownlinkScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject downlinks outside the given frequency range (Hz) with [`DownlinkError::TxFreq`].
+    pub fn with_freq_range(freq_range: Option<RangeInclusive<u32>>) -> Self {
+        Self {
+            scheduled: Vec::new(),
+            freq_range,
+        }
+    }
+
+    /// Check a downlink against timing and already scheduled frames and reserve its airtime.
+    pub fn schedule(
+        &mut self,
+        downlink: Downlink,
+        now: u32,
+    ) -> Result<ScheduledDownlink, DownlinkError> {
+        if let Some(range) = &self.freq_range {
+            if !range.contains(&downlink.freq) {
+                return Err(DownlinkError::TxFreq);
+            }
+        }
+        let lead = TX_LEAD.as_micros() as u32;
+        let start = match downlink.timing {
+            DownlinkTiming::Immediate => now.wrapping_add(lead),
+            DownlinkTiming::Tmst(tmst) => {
+                let delay = tmst.wrapping_sub(now) as i32;
+                if delay < lead as i32 {
+                    return Err(DownlinkError::TooLate);
+                }
+                if delay as u64 > MAX_ADVANCE.as_micros() as u64 {
+                    return Err(DownlinkError::TooEarly);
+                }
+                tmst
+            }
+        };
+        let end = start.wrapping_add(downlink.time_on_air().as_micros() as u32);
+
+        // forget downlinks that are already on air or done
+        self.scheduled
+            .retain(|(_, e)| (e.wrapping_sub(now) as i32) > 0);
+        let overlaps = self
+            .scheduled
+            .iter()
+            .any(|(s, e)| (start.wrapping_sub(*e) as i32) < 0 && (end.wrapping_sub(*s) as i32) > 0);
+        if overlaps {
+            return Err(DownlinkError::Collision);
+        }
+        self.scheduled.push((start, end));
+        Ok(ScheduledDownlink { downlink, start })
+    }
+}
+
+/// SDR sink of the TX chain, retuned to the frequency and power of every downlink.
+#[derive(Debug, Clone, Copy)]
+pub struct TxSink {
+    pub block: BlockId,
+    /// sink gain in dB that gives `max_power`
+    pub gain: f64,
+    /// highest TX power in dBm, higher requests are sent with it like the packet forwarder's TX LUT does
+    pub max_power: i64,
+}
+
+impl TxSink {
+    /// Sink gain for a requested TX power, assuming the output power follows the gain in dB.
+    pub fn gain(&self, power: Option<i64>) -> f64 {
+        match power {
+            Some(power) => self.gain - (self.max_power - power.min(self.max_power)) as f64,
+            None => self.gain,
+        }
+    }
+}
+
+/// Hand scheduled downlinks to the [`Transmitter`](crate::Transmitter) at their start time, retuning the SDR sink if given.
+///
+/// Without a sink, as in simulations, the frequency and power of the downlinks are not applied.
+pub async fn transmit_downlinks(
+    handle: FlowgraphHandle,
+    transmitter: BlockId,
+    sink: Option<TxSink>,
+    sample_rate: usize,
+    downlinks: mpsc::Receiver<ScheduledDownlink>,
+) {
+    downlinks
+        .for_each_concurrent(None, |scheduled| {
+            let mut handle = handle.clone();
+            async move {
+                let delay = scheduled.start.wrapping_sub(tmst_now()) as i32;
+                let wait = delay as i64 - TX_LEAD.as_micros() as i64;
+                if wait > 0 {
+                    Timer::after(Duration::from_micros(wait as u64)).await;
+                } else if delay < 0 {
+                    warn!("downlink is {}us late, sending anyway", -delay);
+                }
+                if let Some(sink) = sink {
+                    if let Err(e) = handle
+                        .call(sink.block, "freq", Pmt::F64(scheduled.downlink.freq as f64))
+                        .await
+                    {
+                        warn!("could not retune sink: {}", e);
+                    }
+                    if scheduled.downlink.power > Some(sink.max_power) {
+                        warn!(
+                            "downlink TX power {:?} dBm limited to {} dBm",
+                            scheduled.downlink.power, sink.max_power
+                        );
+                    }
+                    let gain = sink.gain(scheduled.downlink.power);
+                    if let Err(e) = handle.call(sink.block, "gain", Pmt::F64(gain)).await {
+                        warn!("could not set sink gain: {}", e);
+                    }
+                }
+                match handle
+                    .call(transmitter, "msg", scheduled.downlink.to_pmt(sample_rate))
+                    .await
+                {
+                    Ok(_) => info!(
+                        "sent downlink ({:.3}MHz, {}, {} bytes)",
+                        scheduled.downlink.freq as f64 / 1e6,
+                        scheduled.downlink.sf,
+                        scheduled.downlink.payload.len()
+                    ),
+                    Err(e) => warn!("could not send downlink: {}", e),
+                }
+            }
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downlink(timing: DownlinkTiming) -> Downlink {
+        Downlink::from_txpk(&serde_json::json!({
+            "imme": timing == DownlinkTiming::Immediate,
+            "tmst": match timing {
+                DownlinkTiming::Tmst(tmst) => tmst,
+                DownlinkTiming::Immediate => 0,
+            },
+            "freq": 869.525,
+            "powe": 14,
+            "modu": "LORA",
+            "datr": "SF9BW125",
+            "codr": "4/5",
+            "ipol": true,
+            "size": 4,
+            "data": "AQIDBA==",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_txpk() {
+        let d = downlink(DownlinkTiming::Tmst(1000));
+        assert_eq!(d.payload, [1, 2, 3, 4]);
+        assert_eq!(d.freq, 869_525_000);
+        assert_eq!((d.sf, d.bw), (SpreadingFactor::SF9, Bandwidth::BW125));
+        assert!(matches!(d.code_rate, CodeRate::CR_4_5));
+        assert!(d.invert_iq && d.has_crc);
+        assert_eq!(d.power, Some(14));
+        assert_eq!(d.timing, DownlinkTiming::Tmst(1000));
+    }
+
+    #[test]
+    fn schedule_timing() {
+        let now = u32::MAX - 100_000;
+        let mut scheduler = DownlinkScheduler::new();
+        let too_late = downlink(DownlinkTiming::Tmst(now.wrapping_add(1000)));
+        assert_eq!(
+            scheduler.schedule(too_late, now).unwrap_err(),
+            DownlinkError::TooLate
+        );
+        let too_early = downlink(DownlinkTiming::Tmst(now.wrapping_add(31_000_000)));
+        assert_eq!(
+            scheduler.schedule(too_early, now).unwrap_err(),
+            DownlinkError::TooEarly
+        );
+        // RX1 one second after the uplink, across the counter wrap
+        let rx1 = now.wrapping_add(1_000_000);
+        let scheduled = scheduler
+            .schedule(downlink(DownlinkTiming::Tmst(rx1)), now)
+            .unwrap();
+        assert_eq!(scheduled.start, rx1);
+    }
+
+    #[test]
+    fn schedule_collision() {
+        let now = 5_000_000;
+        let mut scheduler = DownlinkScheduler::new();
+        let first = downlink(DownlinkTiming::Tmst(now + 1_000_000));
+        let airtime = first.time_on_air().as_micros() as u32;
+        scheduler.schedule(first, now).unwrap();
+        let overlapping = downlink(DownlinkTiming::Tmst(now + 1_000_000 + airtime / 2));
+        assert_eq!(
+            scheduler.schedule(overlapping, now).unwrap_err(),
+            DownlinkError::Collision
+        );
+        let after = downlink(DownlinkTiming::Tmst(now + 1_000_000 + airtime));
+        assert!(scheduler.schedule(after, now).is_ok());
+        // once the downlinks are done, their airtime is free again
+        let later = now + 1_000_000 + 2 * airtime + 1;
+        let immediate = downlink(DownlinkTiming::Immediate);
+        assert!(scheduler.schedule(immediate, later).is_ok());
+    }
+
+    #[test]
+    fn schedule_freq_range() {
+        let mut scheduler = DownlinkScheduler::with_freq_range(Some(863_000_000..=870_000_000));
+        let mut d = downlink(DownlinkTiming::Immediate);
+        d.freq = 923_300_000;
+        assert_eq!(scheduler.schedule(d, 0).unwrap_err(), DownlinkError::TxFreq);
+    }
+}
@@ -0,0 +1,947 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futuresdr::channel::mpsc;
+use futuresdr::prelude::*;
+use futuresdr::runtime::BlockId;
+use futuresdr::runtime::FlowgraphHandle;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::Instant;
+
+use crate::adr::tx_power_gain_db;
+use crate::decoder::annotated_payload;
+use crate::default_values::PREAMBLE_LEN;
+use crate::default_values::ldro;
+use crate::downlink::payload_duration;
+use crate::downlink::symbol_duration;
+use crate::lorawan::FCtrl;
+use crate::lorawan::MHdr;
+use crate::lorawan::MType;
+use crate::lorawan::MacCommand;
+use crate::lorawan::MacPayload;
+use crate::lorawan::PhyPayload;
+use crate::lorawan_keys::DecryptedFrame;
+use crate::lorawan_keys::Key;
+use crate::lorawan_keys::KeyStore;
+use crate::lorawan_keys::MicContext;
+use crate::lorawan_keys::OtaaDevice;
+use crate::lorawan_keys::SecurityError;
+use crate::lorawan_keys::SecurityEvent;
+use crate::region::Region;
+use crate::utils::Bandwidth;
+use crate::utils::CodeRate;
+use crate::utils::SpreadingFactor;
+
+/// scheduling jitter and decoding latency tolerated around the RX windows
+const RX_MARGIN: Duration = Duration::from_millis(50);
+/// RX2 opens one second after RX1
+const RX2_OFFSET: Duration = Duration::from_secs(1);
+/// uplinks without downlink before ADRACKReq is set
+const ADR_ACK_LIMIT: u32 = 64;
+/// further uplinks without downlink before each ADR backoff step
+const ADR_ACK_DELAY: u32 = 32;
+
+#[derive(Debug, Clone)]
+pub enum Activation {
+    Otaa(OtaaDevice),
+    Abp {
+        dev_addr: u32,
+        nwk_s_key: Key,
+        app_s_key: Key,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct EndDeviceConfig {
+    pub activation: Activation,
+    pub region: Region,
+    /// initial uplink data rate of the region, e.g. EU868 DR0 (SF12) to DR5 (SF7)
+    pub data_rate: u8,
+    /// set the ADR bit in uplinks
+    pub adr: bool,
+    /// RX1 delay of ABP devices, OTAA devices take it from the Join-Accept
+    pub rx1_delay: Duration,
+    pub join_accept_delay: Duration,
+    /// how long a frame is awaited after its preamble, covers the airtime and the decoding latency
+    pub rx_timeout: Duration,
+    /// transmissions of a confirmed uplink before giving up
+    pub confirmed_attempts: u8,
+    /// pause after a Join-Request without answer
+    pub join_backoff: Duration,
+    /// battery level reported in DevStatusAns, 255 if unknown
+    pub battery: u8,
+}
+
+impl EndDeviceConfig {
+    pub fn new(activation: Activation) -> Self {
+        Self {
+            activation,
+            region: Region::EU868,
+            data_rate: 0,
+            adr: true,
+            rx1_delay: Duration::from_secs(1),
+            join_accept_delay: Duration::from_secs(5),
+            rx_timeout: Duration::from_secs(3),
+            confirmed_attempts: 8,
+            join_backoff: Duration::from_secs(10),
+            battery: 255,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Uplink {
+    pub fport: u8,
+    pub payload: Vec<u8>,
+    pub confirmed: bool,
+}
+
+/// Events reported by a running [`EndDevice`].
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    Joined {
+        dev_addr: u32,
+    },
+    /// no Join-Accept received, the join is retried after the backoff
+    JoinFailed,
+    TxDone {
+        fcnt: u32,
+    },
+    Acked {
+        fcnt: u32,
+    },
+    /// confirmed uplink not acknowledged after all attempts
+    Unacked {
+        fcnt: u32,
+    },
+    Downlink(DecryptedFrame),
+    /// link settings changed by a LinkADRReq
+    LinkAdr {
+        data_rate: u8,
+        tx_power: u8,
+        nb_trans: u8,
+    },
+    LinkCheck {
+        margin: u8,
+        gw_cnt: u8,
+    },
+}
+
+pub enum DeviceCommand {
+    Send(Uplink),
+    LinkCheck,
+    Subscribe(UnboundedSender<DeviceEvent>),
+}
+
+/// Cloneable handle to a running [`EndDevice`].
+#[derive(Clone)]
+pub struct EndDeviceHandle {
+    commands: UnboundedSender<DeviceCommand>,
+}
+
+impl EndDeviceHandle {
+    /// Queue an application uplink, MAC answers are piggybacked in FOpts.
+    pub fn send(&self, fport: u8, payload: Vec<u8>, confirmed: bool) -> bool {
+        self.commands
+            .send(DeviceCommand::Send(Uplink {
+                fport,
+                payload,
+                confirmed,
+            }))
+            .is_ok()
+    }
+
+    /// Add a LinkCheckReq to the next uplink.
+    pub fn link_check(&self) -> bool {
+        self.commands.send(DeviceCommand::LinkCheck).is_ok()
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<DeviceEvent> {
+        let (tx, rx) = unbounded_channel();
+        let _ = self.commands.send(DeviceCommand::Subscribe(tx));
+        rx
+    }
+}
+
+enum InFlight {
+    Join,
+    Data {
+        frame: Vec<u8>,
+        fcnt: u32,
+        confirmed: bool,
+        attempt: u8,
+    },
+}
+
+/// Receive window on the node's channel.
+#[derive(Debug, Clone, Copy)]
+struct RxWindow {
+    open: Instant,
+    sf: SpreadingFactor,
+    /// the radio listens for about one preamble
+    preamble: Duration,
+    /// IQ inversion switched on for the decode chain of `sf`
+    tuned: bool,
+}
+
+impl RxWindow {
+    /// A frame belongs to the window if its preamble started while the window was open.
+    fn contains(&self, sf: SpreadingFactor, start: Instant) -> bool {
+        sf == self.sf
+            && self.open <= start + RX_MARGIN
+            && start <= self.open + self.preamble + RX_MARGIN
+    }
+}
+
+/// RX1 and RX2 after an uplink, `None` for a window the node cannot listen to.
+#[derive(Debug, Clone, Copy)]
+struct RxWindows {
+    rx1: Option<RxWindow>,
+    rx2: Option<RxWindow>,
+    close: Instant,
+}
+
+/// Decode chain of the node for one spreading factor.
+struct RxChain {
+    frame_sync: BlockId,
+    sf: SpreadingFactor,
+    invert_iq: bool,
+}
+
+/// LoRaWAN 1.0.x class A end device on top of a [`Transmitter`](crate::Transmitter) and the RX chains of a
+/// [`Node`](crate::Node).
+///
+/// RX1 opens `rx1_delay` after the uplink on the RX1 frequency and data rate of the region, RX2 exactly one
+/// second later on the RX2 frequency and data rate. Both listen for about one preamble: a decoded frame is
+/// accepted if its reception, computed back from its time on air, started in the window of its spreading
+/// factor. Retuning switches on the IQ inversion of the decode chain of the window's spreading factor. The
+/// node receives on a single channel, so windows on another frequency or bandwidth stay closed and
+/// RxParamSetupReq is only accepted for that channel.
+pub(crate) struct EndDevice {
+    config: EndDeviceConfig,
+    keys: KeyStore,
+    dev_addr: Option<u32>,
+    fcnt_up: u32,
+    dev_nonce: u16,
+    data_rate: u8,
+    tx_power: u8,
+    nb_trans: u8,
+    rx1_delay: Duration,
+    rx1_dr_offset: u8,
+    rx2_data_rate: u8,
+    rx2_freq: Option<u32>,
+    /// frequency of the node's channel in Hz, uplinks are sent and downlinks received on it
+    channel: u32,
+    bw: Bandwidth,
+    rx_chains: Vec<RxChain>,
+    queue: VecDeque<Uplink>,
+    /// MAC commands for the FOpts of the next uplink
+    mac_pending: Vec<MacCommand>,
+    /// FCnt of a confirmed downlink that has to be acknowledged
+    ack_pending: Option<u32>,
+    /// uplinks since the last downlink
+    adr_ack_cnt: u32,
+    last_snr: Option<f64>,
+    in_flight: Option<InFlight>,
+    /// frame handed to the transmitter, waiting for tx_done
+    on_air: bool,
+    /// set once the uplink is on air
+    rx_windows: Option<RxWindows>,
+    next_tx: Instant,
+    subscribers: Vec<UnboundedSender<DeviceEvent>>,
+}
+
+impl EndDevice {
+    pub(crate) fn new(
+        config: EndDeviceConfig,
+        channel: u32,
+        bw: Bandwidth,
+        rx_chains: Vec<(BlockId, SpreadingFactor)>,
+    ) -> Self {
+        let mut keys = KeyStore::new();
+        let dev_addr = match &config.activation {
+            Activation::Otaa(device) => {
+                keys.add_otaa(device.clone());
+                None
+            }
+            Activation::Abp {
+                dev_addr,
+                nwk_s_key,
+                app_s_key,
+            } => {
+                keys.add_abp(String::from("self"), *dev_addr, *nwk_s_key, *app_s_key);
+                Some(*dev_addr)
+            }
+        };
+        Self {
+            keys,
+            dev_addr,
+            fcnt_up: 0,
+            dev_nonce: 0,
+            data_rate: config.data_rate,
+            tx_power: 0,
+            nb_trans: 1,
+            rx1_delay: config.rx1_delay,
+            rx1_dr_offset: 0,
+            rx2_data_rate: config.region.rx2().1,
+            rx2_freq: None,
+            channel,
+            bw,
+            rx_chains: rx_chains
+                .into_iter()
+                .map(|(frame_sync, sf)| RxChain {
+                    frame_sync,
+                    sf,
+                    invert_iq: false,
+                })
+                .collect(),
+            queue: VecDeque::new(),
+            mac_pending: Vec::new(),
+            ack_pending: None,
+            adr_ack_cnt: 0,
+            last_snr: None,
+            in_flight: None,
+            on_air: false,
+            rx_windows: None,
+            next_tx: Instant::now(),
+            subscribers: Vec::new(),
+            config,
+        }
+    }
+
+    pub(crate) fn channel() -> (EndDeviceHandle, UnboundedReceiver<DeviceCommand>) {
+        let (commands, rx) = unbounded_channel();
+        (EndDeviceHandle { commands }, rx)
+    }
+
+    fn emit(&mut self, event: DeviceEvent) {
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+
+    /// Next frame to hand to the transmitter, if the device is idle.
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.on_air || self.rx_windows.is_some() || Instant::now() < self.next_tx {
+            return None;
+        }
+        if let Some(InFlight::Data { frame, .. }) = &self.in_flight {
+            // retransmission with the same FCnt
+            return Some(frame.clone());
+        }
+        if self.in_flight.is_some() {
+            return None;
+        }
+        let Some(dev_addr) = self.dev_addr else {
+            let Activation::Otaa(device) = &self.config.activation else {
+                return None;
+            };
+            self.dev_nonce = self.dev_nonce.wrapping_add(1);
+            self.keys.expect_join_accept(device.dev_eui, self.dev_nonce);
+            let frame = device.seal_join_request(self.dev_nonce);
+            self.in_flight = Some(InFlight::Join);
+            return Some(frame);
+        };
+        let uplink = self.queue.pop_front()?;
+        let max_payload = self.config.region.max_payload(self.data_rate, false);
+        if max_payload.is_some_and(|m| uplink.payload.len() + 8 > m) {
+            warn!(
+                "EndDevice: dropping {} byte uplink, DR{} allows {:?} byte MACPayload",
+                uplink.payload.len(),
+                self.data_rate,
+                max_payload
+            );
+            return None;
+        }
+        // MAC commands not fitting next to the payload wait for a later uplink
+        let fopts_room = max_payload.map_or(15, |m| (m - 8 - uplink.payload.len()).min(15));
+
+        let mut fopts = Vec::new();
+        let mut rest = Vec::new();
+        for cmd in self.mac_pending.drain(..) {
+            let bytes = cmd.to_bytes();
+            if fopts.len() + bytes.len() <= fopts_room {
+                fopts.extend(bytes);
+            } else {
+                rest.push(cmd);
+            }
+        }
+        self.mac_pending = rest;
+
+        let mhdr = MHdr {
+            mtype: if uplink.confirmed {
+                MType::ConfirmedDataUp
+            } else {
+                MType::UnconfirmedDataUp
+            },
+            major: 0,
+        };
+        if self.config.adr {
+            self.adr_backoff();
+        }
+        let conf_fcnt = self.ack_pending.take();
+        let fctrl = FCtrl {
+            adr: self.config.adr,
+            adr_ack_req: self.config.adr && self.adr_ack_cnt >= ADR_ACK_LIMIT,
+            ack: conf_fcnt.is_some(),
+            ..Default::default()
+        };
+        let fcnt = self.fcnt_up;
+        self.fcnt_up = self.fcnt_up.wrapping_add(1);
+        // single channel device, the uplink channel index is always 0
+        let mic = MicContext {
+            conf_fcnt: conf_fcnt.unwrap_or(0),
+            tx_dr: self.data_rate,
+            tx_ch: 0,
+        };
+        let frame = self.keys.session_keys(dev_addr)?.seal_data(
+            mhdr,
+            dev_addr,
+            fctrl,
+            fcnt,
+            &fopts,
+            Some(uplink.fport),
+            &uplink.payload,
+            mic,
+        );
+        if uplink.confirmed {
+            self.keys.expect_ack(dev_addr, fcnt);
+        }
+        self.in_flight = Some(InFlight::Data {
+            frame: frame.clone(),
+            fcnt,
+            confirmed: uplink.confirmed,
+            attempt: 1,
+        });
+        Some(frame)
+    }
+
+    /// Without downlinks, first restore the TX power, then lower the data rate step by step.
+    fn adr_backoff(&mut self) {
+        self.adr_ack_cnt = self.adr_ack_cnt.saturating_add(1);
+        let over = self.adr_ack_cnt.saturating_sub(ADR_ACK_LIMIT);
+        if over == 0 || over % ADR_ACK_DELAY != 0 {
+            return;
+        }
+        if self.tx_power > 0 {
+            self.tx_power = 0;
+        } else if self.data_rate > 0 {
+            self.data_rate -= 1;
+        } else {
+            return;
+        }
+        info!(
+            "EndDevice: no downlink in {} uplinks, DR{} power index {}",
+            self.adr_ack_cnt, self.data_rate, self.tx_power
+        );
+    }
+
+    /// Spreading factor of an uplink data rate of the region.
+    fn data_rate_sf(&self, dr: u8) -> Option<SpreadingFactor> {
+        if dr > self.config.region.max_uplink_data_rate() {
+            return None;
+        }
+        self.config.region.data_rate(dr)?.spreading_factor()
+    }
+
+    fn tx_settings(&self, frame: Vec<u8>) -> Pmt {
+        let sf = self
+            .data_rate_sf(self.data_rate)
+            .unwrap_or(SpreadingFactor::SF12);
+        let mut m = HashMap::new();
+        m.insert(String::from("payload"), Pmt::Blob(frame));
+        m.insert(String::from("sf"), Pmt::Usize(u8::from(sf) as usize));
+        m.insert(String::from("has_crc"), Pmt::Bool(true));
+        m.insert(String::from("ldro"), Pmt::Bool(ldro(sf)));
+        m.insert(String::from("invert_iq"), Pmt::Bool(false));
+        m.insert(
+            String::from("gain_db"),
+            Pmt::F64(tx_power_gain_db(self.tx_power)),
+        );
+        Pmt::MapStrPmt(m)
+    }
+
+    /// Uplink on air, schedule the receive windows.
+    fn on_tx_done(&mut self) {
+        self.on_air = false;
+        let delay = match &self.in_flight {
+            None => {
+                warn!("EndDevice: tx_done without frame in flight");
+                return;
+            }
+            Some(InFlight::Join) => self.config.join_accept_delay,
+            Some(InFlight::Data { fcnt, .. }) => {
+                let event = DeviceEvent::TxDone { fcnt: *fcnt };
+                self.emit(event);
+                self.rx1_delay
+            }
+        };
+        let region = self.config.region;
+        let rx1_open = Instant::now() + delay;
+        let rx2_open = rx1_open + RX2_OFFSET;
+        let rx1 = self.rx_window(
+            rx1_open,
+            region.rx1_channel(self.channel).map(u32::from),
+            region.rx1_data_rate(self.data_rate, self.rx1_dr_offset, false),
+        );
+        let rx2 = self.rx_window(
+            rx2_open,
+            Some(self.rx2_freq.unwrap_or_else(|| region.rx2().0.into())),
+            Some(self.rx2_data_rate),
+        );
+        let close = [rx1, rx2]
+            .into_iter()
+            .flatten()
+            .map(|w| w.open + w.preamble + RX_MARGIN + self.config.rx_timeout)
+            .max()
+            .unwrap_or(rx2_open);
+        self.rx_windows = Some(RxWindows { rx1, rx2, close });
+    }
+
+    /// Spreading factor of a downlink data rate, if the node has a decode chain for it.
+    fn rx_spreading_factor(&self, dr: u8) -> Option<SpreadingFactor> {
+        let data_rate = self.config.region.data_rate(dr)?;
+        let sf = data_rate.spreading_factor()?;
+        (data_rate.bandwidth()? == self.bw && self.rx_chains.iter().any(|c| c.sf == sf))
+            .then_some(sf)
+    }
+
+    /// Preamble and sync word of a downlink.
+    fn preamble_duration(&self, sf: SpreadingFactor) -> Duration {
+        symbol_duration(sf, self.bw).mul_f64(PREAMBLE_LEN as f64 + 4.25)
+    }
+
+    fn rx_window(&self, open: Instant, freq: Option<u32>, dr: Option<u8>) -> Option<RxWindow> {
+        let sf = dr
+            .and_then(|dr| self.rx_spreading_factor(dr))
+            .filter(|_| freq == Some(self.channel));
+        let Some(sf) = sf else {
+            debug!(
+                "EndDevice: cannot listen on {:?} Hz DR{:?}, window skipped",
+                freq, dr
+            );
+            return None;
+        };
+        Some(RxWindow {
+            open,
+            sf,
+            preamble: self.preamble_duration(sf),
+            tuned: false,
+        })
+    }
+
+    /// Retune for the windows that are opening, returns true if a decode chain has to change.
+    fn tune(&mut self, now: Instant) -> bool {
+        let Some(windows) = &mut self.rx_windows else {
+            return self.rx_chains.iter().any(|c| c.invert_iq);
+        };
+        let mut changed = false;
+        for window in [&mut windows.rx1, &mut windows.rx2].into_iter().flatten() {
+            if !window.tuned && window.open <= now + RX_MARGIN {
+                window.tuned = true;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Switch the IQ inversion of the decode chains to the tuned windows.
+    async fn retune(&mut self, handle: &mut FlowgraphHandle) {
+        let tuned: Vec<SpreadingFactor> = self
+            .rx_windows
+            .iter()
+            .flat_map(|w| [w.rx1, w.rx2])
+            .flatten()
+            .filter(|w| w.tuned)
+            .map(|w| w.sf)
+            .collect();
+        for chain in self.rx_chains.iter_mut() {
+            let invert_iq = tuned.contains(&chain.sf);
+            if chain.invert_iq == invert_iq {
+                continue;
+            }
+            chain.invert_iq = invert_iq;
+            if let Err(e) = handle
+                .call(chain.frame_sync, "invert_iq", Pmt::Bool(invert_iq))
+                .await
+            {
+                warn!("EndDevice: flowgraph call error: {}", e);
+            }
+        }
+    }
+
+    /// Whether a decoded frame started in RX1 or RX2, computed back from its time on air.
+    fn in_rx_window(&self, annotations: &HashMap<String, Pmt>, len: usize, now: Instant) -> bool {
+        let Some(windows) = &self.rx_windows else {
+            return false;
+        };
+        let Some(Pmt::Usize(sf)) = annotations.get("sf") else {
+            return false;
+        };
+        let Ok(sf) = SpreadingFactor::try_from(*sf as u8) else {
+            return false;
+        };
+        let code_rate = match annotations.get("code_rate") {
+            Some(Pmt::Usize(cr)) => CodeRate::try_from(*cr as u8).unwrap_or_default(),
+            _ => CodeRate::default(),
+        };
+        let has_crc = matches!(annotations.get("has_crc"), Some(Pmt::Bool(true)));
+        let time_on_air =
+            self.preamble_duration(sf) + payload_duration(sf, self.bw, code_rate, has_crc, len);
+        let Some(start) = now.checked_sub(time_on_air) else {
+            return false;
+        };
+        [windows.rx1, windows.rx2]
+            .into_iter()
+            .flatten()
+            .any(|w| w.contains(sf, start))
+    }
+
+    /// Handle a decoded frame, a valid downlink ends the receive windows.
+    fn on_rx(&mut self, pmt: Pmt) {
+        let Pmt::MapStrPmt(annotations) = pmt else {
+            return;
+        };
+        let now = Instant::now();
+        let Some(raw) = annotated_payload(&annotations) else {
+            return;
+        };
+        if !self.in_rx_window(&annotations, raw.len(), now) {
+            return;
+        }
+        let Ok(phy) = PhyPayload::parse(&raw) else {
+            return;
+        };
+        if phy.mhdr.mtype.is_uplink() {
+            return;
+        }
+        if let (MacPayload::Data(frame), Some(dev_addr)) = (&phy.payload, self.dev_addr) {
+            if frame.dev_addr != dev_addr {
+                return;
+            }
+        }
+        self.last_snr = match annotations.get("snr") {
+            Some(Pmt::F64(snr)) => Some(*snr),
+            _ => self.last_snr,
+        };
+
+        match self.keys.process(&raw, &phy) {
+            Ok(SecurityEvent::Joined {
+                dev_addr,
+                join_accept,
+                ..
+            }) => {
+                info!("EndDevice: joined as {:08X}", dev_addr);
+                self.dev_addr = Some(dev_addr);
+                self.fcnt_up = 0;
+                self.rx1_delay = Duration::from_secs(join_accept.rx_delay.max(1) as u64);
+                self.rx1_dr_offset = join_accept.rx1_dr_offset;
+                self.rx2_data_rate = join_accept.rx2_data_rate;
+                self.rx_windows = None;
+                self.in_flight = None;
+                self.next_tx = now;
+                self.emit(DeviceEvent::Joined { dev_addr });
+            }
+            Ok(SecurityEvent::Data(mut frame)) => {
+                let acked = matches!(&phy.payload, MacPayload::Data(d) if d.fctrl.ack);
+                self.adr_ack_cnt = 0;
+                frame.annotations = annotations;
+                if phy.mhdr.mtype.is_confirmed() {
+                    self.ack_pending = Some(frame.fcnt);
+                }
+                if !frame.retransmission {
+                    let commands = std::mem::take(&mut frame.mac_commands);
+                    for cmd in commands {
+                        self.on_mac_command(cmd);
+                    }
+                }
+                self.finish_uplink(acked);
+                if frame.fport.is_some_and(|p| p != 0) && !frame.retransmission {
+                    self.emit(DeviceEvent::Downlink(frame));
+                }
+            }
+            Ok(SecurityEvent::JoinRequest { .. }) | Err(SecurityError::UnknownDevice) => {}
+            Err(e) => warn!("EndDevice: dropping downlink: {}", e),
+        }
+    }
+
+    fn on_mac_command(&mut self, cmd: MacCommand) {
+        let answer = match cmd {
+            MacCommand::LinkAdrReq {
+                data_rate,
+                tx_power,
+                ch_mask,
+                redundancy,
+            } => {
+                // single channel device, channel 0 has to stay enabled
+                let channel_mask_ack = ch_mask & 0x01 != 0 && (redundancy >> 4) & 0x07 == 0;
+                let data_rate_ack = data_rate == 0x0f || self.data_rate_sf(data_rate).is_some();
+                let power_ack = tx_power == 0x0f || tx_power <= self.config.region.max_tx_power();
+                if channel_mask_ack && data_rate_ack && power_ack {
+                    if data_rate != 0x0f {
+                        self.data_rate = data_rate;
+                    }
+                    if tx_power != 0x0f {
+                        self.tx_power = tx_power;
+                    }
+                    self.nb_trans = (redundancy & 0x0f).max(1);
+                    debug!(
+                        "EndDevice: DR{} power index {} NbTrans {}",
+                        self.data_rate, self.tx_power, self.nb_trans
+                    );
+                    self.emit(DeviceEvent::LinkAdr {
+                        data_rate: self.data_rate,
+                        tx_power: self.tx_power,
+                        nb_trans: self.nb_trans,
+                    });
+                }
+                MacCommand::LinkAdrAns {
+                    power_ack,
+                    data_rate_ack,
+                    channel_mask_ack,
+                }
+            }
+            MacCommand::DevStatusReq => MacCommand::DevStatusAns {
+                battery: self.config.battery,
+                margin: self.last_snr.unwrap_or(0.0).round().clamp(-32.0, 31.0) as i8,
+            },
+            MacCommand::RxParamSetupReq {
+                rx1_dr_offset,
+                rx2_data_rate,
+                freq,
+            } => {
+                let rx1_dr_offset_ack = rx1_dr_offset <= 5;
+                let rx2_data_rate_ack = self.rx_spreading_factor(rx2_data_rate).is_some();
+                // the node cannot retune to another frequency
+                let channel_ack = freq == self.channel;
+                if rx1_dr_offset_ack && rx2_data_rate_ack && channel_ack {
+                    self.rx1_dr_offset = rx1_dr_offset;
+                    self.rx2_data_rate = rx2_data_rate;
+                    self.rx2_freq = Some(freq);
+                    debug!(
+                        "EndDevice: RX1 DR offset {} RX2 DR{} {:?} Hz",
+                        self.rx1_dr_offset, self.rx2_data_rate, self.rx2_freq
+                    );
+                }
+                MacCommand::RxParamSetupAns {
+                    rx1_dr_offset_ack,
+                    rx2_data_rate_ack,
+                    channel_ack,
+                }
+            }
+            MacCommand::RxTimingSetupReq { delay } => {
+                self.rx1_delay = Duration::from_secs(delay.max(1) as u64);
+                MacCommand::RxTimingSetupAns
+            }
+            MacCommand::LinkCheckAns { margin, gw_cnt } => {
+                self.emit(DeviceEvent::LinkCheck { margin, gw_cnt });
+                return;
+            }
+            cmd => {
+                debug!("EndDevice: ignoring MAC command {:?}", cmd);
+                return;
+            }
+        };
+        self.mac_pending.push(answer);
+    }
+
+    /// Receive windows are over, with or without a downlink.
+    fn finish_uplink(&mut self, acked: bool) {
+        self.rx_windows = None;
+        let now = Instant::now();
+        self.next_tx = now;
+        match self.in_flight.take() {
+            Some(InFlight::Join) => {
+                info!("EndDevice: no Join-Accept");
+                self.next_tx = now + self.config.join_backoff;
+                self.emit(DeviceEvent::JoinFailed);
+            }
+            Some(InFlight::Data {
+                frame,
+                fcnt,
+                confirmed,
+                attempt,
+            }) => {
+                if confirmed && acked {
+                    self.emit(DeviceEvent::Acked { fcnt });
+                    return;
+                }
+                let attempts = if confirmed {
+                    self.config.confirmed_attempts
+                } else {
+                    self.nb_trans
+                };
+                if attempt < attempts {
+                    // ACK_TIMEOUT of 1 to 3 s before the retransmission with the same FCnt
+                    let backoff = Duration::from_millis(1000 + rand::random::<u64>() % 2000);
+                    self.next_tx = now + backoff;
+                    self.in_flight = Some(InFlight::Data {
+                        frame,
+                        fcnt,
+                        confirmed,
+                        attempt: attempt + 1,
+                    });
+                } else if confirmed {
+                    info!("EndDevice: uplink {} not acknowledged", fcnt);
+                    self.emit(DeviceEvent::Unacked { fcnt });
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn next_wakeup(&self) -> Instant {
+        match &self.rx_windows {
+            Some(windows) => [windows.rx1, windows.rx2]
+                .into_iter()
+                .flatten()
+                .filter(|w| !w.tuned)
+                .map(|w| w.open.checked_sub(RX_MARGIN).unwrap_or(w.open))
+                .fold(windows.close, Instant::min),
+            None if !self.on_air
+                && (self.in_flight.is_some()
+                    || !self.queue.is_empty()
+                    || self.dev_addr.is_none()) =>
+            {
+                self.next_tx
+            }
+            None => Instant::now() + Duration::from_secs(3600),
+        }
+    }
+
+    /// Run the device until the command channel is closed.
+    pub(crate) async fn run(
+        mut self,
+        mut handle: FlowgraphHandle,
+        transmitter: BlockId,
+        mut commands: UnboundedReceiver<DeviceCommand>,
+        mut tx_done: mpsc::Receiver<Pmt>,
+        mut rx_frames: mpsc::Receiver<Pmt>,
+    ) {
+        loop {
+            if let Some(frame) = self.next_frame() {
+                match handle
+                    .call(transmitter, "msg", self.tx_settings(frame))
+                    .await
+                {
+                    Ok(_) => self.on_air = true,
+                    Err(e) => {
+                        warn!("EndDevice: flowgraph call error: {}", e);
+                        self.in_flight = None;
+                    }
+                }
+                continue;
+            }
+
+            tokio::select! {
+                cmd = commands.recv() => match cmd {
+                    Some(DeviceCommand::Send(uplink)) => self.queue.push_back(uplink),
+                    Some(DeviceCommand::LinkCheck) => self.mac_pending.push(MacCommand::LinkCheckReq),
+                    Some(DeviceCommand::Subscribe(sender)) => self.subscribers.push(sender),
+                    None => break,
+                },
+                Some(_) = tx_done.next() => {
+                    self.on_tx_done();
+                }
+                Some(pmt) = rx_frames.next() => {
+                    self.on_rx(pmt);
+                }
+                _ = tokio::time::sleep_until(self.next_wakeup()) => {
+                    if self.rx_windows.is_some_and(|w| w.close <= Instant::now()) {
+                        self.finish_uplink(false);
+                    }
+                }
+            }
+            if self.tune(Instant::now()) {
+                self.retune(&mut handle).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: usize = 20;
+
+    fn device() -> EndDevice {
+        let config = EndDeviceConfig::new(Activation::Abp {
+            dev_addr: 0x2600_0001,
+            nwk_s_key: [1; 16],
+            app_s_key: [2; 16],
+        });
+        EndDevice::new(config, 868_100_000, Bandwidth::BW125, Vec::new())
+    }
+
+    fn annotations(sf: SpreadingFactor) -> HashMap<String, Pmt> {
+        HashMap::from([
+            (String::from("sf"), Pmt::Usize(u8::from(sf) as usize)),
+            (String::from("code_rate"), Pmt::Usize(1)),
+            (String::from("has_crc"), Pmt::Bool(false)),
+        ])
+    }
+
+    /// decode time of a frame whose preamble starts at `start`
+    fn decoded(device: &EndDevice, sf: SpreadingFactor, start: Instant) -> Instant {
+        start
+            + device.preamble_duration(sf)
+            + payload_duration(sf, Bandwidth::BW125, CodeRate::CR_4_5, false, LEN)
+    }
+
+    #[test]
+    fn rx_window_acceptance() {
+        use SpreadingFactor::*;
+
+        let mut device = device();
+        let rx1_open = Instant::now() + Duration::from_secs(1);
+        let rx2_open = rx1_open + RX2_OFFSET;
+        let window = |open, sf| RxWindow {
+            open,
+            sf,
+            preamble: device.preamble_duration(sf),
+            tuned: true,
+        };
+        let (rx1, rx2) = (window(rx1_open, SF7), window(rx2_open, SF12));
+        device.rx_windows = Some(RxWindows {
+            rx1: Some(rx1),
+            rx2: Some(rx2),
+            close: rx2_open + Duration::from_secs(5),
+        });
+        let late = RX_MARGIN + Duration::from_millis(10);
+        let accepted =
+            |sf, start| device.in_rx_window(&annotations(sf), LEN, decoded(&device, sf, start));
+
+        assert!(accepted(SF7, rx1_open));
+        assert!(accepted(SF7, rx1_open + rx1.preamble));
+        assert!(accepted(SF12, rx2_open - RX_MARGIN));
+        // the radio stops listening after about one preamble
+        assert!(!accepted(SF7, rx1_open + rx1.preamble + late));
+        assert!(!accepted(SF7, rx1_open - late));
+        // each window only decodes its own data rate
+        assert!(!accepted(SF12, rx1_open));
+        assert!(!accepted(SF7, rx2_open));
+        assert!(!accepted(SF9, rx1_open));
+    }
+
+    #[test]
+    fn no_window_without_uplink_or_channel() {
+        let mut device = device();
+        let now = Instant::now();
+        assert!(!device.in_rx_window(&annotations(SpreadingFactor::SF7), LEN, now));
+
+        // RX2 of EU868 is on 869.525 MHz, the node only listens on its own channel
+        assert!(device.rx_window(now, Some(869_525_000), Some(0)).is_none());
+        device.rx_windows = Some(RxWindows {
+            rx1: None,
+            rx2: None,
+            close: now,
+        });
+        assert!(!device.in_rx_window(
+            &annotations(SpreadingFactor::SF7),
+            LEN,
+            decoded(&device, SpreadingFactor::SF7, now)
+        ));
+    }
+}
@@ -81,6 +81,7 @@ struct State {
     m_bw: Bandwidth,             //< Bandwidth
     m_sf: SpreadingFactor,       //< Spreading factor
     m_impl_head: bool,           //< use implicit header mode
+    m_invert_iq: bool,           //< receive inverted IQ (downlink polarity)
     m_os_factor: usize,          //< oversampling factor
     m_n_up_req: SyncState,       //< number of consecutive upchirps required to trigger a detection
     m_number_of_bins: usize,     //< Number of bins in each lora Symbol
@@ -88,6 +89,7 @@ struct State {
     m_symb_numb: usize,          //<number of payload lora symbols
     m_received_head: bool, //< indicate that the header has be decoded and received by this block
     snr_est: f64,          //< estimate of the snr
+    rssi_est: f32,         //< uncalibrated rssi estimate, also reported as CMD_RSSI
     in_down: Vec<Complex32>, //< downsampled input
     m_downchirp: Vec<Complex32>, //< Reference downchirp
     m_upchirp: Vec<Complex32>, //< Reference upchirp
@@ -705,6 +707,7 @@ impl State {
         frame_info.insert(String::from("cfo_int"), Pmt::Isize(m_cfo_int));
         frame_info.insert(String::from("cfo_frac"), Pmt::F64(self.m_cfo_frac));
         frame_info.insert(String::from("sf"), Pmt::Usize(self.m_sf.into()));
+        frame_info.insert(String::from("invert_iq"), Pmt::Bool(self.m_invert_iq));
         frame_info.insert(
             String::from("timestamp"),
             Pmt::U64(
@@ -855,6 +858,20 @@ impl State {
             SyncState::QuarterDown => {
                 (items_to_consume, items_to_output) =
                     self.sync_quarter_down(input, out, tags, nitems_to_process);
+                if self.m_state == DecoderState::SfoCompensation {
+                    let detection = HashMap::from([
+                        (String::from("sf"), Pmt::Usize(self.m_sf.into())),
+                        (String::from("snr"), Pmt::F64(self.snr_est)),
+                        (String::from("freq"), Pmt::F64(self.m_center_freq as f64)),
+                    ]);
+                    let _ = _mio
+                        .post("frame_detected", Pmt::MapStrPmt(detection))
+                        .await;
+                } else {
+                    let _ = _mio
+                        .post("detection_failed", Pmt::Usize(self.m_sf.into()))
+                        .await;
+                }
                 
                 let snr = self.snr_est as f32;
                 let snr_bytes = snr.to_le_bytes();
@@ -877,6 +894,7 @@ impl State {
                     }
                     avg_rssi = sum_dbm / nitems_to_process as f32
                 }
+                self.rssi_est = avg_rssi;
                 
                 let cmd_rssi = create_cmd(kiss::CMD_RSSI,&avg_rssi.to_le_bytes() );
                 _mio.post("kiss", Pmt::Blob(cmd_rssi.clone())).await;
@@ -910,6 +928,7 @@ impl State {
             // frame_info.insert(String::from("cfo_int"), Pmt::Isize(self.m_cfo_int));
             // frame_info.insert(String::from("cfo_frac"), Pmt::F64(self.m_cfo_frac));
             frame_info.insert(String::from("snr"), Pmt::F64(self.snr_est));
+            frame_info.insert(String::from("rssi"), Pmt::F64(self.rssi_est as f64));
             tags.add_tag(
                 0,
                 Tag::NamedAny(
@@ -1063,8 +1082,43 @@ impl State {
 const ADDITIONAL_SAMPLES_FOR_NET_ID_RESYNCHING: usize = 4; // might need to consider os_factor
 const MAX_UNKNOWN_NET_ID_OFFSET: usize = 1;
 
+/// Conjugated copy of the unconsumed input samples for inverted IQ.
+///
+/// Every sample is conjugated once when it first shows up in the input buffer; consumed samples are
+/// only dropped from the front once they make up half of the copy.
+#[derive(Default)]
+struct ConjugatedInput {
+    samples: Vec<Complex32>,
+    start: usize,
+}
+
+impl ConjugatedInput {
+    /// Conjugated view of `input`, which has to start at the first sample not consumed yet.
+    fn update(&mut self, input: &[Complex32]) -> &[Complex32] {
+        if self.samples.len() - self.start > input.len() {
+            self.clear();
+        }
+        let known = self.samples.len() - self.start;
+        self.samples.extend(input[known..].iter().map(|x| x.conj()));
+        &self.samples[self.start..]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start += n;
+        if self.start >= self.samples.len() / 2 {
+            self.samples.drain(..self.start);
+            self.start = 0;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.start = 0;
+    }
+}
+
 #[derive(Block)]
-#[message_inputs(bandwidth, center_freq, frame_info, payload_crc_result, poke)]
+#[message_inputs(bandwidth, center_freq, frame_info, payload_crc_result, poke, invert_iq)]
 #[message_outputs(net_id_caching, frame_detected, detection_failed, kiss)]
 pub struct FrameSync<I = DefaultCpuReader<Complex32>, O = DefaultCpuWriter<Complex32>>
 where
@@ -1076,6 +1130,8 @@ where
     #[output]
     output: O,
     s: State,
+    /// conjugated input samples when receiving inverted IQ
+    input_conj: ConjugatedInput,
 }
 
 impl<I, O> FrameSync<I, O>
@@ -1095,6 +1151,7 @@ where
         net_id_caching_policy: Option<&str>,
         collect_receive_statistics: bool,
         startup_timestamp: Option<SystemTime>,
+        invert_iq: bool,
     ) -> Self {
         let net_id_caching_policy_tmp = match NetIdCachingPolicy::from_str(
             net_id_caching_policy.unwrap_or("header_crc_ok"),
@@ -1144,6 +1201,7 @@ where
                 up_symb_to_use: preamble_len_tmp - 4, //< number of upchirp symbols to use for CFO and STO frac estimation
                 m_sto_frac: 0.0,                      //< fractional part of CFO
                 m_impl_head: impl_head,               //< use implicit header mode
+                m_invert_iq: invert_iq,               //< receive inverted IQ (downlink polarity)
                 m_number_of_bins: m_number_of_bins_tmp, //< Number of bins in each lora Symbol
                 m_samples_per_symbol: m_samples_per_symbol_tmp, //< Number of samples received per lora symbols
                 additional_symbol_samp: vec![Complex32::new(0., 0.); 2 * m_samples_per_symbol_tmp], //< save the value of the last 1.25 downchirp as it might contain the first payload symbol
@@ -1174,6 +1232,7 @@ where
                 m_symb_numb: 0,                //<number of payload lora symbols
                 m_received_head: false, //< indicate that the header has be decoded and received by this block
                 snr_est: 0.0,           //< estimate of the snr
+                rssi_est: 0.0,          //< uncalibrated rssi estimate, also reported as CMD_RSSI
                 additional_upchirps: 0, //< indicate the number of additional upchirps found in preamble (in addition to the minimum required to trigger a detection)
                 m_cfo_frac: 0.0,        //< fractional part of CFO
                 sfo_hat: 0.0,           //< estimated sampling frequency offset
@@ -1199,6 +1258,7 @@ where
                     .as_nanos() as u64,
                 processed_samples: 0,
             },
+            input_conj: ConjugatedInput::default(),
         }
     }
 
@@ -1246,6 +1306,24 @@ where
         Ok(Pmt::Null)
     }
 
+    async fn invert_iq(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        if let Pmt::Bool(invert_iq) = p {
+            self.s.m_invert_iq = invert_iq;
+            // the copy is not maintained while receiving normal polarity
+            self.input_conj.clear();
+            self.s.reset(false);
+        } else {
+            warn! {"PMT to invert_iq_handler was not a bool"}
+        }
+        Ok(Pmt::Null)
+    }
+
     async fn payload_crc_result(
         &mut self,
         _io: &mut WorkIo,
@@ -1392,7 +1470,12 @@ where
         _b: &mut BlockMeta,
     ) -> Result<()> {
         let (out, mut out_tags) = self.output.slice_with_tags();
-        let input = self.input.slice();
+        let input = if self.s.m_invert_iq {
+            // conjugate, so that the rest of the chain sees normal polarity chirps
+            self.input_conj.update(self.input.slice())
+        } else {
+            self.input.slice()
+        };
         let nitems_to_process = input.len();
         let out_len = out.len();
 
@@ -1442,6 +1525,9 @@ where
             if items_to_consume > 0 {
                 self.s.processed_samples += items_to_consume as u64;
                 self.input.consume(items_to_consume as usize);
+                if self.s.m_invert_iq {
+                    self.input_conj.consume(items_to_consume as usize);
+                }
             }
             if items_to_output > 0 {
                 self.output.produce(items_to_output);
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::anyhow;
+use futuresdr::tracing::warn;
+use serde::Deserialize;
+use serde_json::Value;
+use strum::IntoEnumIterator;
+
+use crate::utils::Bandwidth;
+use crate::utils::Channel;
+use crate::utils::SpreadingFactor;
+
+/// Semtech packet forwarder configuration, as found in `global_conf.json` and `local_conf.json`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GatewayConfig {
+    #[serde(rename = "SX130x_conf", alias = "SX1301_conf", alias = "SX1302_conf")]
+    pub concentrator: ConcentratorConf,
+    #[serde(rename = "gateway_conf")]
+    pub gateway: GatewayConf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConcentratorConf {
+    #[serde(default = "default_true")]
+    pub lorawan_public: bool,
+    pub radio_0: RadioConf,
+    pub radio_1: Option<RadioConf>,
+    #[serde(rename = "chan_multiSF_All")]
+    pub chan_multi_sf_all: Option<ChanMultiSfAll>,
+    #[serde(rename = "chan_Lora_std")]
+    pub chan_lora_std: Option<ChanLoraStd>,
+    /// remaining entries, including the numbered `chan_multiSF_<n>` objects
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RadioConf {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(rename = "type")]
+    pub radio_type: Option<String>,
+    pub freq: u32,
+    #[serde(default)]
+    pub tx_enable: bool,
+    pub tx_freq_min: Option<u32>,
+    pub tx_freq_max: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChanMultiSfAll {
+    pub spreading_factor_enable: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChanMultiSf {
+    #[serde(default)]
+    pub enable: bool,
+    pub radio: usize,
+    #[serde(rename = "if")]
+    pub if_freq: i32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChanLoraStd {
+    #[serde(default)]
+    pub enable: bool,
+    pub radio: usize,
+    #[serde(rename = "if")]
+    pub if_freq: i32,
+    pub bandwidth: Option<u32>,
+    pub spread_factor: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GatewayConf {
+    #[serde(rename = "gateway_ID")]
+    pub gateway_id: String,
+    pub server_address: String,
+    pub serv_port_up: u16,
+    pub serv_port_down: u16,
+    pub keepalive_interval: Option<u64>,
+    pub stat_interval: Option<u64>,
+    pub ref_latitude: Option<f64>,
+    pub ref_longitude: Option<f64>,
+    pub ref_altitude: Option<f64>,
+}
+
+/// One receive channel of the gateway with the spreading factors to decode on it.
+#[derive(Debug, Clone)]
+pub struct GatewayChannel {
+    pub channel: Channel,
+    pub bandwidth: Bandwidth,
+    pub spreading_factors: Vec<SpreadingFactor>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// remove `/* */` and `//` comments, which the Semtech reference parser accepts
+fn strip_comments(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    let mut chars = json.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            if c == '\\' {
+                if let Some(escaped) = chars.next() {
+                    out.push(escaped);
+                }
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        out.push(c);
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// recursively merge `overlay` into `base`, objects are merged, everything else is replaced
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+impl GatewayConfig {
+    fn parse_value(json: &str) -> Result<Value> {
+        Ok(serde_json::from_str(&strip_comments(json))?)
+    }
+
+    pub fn from_json(global: &str, local: Option<&str>) -> Result<Self> {
+        let mut value = Self::parse_value(global)?;
+        if let Some(local) = local {
+            merge(&mut value, Self::parse_value(local)?);
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Load `global_conf.json`, optionally overridden by a `local_conf.json`.
+    pub fn load(global: impl AsRef<Path>, local: Option<impl AsRef<Path>>) -> Result<Self> {
+        let global = global.as_ref();
+        let global_json = std::fs::read_to_string(global)
+            .with_context(|| format!("reading {}", global.display()))?;
+        let local_json = match local {
+            Some(local) => {
+                let local = local.as_ref();
+                Some(
+                    std::fs::read_to_string(local)
+                        .with_context(|| format!("reading {}", local.display()))?,
+                )
+            }
+            None => None,
+        };
+        Self::from_json(&global_json, local_json.as_deref())
+    }
+
+    /// gateway ID in the dotted notation expected by [`PacketForwarderClient`](crate::PacketForwarderClient)
+    pub fn gateway_mac(&self) -> Result<String> {
+        let id = self.gateway.gateway_id.replace([':', '-', '.'], "");
+        if id.len() != 16 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("invalid gateway_ID {}", self.gateway.gateway_id));
+        }
+        Ok(format!("{}.{}.{}.{}", &id[0..4], &id[4..8], &id[8..12], &id[12..16]).to_lowercase())
+    }
+
+    /// resolve the network server address
+    ///
+    /// The forwarder sends PUSH_DATA and PULL_DATA on one socket, so the uplink and downlink ports
+    /// have to be the same.
+    pub fn server_addr(&self) -> Result<SocketAddr> {
+        if self.gateway.serv_port_up != self.gateway.serv_port_down {
+            return Err(anyhow!(
+                "serv_port_up ({}) and serv_port_down ({}) differ, only one server port is supported",
+                self.gateway.serv_port_up,
+                self.gateway.serv_port_down
+            ));
+        }
+        (
+            self.gateway.server_address.as_str(),
+            self.gateway.serv_port_up,
+        )
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("could not resolve {}", self.gateway.server_address))
+    }
+
+    pub fn multi_sf_channels(&self) -> Vec<ChanMultiSf> {
+        self.concentrator.multi_sf_channels()
+    }
+
+    /// `tx_freq_min` to `tx_freq_max` of the first TX enabled radio.
+    pub fn tx_freq_range(&self) -> Option<RangeInclusive<u32>> {
+        [
+            Some(&self.concentrator.radio_0),
+            self.concentrator.radio_1.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|radio| radio.tx_enable)
+        .and_then(|radio| Some(radio.tx_freq_min?..=radio.tx_freq_max?))
+    }
+
+    /// All enabled LoRa channels: the multi-SF channels and the LoRa standard channel.
+    pub fn lora_channels(&self) -> Vec<GatewayChannel> {
+        self.concentrator.lora_channels()
+    }
+}
+
+impl ConcentratorConf {
+    fn radio_freq(&self, radio: usize) -> Option<u32> {
+        let radio = match radio {
+            0 => Some(&self.radio_0),
+            1 => self.radio_1.as_ref(),
+            _ => None,
+        }?;
+        radio.enable.then_some(radio.freq)
+    }
+
+    pub fn multi_sf_channels(&self) -> Vec<ChanMultiSf> {
+        let mut chans: Vec<(usize, ChanMultiSf)> = self
+            .other
+            .iter()
+            .filter_map(|(k, v)| {
+                let n = k.strip_prefix("chan_multiSF_")?.parse::<usize>().ok()?;
+                match serde_json::from_value::<ChanMultiSf>(v.clone()) {
+                    Ok(c) => Some((n, c)),
+                    Err(e) => {
+                        warn!("ignoring invalid {k}: {e}");
+                        None
+                    }
+                }
+            })
+            .collect();
+        chans.sort_by_key(|(n, _)| *n);
+        chans.into_iter().map(|(_, c)| c).collect()
+    }
+
+    fn multi_sf_set(&self) -> Vec<SpreadingFactor> {
+        match &self.chan_multi_sf_all {
+            Some(all) => all
+                .spreading_factor_enable
+                .iter()
+                .filter_map(|sf| SpreadingFactor::try_from(*sf).ok())
+                .collect(),
+            None => SpreadingFactor::iter()
+                .filter(|sf| *sf >= SpreadingFactor::SF7)
+                .collect(),
+        }
+    }
+
+    /// All enabled LoRa channels: the multi-SF channels and the LoRa standard channel.
+    pub fn lora_channels(&self) -> Vec<GatewayChannel> {
+        let sfs = self.multi_sf_set();
+        let mut channels: Vec<GatewayChannel> = self
+            .multi_sf_channels()
+            .into_iter()
+            .filter(|c| c.enable)
+            .filter_map(|c| {
+                let freq = self.radio_freq(c.radio)? as i64 + c.if_freq as i64;
+                Some(GatewayChannel {
+                    channel: Channel::from(freq as u32),
+                    bandwidth: Bandwidth::BW125,
+                    spreading_factors: sfs.clone(),
+                })
+            })
+            .collect();
+
+        if let Some(std) = self.chan_lora_std.as_ref().filter(|c| c.enable) {
+            let bandwidth = std
+                .bandwidth
+                .and_then(|bw| Bandwidth::try_from(bw).ok())
+                .unwrap_or(Bandwidth::BW250);
+            let sf = std
+                .spread_factor
+                .and_then(|sf| SpreadingFactor::try_from(sf).ok())
+                .unwrap_or(SpreadingFactor::SF7);
+            if let Some(radio) = self.radio_freq(std.radio) {
+                channels.push(GatewayChannel {
+                    channel: Channel::from((radio as i64 + std.if_freq as i64) as u32),
+                    bandwidth,
+                    spreading_factors: vec![sf],
+                });
+            }
+        }
+        channels
+    }
+}
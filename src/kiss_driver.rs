@@ -9,6 +9,13 @@
 // TX commands
 // CMD DATA
 // CMD READY
+// CMD TX_REQ (prio, flags, dest, data)
+//---------------
+// TX reports (frame id, u16 le)
+// CMD TX_QUEUED
+// CMD TX_DONE
+// CMD TX_ACKED
+// CMD TX_FAILED
 
 pub const RADIOLIB_SX126X_IRQ_HEADER_ERR: u8   = 0b00100000; // 5: LoRa header CRC error
 pub const RADIOLIB_SX126X_IRQ_HEADER_VALID: u8 = 0b00010000; // 4: valid LoRa header received
@@ -25,6 +32,15 @@ pub mod kiss {
     pub const CMD_SNR    : u8 = 0x24;
     pub const CMD_RSSI   : u8 = 0x23;
     pub const CMD_READY  : u8 = 0x0F;
+
+    pub const CMD_TX_REQ    : u8 = 0x30;
+    pub const CMD_TX_QUEUED : u8 = 0x31;
+    pub const CMD_TX_DONE   : u8 = 0x32;
+    pub const CMD_TX_ACKED  : u8 = 0x33;
+    pub const CMD_TX_FAILED : u8 = 0x34;
+
+    /// TX_REQ flag: request a link-layer acknowledgement
+    pub const TX_FLAG_ACK   : u8 = 0x01;
 }
 
 pub fn escape(data: &[u8]) -> Vec<u8> {
@@ -20,22 +20,41 @@ pub use awgn::AddAWGN;
 pub use node::Node;
 pub use kiss_driver::{create_cmd, escape, descape};
 
+pub mod adr;
 pub mod awgn;
+pub mod basic_station;
 pub mod kiss_driver;
+pub mod lorawan;
+pub mod lorawan_keys;
 pub mod channel;
+pub mod chirpstack;
 pub mod decoder;
 pub mod default_values;
 pub mod deinterleaver;
+pub mod downlink;
+pub mod end_device;
 pub mod encoder;
 pub mod fft_demod;
 pub mod frame_sync;
+pub mod gateway_config;
 pub mod gray_mapping;
 pub mod hamming_dec;
 pub mod header_decoder;
+pub mod mac;
+pub mod network_server;
 pub mod meshtastic;
+pub mod meshtastic_api;
+pub mod meshtastic_mqtt;
+pub mod meshtastic_nodedb;
+pub mod meshtastic_pki;
+pub mod meshtastic_region;
+pub mod meshtastic_router;
+pub mod meshtastic_url;
 pub mod modulator;
+pub mod multi_channel_gateway;
 pub mod node;
 pub mod packet_forwarder_client;
+pub mod region;
 pub mod shmem;
 pub mod stream_adder;
 pub mod transmitter;
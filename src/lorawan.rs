@@ -0,0 +1,808 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use anyhow::anyhow;
+use futuresdr::prelude::*;
+
+use crate::decoder::annotated_payload;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn u24_le(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MType {
+    JoinRequest,
+    JoinAccept,
+    UnconfirmedDataUp,
+    UnconfirmedDataDown,
+    ConfirmedDataUp,
+    ConfirmedDataDown,
+    RejoinRequest,
+    Proprietary,
+}
+
+impl From<u8> for MType {
+    fn from(mhdr: u8) -> Self {
+        match mhdr >> 5 {
+            0 => MType::JoinRequest,
+            1 => MType::JoinAccept,
+            2 => MType::UnconfirmedDataUp,
+            3 => MType::UnconfirmedDataDown,
+            4 => MType::ConfirmedDataUp,
+            5 => MType::ConfirmedDataDown,
+            6 => MType::RejoinRequest,
+            _ => MType::Proprietary,
+        }
+    }
+}
+
+impl MType {
+    /// sent by the end device
+    pub fn is_uplink(&self) -> bool {
+        matches!(
+            self,
+            MType::JoinRequest
+                | MType::UnconfirmedDataUp
+                | MType::ConfirmedDataUp
+                | MType::RejoinRequest
+        )
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        matches!(self, MType::ConfirmedDataUp | MType::ConfirmedDataDown)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MHdr {
+    pub mtype: MType,
+    /// 0 for LoRaWAN R1
+    pub major: u8,
+}
+
+impl From<u8> for MHdr {
+    fn from(mhdr: u8) -> Self {
+        Self {
+            mtype: MType::from(mhdr),
+            major: mhdr & 0x03,
+        }
+    }
+}
+
+impl From<MHdr> for u8 {
+    fn from(mhdr: MHdr) -> Self {
+        let mtype = match mhdr.mtype {
+            MType::JoinRequest => 0,
+            MType::JoinAccept => 1,
+            MType::UnconfirmedDataUp => 2,
+            MType::UnconfirmedDataDown => 3,
+            MType::ConfirmedDataUp => 4,
+            MType::ConfirmedDataDown => 5,
+            MType::RejoinRequest => 6,
+            MType::Proprietary => 7,
+        };
+        (mtype << 5) | (mhdr.major & 0x03)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FCtrl {
+    pub adr: bool,
+    /// ADRACKReq in uplinks, RFU in downlinks
+    pub adr_ack_req: bool,
+    pub ack: bool,
+    /// FPending in downlinks, ClassB in uplinks
+    pub f_pending: bool,
+    pub fopts_len: u8,
+}
+
+impl From<u8> for FCtrl {
+    fn from(fctrl: u8) -> Self {
+        Self {
+            adr: fctrl & 0x80 != 0,
+            adr_ack_req: fctrl & 0x40 != 0,
+            ack: fctrl & 0x20 != 0,
+            f_pending: fctrl & 0x10 != 0,
+            fopts_len: fctrl & 0x0f,
+        }
+    }
+}
+
+impl From<FCtrl> for u8 {
+    fn from(fctrl: FCtrl) -> Self {
+        (fctrl.adr as u8) << 7
+            | (fctrl.adr_ack_req as u8) << 6
+            | (fctrl.ack as u8) << 5
+            | (fctrl.f_pending as u8) << 4
+            | (fctrl.fopts_len & 0x0f)
+    }
+}
+
+/// MAC command, carried in FOpts or in an FRMPayload with FPort 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacCommand {
+    ResetInd {
+        minor: u8,
+    },
+    ResetConf {
+        minor: u8,
+    },
+    LinkCheckReq,
+    LinkCheckAns {
+        margin: u8,
+        gw_cnt: u8,
+    },
+    LinkAdrReq {
+        data_rate: u8,
+        tx_power: u8,
+        ch_mask: u16,
+        redundancy: u8,
+    },
+    LinkAdrAns {
+        power_ack: bool,
+        data_rate_ack: bool,
+        channel_mask_ack: bool,
+    },
+    DutyCycleReq {
+        max_duty_cycle: u8,
+    },
+    DutyCycleAns,
+    RxParamSetupReq {
+        rx1_dr_offset: u8,
+        rx2_data_rate: u8,
+        freq: u32,
+    },
+    RxParamSetupAns {
+        rx1_dr_offset_ack: bool,
+        rx2_data_rate_ack: bool,
+        channel_ack: bool,
+    },
+    DevStatusReq,
+    /// battery 0: external power, 1-254: level, 255: unknown; margin in dB
+    DevStatusAns {
+        battery: u8,
+        margin: i8,
+    },
+    NewChannelReq {
+        ch_index: u8,
+        freq: u32,
+        max_dr: u8,
+        min_dr: u8,
+    },
+    NewChannelAns {
+        data_rate_ok: bool,
+        channel_freq_ok: bool,
+    },
+    RxTimingSetupReq {
+        delay: u8,
+    },
+    RxTimingSetupAns,
+    TxParamSetupReq {
+        downlink_dwell_time: bool,
+        uplink_dwell_time: bool,
+        max_eirp: u8,
+    },
+    TxParamSetupAns,
+    DlChannelReq {
+        ch_index: u8,
+        freq: u32,
+    },
+    DlChannelAns {
+        uplink_freq_exists: bool,
+        channel_freq_ok: bool,
+    },
+    RekeyInd {
+        minor: u8,
+    },
+    RekeyConf {
+        minor: u8,
+    },
+    AdrParamSetupReq {
+        limit_exp: u8,
+        delay_exp: u8,
+    },
+    AdrParamSetupAns,
+    DeviceTimeReq,
+    /// GPS epoch seconds and fractional seconds in 1/256 s
+    DeviceTimeAns {
+        seconds: u32,
+        fractional: u8,
+    },
+    ForceRejoinReq {
+        period: u8,
+        max_retries: u8,
+        rejoin_type: u8,
+        dr: u8,
+    },
+    RejoinParamSetupReq {
+        max_time_n: u8,
+        max_count_n: u8,
+    },
+    RejoinParamSetupAns {
+        time_ok: bool,
+    },
+    /// proprietary (0x80-0xff) or unknown command, the remaining bytes can not be split
+    Unknown {
+        cid: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// payload length of the command with the given CID, depending on the direction
+fn mac_command_len(cid: u8, uplink: bool) -> Option<usize> {
+    let len = match (cid, uplink) {
+        (0x01, _) => 1,
+        (0x02, true) => 0,
+        (0x02, false) => 2,
+        (0x03, true) => 1,
+        (0x03, false) => 4,
+        (0x04, true) => 0,
+        (0x04, false) => 1,
+        (0x05, true) => 1,
+        (0x05, false) => 4,
+        (0x06, true) => 2,
+        (0x06, false) => 0,
+        (0x07, true) => 1,
+        (0x07, false) => 5,
+        (0x08, true) => 0,
+        (0x08, false) => 1,
+        (0x09, true) => 0,
+        (0x09, false) => 1,
+        (0x0a, true) => 1,
+        (0x0a, false) => 4,
+        (0x0b, _) => 1,
+        (0x0c, true) => 0,
+        (0x0c, false) => 1,
+        (0x0d, true) => 0,
+        (0x0d, false) => 5,
+        (0x0e, false) => 2,
+        (0x0f, true) => 1,
+        (0x0f, false) => 1,
+        _ => return None,
+    };
+    Some(len)
+}
+
+impl MacCommand {
+    fn parse(cid: u8, p: &[u8], uplink: bool) -> Self {
+        match (cid, uplink) {
+            (0x01, true) => MacCommand::ResetInd { minor: p[0] & 0x0f },
+            (0x01, false) => MacCommand::ResetConf { minor: p[0] & 0x0f },
+            (0x02, true) => MacCommand::LinkCheckReq,
+            (0x02, false) => MacCommand::LinkCheckAns {
+                margin: p[0],
+                gw_cnt: p[1],
+            },
+            (0x03, true) => MacCommand::LinkAdrAns {
+                power_ack: p[0] & 0x04 != 0,
+                data_rate_ack: p[0] & 0x02 != 0,
+                channel_mask_ack: p[0] & 0x01 != 0,
+            },
+            (0x03, false) => MacCommand::LinkAdrReq {
+                data_rate: p[0] >> 4,
+                tx_power: p[0] & 0x0f,
+                ch_mask: u16::from_le_bytes([p[1], p[2]]),
+                redundancy: p[3],
+            },
+            (0x04, true) => MacCommand::DutyCycleAns,
+            (0x04, false) => MacCommand::DutyCycleReq {
+                max_duty_cycle: p[0] & 0x0f,
+            },
+            (0x05, true) => MacCommand::RxParamSetupAns {
+                rx1_dr_offset_ack: p[0] & 0x04 != 0,
+                rx2_data_rate_ack: p[0] & 0x02 != 0,
+                channel_ack: p[0] & 0x01 != 0,
+            },
+            (0x05, false) => MacCommand::RxParamSetupReq {
+                rx1_dr_offset: (p[0] >> 4) & 0x07,
+                rx2_data_rate: p[0] & 0x0f,
+                freq: u24_le(&p[1..4]) * 100,
+            },
+            (0x06, true) => MacCommand::DevStatusAns {
+                battery: p[0],
+                // 6 bit signed
+                margin: ((p[1] << 2) as i8) >> 2,
+            },
+            (0x06, false) => MacCommand::DevStatusReq,
+            (0x07, true) => MacCommand::NewChannelAns {
+                data_rate_ok: p[0] & 0x02 != 0,
+                channel_freq_ok: p[0] & 0x01 != 0,
+            },
+            (0x07, false) => MacCommand::NewChannelReq {
+                ch_index: p[0],
+                freq: u24_le(&p[1..4]) * 100,
+                max_dr: p[4] >> 4,
+                min_dr: p[4] & 0x0f,
+            },
+            (0x08, true) => MacCommand::RxTimingSetupAns,
+            (0x08, false) => MacCommand::RxTimingSetupReq { delay: p[0] & 0x0f },
+            (0x09, true) => MacCommand::TxParamSetupAns,
+            (0x09, false) => MacCommand::TxParamSetupReq {
+                downlink_dwell_time: p[0] & 0x20 != 0,
+                uplink_dwell_time: p[0] & 0x10 != 0,
+                max_eirp: p[0] & 0x0f,
+            },
+            (0x0a, true) => MacCommand::DlChannelAns {
+                uplink_freq_exists: p[0] & 0x02 != 0,
+                channel_freq_ok: p[0] & 0x01 != 0,
+            },
+            (0x0a, false) => MacCommand::DlChannelReq {
+                ch_index: p[0],
+                freq: u24_le(&p[1..4]) * 100,
+            },
+            (0x0b, true) => MacCommand::RekeyInd { minor: p[0] & 0x0f },
+            (0x0b, false) => MacCommand::RekeyConf { minor: p[0] & 0x0f },
+            (0x0c, true) => MacCommand::AdrParamSetupAns,
+            (0x0c, false) => MacCommand::AdrParamSetupReq {
+                limit_exp: p[0] >> 4,
+                delay_exp: p[0] & 0x0f,
+            },
+            (0x0d, true) => MacCommand::DeviceTimeReq,
+            (0x0d, false) => MacCommand::DeviceTimeAns {
+                seconds: u32::from_le_bytes([p[0], p[1], p[2], p[3]]),
+                fractional: p[4],
+            },
+            (0x0e, false) => {
+                let v = u16::from_le_bytes([p[0], p[1]]);
+                MacCommand::ForceRejoinReq {
+                    period: ((v >> 11) & 0x07) as u8,
+                    max_retries: ((v >> 8) & 0x07) as u8,
+                    rejoin_type: ((v >> 4) & 0x07) as u8,
+                    dr: (v & 0x0f) as u8,
+                }
+            }
+            (0x0f, true) => MacCommand::RejoinParamSetupAns {
+                time_ok: p[0] & 0x01 != 0,
+            },
+            (0x0f, false) => MacCommand::RejoinParamSetupReq {
+                max_time_n: p[0] >> 4,
+                max_count_n: p[0] & 0x0f,
+            },
+            _ => MacCommand::Unknown {
+                cid,
+                data: p.to_vec(),
+            },
+        }
+    }
+
+    pub fn cid(&self) -> u8 {
+        match self {
+            Self::ResetInd { .. } | Self::ResetConf { .. } => 0x01,
+            Self::LinkCheckReq | Self::LinkCheckAns { .. } => 0x02,
+            Self::LinkAdrReq { .. } | Self::LinkAdrAns { .. } => 0x03,
+            Self::DutyCycleReq { .. } | Self::DutyCycleAns => 0x04,
+            Self::RxParamSetupReq { .. } | Self::RxParamSetupAns { .. } => 0x05,
+            Self::DevStatusReq | Self::DevStatusAns { .. } => 0x06,
+            Self::NewChannelReq { .. } | Self::NewChannelAns { .. } => 0x07,
+            Self::RxTimingSetupReq { .. } | Self::RxTimingSetupAns => 0x08,
+            Self::TxParamSetupReq { .. } | Self::TxParamSetupAns => 0x09,
+            Self::DlChannelReq { .. } | Self::DlChannelAns { .. } => 0x0a,
+            Self::RekeyInd { .. } | Self::RekeyConf { .. } => 0x0b,
+            Self::AdrParamSetupReq { .. } | Self::AdrParamSetupAns => 0x0c,
+            Self::DeviceTimeReq | Self::DeviceTimeAns { .. } => 0x0d,
+            Self::ForceRejoinReq { .. } => 0x0e,
+            Self::RejoinParamSetupReq { .. } | Self::RejoinParamSetupAns { .. } => 0x0f,
+            Self::Unknown { cid, .. } => *cid,
+        }
+    }
+
+    /// Serialize the command including the CID, the inverse of [`parse_mac_commands`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let freq = |f: u32| (f / 100).to_le_bytes()[..3].to_vec();
+        let mut data = vec![self.cid()];
+        match self {
+            Self::ResetInd { minor }
+            | Self::ResetConf { minor }
+            | Self::RekeyInd { minor }
+            | Self::RekeyConf { minor } => data.push(minor & 0x0f),
+            Self::LinkCheckAns { margin, gw_cnt } => data.extend([*margin, *gw_cnt]),
+            Self::LinkAdrReq {
+                data_rate,
+                tx_power,
+                ch_mask,
+                redundancy,
+            } => {
+                data.push(data_rate << 4 | tx_power & 0x0f);
+                data.extend(ch_mask.to_le_bytes());
+                data.push(*redundancy);
+            }
+            Self::LinkAdrAns {
+                power_ack,
+                data_rate_ack,
+                channel_mask_ack,
+            } => data.push(
+                (*power_ack as u8) << 2 | (*data_rate_ack as u8) << 1 | *channel_mask_ack as u8,
+            ),
+            Self::DutyCycleReq { max_duty_cycle } => data.push(max_duty_cycle & 0x0f),
+            Self::RxParamSetupReq {
+                rx1_dr_offset,
+                rx2_data_rate,
+                freq: f,
+            } => {
+                data.push((rx1_dr_offset & 0x07) << 4 | rx2_data_rate & 0x0f);
+                data.extend(freq(*f));
+            }
+            Self::RxParamSetupAns {
+                rx1_dr_offset_ack,
+                rx2_data_rate_ack,
+                channel_ack,
+            } => data.push(
+                (*rx1_dr_offset_ack as u8) << 2
+                    | (*rx2_data_rate_ack as u8) << 1
+                    | *channel_ack as u8,
+            ),
+            Self::DevStatusAns { battery, margin } => {
+                data.extend([*battery, (*margin as u8) & 0x3f]);
+            }
+            Self::NewChannelReq {
+                ch_index,
+                freq: f,
+                max_dr,
+                min_dr,
+            } => {
+                data.push(*ch_index);
+                data.extend(freq(*f));
+                data.push(max_dr << 4 | min_dr & 0x0f);
+            }
+            Self::NewChannelAns {
+                data_rate_ok,
+                channel_freq_ok,
+            } => data.push((*data_rate_ok as u8) << 1 | *channel_freq_ok as u8),
+            Self::RxTimingSetupReq { delay } => data.push(delay & 0x0f),
+            Self::TxParamSetupReq {
+                downlink_dwell_time,
+                uplink_dwell_time,
+                max_eirp,
+            } => data.push(
+                (*downlink_dwell_time as u8) << 5
+                    | (*uplink_dwell_time as u8) << 4
+                    | max_eirp & 0x0f,
+            ),
+            Self::DlChannelReq { ch_index, freq: f } => {
+                data.push(*ch_index);
+                data.extend(freq(*f));
+            }
+            Self::DlChannelAns {
+                uplink_freq_exists,
+                channel_freq_ok,
+            } => data.push((*uplink_freq_exists as u8) << 1 | *channel_freq_ok as u8),
+            Self::AdrParamSetupReq {
+                limit_exp,
+                delay_exp,
+            } => data.push(limit_exp << 4 | delay_exp & 0x0f),
+            Self::DeviceTimeAns {
+                seconds,
+                fractional,
+            } => {
+                data.extend(seconds.to_le_bytes());
+                data.push(*fractional);
+            }
+            Self::ForceRejoinReq {
+                period,
+                max_retries,
+                rejoin_type,
+                dr,
+            } => {
+                let v = (*period as u16 & 0x07) << 11
+                    | (*max_retries as u16 & 0x07) << 8
+                    | (*rejoin_type as u16 & 0x07) << 4
+                    | *dr as u16 & 0x0f;
+                data.extend(v.to_le_bytes());
+            }
+            Self::RejoinParamSetupReq {
+                max_time_n,
+                max_count_n,
+            } => data.push(max_time_n << 4 | max_count_n & 0x0f),
+            Self::RejoinParamSetupAns { time_ok } => data.push(*time_ok as u8),
+            Self::Unknown { data: d, .. } => data.extend_from_slice(d),
+            Self::LinkCheckReq
+            | Self::DutyCycleAns
+            | Self::DevStatusReq
+            | Self::RxTimingSetupAns
+            | Self::TxParamSetupAns
+            | Self::AdrParamSetupAns
+            | Self::DeviceTimeReq => {}
+        }
+        data
+    }
+}
+
+/// Split a sequence of MAC commands, an unknown CID swallows the remaining bytes.
+pub fn parse_mac_commands(data: &[u8], uplink: bool) -> Vec<MacCommand> {
+    let mut commands = Vec::new();
+    let mut rest = data;
+    while let Some((&cid, payload)) = rest.split_first() {
+        match mac_command_len(cid, uplink) {
+            Some(len) if len <= payload.len() => {
+                commands.push(MacCommand::parse(cid, &payload[..len], uplink));
+                rest = &payload[len..];
+            }
+            _ => {
+                commands.push(MacCommand::Unknown {
+                    cid,
+                    data: payload.to_vec(),
+                });
+                break;
+            }
+        }
+    }
+    commands
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRequest {
+    pub join_eui: u64,
+    pub dev_eui: u64,
+    pub dev_nonce: u16,
+}
+
+/// Decrypted Join-Accept, see [`JoinAccept::parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinAccept {
+    pub join_nonce: u32,
+    pub net_id: u32,
+    pub dev_addr: u32,
+    /// OptNeg bit of DLSettings, set by LoRaWAN 1.1 join servers
+    pub opt_neg: bool,
+    pub rx1_dr_offset: u8,
+    pub rx2_data_rate: u8,
+    pub rx_delay: u8,
+    pub cf_list: Option<[u8; 16]>,
+}
+
+impl JoinAccept {
+    /// Parse the plaintext of a Join-Accept (without MHDR and MIC).
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() != 12 && data.len() != 28 {
+            return Err(anyhow!("invalid Join-Accept length {}", data.len()));
+        }
+        Ok(Self {
+            join_nonce: u24_le(&data[0..3]),
+            net_id: u24_le(&data[3..6]),
+            dev_addr: u32::from_le_bytes(data[6..10].try_into()?),
+            opt_neg: data[10] & 0x80 != 0,
+            rx1_dr_offset: (data[10] >> 4) & 0x07,
+            rx2_data_rate: data[10] & 0x0f,
+            rx_delay: data[11] & 0x0f,
+            cf_list: data.get(12..28).map(|c| c.try_into().unwrap()),
+        })
+    }
+
+    /// Plaintext of the Join-Accept, without MHDR and MIC.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(28);
+        data.extend_from_slice(&self.join_nonce.to_le_bytes()[..3]);
+        data.extend_from_slice(&self.net_id.to_le_bytes()[..3]);
+        data.extend_from_slice(&self.dev_addr.to_le_bytes());
+        data.push(
+            (self.opt_neg as u8) << 7
+                | (self.rx1_dr_offset & 0x07) << 4
+                | (self.rx2_data_rate & 0x0f),
+        );
+        data.push(self.rx_delay & 0x0f);
+        if let Some(cf_list) = &self.cf_list {
+            data.extend_from_slice(cf_list);
+        }
+        data
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataFrame {
+    pub dev_addr: u32,
+    pub fctrl: FCtrl,
+    pub fcnt: u16,
+    /// encrypted in LoRaWAN 1.1
+    pub fopts: Vec<u8>,
+    pub fport: Option<u8>,
+    /// encrypted with the AppSKey, or the NwkSKey for FPort 0
+    pub frm_payload: Vec<u8>,
+}
+
+impl DataFrame {
+    /// MAC commands in FOpts, only meaningful for LoRaWAN 1.0.x where FOpts are not encrypted
+    pub fn mac_commands(&self, uplink: bool) -> Vec<MacCommand> {
+        parse_mac_commands(&self.fopts, uplink)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacPayload {
+    JoinRequest(JoinRequest),
+    /// still encrypted with the AppKey (1.0) or NwkKey (1.1), including the MIC
+    JoinAccept(Vec<u8>),
+    Data(DataFrame),
+    RejoinRequest(Vec<u8>),
+    Proprietary(Vec<u8>),
+}
+
+/// LoRaWAN 1.0.x/1.1 PHYPayload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhyPayload {
+    pub mhdr: MHdr,
+    pub payload: MacPayload,
+    /// zero for Join-Accept and proprietary frames, where it is not accessible
+    pub mic: [u8; 4],
+}
+
+impl PhyPayload {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let (&mhdr, rest) = data.split_first().ok_or_else(|| anyhow!("empty frame"))?;
+        let mhdr = MHdr::from(mhdr);
+        if mhdr.major != 0 {
+            return Err(anyhow!("unsupported LoRaWAN major version {}", mhdr.major));
+        }
+        if let MType::JoinAccept | MType::Proprietary = mhdr.mtype {
+            let payload = match mhdr.mtype {
+                MType::JoinAccept if rest.len() == 16 || rest.len() == 32 => {
+                    MacPayload::JoinAccept(rest.to_vec())
+                }
+                MType::JoinAccept => {
+                    return Err(anyhow!("invalid Join-Accept length {}", data.len()));
+                }
+                _ => MacPayload::Proprietary(rest.to_vec()),
+            };
+            return Ok(Self {
+                mhdr,
+                payload,
+                mic: [0; 4],
+            });
+        }
+
+        if rest.len() < 4 {
+            return Err(anyhow!("frame too short for MIC"));
+        }
+        let (p, mic) = rest.split_at(rest.len() - 4);
+        let mic: [u8; 4] = mic.try_into()?;
+        let payload = match mhdr.mtype {
+            MType::JoinRequest => {
+                if p.len() != 18 {
+                    return Err(anyhow!("invalid Join-Request length {}", data.len()));
+                }
+                MacPayload::JoinRequest(JoinRequest {
+                    join_eui: u64::from_le_bytes(p[0..8].try_into()?),
+                    dev_eui: u64::from_le_bytes(p[8..16].try_into()?),
+                    dev_nonce: u16::from_le_bytes(p[16..18].try_into()?),
+                })
+            }
+            MType::RejoinRequest => MacPayload::RejoinRequest(p.to_vec()),
+            _ => {
+                if p.len() < 7 {
+                    return Err(anyhow!("data frame too short"));
+                }
+                let fctrl = FCtrl::from(p[4]);
+                let fhdr_len = 7 + fctrl.fopts_len as usize;
+                if p.len() < fhdr_len {
+                    return Err(anyhow!("FOpts exceed frame"));
+                }
+                let (fport, frm_payload) = match p.get(fhdr_len) {
+                    Some(fport) => (Some(*fport), p[fhdr_len + 1..].to_vec()),
+                    None => (None, Vec::new()),
+                };
+                MacPayload::Data(DataFrame {
+                    dev_addr: u32::from_le_bytes(p[0..4].try_into()?),
+                    fctrl,
+                    fcnt: u16::from_le_bytes(p[5..7].try_into()?),
+                    fopts: p[7..fhdr_len].to_vec(),
+                    fport,
+                    frm_payload,
+                })
+            }
+        };
+        Ok(Self { mhdr, payload, mic })
+    }
+}
+
+impl Display for PhyPayload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.mhdr.mtype)?;
+        match &self.payload {
+            MacPayload::JoinRequest(j) => write!(
+                f,
+                " JoinEUI {:016X} DevEUI {:016X} DevNonce {}",
+                j.join_eui, j.dev_eui, j.dev_nonce
+            ),
+            MacPayload::JoinAccept(p)
+            | MacPayload::RejoinRequest(p)
+            | MacPayload::Proprietary(p) => {
+                write!(f, " {}", to_hex(p))
+            }
+            MacPayload::Data(d) => {
+                write!(f, " DevAddr {:08X} FCnt {}", d.dev_addr, d.fcnt)?;
+                for (set, flag) in [
+                    (d.fctrl.adr, "ADR"),
+                    (d.fctrl.adr_ack_req, "ADRACKReq"),
+                    (d.fctrl.ack, "ACK"),
+                    (d.fctrl.f_pending, "FPending"),
+                ] {
+                    if set {
+                        write!(f, " {flag}")?;
+                    }
+                }
+                if !d.fopts.is_empty() {
+                    write!(
+                        f,
+                        " FOpts {:?}",
+                        d.mac_commands(self.mhdr.mtype.is_uplink())
+                    )?;
+                }
+                if let Some(fport) = d.fport {
+                    write!(f, " FPort {} FRMPayload {}", fport, to_hex(&d.frm_payload))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// [`PhyPayload`] with the annotations of the frame it was received in.
+#[derive(Debug, Clone)]
+pub struct LorawanFrame {
+    pub phy: PhyPayload,
+    /// raw PHYPayload, needed for MIC verification
+    pub raw: Vec<u8>,
+    pub annotations: HashMap<String, Pmt>,
+}
+
+/// Parse LoRaWAN frames from the `out_annotated` messages of the [`Decoder`](crate::Decoder).
+///
+/// Frames are posted as [`LorawanFrame`] in a `Pmt::Any`, frames that are no valid LoRaWAN PHYPayload are dropped.
+#[derive(Block)]
+#[message_inputs(r#in)]
+#[message_outputs(out)]
+#[null_kernel]
+pub struct LorawanParser;
+
+impl LorawanParser {
+    pub fn new() -> Self {
+        Self
+    }
+
+    async fn r#in(
+        &mut self,
+        io: &mut WorkIo,
+        mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::Finished => {
+                io.finished = true;
+            }
+            Pmt::MapStrPmt(mut annotations) => {
+                let Some(raw) = annotated_payload(&annotations) else {
+                    return Ok(Pmt::InvalidValue);
+                };
+                match PhyPayload::parse(&raw) {
+                    Ok(phy) => {
+                        annotations.remove("payload");
+                        mio.post(
+                            "out",
+                            Pmt::Any(Box::new(LorawanFrame {
+                                phy,
+                                raw,
+                                annotations,
+                            })),
+                        )
+                        .await?;
+                    }
+                    Err(e) => debug!("not a LoRaWAN frame: {}", e),
+                }
+            }
+            _ => return Ok(Pmt::InvalidValue),
+        }
+        Ok(Pmt::Ok)
+    }
+}
+
+impl Default for LorawanParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,852 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::Path;
+
+use aes::Aes128;
+use aes::Block;
+use aes::cipher::BlockDecrypt;
+use aes::cipher::BlockEncrypt;
+use aes::cipher::KeyInit;
+use anyhow::Context;
+use anyhow::anyhow;
+use cmac::Cmac;
+use cmac::Mac;
+use serde::Deserialize;
+
+use futuresdr::prelude::*;
+
+use crate::lorawan::FCtrl;
+use crate::lorawan::JoinAccept;
+use crate::lorawan::LorawanFrame;
+use crate::lorawan::MHdr;
+use crate::lorawan::MType;
+use crate::lorawan::MacCommand;
+use crate::lorawan::MacPayload;
+use crate::lorawan::PhyPayload;
+use crate::lorawan::parse_mac_commands;
+
+/// frames with a larger gap to the last frame counter are rejected
+pub const MAX_FCNT_GAP: u32 = 16384;
+
+pub type Key = [u8; 16];
+
+pub fn parse_key(hex: &str) -> anyhow::Result<Key> {
+    let hex: String = hex.chars().filter(|c| !" -:".contains(*c)).collect();
+    if hex.len() != 32 {
+        return Err(anyhow!("invalid key {hex}"));
+    }
+    let mut key = [0u8; 16];
+    for (i, k) in key.iter_mut().enumerate() {
+        *k = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(key)
+}
+
+fn parse_hex_u64(hex: &str) -> anyhow::Result<u64> {
+    let hex: String = hex.chars().filter(|c| !"-:.".contains(*c)).collect();
+    Ok(u64::from_str_radix(&hex, 16)?)
+}
+
+fn aes_encrypt(key: &Key, block: [u8; 16]) -> [u8; 16] {
+    let mut block = Block::from(block);
+    Aes128::new(key.into()).encrypt_block(&mut block);
+    block.into()
+}
+
+fn aes_decrypt(key: &Key, block: [u8; 16]) -> [u8; 16] {
+    let mut block = Block::from(block);
+    Aes128::new(key.into()).decrypt_block(&mut block);
+    block.into()
+}
+
+fn cmac(key: &Key, parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Cmac<Aes128> as KeyInit>::new(key.into());
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// B0 / A_i block used by the MIC and the payload encryption of data frames
+fn block(first: u8, uplink: bool, dev_addr: u32, fcnt: u32, last: u8) -> [u8; 16] {
+    let mut b = [0u8; 16];
+    b[0] = first;
+    b[5] = if uplink { 0 } else { 1 };
+    b[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    b[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    b[15] = last;
+    b
+}
+
+/// XOR `data` with the AES-CTR like key stream of LoRaWAN, first block counter `first`.
+fn crypt(key: &Key, uplink: bool, dev_addr: u32, fcnt: u32, first: u8, data: &[u8]) -> Vec<u8> {
+    data.chunks(16)
+        .enumerate()
+        .flat_map(|(i, chunk)| {
+            let s = aes_encrypt(key, block(0x01, uplink, dev_addr, fcnt, first + i as u8));
+            chunk.iter().zip(s).map(|(d, s)| d ^ s).collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecurityError {
+    UnknownDevice,
+    InvalidMic,
+    /// frame counter not newer than the last accepted one
+    Replay {
+        fcnt: u32,
+        last: u32,
+    },
+    Unsupported(String),
+}
+
+impl Display for SecurityError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownDevice => write!(f, "unknown device"),
+            Self::InvalidMic => write!(f, "invalid MIC"),
+            Self::Replay { fcnt, last } => {
+                write!(f, "replayed frame counter {fcnt} (last {last})")
+            }
+            Self::Unsupported(reason) => write!(f, "unsupported frame: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SecurityError {}
+
+/// Inputs of the LoRaWAN 1.1 MIC that are not part of the frame, ignored for LoRaWAN 1.0.x sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MicContext {
+    /// FCnt of the confirmed frame acknowledged by the ACK bit
+    pub conf_fcnt: u32,
+    /// data rate and channel index of an uplink
+    pub tx_dr: u8,
+    pub tx_ch: u8,
+}
+
+/// Session keys of an activated device.
+///
+/// For LoRaWAN 1.0.x, the three network keys are the NwkSKey.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    pub f_nwk_s_int_key: Key,
+    pub s_nwk_s_int_key: Key,
+    pub nwk_s_enc_key: Key,
+    pub app_s_key: Key,
+    pub lorawan_1_1: bool,
+}
+
+impl SessionKeys {
+    /// Build a data frame PHYPayload, encrypting the payload and, for LoRaWAN 1.1, FOpts.
+    ///
+    /// LoRaWAN 1.1 uplinks carry the SNwkSIntKey MIC over B1 in the first and the FNwkSIntKey MIC over B0
+    /// in the second half; `mic` provides the B1 fields and the ConfFCnt of 1.1 ACKs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn seal_data(
+        &self,
+        mhdr: MHdr,
+        dev_addr: u32,
+        mut fctrl: FCtrl,
+        fcnt: u32,
+        fopts: &[u8],
+        fport: Option<u8>,
+        payload: &[u8],
+        mic: MicContext,
+    ) -> Vec<u8> {
+        let uplink = mhdr.mtype.is_uplink();
+        fctrl.fopts_len = fopts.len().min(15) as u8;
+        let mut frame = vec![mhdr.into()];
+        frame.extend_from_slice(&dev_addr.to_le_bytes());
+        frame.push(fctrl.into());
+        frame.extend_from_slice(&(fcnt as u16).to_le_bytes());
+        if self.lorawan_1_1 {
+            frame.extend(crypt(
+                &self.nwk_s_enc_key,
+                uplink,
+                dev_addr,
+                fcnt,
+                0,
+                &fopts[..fctrl.fopts_len as usize],
+            ));
+        } else {
+            frame.extend_from_slice(&fopts[..fctrl.fopts_len as usize]);
+        }
+        if let Some(fport) = fport {
+            let key = if fport == 0 {
+                &self.nwk_s_enc_key
+            } else {
+                &self.app_s_key
+            };
+            frame.push(fport);
+            frame.extend(crypt(key, uplink, dev_addr, fcnt, 1, payload));
+        }
+        let conf_fcnt = if fctrl.ack { mic.conf_fcnt as u16 } else { 0 };
+        let mut b0 = block(0x49, uplink, dev_addr, fcnt, frame.len() as u8);
+        if self.lorawan_1_1 && uplink {
+            let mut b1 = b0;
+            b1[1..3].copy_from_slice(&conf_fcnt.to_le_bytes());
+            b1[3] = mic.tx_dr;
+            b1[4] = mic.tx_ch;
+            let cmac_s = cmac(&self.s_nwk_s_int_key, &[&b1, &frame]);
+            let cmac_f = cmac(&self.f_nwk_s_int_key, &[&b0, &frame]);
+            frame.extend_from_slice(&cmac_s[..2]);
+            frame.extend_from_slice(&cmac_f[..2]);
+        } else {
+            if self.lorawan_1_1 {
+                b0[1..3].copy_from_slice(&conf_fcnt.to_le_bytes());
+            }
+            let mic = cmac(&self.s_nwk_s_int_key, &[&b0, &frame]);
+            frame.extend_from_slice(&mic[..4]);
+        }
+        frame
+    }
+
+    pub fn from_abp(nwk_s_key: Key, app_s_key: Key) -> Self {
+        Self {
+            f_nwk_s_int_key: nwk_s_key,
+            s_nwk_s_int_key: nwk_s_key,
+            nwk_s_enc_key: nwk_s_key,
+            app_s_key,
+            lorawan_1_1: false,
+        }
+    }
+
+    /// Derive the session keys of an OTAA join.
+    ///
+    /// Without OptNeg in the Join-Accept, the LoRaWAN 1.0.x derivation is used, from the NwkKey of LoRaWAN 1.1
+    /// devices and from the AppKey otherwise.
+    pub fn derive(
+        app_key: &Key,
+        nwk_key: Option<&Key>,
+        join_accept: &JoinAccept,
+        join_eui: u64,
+        dev_nonce: u16,
+    ) -> Self {
+        let join_nonce = &join_accept.join_nonce.to_le_bytes()[..3];
+        let dev_nonce = dev_nonce.to_le_bytes();
+        match nwk_key {
+            Some(nwk_key) if join_accept.opt_neg => {
+                // kind | JoinNonce | JoinEUI | DevNonce | padding
+                let input = |kind: u8| {
+                    let mut b = [0u8; 16];
+                    b[0] = kind;
+                    b[1..4].copy_from_slice(join_nonce);
+                    b[4..12].copy_from_slice(&join_eui.to_le_bytes());
+                    b[12..14].copy_from_slice(&dev_nonce);
+                    b
+                };
+                Self {
+                    f_nwk_s_int_key: aes_encrypt(nwk_key, input(0x01)),
+                    app_s_key: aes_encrypt(app_key, input(0x02)),
+                    s_nwk_s_int_key: aes_encrypt(nwk_key, input(0x03)),
+                    nwk_s_enc_key: aes_encrypt(nwk_key, input(0x04)),
+                    lorawan_1_1: true,
+                }
+            }
+            _ => {
+                let root_key = nwk_key.unwrap_or(app_key);
+                // kind | JoinNonce | NetID | DevNonce | padding
+                let input = |kind: u8| {
+                    let mut b = [0u8; 16];
+                    b[0] = kind;
+                    b[1..4].copy_from_slice(join_nonce);
+                    b[4..7].copy_from_slice(&join_accept.net_id.to_le_bytes()[..3]);
+                    b[7..9].copy_from_slice(&dev_nonce);
+                    b
+                };
+                Self::from_abp(
+                    aes_encrypt(root_key, input(0x01)),
+                    aes_encrypt(root_key, input(0x02)),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct AbpEntry {
+    #[serde(default)]
+    name: Option<String>,
+    dev_addr: String,
+    nwk_s_key: String,
+    app_s_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct OtaaEntry {
+    #[serde(default)]
+    name: Option<String>,
+    dev_eui: String,
+    join_eui: String,
+    app_key: String,
+    /// LoRaWAN 1.1 root network key
+    #[serde(default)]
+    nwk_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct KeyFile {
+    #[serde(default)]
+    abp: Vec<AbpEntry>,
+    #[serde(default)]
+    otaa: Vec<OtaaEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OtaaDevice {
+    pub name: String,
+    pub dev_eui: u64,
+    pub join_eui: u64,
+    pub app_key: Key,
+    pub nwk_key: Option<Key>,
+}
+
+impl OtaaDevice {
+    /// key of the Join-Request MIC and the Join-Accept encryption
+    fn join_key(&self) -> &Key {
+        self.nwk_key.as_ref().unwrap_or(&self.app_key)
+    }
+
+    /// Join-Request PHYPayload with MIC, as sent by the end device.
+    pub fn seal_join_request(&self, dev_nonce: u16) -> Vec<u8> {
+        let mut frame = vec![
+            MHdr {
+                mtype: MType::JoinRequest,
+                major: 0,
+            }
+            .into(),
+        ];
+        frame.extend_from_slice(&self.join_eui.to_le_bytes());
+        frame.extend_from_slice(&self.dev_eui.to_le_bytes());
+        frame.extend_from_slice(&dev_nonce.to_le_bytes());
+        let mic = cmac(self.join_key(), &[&frame]);
+        frame.extend_from_slice(&mic[..4]);
+        frame
+    }
+
+    /// Encrypted Join-Accept PHYPayload with MIC, as sent by a LoRaWAN 1.0.x join server.
+    pub fn seal_join_accept(&self, join_accept: &JoinAccept) -> Vec<u8> {
+        let mhdr: u8 = MHdr {
+            mtype: MType::JoinAccept,
+            major: 0,
+        }
+        .into();
+        let mut body = join_accept.to_bytes();
+        let mic = cmac(self.join_key(), &[&[mhdr], &body]);
+        body.extend_from_slice(&mic[..4]);
+        // the end device encrypts to decrypt, so the server uses AES decrypt
+        let mut frame = vec![mhdr];
+        for chunk in body.chunks(16) {
+            frame.extend_from_slice(&aes_decrypt(self.join_key(), chunk.try_into().unwrap()));
+        }
+        frame
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    name: String,
+    keys: SessionKeys,
+    fcnt_up: Option<u32>,
+    fcnt_down: Option<u32>,
+    /// FCnt of the last confirmed uplink sent by this device, the ConfFCnt of LoRaWAN 1.1 downlink ACKs
+    confirmed_up: u32,
+}
+
+/// Verified and decrypted data frame.
+#[derive(Debug, Clone)]
+pub struct DecryptedFrame {
+    pub device: String,
+    pub dev_addr: u32,
+    pub uplink: bool,
+    /// full 32 bit frame counter
+    pub fcnt: u32,
+    /// same frame counter as the last accepted frame, e.g. a confirmed uplink repeated after a lost ACK
+    pub retransmission: bool,
+    pub fport: Option<u8>,
+    pub payload: Vec<u8>,
+    /// from FOpts or, with FPort 0, from the payload
+    pub mac_commands: Vec<MacCommand>,
+    pub annotations: HashMap<String, Pmt>,
+}
+
+/// Result of feeding a frame to the [`KeyStore`].
+#[derive(Debug, Clone)]
+pub enum SecurityEvent {
+    Data(DecryptedFrame),
+    JoinRequest {
+        device: String,
+        dev_nonce: u16,
+    },
+    Joined {
+        device: String,
+        dev_addr: u32,
+        join_accept: JoinAccept,
+    },
+}
+
+/// Session keys of ABP devices and root keys of OTAA devices, with frame counter tracking.
+///
+/// Keys are loaded from a JSON file:
+///
+/// ```json
+/// {
+///   "abp": [{ "name": "sensor", "dev_addr": "26011BDA", "nwk_s_key": "...", "app_s_key": "..." }],
+///   "otaa": [{ "name": "tracker", "dev_eui": "...", "join_eui": "...", "app_key": "...", "nwk_key": "..." }]
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KeyStore {
+    sessions: HashMap<u32, Session>,
+    otaa: Vec<OtaaDevice>,
+    /// DevNonce of the last Join-Request per DevEUI, needed to derive the keys from the Join-Accept
+    pending_joins: HashMap<u64, u16>,
+}
+
+impl KeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let file: KeyFile = serde_json::from_str(json)?;
+        let mut store = Self::new();
+        for e in file.abp {
+            let dev_addr = u32::from_str_radix(&e.dev_addr, 16)
+                .with_context(|| format!("invalid DevAddr {}", e.dev_addr))?;
+            store.add_abp(
+                e.name.unwrap_or_else(|| format!("{dev_addr:08X}")),
+                dev_addr,
+                parse_key(&e.nwk_s_key)?,
+                parse_key(&e.app_s_key)?,
+            );
+        }
+        for e in file.otaa {
+            let dev_eui = parse_hex_u64(&e.dev_eui)
+                .with_context(|| format!("invalid DevEUI {}", e.dev_eui))?;
+            store.otaa.push(OtaaDevice {
+                name: e.name.unwrap_or_else(|| format!("{dev_eui:016X}")),
+                dev_eui,
+                join_eui: parse_hex_u64(&e.join_eui)
+                    .with_context(|| format!("invalid JoinEUI {}", e.join_eui))?,
+                app_key: parse_key(&e.app_key)?,
+                nwk_key: e.nwk_key.as_deref().map(parse_key).transpose()?,
+            });
+        }
+        Ok(store)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let json =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::from_json(&json)
+    }
+
+    pub fn add_abp(&mut self, name: String, dev_addr: u32, nwk_s_key: Key, app_s_key: Key) {
+        self.add_session(name, dev_addr, SessionKeys::from_abp(nwk_s_key, app_s_key));
+    }
+
+    pub fn add_session(&mut self, name: String, dev_addr: u32, keys: SessionKeys) {
+        self.sessions.insert(
+            dev_addr,
+            Session {
+                name,
+                keys,
+                fcnt_up: None,
+                fcnt_down: None,
+                confirmed_up: 0,
+            },
+        );
+    }
+
+    pub fn add_otaa(&mut self, device: OtaaDevice) {
+        self.otaa.push(device);
+    }
+
+    pub fn otaa_device(&self, dev_eui: u64) -> Option<&OtaaDevice> {
+        self.otaa.iter().find(|d| d.dev_eui == dev_eui)
+    }
+
+    pub fn session_keys(&self, dev_addr: u32) -> Option<&SessionKeys> {
+        self.sessions.get(&dev_addr).map(|s| &s.keys)
+    }
+
+    /// Record a confirmed uplink sent by the device itself, its FCnt is part of the MIC of LoRaWAN 1.1 ACKs.
+    pub fn expect_ack(&mut self, dev_addr: u32, fcnt: u32) {
+        if let Some(session) = self.sessions.get_mut(&dev_addr) {
+            session.confirmed_up = fcnt;
+        }
+    }
+
+    /// Record a Join-Request sent by the device itself, so that the Join-Accept can be processed.
+    pub fn expect_join_accept(&mut self, dev_eui: u64, dev_nonce: u16) {
+        self.pending_joins.insert(dev_eui, dev_nonce);
+    }
+
+    /// Verify and decrypt a parsed frame, `raw` is the complete PHYPayload.
+    pub fn process(
+        &mut self,
+        raw: &[u8],
+        phy: &PhyPayload,
+    ) -> Result<SecurityEvent, SecurityError> {
+        match &phy.payload {
+            MacPayload::Data(_) => self.process_data(raw, phy).map(SecurityEvent::Data),
+            MacPayload::JoinRequest(j) => {
+                let device = self
+                    .otaa
+                    .iter()
+                    .find(|d| d.dev_eui == j.dev_eui && d.join_eui == j.join_eui)
+                    .ok_or(SecurityError::UnknownDevice)?;
+                let mic = cmac(device.join_key(), &[&raw[..raw.len() - 4]]);
+                if mic[..4] != phy.mic {
+                    return Err(SecurityError::InvalidMic);
+                }
+                let device = device.name.clone();
+                self.pending_joins.insert(j.dev_eui, j.dev_nonce);
+                Ok(SecurityEvent::JoinRequest {
+                    device,
+                    dev_nonce: j.dev_nonce,
+                })
+            }
+            MacPayload::JoinAccept(encrypted) => self.process_join_accept(raw[0], encrypted),
+            _ => Err(SecurityError::Unsupported(format!("{:?}", phy.mhdr.mtype))),
+        }
+    }
+
+    /// Try the Join-Accept against all devices with a pending Join-Request.
+    fn process_join_accept(
+        &mut self,
+        mhdr: u8,
+        encrypted: &[u8],
+    ) -> Result<SecurityEvent, SecurityError> {
+        let joined = self.otaa.iter().find_map(|device| {
+            let dev_nonce = *self.pending_joins.get(&device.dev_eui)?;
+            // encrypted with AES decrypt, so encrypting restores the plaintext
+            let plain: Vec<u8> = encrypted
+                .chunks(16)
+                .flat_map(|c| aes_encrypt(device.join_key(), c.try_into().unwrap()))
+                .collect();
+            let (body, mic) = plain.split_at(plain.len() - 4);
+            let join_accept = JoinAccept::parse(body).ok()?;
+            let expected = if join_accept.opt_neg && device.nwk_key.is_some() {
+                let mut input = [0u8; 16];
+                input[0] = 0x06;
+                input[1..9].copy_from_slice(&device.dev_eui.to_le_bytes());
+                let js_int_key = aes_encrypt(device.join_key(), input);
+                cmac(
+                    &js_int_key,
+                    &[
+                        &[0xff],
+                        &device.join_eui.to_le_bytes(),
+                        &dev_nonce.to_le_bytes(),
+                        &[mhdr],
+                        body,
+                    ],
+                )
+            } else {
+                cmac(device.join_key(), &[&[mhdr], body])
+            };
+            if expected[..4] != *mic {
+                return None;
+            }
+            let keys = SessionKeys::derive(
+                &device.app_key,
+                device.nwk_key.as_ref(),
+                &join_accept,
+                device.join_eui,
+                dev_nonce,
+            );
+            Some((device.name.clone(), device.dev_eui, join_accept, keys))
+        });
+
+        let (device, dev_eui, join_accept, keys) = joined.ok_or(SecurityError::UnknownDevice)?;
+        let dev_addr = join_accept.dev_addr;
+        self.pending_joins.remove(&dev_eui);
+        self.add_session(device.clone(), dev_addr, keys);
+        Ok(SecurityEvent::Joined {
+            device,
+            dev_addr,
+            join_accept,
+        })
+    }
+
+    fn process_data(
+        &mut self,
+        raw: &[u8],
+        phy: &PhyPayload,
+    ) -> Result<DecryptedFrame, SecurityError> {
+        let MacPayload::Data(frame) = &phy.payload else {
+            return Err(SecurityError::Unsupported(String::from("not a data frame")));
+        };
+        let uplink = phy.mhdr.mtype.is_uplink();
+        let session = self
+            .sessions
+            .get_mut(&frame.dev_addr)
+            .ok_or(SecurityError::UnknownDevice)?;
+        let last = if uplink {
+            session.fcnt_up
+        } else {
+            session.fcnt_down
+        };
+
+        // restore the upper 16 bit of the counter from the last accepted frame
+        let fcnt = match last {
+            // repeated frame, checked like a new one but not delivered again
+            Some(last) if last as u16 == frame.fcnt => last,
+            Some(last) => {
+                let restored = (last & 0xffff_0000) | frame.fcnt as u32;
+                let fcnt = if restored <= last {
+                    restored.wrapping_add(0x1_0000)
+                } else {
+                    restored
+                };
+                if fcnt.wrapping_sub(last) > MAX_FCNT_GAP {
+                    return Err(SecurityError::Replay {
+                        fcnt: restored,
+                        last,
+                    });
+                }
+                fcnt
+            }
+            None => frame.fcnt as u32,
+        };
+
+        let keys = &session.keys;
+        let msg = &raw[..raw.len() - 4];
+        let mut b0 = block(0x49, uplink, frame.dev_addr, fcnt, msg.len() as u8);
+        let mic_ok = if keys.lorawan_1_1 && uplink {
+            // the SNwkSIntKey half depends on the TX data rate and channel, only check the FNwkSIntKey half
+            cmac(&keys.f_nwk_s_int_key, &[&b0, msg])[..2] == phy.mic[2..]
+        } else {
+            if keys.lorawan_1_1 && frame.fctrl.ack {
+                b0[1..3].copy_from_slice(&(session.confirmed_up as u16).to_le_bytes());
+            }
+            cmac(&keys.s_nwk_s_int_key, &[&b0, msg])[..4] == phy.mic
+        };
+        if !mic_ok {
+            return Err(SecurityError::InvalidMic);
+        }
+
+        let fopts = if keys.lorawan_1_1 {
+            crypt(
+                &keys.nwk_s_enc_key,
+                uplink,
+                frame.dev_addr,
+                fcnt,
+                0,
+                &frame.fopts,
+            )
+        } else {
+            frame.fopts.clone()
+        };
+        let key = match frame.fport {
+            Some(0) => &keys.nwk_s_enc_key,
+            _ => &keys.app_s_key,
+        };
+        let payload = crypt(key, uplink, frame.dev_addr, fcnt, 1, &frame.frm_payload);
+        let mac_commands = match frame.fport {
+            Some(0) => parse_mac_commands(&payload, uplink),
+            _ => parse_mac_commands(&fopts, uplink),
+        };
+
+        let retransmission = last == Some(fcnt);
+        if uplink {
+            session.fcnt_up = Some(fcnt);
+        } else {
+            session.fcnt_down = Some(fcnt);
+        }
+        Ok(DecryptedFrame {
+            device: session.name.clone(),
+            dev_addr: frame.dev_addr,
+            uplink,
+            fcnt,
+            retransmission,
+            fport: frame.fport,
+            payload,
+            mac_commands,
+            annotations: HashMap::new(),
+        })
+    }
+}
+
+/// Verify and decrypt the [`LorawanFrame`]s of a [`LorawanParser`](crate::lorawan::LorawanParser).
+///
+/// Decrypted data frames are posted as [`DecryptedFrame`] in a `Pmt::Any`, join events as `Pmt::String`.
+#[derive(Block)]
+#[message_inputs(r#in)]
+#[message_outputs(out)]
+#[null_kernel]
+pub struct LorawanDecryptor {
+    keys: KeyStore,
+}
+
+impl LorawanDecryptor {
+    pub fn new(keys: KeyStore) -> Self {
+        Self { keys }
+    }
+
+    async fn r#in(
+        &mut self,
+        io: &mut WorkIo,
+        mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::Finished => {
+                io.finished = true;
+            }
+            Pmt::Any(a) => {
+                let Some(frame) = a.downcast_ref::<LorawanFrame>() else {
+                    return Ok(Pmt::InvalidValue);
+                };
+                match self.keys.process(&frame.raw, &frame.phy) {
+                    Ok(SecurityEvent::Data(decrypted)) if decrypted.retransmission => {
+                        debug!("{}: repeated FCnt {}", decrypted.device, decrypted.fcnt);
+                    }
+                    Ok(SecurityEvent::Data(mut decrypted)) => {
+                        decrypted.annotations = frame.annotations.clone();
+                        mio.post("out", Pmt::Any(Box::new(decrypted))).await?;
+                    }
+                    Ok(SecurityEvent::JoinRequest { device, dev_nonce }) => {
+                        mio.post(
+                            "out",
+                            Pmt::String(format!("{device}: Join-Request, DevNonce {dev_nonce}")),
+                        )
+                        .await?;
+                    }
+                    Ok(SecurityEvent::Joined {
+                        device, dev_addr, ..
+                    }) => {
+                        mio.post(
+                            "out",
+                            Pmt::String(format!("{device}: joined as {dev_addr:08X}")),
+                        )
+                        .await?;
+                    }
+                    Err(SecurityError::UnknownDevice) => {}
+                    Err(e) => warn!("dropping LoRaWAN frame: {}", e),
+                }
+            }
+            _ => return Ok(Pmt::InvalidValue),
+        }
+        Ok(Pmt::Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    const DEV_ADDR: u32 = 0x49BE7DF1;
+
+    fn abp_keys() -> (Key, Key) {
+        (
+            parse_key("44024241ed4ce9a68c6a8bc055233fd3").unwrap(),
+            parse_key("ec925802ae430ca77fd3dd73cb2cc588").unwrap(),
+        )
+    }
+
+    #[test]
+    fn cmac_rfc4493() {
+        let key = parse_key("2b7e151628aed2a6abf7158809cf4f3c").unwrap();
+        assert_eq!(
+            cmac(&key, &[]).to_vec(),
+            hex("bb1d6929e95937287fa37d129b756746")
+        );
+        assert_eq!(
+            cmac(&key, &[&hex("6bc1bee22e409f96e93d7e117393172a")]).to_vec(),
+            hex("070a16b46b4d4144f79bdd9dd04a287c")
+        );
+    }
+
+    #[test]
+    fn seal_data_1_0() {
+        let (nwk_s_key, app_s_key) = abp_keys();
+        let frame = SessionKeys::from_abp(nwk_s_key, app_s_key).seal_data(
+            MHdr::from(0x40),
+            DEV_ADDR,
+            FCtrl::default(),
+            2,
+            &[],
+            Some(1),
+            b"test",
+            MicContext::default(),
+        );
+        assert_eq!(frame, hex("40f17dbe4900020001954378762b11ff0d"));
+    }
+
+    #[test]
+    fn seal_data_1_1_uplink() {
+        let (_, app_s_key) = abp_keys();
+        let keys = SessionKeys {
+            f_nwk_s_int_key: [0x11; 16],
+            s_nwk_s_int_key: [0x22; 16],
+            nwk_s_enc_key: [0x33; 16],
+            app_s_key,
+            lorawan_1_1: true,
+        };
+        let fctrl = FCtrl {
+            ack: true,
+            ..Default::default()
+        };
+        let mic = MicContext {
+            conf_fcnt: 5,
+            tx_dr: 5,
+            tx_ch: 2,
+        };
+        let frame = keys.seal_data(
+            MHdr::from(0x80),
+            DEV_ADDR,
+            fctrl,
+            2,
+            &[],
+            Some(1),
+            b"test",
+            mic,
+        );
+        // cmacS(B1)[0..2] | cmacF(B0)[0..2]
+        assert_eq!(frame, hex("80f17dbe4920020001954378768532cd1f"));
+    }
+
+    #[test]
+    fn process_data_and_retransmission() {
+        let (nwk_s_key, app_s_key) = abp_keys();
+        let mut store = KeyStore::new();
+        store.add_abp(String::from("test"), DEV_ADDR, nwk_s_key, app_s_key);
+        let raw = hex("40f17dbe4900020001954378762b11ff0d");
+        let phy = PhyPayload::parse(&raw).unwrap();
+
+        let Ok(SecurityEvent::Data(frame)) = store.process(&raw, &phy) else {
+            panic!("frame not accepted");
+        };
+        assert_eq!(frame.fcnt, 2);
+        assert_eq!(frame.fport, Some(1));
+        assert_eq!(frame.payload, b"test");
+        assert!(!frame.retransmission);
+
+        let Ok(SecurityEvent::Data(frame)) = store.process(&raw, &phy) else {
+            panic!("retransmission not accepted");
+        };
+        assert!(frame.retransmission);
+
+        let mut corrupted = raw.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let phy = PhyPayload::parse(&corrupted).unwrap();
+        assert!(matches!(
+            store.process(&corrupted, &phy),
+            Err(SecurityError::InvalidMic)
+        ));
+    }
+}
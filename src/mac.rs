@@ -0,0 +1,770 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futuresdr::channel::mpsc;
+use futuresdr::prelude::*;
+use futuresdr::runtime::BlockId;
+use futuresdr::runtime::FlowgraphHandle;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::Instant;
+
+use crate::kiss_driver::create_cmd;
+use crate::kiss_driver::kiss;
+use crate::utils::Bandwidth;
+use crate::utils::SpreadingFactor;
+
+/// length of the link-layer header prepended to every frame when MAC framing is enabled
+pub const MAC_HEADER_LEN: usize = 4;
+/// destination address of frames for all nodes, never acknowledged
+pub const BROADCAST_ADDR: u8 = 0xff;
+/// number of recent sequence numbers remembered per sender to drop retransmitted duplicates
+const DUP_WINDOW: usize = 16;
+
+pub mod flags {
+    pub const ACK_REQ: u8 = 0x01;
+    pub const ACK: u8 = 0x02;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    #[default]
+    Normal = 1,
+    High = 2,
+    /// reserved for link-layer acknowledgements, always sent first
+    Ack = 3,
+}
+
+impl From<u8> for Priority {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Priority::Low,
+            1 => Priority::Normal,
+            2 => Priority::High,
+            _ => Priority::Ack,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MacConfig {
+    /// prepend a [`MacHeader`] to every frame; required for ACK/ARQ
+    pub framing: bool,
+    /// time to wait for an ACK after the frame left the transmitter
+    pub ack_timeout: Duration,
+    /// number of retransmissions before a frame is reported as failed
+    pub max_retries: u8,
+    /// first retransmission backoff, doubled on every further attempt
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// link-layer address of this node, identifies the sender and receiver of framed frames
+    pub address: u8,
+    /// time to wait for `tx_done` on top of the time on air before the frame is given up
+    pub tx_done_margin: Duration,
+}
+
+impl Default for MacConfig {
+    fn default() -> Self {
+        Self {
+            framing: false,
+            ack_timeout: Duration::from_secs(3),
+            max_retries: 3,
+            backoff_base: Duration::from_millis(500),
+            backoff_max: Duration::from_secs(8),
+            address: rand::random_range(0..BROADCAST_ADDR),
+            tx_done_margin: Duration::from_secs(2),
+        }
+    }
+}
+
+impl MacConfig {
+    fn backoff(&self, attempt: u8) -> Duration {
+        let backoff = self
+            .backoff_base
+            .saturating_mul(1 << attempt.min(16) as u32)
+            .min(self.backoff_max);
+        // random jitter of up to 50% to de-synchronize competing nodes
+        backoff + backoff.mul_f32(rand::random::<f32>() * 0.5)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacHeader {
+    pub flags: u8,
+    /// receiver of a frame or [`BROADCAST_ADDR`], for ACKs the sender of the acknowledged frame
+    pub dest: u8,
+    /// sender of a frame
+    pub addr: u8,
+    pub seq: u8,
+}
+
+impl MacHeader {
+    pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+        if frame.len() < MAC_HEADER_LEN {
+            return None;
+        }
+        Some((
+            Self {
+                flags: frame[0],
+                dest: frame[1],
+                addr: frame[2],
+                seq: frame[3],
+            },
+            &frame[MAC_HEADER_LEN..],
+        ))
+    }
+
+    pub fn frame(&self, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(payload.len() + MAC_HEADER_LEN);
+        out.push(self.flags);
+        out.push(self.dest);
+        out.push(self.addr);
+        out.push(self.seq);
+        out.extend_from_slice(payload);
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxRequest {
+    pub id: u16,
+    pub payload: Vec<u8>,
+    pub priority: Priority,
+    /// link-layer address of the receiver, only unicast frames are acknowledged
+    pub dest: u8,
+    pub want_ack: bool,
+    /// do not transmit before this point in time, e.g. for contention windows
+    pub not_before: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacEvent {
+    Queued(u16),
+    TxDone(u16),
+    Delivered(u16),
+    DeliveryFailed(u16),
+    Cancelled(u16),
+}
+
+impl MacEvent {
+    pub fn id(&self) -> u16 {
+        match self {
+            Self::Queued(id)
+            | Self::TxDone(id)
+            | Self::Delivered(id)
+            | Self::DeliveryFailed(id)
+            | Self::Cancelled(id) => *id,
+        }
+    }
+
+    /// encode the event as KISS command for the UDP client
+    pub fn to_kiss(&self) -> Vec<u8> {
+        let cmd = match self {
+            Self::Queued(_) => kiss::CMD_TX_QUEUED,
+            Self::TxDone(_) => kiss::CMD_TX_DONE,
+            Self::Delivered(_) => kiss::CMD_TX_ACKED,
+            Self::DeliveryFailed(_) | Self::Cancelled(_) => kiss::CMD_TX_FAILED,
+        };
+        create_cmd(cmd, &self.id().to_le_bytes())
+    }
+}
+
+/// decoded frame as handed to MAC subscribers, MAC header already stripped
+#[derive(Debug, Clone)]
+pub struct RxFrame {
+    pub payload: Vec<u8>,
+    pub snr: Option<f64>,
+    pub annotations: HashMap<String, Pmt>,
+}
+
+pub enum MacCommand {
+    Submit(TxRequest),
+    Cancel(u16),
+    Subscribe(UnboundedSender<RxFrame>),
+}
+
+/// Cloneable handle to submit frames to a running MAC task.
+#[derive(Clone)]
+pub struct MacHandle {
+    commands: UnboundedSender<MacCommand>,
+}
+
+impl MacHandle {
+    pub fn submit(&self, request: TxRequest) -> bool {
+        self.commands.send(MacCommand::Submit(request)).is_ok()
+    }
+
+    pub fn cancel(&self, id: u16) -> bool {
+        self.commands.send(MacCommand::Cancel(id)).is_ok()
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<RxFrame> {
+        let (tx, rx) = unbounded_channel();
+        let _ = self.commands.send(MacCommand::Subscribe(tx));
+        rx
+    }
+}
+
+#[derive(Debug)]
+struct QueuedFrame {
+    request: TxRequest,
+    seq: u8,
+    attempt: u8,
+    order: u64,
+    /// cancelled while in flight, neither reported nor retransmitted after `tx_done`
+    cancelled: bool,
+}
+
+/// Priority queue of frames waiting for the transmitter.
+///
+/// Frames are served highest priority first and FIFO within one priority. Frames with a
+/// `not_before` in the future are skipped until they become ready.
+#[derive(Default)]
+pub struct MacQueue {
+    frames: Vec<QueuedFrame>,
+    next_order: u64,
+}
+
+impl MacQueue {
+    fn push(&mut self, request: TxRequest, seq: u8, attempt: u8) {
+        self.frames.push(QueuedFrame {
+            request,
+            seq,
+            attempt,
+            order: self.next_order,
+            cancelled: false,
+        });
+        self.next_order += 1;
+    }
+
+    fn is_ready(frame: &QueuedFrame, now: Instant) -> bool {
+        frame.request.not_before.is_none_or(|t| t <= now)
+    }
+
+    fn pop_ready(&mut self, now: Instant) -> Option<QueuedFrame> {
+        let idx = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| Self::is_ready(f, now))
+            .max_by(|(_, a), (_, b)| {
+                a.request
+                    .priority
+                    .cmp(&b.request.priority)
+                    .then(b.order.cmp(&a.order))
+            })
+            .map(|(i, _)| i)?;
+        Some(self.frames.remove(idx))
+    }
+
+    fn remove(&mut self, id: u16) -> bool {
+        let len = self.frames.len();
+        self.frames.retain(|f| f.request.id != id);
+        len != self.frames.len()
+    }
+
+    fn next_ready_at(&self) -> Option<Instant> {
+        self.frames
+            .iter()
+            .filter_map(|f| f.request.not_before)
+            .min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/// Time on air of a frame as sent by the [`Node`](crate::Node) transmitter (8 symbol preamble, explicit
+/// header, CR 4/5, CRC), assuming low data rate optimization to get an upper bound.
+fn time_on_air(sf: SpreadingFactor, bw: Bandwidth, len: usize) -> Duration {
+    let sf = Into::<f64>::into(sf);
+    let t_sym = 2f64.powf(sf) / Into::<f64>::into(bw);
+    let payload_symbols = 8.
+        + ((8. * len as f64 - 4. * sf + 28. + 16.) / (4. * (sf - 2.)))
+            .ceil()
+            .max(0.)
+            * 5.;
+    Duration::from_secs_f64((8. + 4.25 + payload_symbols) * t_sym)
+}
+
+pub(crate) struct Mac {
+    config: MacConfig,
+    sf: SpreadingFactor,
+    bw: Bandwidth,
+    queue: MacQueue,
+    /// frame handed to the transmitter and the deadline for its `tx_done`
+    in_flight: Option<(QueuedFrame, Instant)>,
+    awaiting_ack: HashMap<u8, (QueuedFrame, Instant)>,
+    /// recently received sequence numbers per sender
+    received: HashMap<u8, VecDeque<u8>>,
+    next_seq: u8,
+    subscribers: Vec<UnboundedSender<RxFrame>>,
+    events: UnboundedSender<MacEvent>,
+}
+
+impl Mac {
+    pub(crate) fn new(
+        config: MacConfig,
+        sf: SpreadingFactor,
+        bw: Bandwidth,
+        events: UnboundedSender<MacEvent>,
+    ) -> Self {
+        Self {
+            config,
+            sf,
+            bw,
+            queue: MacQueue::default(),
+            in_flight: None,
+            awaiting_ack: HashMap::new(),
+            received: HashMap::new(),
+            next_seq: 0,
+            subscribers: Vec::new(),
+            events,
+        }
+    }
+
+    pub(crate) fn channel() -> (MacHandle, UnboundedReceiver<MacCommand>) {
+        let (commands, rx) = unbounded_channel();
+        (MacHandle { commands }, rx)
+    }
+
+    fn emit(&self, event: MacEvent) {
+        let _ = self.events.send(event);
+    }
+
+    fn submit(&mut self, request: TxRequest) {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if request.priority != Priority::Ack {
+            self.emit(MacEvent::Queued(request.id));
+        }
+        self.queue.push(request, seq, 0);
+    }
+
+    fn cancel(&mut self, id: u16) {
+        if let Some((frame, _)) = self.in_flight.as_mut().filter(|(f, _)| {
+            f.request.id == id && !f.cancelled && f.request.priority != Priority::Ack
+        }) {
+            // the transmitter cannot be stopped, but the frame is not retransmitted
+            frame.cancelled = true;
+            self.emit(MacEvent::Cancelled(id));
+            return;
+        }
+        let awaiting = self
+            .awaiting_ack
+            .iter()
+            .find(|(_, (f, _))| f.request.id == id)
+            .map(|(seq, _)| *seq);
+        if let Some(seq) = awaiting {
+            self.awaiting_ack.remove(&seq);
+            self.emit(MacEvent::Cancelled(id));
+        } else if self.queue.remove(id) {
+            self.emit(MacEvent::Cancelled(id));
+        }
+    }
+
+    /// Frames sent with ACK_REQ, i.e. framed unicast frames that want an ACK.
+    fn wants_ack(&self, frame: &QueuedFrame) -> bool {
+        self.config.framing && frame.request.want_ack && frame.request.dest != BROADCAST_ADDR
+    }
+
+    fn on_tx_done(&mut self) {
+        let Some((frame, _)) = self.in_flight.take() else {
+            warn!("Mac: tx_done without frame in flight");
+            return;
+        };
+        if frame.request.priority == Priority::Ack || frame.cancelled {
+            return;
+        }
+        self.emit(MacEvent::TxDone(frame.request.id));
+        if self.wants_ack(&frame) {
+            let deadline = Instant::now() + self.config.ack_timeout;
+            self.awaiting_ack.insert(frame.seq, (frame, deadline));
+        }
+    }
+
+    /// The transmitter did not report `tx_done` in time, release the queue.
+    fn on_tx_timeout(&mut self, now: Instant) {
+        let Some((mut frame, _)) = self.in_flight.take() else {
+            return;
+        };
+        warn!("Mac: no tx_done for frame {}", frame.request.id);
+        if frame.request.priority == Priority::Ack || frame.cancelled {
+            return;
+        }
+        if self.wants_ack(&frame) && frame.attempt < self.config.max_retries {
+            frame.attempt += 1;
+            frame.request.not_before = Some(now + self.config.backoff(frame.attempt));
+            self.queue.push(frame.request, frame.seq, frame.attempt);
+        } else {
+            self.emit(MacEvent::DeliveryFailed(frame.request.id));
+        }
+    }
+
+    /// Remember a received sequence number, returns `false` for duplicates.
+    fn remember(&mut self, addr: u8, seq: u8) -> bool {
+        let window = self.received.entry(addr).or_default();
+        if window.contains(&seq) {
+            return false;
+        }
+        if window.len() == DUP_WINDOW {
+            window.pop_front();
+        }
+        window.push_back(seq);
+        true
+    }
+
+    fn on_rx(&mut self, pmt: Pmt) {
+        let Pmt::MapStrPmt(mut annotations) = pmt else {
+            return;
+        };
+        let Some(Pmt::Blob(mut payload)) = annotations.remove("payload") else {
+            return;
+        };
+        if let Some(Pmt::Bool(true)) = annotations.get("has_crc") {
+            payload.truncate(payload.len().saturating_sub(2));
+        }
+        let snr = match annotations.get("snr") {
+            Some(Pmt::F64(snr)) => Some(*snr),
+            _ => None,
+        };
+
+        if self.config.framing {
+            let Some((header, data)) = MacHeader::parse(&payload) else {
+                debug!("Mac: dropping frame shorter than MAC header");
+                return;
+            };
+            if header.dest != self.config.address && header.dest != BROADCAST_ADDR {
+                return;
+            }
+            if header.flags & flags::ACK != 0 {
+                let acked = self
+                    .awaiting_ack
+                    .get(&header.seq)
+                    .is_some_and(|(frame, _)| frame.request.dest == header.addr);
+                if acked {
+                    let (frame, _) = self.awaiting_ack.remove(&header.seq).unwrap();
+                    self.emit(MacEvent::Delivered(frame.request.id));
+                }
+                return;
+            }
+            if header.flags & flags::ACK_REQ != 0 && header.dest == self.config.address {
+                // duplicates are acknowledged again, their ACK was probably lost
+                let ack = MacHeader {
+                    flags: flags::ACK,
+                    dest: header.addr,
+                    addr: self.config.address,
+                    seq: header.seq,
+                };
+                self.queue.push(
+                    TxRequest {
+                        id: 0,
+                        payload: ack.frame(&[]),
+                        priority: Priority::Ack,
+                        dest: header.addr,
+                        want_ack: false,
+                        not_before: None,
+                    },
+                    header.seq,
+                    0,
+                );
+            }
+            if !self.remember(header.addr, header.seq) {
+                debug!(
+                    "Mac: dropping duplicate frame {} of {}",
+                    header.seq, header.addr
+                );
+                return;
+            }
+            payload = data.to_vec();
+        }
+
+        let frame = RxFrame {
+            payload,
+            snr,
+            annotations,
+        };
+        self.subscribers.retain(|s| s.send(frame.clone()).is_ok());
+    }
+
+    fn check_timeouts(&mut self, now: Instant) {
+        let expired: Vec<u8> = self
+            .awaiting_ack
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(seq, _)| *seq)
+            .collect();
+        for seq in expired {
+            let (mut frame, _) = self.awaiting_ack.remove(&seq).unwrap();
+            if frame.attempt >= self.config.max_retries {
+                info!(
+                    "Mac: frame {} not acknowledged after {} retries",
+                    frame.request.id, frame.attempt
+                );
+                self.emit(MacEvent::DeliveryFailed(frame.request.id));
+            } else {
+                frame.attempt += 1;
+                frame.request.not_before = Some(now + self.config.backoff(frame.attempt));
+                debug!(
+                    "Mac: retransmitting frame {} (attempt {})",
+                    frame.request.id, frame.attempt
+                );
+                self.queue.push(frame.request, frame.seq, frame.attempt);
+            }
+        }
+    }
+
+    fn next_wakeup(&self) -> Option<Instant> {
+        let ack_deadline = self.awaiting_ack.values().map(|(_, d)| *d).min();
+        // while a frame is in flight, ready frames have to wait for tx_done anyway
+        let next = match &self.in_flight {
+            Some((_, tx_deadline)) => Some(*tx_deadline),
+            None => self.queue.next_ready_at(),
+        };
+        match (next, ack_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn frame_for_air(&self, frame: &QueuedFrame) -> Vec<u8> {
+        if !self.config.framing || frame.request.priority == Priority::Ack {
+            return frame.request.payload.clone();
+        }
+        let header = MacHeader {
+            flags: if self.wants_ack(frame) {
+                flags::ACK_REQ
+            } else {
+                0
+            },
+            dest: frame.request.dest,
+            addr: self.config.address,
+            seq: frame.seq,
+        };
+        header.frame(&frame.request.payload)
+    }
+
+    /// Run the MAC until the command channel is closed.
+    ///
+    /// Only one frame is handed to the [`Transmitter`](crate::Transmitter) at a time; the next one
+    /// is released when the transmitter reports `tx_done` or did not do so within the time on air
+    /// plus [`MacConfig::tx_done_margin`].
+    pub(crate) async fn run(
+        mut self,
+        mut handle: FlowgraphHandle,
+        transmitter: BlockId,
+        mut commands: UnboundedReceiver<MacCommand>,
+        mut tx_done: mpsc::Receiver<Pmt>,
+        mut rx_frames: mpsc::Receiver<Pmt>,
+    ) {
+        loop {
+            if self.in_flight.is_none() {
+                if let Some(frame) = self.queue.pop_ready(Instant::now()) {
+                    let data = self.frame_for_air(&frame);
+                    let airtime = time_on_air(self.sf, self.bw, data.len());
+                    if let Err(e) = handle.call(transmitter, "msg", Pmt::Blob(data)).await {
+                        warn!("Mac: flowgraph call error: {}", e);
+                        self.emit(MacEvent::DeliveryFailed(frame.request.id));
+                    } else {
+                        let deadline = Instant::now() + airtime + self.config.tx_done_margin;
+                        self.in_flight = Some((frame, deadline));
+                    }
+                    continue;
+                }
+            }
+
+            let wakeup = self
+                .next_wakeup()
+                .unwrap_or_else(|| Instant::now() + Duration::from_secs(3600));
+
+            tokio::select! {
+                cmd = commands.recv() => match cmd {
+                    Some(MacCommand::Submit(request)) => self.submit(request),
+                    Some(MacCommand::Cancel(id)) => self.cancel(id),
+                    Some(MacCommand::Subscribe(sender)) => self.subscribers.push(sender),
+                    None => break,
+                },
+                Some(_) = tx_done.next() => self.on_tx_done(),
+                Some(pmt) = rx_frames.next() => self.on_rx(pmt),
+                _ = tokio::time::sleep_until(wakeup) => {
+                    let now = Instant::now();
+                    if self.in_flight.as_ref().is_some_and(|(_, deadline)| *deadline <= now) {
+                        self.on_tx_timeout(now);
+                    }
+                    self.check_timeouts(now);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(address: u8) -> (Mac, UnboundedReceiver<MacEvent>) {
+        let (events, rx) = unbounded_channel();
+        let config = MacConfig {
+            framing: true,
+            address,
+            ..MacConfig::default()
+        };
+        let mac = Mac::new(config, SpreadingFactor::SF7, Bandwidth::BW125, events);
+        (mac, rx)
+    }
+
+    fn rx_pmt(header: MacHeader, payload: &[u8]) -> Pmt {
+        let annotations =
+            HashMap::from([("payload".to_string(), Pmt::Blob(header.frame(payload)))]);
+        Pmt::MapStrPmt(annotations)
+    }
+
+    /// Hand the next ready frame to the "transmitter" and report `tx_done`.
+    fn transmit(mac: &mut Mac) -> Vec<u8> {
+        let frame = mac.queue.pop_ready(Instant::now()).unwrap();
+        let data = mac.frame_for_air(&frame);
+        mac.in_flight = Some((frame, Instant::now()));
+        mac.on_tx_done();
+        data
+    }
+
+    fn request(id: u16, dest: u8, want_ack: bool) -> TxRequest {
+        TxRequest {
+            id,
+            payload: vec![1, 2, 3],
+            priority: Priority::Normal,
+            dest,
+            want_ack,
+            not_before: None,
+        }
+    }
+
+    #[test]
+    fn duplicates_are_acked_but_delivered_once() {
+        let (mut mac, _events) = mac(1);
+        let (tx, mut frames) = unbounded_channel();
+        mac.subscribers.push(tx);
+        let header = MacHeader {
+            flags: flags::ACK_REQ,
+            dest: 1,
+            addr: 2,
+            seq: 7,
+        };
+        mac.on_rx(rx_pmt(header, b"hi"));
+        mac.on_rx(rx_pmt(header, b"hi"));
+
+        assert_eq!(frames.try_recv().unwrap().payload, b"hi");
+        assert!(frames.try_recv().is_err());
+        assert_eq!(mac.queue.len(), 2);
+        let ack = transmit(&mut mac);
+        assert_eq!(
+            MacHeader::parse(&ack).unwrap().0,
+            MacHeader {
+                flags: flags::ACK,
+                dest: 2,
+                addr: 1,
+                seq: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn only_frames_for_us_are_acked() {
+        let (mut mac, _events) = mac(1);
+        let (tx, mut frames) = unbounded_channel();
+        mac.subscribers.push(tx);
+        let mut header = MacHeader {
+            flags: flags::ACK_REQ,
+            dest: 3,
+            addr: 2,
+            seq: 0,
+        };
+        mac.on_rx(rx_pmt(header, b"other"));
+        header.dest = BROADCAST_ADDR;
+        mac.on_rx(rx_pmt(header, b"all"));
+
+        assert_eq!(frames.try_recv().unwrap().payload, b"all");
+        assert!(frames.try_recv().is_err());
+        assert!(mac.queue.is_empty());
+    }
+
+    #[test]
+    fn ack_delivers_frame() {
+        let (mut mac, mut events) = mac(1);
+        mac.submit(request(42, 2, true));
+        let data = transmit(&mut mac);
+        let (header, payload) = MacHeader::parse(&data).unwrap();
+        assert_eq!(
+            (header.flags, header.dest, header.addr),
+            (flags::ACK_REQ, 2, 1)
+        );
+        assert_eq!(payload, [1, 2, 3]);
+
+        // an ACK from another node does not count
+        let mut ack = MacHeader {
+            flags: flags::ACK,
+            dest: 1,
+            addr: 3,
+            seq: header.seq,
+        };
+        mac.on_rx(rx_pmt(ack, &[]));
+        ack.addr = 2;
+        mac.on_rx(rx_pmt(ack, &[]));
+
+        assert_eq!(events.try_recv().unwrap(), MacEvent::Queued(42));
+        assert_eq!(events.try_recv().unwrap(), MacEvent::TxDone(42));
+        assert_eq!(events.try_recv().unwrap(), MacEvent::Delivered(42));
+        assert!(mac.awaiting_ack.is_empty());
+    }
+
+    #[test]
+    fn missing_ack_retransmits_then_fails() {
+        let (mut mac, mut events) = mac(1);
+        mac.config.max_retries = 1;
+        mac.submit(request(5, 2, true));
+        let first = transmit(&mut mac);
+
+        let later = Instant::now() + mac.config.ack_timeout + Duration::from_secs(1);
+        mac.check_timeouts(later);
+        assert!(mac.queue.pop_ready(Instant::now()).is_none());
+        let retry = mac
+            .queue
+            .pop_ready(later + mac.config.backoff_max * 2)
+            .unwrap();
+        assert_eq!(retry.attempt, 1);
+        assert_eq!(mac.frame_for_air(&retry), first);
+        mac.in_flight = Some((retry, Instant::now()));
+        mac.on_tx_done();
+
+        mac.check_timeouts(later + mac.config.ack_timeout * 2);
+        let events: Vec<MacEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(
+            events,
+            [
+                MacEvent::Queued(5),
+                MacEvent::TxDone(5),
+                MacEvent::TxDone(5),
+                MacEvent::DeliveryFailed(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn broadcasts_are_not_acked() {
+        let (mut mac, mut events) = mac(1);
+        mac.submit(request(9, BROADCAST_ADDR, true));
+        let data = transmit(&mut mac);
+        assert_eq!(MacHeader::parse(&data).unwrap().0.flags, 0);
+        assert!(mac.awaiting_ack.is_empty());
+        assert_eq!(events.try_recv().unwrap(), MacEvent::Queued(9));
+        assert_eq!(events.try_recv().unwrap(), MacEvent::TxDone(9));
+    }
+}
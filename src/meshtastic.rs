@@ -1,8 +1,24 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
 use base64::prelude::*;
 use ctr::cipher::KeyIvInit;
 use ctr::cipher::StreamCipher;
-use futuresdr::tracing::info;
+use futuresdr::runtime::Pmt;
+use futuresdr::tracing::debug;
 use meshtastic::Message;
+use meshtastic::protobufs;
+use meshtastic::protobufs::PortNum;
+use serde::Serialize;
+
+use crate::decoder::annotated_payload;
+use crate::meshtastic_pki::PKI_CHANNEL_NAME;
+use crate::meshtastic_pki::PkiKeys;
+use crate::meshtastic_region::FrequencyPlan;
+use crate::meshtastic_region::MeshtasticRegion;
+use crate::meshtastic_region::Preset;
+use crate::meshtastic_region::default_channels;
 
 use crate::utils::Bandwidth;
 use crate::utils::Channel;
@@ -39,207 +55,287 @@ pub enum MeshtasticConfig {
     VeryLongSlowUs,
 }
 
-pub struct Setting {
-    ch_500_khz: Channel,
-    ch_250_khz: Channel,
-    ch_125_khz: Channel,
-}
-
-
 impl MeshtasticConfig {
-    const REGION_FREQ : [( &'static str, Setting ); 3] = [
-        ("EU433", Setting { ch_500_khz: Channel::Custom(433_250_000), ch_250_khz: Channel::Custom(433_125_000), ch_125_khz: Channel::Custom(433_062_500) }),
-        ("EU868", Setting { ch_500_khz: Channel::Custom(869_650_000), ch_250_khz: Channel::Custom(869_525_000), ch_125_khz: Channel::Custom(869_462_500) }),
-        ("RU",    Setting { ch_500_khz: Channel::Custom(868_950_000), ch_250_khz: Channel::Custom(868_825_000), ch_125_khz: Channel::Custom(868_762_500) }),
-
-    ];
-      
+    /// Region and preset of the config, on the preset's default slot.
+    pub fn frequency_plan(&self) -> FrequencyPlan {
+        let region = MeshtasticRegion::from_region_code(self.region_code() as i32).unwrap();
+        let preset = Preset::from_modem_preset(self.preset() as i32).unwrap();
+        FrequencyPlan::new(region, preset)
+    }
 
+    /// Default channels of all presets in a region given by its firmware name, e.g. `EU_868`.
     pub fn get_all_configs(&self, region: &str) -> Vec<(Bandwidth, Channel, Vec<(SpreadingFactor, bool, CodeRate)>)> {
-        // Find the region settings
-        let setting = Self::REGION_FREQ
-            .iter()
-            .find(|(r, _)| *r == region)
-            .map(|(_, s)| s)
-            .expect("Unknown region");
-        
-        // only 0 slots
-        vec![
-            (
-                Bandwidth::BW500,
-                setting.ch_500_khz,
-                vec![
-                    (SpreadingFactor::SF7, false, CodeRate::CR_4_5),
-                    (SpreadingFactor::SF11, false, CodeRate::CR_4_8),
-                ],
-            ),
-            (
-                Bandwidth::BW250,
-                setting.ch_250_khz,
-                vec![
-                    (SpreadingFactor::SF7, false, CodeRate::CR_4_5),
-                    (SpreadingFactor::SF8, false, CodeRate::CR_4_5),
-                    (SpreadingFactor::SF9, false, CodeRate::CR_4_5),
-                    (SpreadingFactor::SF10, false, CodeRate::CR_4_5),
-                    (SpreadingFactor::SF11, false, CodeRate::CR_4_5),
-                ],
-            ),
-            (
-                Bandwidth::BW125,
-                setting.ch_125_khz,
-                vec![
-                    (SpreadingFactor::SF11, true, CodeRate::CR_4_8),
-                    (SpreadingFactor::SF12, true, CodeRate::CR_4_8),
-                ],
-            ),
-        ]
+        let region = <MeshtasticRegion as clap::ValueEnum>::from_str(region, true).expect("Unknown region");
+        default_channels(region)
     }
 
     pub fn to_config(&self) -> (Bandwidth, SpreadingFactor, CodeRate, Channel, bool) {
+        self.frequency_plan()
+            .to_config("")
+            .expect("preset does not fit the region")
+    }
+}
+
+/// Destination of broadcast packets
+pub const BROADCAST: u32 = 0xffff_ffff;
+/// Node number `tx_meshtastic` sent from before the identity was configurable
+pub const DEFAULT_NODE_NUM: u32 = 0x3a48290e;
+/// Hop limit of the firmware's default LoRa config
+pub const DEFAULT_HOP_LIMIT: u8 = 3;
+
+/// Length of the radio header in front of the encrypted payload
+pub const HEADER_LEN: usize = 16;
+
+const FLAG_HOP_LIMIT: u8 = 0x07;
+const FLAG_WANT_ACK: u8 = 0x08;
+const FLAG_VIA_MQTT: u8 = 0x10;
+const FLAG_HOP_START_SHIFT: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MeshPacketError {
+    /// frame shorter than the radio header
+    TooShort(usize),
+    /// header without encrypted payload
+    NoPayload,
+}
+
+impl Display for MeshPacketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::ShortFastEu => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF7,
-                CodeRate::CR_4_5,
-                Channel::Custom(869525000),
-                false,
-            ),
-            Self::ShortSlowEu => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF8,
-                CodeRate::CR_4_5,
-                Channel::Custom(869525000),
-                false,
-            ),
-            Self::MediumFastEu => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF9,
-                CodeRate::CR_4_5,
-                Channel::Custom(869525000),
-                false,
-            ),
-            Self::MediumSlowEu => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF10,
-                CodeRate::CR_4_5,
-                Channel::Custom(869525000),
-                false,
-            ),
-            Self::LongFastEu => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF11,
-                CodeRate::CR_4_5,
-                Channel::Custom(869525000),
-                false,
-            ),
-            Self::LongModerateEu => (
-                Bandwidth::BW125,
-                SpreadingFactor::SF11,
-                CodeRate::CR_4_8,
-                Channel::Custom(869587500),
-                true,
-            ),
-            Self::LongSlowEu => (
-                Bandwidth::BW125,
-                SpreadingFactor::SF12,
-                CodeRate::CR_4_8,
-                Channel::Custom(869587500),
-                true,
-            ),
-            Self::VeryLongSlowEu => (
-                Bandwidth::BW62,
-                SpreadingFactor::SF12,
-                CodeRate::CR_4_8,
-                Channel::Custom(869492500),
-                true,
-            ),
-            Self::ShortFastUs => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF7,
-                CodeRate::CR_4_5,
-                Channel::Custom(906875000),
-                false,
-            ),
-            Self::ShortSlowUs => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF8,
-                CodeRate::CR_4_5,
-                Channel::Custom(906875000),
-                false,
-            ),
-            Self::MediumFastUs => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF9,
-                CodeRate::CR_4_5,
-                Channel::Custom(906875000),
-                false,
-            ),
-            Self::MediumSlowUs => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF10,
-                CodeRate::CR_4_5,
-                Channel::Custom(906875000),
-                false,
-            ),
-            Self::LongFastUs => (
-                Bandwidth::BW250,
-                SpreadingFactor::SF11,
-                CodeRate::CR_4_5,
-                Channel::Custom(906875000),
-                false,
-            ),
-            Self::LongModerateUs => (
-                Bandwidth::BW125,
-                SpreadingFactor::SF11,
-                CodeRate::CR_4_8,
-                Channel::Custom(904437500),
-                true,
-            ),
-            Self::LongSlowUs => (
-                Bandwidth::BW125,
-                SpreadingFactor::SF12,
-                CodeRate::CR_4_8,
-                Channel::Custom(904437500),
-                true,
-            ),
-            Self::VeryLongSlowUs => (
-                Bandwidth::BW62,
-                SpreadingFactor::SF12,
-                CodeRate::CR_4_8,
-                Channel::Custom(916218750),
-                true,
-            ),
+            Self::TooShort(len) => {
+                write!(f, "frame of {len} bytes shorter than the {HEADER_LEN} byte header")
+            }
+            Self::NoPayload => write!(f, "frame without payload"),
         }
     }
 }
 
-#[derive(Debug)]
+impl std::error::Error for MeshPacketError {}
+
+/// Meshtastic frame as sent over the air: the radio header and the encrypted `Data` protobuf.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MeshPacket {
-    _dest: u32,
-    sender: u32,
-    packet_id: u32,
-    _flags: u8,
-    channel_hash: u8,
-    _reserved: u16,
-    data: Vec<u8>,
+    pub dest: u32,
+    pub sender: u32,
+    pub packet_id: u32,
+    /// remaining hops
+    pub hop_limit: u8,
+    pub want_ack: bool,
+    pub via_mqtt: bool,
+    /// hop limit set by the sender, 0 for firmware before 2.3
+    pub hop_start: u8,
+    pub channel_hash: u8,
+    /// last byte of the next hop's node number, 0 if unknown (firmware 2.6 and later)
+    pub next_hop: u8,
+    /// last byte of the relaying node's number, 0 if unknown (firmware 2.6 and later)
+    pub relay_node: u8,
+    pub data: Vec<u8>,
 }
 
 impl MeshPacket {
-    pub fn new(bytes: &[u8]) -> Self {
-        Self {
-            _dest: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+    pub fn parse(bytes: &[u8]) -> Result<Self, MeshPacketError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(MeshPacketError::TooShort(bytes.len()));
+        }
+        if bytes.len() == HEADER_LEN {
+            return Err(MeshPacketError::NoPayload);
+        }
+        let flags = bytes[12];
+        Ok(Self {
+            dest: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
             sender: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
             packet_id: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
-            _flags: bytes[12],
+            hop_limit: flags & FLAG_HOP_LIMIT,
+            want_ack: flags & FLAG_WANT_ACK != 0,
+            via_mqtt: flags & FLAG_VIA_MQTT != 0,
+            hop_start: flags >> FLAG_HOP_START_SHIFT,
             channel_hash: bytes[13],
-            _reserved: u16::from_le_bytes(bytes[14..16].try_into().unwrap()),
-            data: bytes[16..].to_vec(),
+            next_hop: bytes[14],
+            relay_node: bytes[15],
+            data: bytes[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    /// Serialize header and payload, the inverse of [`MeshPacket::parse`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = self.hop_limit & FLAG_HOP_LIMIT;
+        if self.want_ack {
+            flags |= FLAG_WANT_ACK;
+        }
+        if self.via_mqtt {
+            flags |= FLAG_VIA_MQTT;
+        }
+        flags |= (self.hop_start & FLAG_HOP_LIMIT) << FLAG_HOP_START_SHIFT;
+
+        let mut out = Vec::with_capacity(HEADER_LEN + self.data.len());
+        out.extend_from_slice(&self.dest.to_le_bytes());
+        out.extend_from_slice(&self.sender.to_le_bytes());
+        out.extend_from_slice(&self.packet_id.to_le_bytes());
+        out.push(flags);
+        out.push(self.channel_hash);
+        out.push(self.next_hop);
+        out.push(self.relay_node);
+        out.extend_from_slice(&self.data);
+        out
+    }
+
+    /// Hops the packet travelled, if the sender reported its hop limit.
+    pub fn hops_away(&self) -> Option<u8> {
+        (self.hop_start != 0).then(|| self.hop_start.saturating_sub(self.hop_limit))
+    }
+}
+
+/// Reception metadata of a frame, taken from the `out_annotated` message of the [`Decoder`](crate::Decoder).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RxMetadata {
+    pub snr: Option<f64>,
+    /// uncalibrated power estimate of the [`FrameSync`](crate::FrameSync) in dB, only comparable
+    /// between frames of the same receiver
+    pub rssi: Option<f64>,
+    /// center frequency in Hz
+    pub freq: Option<f64>,
+    pub sf: Option<u32>,
+    /// bandwidth in kHz
+    pub bw: Option<u32>,
+    /// ns since the Unix epoch
+    pub timestamp: Option<u64>,
+}
+
+impl RxMetadata {
+    pub fn from_annotations(annotations: &HashMap<String, Pmt>) -> Self {
+        let u32_value = |key: &str| match annotations.get(key) {
+            Some(Pmt::U32(v)) => Some(*v),
+            Some(Pmt::Usize(v)) => Some(*v as u32),
+            _ => None,
+        };
+        Self {
+            snr: match annotations.get("snr") {
+                Some(Pmt::F64(snr)) => Some(*snr),
+                _ => None,
+            },
+            rssi: match annotations.get("rssi") {
+                Some(Pmt::F64(rssi)) => Some(*rssi),
+                _ => None,
+            },
+            freq: match annotations.get("freq") {
+                Some(Pmt::F64(freq)) => Some(*freq),
+                _ => None,
+            },
+            sf: u32_value("sf"),
+            bw: u32_value("bw"),
+            timestamp: match annotations.get("timestamp") {
+                Some(Pmt::U64(t)) => Some(*t),
+                _ => None,
+            },
         }
     }
 }
 
-#[derive(Debug)]
+/// Application payload of a decoded packet, by port number.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MeshtasticPayload {
+    TextMessage { text: String },
+    Position(protobufs::Position),
+    NodeInfo(protobufs::User),
+    Telemetry(protobufs::Telemetry),
+    Routing(protobufs::Routing),
+    Traceroute(protobufs::RouteDiscovery),
+    Waypoint(protobufs::Waypoint),
+    /// other ports or payloads that did not parse
+    Other { portnum: i32, payload: Vec<u8> },
+}
+
+impl MeshtasticPayload {
+    fn from_data(data: &protobufs::Data) -> Self {
+        fn parse<T: Message + Default>(payload: &[u8]) -> Option<T> {
+            T::decode(payload).ok()
+        }
+        let payload = &data.payload[..];
+        let parsed = match PortNum::try_from(data.portnum) {
+            Ok(PortNum::TextMessageApp) => Some(Self::TextMessage {
+                text: String::from_utf8_lossy(payload).into_owned(),
+            }),
+            Ok(PortNum::PositionApp) => parse(payload).map(Self::Position),
+            Ok(PortNum::NodeinfoApp) => parse(payload).map(Self::NodeInfo),
+            Ok(PortNum::TelemetryApp) => parse(payload).map(Self::Telemetry),
+            Ok(PortNum::RoutingApp) => parse(payload).map(Self::Routing),
+            Ok(PortNum::TracerouteApp) => parse(payload).map(Self::Traceroute),
+            Ok(PortNum::WaypointApp) => parse(payload).map(Self::Waypoint),
+            _ => None,
+        };
+        parsed.unwrap_or_else(|| Self::Other {
+            portnum: data.portnum,
+            payload: data.payload.clone(),
+        })
+    }
+}
+
+/// A decrypted Meshtastic packet with its routing header and reception metadata.
+#[derive(Debug, Clone, Serialize)]
+pub struct MeshtasticEvent {
+    pub from: u32,
+    pub to: u32,
+    pub packet_id: u32,
+    pub hop_limit: u8,
+    pub hop_start: u8,
+    pub want_ack: bool,
+    pub via_mqtt: bool,
+    pub next_hop: u8,
+    pub relay_node: u8,
+    pub channel: String,
+    pub channel_hash: u8,
+    pub rx: Option<RxMetadata>,
+    pub payload: MeshtasticPayload,
+}
+
+impl MeshtasticEvent {
+    /// Event of a decrypted packet on the named channel.
+    pub fn new(packet: &MeshPacket, channel: &str, data: &protobufs::Data) -> Self {
+        Self {
+            from: packet.sender,
+            to: packet.dest,
+            packet_id: packet.packet_id,
+            hop_limit: packet.hop_limit,
+            hop_start: packet.hop_start,
+            want_ack: packet.want_ack,
+            via_mqtt: packet.via_mqtt,
+            next_hop: packet.next_hop,
+            relay_node: packet.relay_node,
+            channel: channel.to_string(),
+            channel_hash: packet.channel_hash,
+            rx: None,
+            payload: MeshtasticPayload::from_data(data),
+        }
+    }
+
+    /// One line of JSON, e.g. for JSON-lines logs.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl Display for MeshtasticEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "!{:08x} -> !{:08x} on {} (id {:08x}, hops {}/{}): ",
+            self.from, self.to, self.channel, self.packet_id, self.hop_limit, self.hop_start
+        )?;
+        match &self.payload {
+            MeshtasticPayload::TextMessage { text } => write!(f, "{text:?}"),
+            MeshtasticPayload::Other { portnum, payload } => {
+                write!(f, "port {portnum} payload {payload:02x?}")
+            }
+            payload => write!(f, "{payload:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 enum Key {
+    /// unencrypted channel
+    None,
     Aes128([u8; 16]),
     Aes256([u8; 32]),
 }
@@ -247,41 +343,115 @@ enum Key {
 impl Key {
     fn as_slice(&self) -> &[u8] {
         match self {
+            Self::None => &[],
             Self::Aes128(x) => x,
             Self::Aes256(x) => x,
         }
     }
+
+    /// Expand a channel PSK like the firmware: empty for no encryption, one byte to select a variant of
+    /// the default key, or a 16/32 byte AES key.
+    fn from_psk(psk: &[u8]) -> Result<Self, ChannelKeyError> {
+        match psk.len() {
+            0 | 1 if psk.first().is_none_or(|&index| index == 0) => Ok(Self::None),
+            1 => {
+                let mut key = DEFAULT_KEY;
+                key[15] = key[15].wrapping_add(psk[0] - 1);
+                Ok(Self::Aes128(key))
+            }
+            16 => Ok(Self::Aes128(psk.try_into().unwrap())),
+            32 => Ok(Self::Aes256(psk.try_into().unwrap())),
+            len => Err(ChannelKeyError::InvalidLength(len)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelKeyError {
+    /// key is no valid base64
+    InvalidBase64,
+    /// key of a length other than 0, 1, 16 or 32 bytes
+    InvalidLength(usize),
 }
 
-#[derive(Debug)]
+impl Display for ChannelKeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidBase64 => write!(f, "channel key is no valid base64"),
+            Self::InvalidLength(len) => {
+                write!(f, "channel key of {len} bytes (0, 1, 16 or 32 expected)")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChannelKeyError {}
+
+#[derive(Debug, Clone)]
 pub struct MeshtasticChannel {
     key: Key,
+    psk: Vec<u8>,
     hash: u8,
     name: String,
 }
 
 impl MeshtasticChannel {
+    /// Channel from name and base64 key, panics on invalid keys, see [`MeshtasticChannel::try_new`].
     pub fn new(name: &str, key: &str) -> Self {
-        let key = BASE64_STANDARD.decode(key).unwrap();
-        let key = if key == [0x01] {
-            Key::Aes128(DEFAULT_KEY)
-        } else if key.len() == 16 {
-            Key::Aes128(key.clone().try_into().unwrap())
-        } else if key.len() == 32 {
-            Key::Aes256(key.clone().try_into().unwrap())
-        } else {
-            panic!("wrong key (base64-encoded 1/16/32 bytes expected)");
-        };
+        Self::try_new(name, key).unwrap()
+    }
 
-        let (hash, name) = if name.is_empty() || name == "\n" {
-            let hash = Self::hash("\n", key.as_slice());
-            (hash, "<unset>".to_string())
-        } else {
-            let hash = Self::hash(name, key.as_slice());
-            (hash, name.to_string())
-        };
+    /// Channel from name and base64-encoded PSK.
+    ///
+    /// An empty name stands for the primary channel, which is hashed with the name `"\n"` like before
+    /// preset names were used. Use [`MeshtasticChannel::from_psk`] to hash with the preset name.
+    pub fn try_new(name: &str, key: &str) -> Result<Self, ChannelKeyError> {
+        let psk = BASE64_STANDARD
+            .decode(key.trim())
+            .map_err(|_| ChannelKeyError::InvalidBase64)?;
+        let name = if name == "\n" { "" } else { name };
+        let hash_name = if name.is_empty() { "\n" } else { name };
+        Self::from_psk(name, hash_name, &psk)
+    }
+
+    /// Channel from its raw PSK, `hash_name` is the name the firmware uses for the channel hash, i.e.
+    /// the modem preset name for channels without name.
+    pub fn from_psk(name: &str, hash_name: &str, psk: &[u8]) -> Result<Self, ChannelKeyError> {
+        let key = Key::from_psk(psk)?;
+        Ok(Self {
+            hash: Self::hash(hash_name, key.as_slice()),
+            key,
+            psk: psk.to_vec(),
+            name: name.to_string(),
+        })
+    }
 
-        Self { key, hash, name }
+    /// Channel name, empty for the unnamed primary channel
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// PSK as configured, before expanding default keys
+    pub fn psk(&self) -> &[u8] {
+        &self.psk
+    }
+
+    pub fn hash_value(&self) -> u8 {
+        self.hash
+    }
+
+    /// Channel hash with the key of this channel and another name, e.g. to find the preset of a packet on
+    /// the unnamed channel.
+    pub fn hash_with(&self, hash_name: &str) -> u8 {
+        Self::hash(hash_name, self.key.as_slice())
+    }
+
+    fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            "<unset>"
+        } else {
+            &self.name
+        }
     }
 
     fn hash(name: &str, key: &[u8]) -> u8 {
@@ -295,96 +465,222 @@ impl MeshtasticChannel {
         xor
     }
 
-    pub fn decode(&self, packet: &MeshPacket) -> bool {
-        info!("MeshPacket: {:?}", packet);
+    /// AES-CTR with the nonce built from packet ID and sender, encrypts and decrypts.
+    fn apply_keystream(&self, packet_id: u32, sender: u32, bytes: &mut [u8]) {
         let mut iv = vec![];
-        iv.extend_from_slice(&(packet.packet_id as u64).to_le_bytes());
-        iv.extend_from_slice(&(packet.sender as u64).to_le_bytes());
+        iv.extend_from_slice(&(packet_id as u64).to_le_bytes());
+        iv.extend_from_slice(&(sender as u64).to_le_bytes());
         let iv: [u8; 16] = iv.try_into().unwrap();
 
-        let mut bytes = packet.data.clone();
         match self.key {
+            Key::None => {}
             Key::Aes128(key) => {
                 let mut cipher = Aes128::new(&key.into(), &iv.into());
-                cipher.apply_keystream(&mut bytes);
+                cipher.apply_keystream(bytes);
             }
             Key::Aes256(key) => {
                 let mut cipher = Aes256::new(&key.into(), &iv.into());
-                cipher.apply_keystream(&mut bytes);
+                cipher.apply_keystream(bytes);
             }
         }
-        if let Ok(res) = meshtastic::protobufs::Data::decode(&*bytes) {
-            if res.portnum == meshtastic::protobufs::PortNum::TextMessageApp as i32 {
-                info!(
-                    "Channel {}: Message {:?}",
-                    self.name,
-                    String::from_utf8_lossy(&res.payload)
-                );
-                true
-            } else {
-                info!("Channel {}: Message {:?}", self.name, res);
-                true
-            }
-        } else {
-            false
-        }
     }
 
-    pub fn encode(&self, data: String) -> Vec<u8> {
-        let packet_id = 0u32;
-        let dest = 0xffffffffu32;
-        let sender = 0x3a48290eu32;
-
-        let data = meshtastic::protobufs::Data {
-            portnum: 1,
-            payload: data.into_bytes(),
-            want_response: false,
-            dest: 0,
-            source: 0,
-            request_id: 0,
-            reply_id: 0,
-            emoji: 0,
-            bitfield: None,
-        };
+    /// Decrypt the `Data` message of a packet of this channel, `None` if the payload does not parse.
+    pub fn decrypt(&self, packet: &MeshPacket) -> Option<protobufs::Data> {
+        debug!("MeshPacket: {:?}", packet);
+        let mut bytes = packet.data.clone();
+        self.apply_keystream(packet.packet_id, packet.sender, &mut bytes);
+        protobufs::Data::decode(&*bytes).ok()
+    }
 
+    /// Decrypt a packet of this channel, `None` if the payload is no valid `Data` message.
+    pub fn decode(&self, packet: &MeshPacket) -> Option<MeshtasticEvent> {
+        let data = self.decrypt(packet)?;
+        Some(MeshtasticEvent::new(packet, self.display_name(), &data))
+    }
+
+    /// Encrypt `data` as payload of `packet` and set the channel hash.
+    pub fn encrypt(&self, packet: &mut MeshPacket, data: &protobufs::Data) {
         let mut bytes = data.encode_to_vec();
+        self.apply_keystream(packet.packet_id, packet.sender, &mut bytes);
+        packet.channel_hash = self.hash;
+        packet.data = bytes;
+    }
 
-        let mut iv = vec![];
-        iv.extend_from_slice(&(packet_id as u64).to_le_bytes());
-        iv.extend_from_slice(&(sender as u64).to_le_bytes());
-        let iv: [u8; 16] = iv.try_into().unwrap();
+    /// Broadcast text message from [`DEFAULT_NODE_NUM`] with a random packet ID.
+    ///
+    /// Use a [`MeshtasticEncoder`] to configure the identity and header fields.
+    pub fn encode(&self, data: String) -> Vec<u8> {
+        MeshtasticEncoder::new(DEFAULT_NODE_NUM)
+            .text(self, &data)
+            .to_bytes()
+    }
+}
 
-        match self.key {
-            Key::Aes128(key) => {
-                let mut cipher = Aes128::new(&key.into(), &iv.into());
-                cipher.apply_keystream(&mut bytes);
-            }
-            Key::Aes256(key) => {
-                let mut cipher = Aes256::new(&key.into(), &iv.into());
-                cipher.apply_keystream(&mut bytes);
+/// How a [`MeshtasticEncoder`] picks packet IDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketIds {
+    /// random non-zero IDs
+    Random,
+    /// counting up from the given ID, skipping 0
+    Sequential(u32),
+}
+
+/// Builds the outgoing packets of one node.
+///
+/// The packet ID is part of the AES-CTR nonce and the key of the duplicate detection of other nodes, so it
+/// has to change with every packet.
+#[derive(Debug, Clone)]
+pub struct MeshtasticEncoder {
+    node_num: u32,
+    dest: u32,
+    hop_limit: u8,
+    hop_start: Option<u8>,
+    want_ack: bool,
+    packet_ids: PacketIds,
+}
+
+impl MeshtasticEncoder {
+    /// Broadcast encoder with the default hop limit and random packet IDs.
+    pub fn new(node_num: u32) -> Self {
+        Self {
+            node_num,
+            dest: BROADCAST,
+            hop_limit: DEFAULT_HOP_LIMIT,
+            hop_start: None,
+            want_ack: false,
+            packet_ids: PacketIds::Random,
+        }
+    }
+
+    pub fn dest(mut self, dest: u32) -> Self {
+        self.dest = dest;
+        self
+    }
+
+    /// Hop limit, at most 7.
+    pub fn hop_limit(mut self, hop_limit: u8) -> Self {
+        self.hop_limit = hop_limit.min(FLAG_HOP_LIMIT);
+        self
+    }
+
+    /// Hop start, defaults to the hop limit.
+    pub fn hop_start(mut self, hop_start: u8) -> Self {
+        self.hop_start = Some(hop_start.min(FLAG_HOP_LIMIT));
+        self
+    }
+
+    pub fn want_ack(mut self, want_ack: bool) -> Self {
+        self.want_ack = want_ack;
+        self
+    }
+
+    pub fn packet_ids(mut self, packet_ids: PacketIds) -> Self {
+        self.packet_ids = packet_ids;
+        self
+    }
+
+    pub fn node_num(&self) -> u32 {
+        self.node_num
+    }
+
+    pub fn next_packet_id(&mut self) -> u32 {
+        match &mut self.packet_ids {
+            PacketIds::Random => loop {
+                let id = rand::random::<u32>();
+                if id != 0 {
+                    return id;
+                }
+            },
+            PacketIds::Sequential(next) => {
+                if *next == 0 {
+                    *next = 1;
+                }
+                let id = *next;
+                *next = next.wrapping_add(1);
+                id
             }
         }
+    }
 
-        let mut out = vec![];
-        out.extend_from_slice(&dest.to_le_bytes());
-        out.extend_from_slice(&sender.to_le_bytes());
-        out.extend_from_slice(&packet_id.to_le_bytes());
-        out.push(0);
-        out.push(self.hash);
-        out.extend_from_slice(&[0; 2]);
-        out.extend_from_slice(&bytes);
-        out
+    /// Header of the next packet with a fresh packet ID and no payload.
+    pub fn header(&mut self) -> MeshPacket {
+        MeshPacket {
+            dest: self.dest,
+            sender: self.node_num,
+            packet_id: self.next_packet_id(),
+            hop_limit: self.hop_limit,
+            want_ack: self.want_ack,
+            via_mqtt: false,
+            hop_start: self.hop_start.unwrap_or(self.hop_limit),
+            channel_hash: 0,
+            next_hop: 0,
+            relay_node: 0,
+            data: Vec::new(),
+        }
     }
+
+    /// Encrypted packet with the given `Data` message.
+    pub fn data(&mut self, channel: &MeshtasticChannel, data: &protobufs::Data) -> MeshPacket {
+        let mut packet = self.header();
+        channel.encrypt(&mut packet, data);
+        packet
+    }
+
+    /// Encrypted packet with a raw payload for the given port.
+    pub fn payload(
+        &mut self,
+        channel: &MeshtasticChannel,
+        portnum: PortNum,
+        payload: Vec<u8>,
+    ) -> MeshPacket {
+        let data = protobufs::Data {
+            portnum: portnum as i32,
+            payload,
+            ..Default::default()
+        };
+        self.data(channel, &data)
+    }
+
+    pub fn text(&mut self, channel: &MeshtasticChannel, text: &str) -> MeshPacket {
+        self.payload(channel, PortNum::TextMessageApp, text.as_bytes().to_vec())
+    }
+}
+
+/// Parse a node number given as `!3a48290e`, `0x3a48290e`, decimal, or `^all` for broadcast.
+pub fn parse_node_num(s: &str) -> anyhow::Result<u32> {
+    let s = s.trim();
+    if s == "^all" {
+        return Ok(BROADCAST);
+    }
+    let num = if let Some(hex) = s.strip_prefix('!').or_else(|| s.strip_prefix("0x")) {
+        u32::from_str_radix(hex, 16)?
+    } else {
+        s.parse()?
+    };
+    Ok(num)
 }
 
 pub struct MeshtasticChannels {
     channels: Vec<MeshtasticChannel>,
+    pki: Option<PkiKeys>,
 }
 
 impl MeshtasticChannels {
     pub fn new() -> Self {
         Self {
             channels: vec![MeshtasticChannel::new("", "AQ==")],
+            pki: None,
+        }
+    }
+
+    /// Channel set without the default channel, the first channel is the primary one. Panics without
+    /// channels.
+    pub fn from_channels(channels: Vec<MeshtasticChannel>) -> Self {
+        assert!(!channels.is_empty(), "channel set without channels");
+        Self {
+            channels,
+            pki: None,
         }
     }
 
@@ -392,15 +688,76 @@ impl MeshtasticChannels {
         self.channels.push(chan);
     }
 
-    pub fn decode(&self, bytes: &[u8]) {
-        let packet = MeshPacket::new(bytes);
+    pub fn channels(&self) -> &[MeshtasticChannel] {
+        &self.channels
+    }
+
+    /// Decrypt direct messages to the node of these keys.
+    pub fn set_pki(&mut self, keys: PkiKeys) {
+        self.pki = Some(keys);
+    }
+
+    pub fn pki(&self) -> Option<&PkiKeys> {
+        self.pki.as_ref()
+    }
+
+    /// Remember public keys announced in NodeInfo packets, returns if a key was new or changed.
+    pub fn learn(&mut self, event: &MeshtasticEvent) -> bool {
+        self.pki
+            .as_mut()
+            .is_some_and(|pki| pki.learn_from_event(event))
+    }
+
+    /// Decode with the first channel matching the channel hash, falling back to the default channel.
+    pub fn decode(&self, bytes: &[u8]) -> Option<MeshtasticEvent> {
+        let packet = match MeshPacket::parse(bytes) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("not a Meshtastic frame: {}", e);
+                return None;
+            }
+        };
+
+        self.decode_packet(&packet)
+    }
+
+    /// Decrypt a parsed packet, see [`decrypt`](Self::decrypt).
+    pub fn decode_packet(&self, packet: &MeshPacket) -> Option<MeshtasticEvent> {
+        let (index, data) = self.decrypt(packet)?;
+        let channel = match index {
+            Some(index) => self.channels[index].display_name(),
+            None => PKI_CHANNEL_NAME,
+        };
+        Some(MeshtasticEvent::new(packet, channel, &data))
+    }
 
-        for chan in self.channels.iter() {
-            if packet.channel_hash == chan.hash && chan.decode(&packet) {
-                return;
+    /// Decrypt with PKI or the first channel matching the channel hash, falling back to the default channel.
+    ///
+    /// Returns the index of the channel, `None` for PKI direct messages, and the `Data` message.
+    pub fn decrypt(&self, packet: &MeshPacket) -> Option<(Option<usize>, protobufs::Data)> {
+        if let Some(pki) = &self.pki {
+            if pki.is_pki_packet(packet) {
+                match pki.decrypt(packet) {
+                    Ok(data) => return Some((None, data)),
+                    Err(e) => debug!("PKI packet {:08x}: {}", packet.packet_id, e),
+                }
             }
         }
-        self.channels[0].decode(&packet);
+
+        self.channels
+            .iter()
+            .enumerate()
+            .filter(|(_, chan)| packet.channel_hash == chan.hash)
+            .find_map(|(index, chan)| Some((Some(index), chan.decrypt(packet)?)))
+            .or_else(|| Some((Some(0), self.channels[0].decrypt(packet)?)))
+    }
+
+    /// Decode the `out_annotated` message of a [`Decoder`](crate::Decoder), including the RX metadata.
+    pub fn decode_annotated(&self, annotations: &HashMap<String, Pmt>) -> Option<MeshtasticEvent> {
+        let payload = annotated_payload(annotations)?;
+        let mut event = self.decode(&payload)?;
+        event.rx = Some(RxMetadata::from_annotations(annotations));
+        Some(event)
     }
 }
 
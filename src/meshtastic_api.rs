@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use futuresdr::tracing::debug;
+use futuresdr::tracing::info;
+use futuresdr::tracing::warn;
+use meshtastic::Message;
+use meshtastic::protobufs;
+use meshtastic::protobufs::HardwareModel;
+use meshtastic::protobufs::PortNum;
+use meshtastic::protobufs::from_radio;
+use meshtastic::protobufs::mesh_packet;
+use meshtastic::protobufs::routing;
+use meshtastic::protobufs::to_radio;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::meshtastic::BROADCAST;
+use crate::meshtastic::DEFAULT_HOP_LIMIT;
+use crate::meshtastic::MeshPacket;
+use crate::meshtastic::MeshtasticChannel;
+use crate::meshtastic::MeshtasticChannels;
+use crate::meshtastic::MeshtasticConfig;
+use crate::meshtastic::MeshtasticEncoder;
+use crate::meshtastic::MeshtasticEvent;
+use crate::meshtastic::RxMetadata;
+use crate::meshtastic_nodedb::NodeDb;
+use crate::meshtastic_nodedb::SAVE_INTERVAL;
+use crate::meshtastic_pki::PKI_CHANNEL_NAME;
+use crate::meshtastic_pki::PkiKeys;
+use crate::meshtastic_router::RouterEvent;
+use crate::meshtastic_router::RouterHandle;
+use crate::meshtastic_url::ChannelUrl;
+
+/// TCP port of the firmware's client API
+pub const API_PORT: u16 = 4403;
+/// Start of every frame of the stream API
+pub const START1: u8 = 0x94;
+pub const START2: u8 = 0xc3;
+/// Largest protobuf the firmware accepts in one frame
+pub const MAX_FRAME_LEN: usize = 512;
+/// Channels reported to clients, unused ones as disabled
+const MAX_NUM_CHANNELS: usize = 8;
+/// Firmware version reported to clients, the apps refuse to talk to old firmware
+pub const FIRMWARE_VERSION: &str = "2.5.0.sdr";
+const MIN_APP_VERSION: u32 = 30200;
+
+/// Frame of the stream API: start bytes, big-endian length and protobuf.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.push(START1);
+    frame.push(START2);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Splits a byte stream into the protobufs of the stream API.
+///
+/// Bytes outside of frames, like the wake-up sequence or debug output of the firmware, are skipped.
+/// Frames longer than [`MAX_FRAME_LEN`] are treated as noise and the decoder resyncs on the next start bytes.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Next complete frame payload, `None` if more bytes are needed.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.buf.iter().position(|b| *b == START1) {
+                Some(start) => {
+                    self.buf.drain(..start);
+                }
+                None => {
+                    self.buf.clear();
+                    return None;
+                }
+            }
+            if self.buf.len() < 4 {
+                return None;
+            }
+            let len = u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize;
+            if self.buf[1] != START2 || len > MAX_FRAME_LEN {
+                self.buf.drain(..1);
+                continue;
+            }
+            if self.buf.len() < 4 + len {
+                return None;
+            }
+            let frame = self.buf[4..4 + len].to_vec();
+            self.buf.drain(..4 + len);
+            return Some(frame);
+        }
+    }
+}
+
+fn now_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// Identity, channels and state of the node served by a [`MeshtasticApiServer`].
+pub struct ApiConfig {
+    pub addr: SocketAddr,
+    pub node_num: u32,
+    pub long_name: String,
+    pub short_name: String,
+    /// channels and LoRa config reported to clients
+    pub channel_url: ChannelUrl,
+    /// keys for PKI direct messages
+    pub pki: Option<PkiKeys>,
+    /// file to save learned public keys to
+    pub pki_path: Option<PathBuf>,
+    /// JSON file of the node database, created if missing
+    pub node_db: Option<PathBuf>,
+}
+
+impl ApiConfig {
+    /// Config listening on all interfaces on [`API_PORT`], named after the node number like the firmware.
+    pub fn new(node_num: u32, channel_url: ChannelUrl) -> Self {
+        let short = format!("{:04x}", node_num & 0xffff);
+        Self {
+            addr: SocketAddr::from(([0, 0, 0, 0], API_PORT)),
+            node_num,
+            long_name: format!("Meshtastic {short}"),
+            short_name: short,
+            channel_url,
+            pki: None,
+            pki_path: None,
+            node_db: None,
+        }
+    }
+
+    /// Default `LongFast` channel with the default key.
+    pub fn default_channel_url(config: MeshtasticConfig) -> ChannelUrl {
+        ChannelUrl::from_channels([&MeshtasticChannel::new("", "AQ==")], config)
+    }
+}
+
+enum ApiCommand {
+    Connected(u32, UnboundedSender<protobufs::FromRadio>),
+    ToRadio(u32, protobufs::ToRadio),
+    Disconnected(u32),
+}
+
+struct ApiClient {
+    sender: UnboundedSender<protobufs::FromRadio>,
+    /// packets are only forwarded after the client requested the config
+    configured: bool,
+}
+
+/// Client API of the firmware over TCP, as used by the Python CLI, the web client and the apps.
+///
+/// Clients exchange `ToRadio` and `FromRadio` protobufs in frames of the stream API. A `want_config_id`
+/// is answered with the own node, the node database, the channels and the LoRa config. Afterwards,
+/// received packets for us or broadcast are forwarded decrypted and packets of the client are encrypted
+/// and sent through the router.
+pub struct MeshtasticApiServer {
+    config: ApiConfig,
+    channels: MeshtasticChannels,
+    node_db: NodeDb,
+    encoder: MeshtasticEncoder,
+    clients: HashMap<u32, ApiClient>,
+    next_from_radio_id: u32,
+}
+
+impl MeshtasticApiServer {
+    pub fn new(mut config: ApiConfig) -> anyhow::Result<Self> {
+        let mut channels = config.channel_url.meshtastic_channels()?;
+        if let Some(pki) = config.pki.take() {
+            channels.set_pki(pki);
+        }
+        let node_db = match &config.node_db {
+            Some(path) => NodeDb::open(path)?,
+            None => NodeDb::new(),
+        };
+        Ok(Self {
+            encoder: MeshtasticEncoder::new(config.node_num),
+            config,
+            channels,
+            node_db,
+            clients: HashMap::new(),
+            next_from_radio_id: 1,
+        })
+    }
+
+    fn from_radio(&mut self, payload: from_radio::PayloadVariant) -> protobufs::FromRadio {
+        let id = self.next_from_radio_id;
+        self.next_from_radio_id = self.next_from_radio_id.wrapping_add(1);
+        protobufs::FromRadio {
+            id,
+            payload_variant: Some(payload),
+        }
+    }
+
+    fn send_to(&mut self, client: u32, payload: from_radio::PayloadVariant) {
+        let msg = self.from_radio(payload);
+        if let Some(c) = self.clients.get(&client) {
+            let _ = c.sender.send(msg);
+        }
+    }
+
+    /// Send to all clients that finished the config handshake.
+    fn broadcast(&mut self, payload: from_radio::PayloadVariant) {
+        let msg = self.from_radio(payload);
+        self.clients
+            .retain(|_, c| !c.configured || c.sender.send(msg.clone()).is_ok());
+    }
+
+    fn own_node_info(&self) -> protobufs::NodeInfo {
+        let public_key = self
+            .channels
+            .pki()
+            .map(|pki| pki.key_pair().public_key().to_vec())
+            .unwrap_or_default();
+        protobufs::NodeInfo {
+            num: self.config.node_num,
+            user: Some(protobufs::User {
+                id: format!("!{:08x}", self.config.node_num),
+                long_name: self.config.long_name.clone(),
+                short_name: self.config.short_name.clone(),
+                hw_model: HardwareModel::Portduino as i32,
+                public_key,
+                ..Default::default()
+            }),
+            last_heard: now_secs(),
+            ..Default::default()
+        }
+    }
+
+    /// Answer of the firmware to `want_config_id`.
+    fn config_payloads(&self, config_id: u32) -> Vec<from_radio::PayloadVariant> {
+        use from_radio::PayloadVariant;
+
+        let mut payloads = vec![PayloadVariant::MyInfo(protobufs::MyNodeInfo {
+            my_node_num: self.config.node_num,
+            min_app_version: MIN_APP_VERSION,
+            ..Default::default()
+        })];
+        payloads.push(PayloadVariant::NodeInfo(self.own_node_info()));
+        payloads.extend(
+            self.node_db
+                .nodes()
+                .filter(|node| node.num != self.config.node_num)
+                .map(|node| PayloadVariant::NodeInfo(node.to_node_info())),
+        );
+        payloads.push(PayloadVariant::Metadata(protobufs::DeviceMetadata {
+            firmware_version: FIRMWARE_VERSION.to_string(),
+            hw_model: HardwareModel::Portduino as i32,
+            ..Default::default()
+        }));
+
+        let settings = &self.config.channel_url.channel_set.settings;
+        for index in 0..MAX_NUM_CHANNELS {
+            let role = match index {
+                _ if index >= settings.len() => protobufs::channel::Role::Disabled,
+                0 => protobufs::channel::Role::Primary,
+                _ => protobufs::channel::Role::Secondary,
+            };
+            payloads.push(PayloadVariant::Channel(protobufs::Channel {
+                index: index as i32,
+                settings: settings.get(index).cloned(),
+                role: role as i32,
+            }));
+        }
+
+        let lora = self
+            .config
+            .channel_url
+            .lora_config()
+            .cloned()
+            .unwrap_or_default();
+        for config in [
+            protobufs::config::PayloadVariant::Device(Default::default()),
+            protobufs::config::PayloadVariant::Lora(lora),
+        ] {
+            payloads.push(PayloadVariant::Config(protobufs::Config {
+                payload_variant: Some(config),
+            }));
+        }
+        payloads.push(PayloadVariant::ConfigCompleteId(config_id));
+        payloads
+    }
+
+    /// Routing message from us, how the firmware reports the fate of a packet of the client.
+    fn routing_reply(&mut self, request_id: u32, error: routing::Error) -> protobufs::MeshPacket {
+        let routing = protobufs::Routing {
+            variant: Some(routing::Variant::ErrorReason(error as i32)),
+        };
+        protobufs::MeshPacket {
+            from: self.config.node_num,
+            to: self.config.node_num,
+            id: self.encoder.next_packet_id(),
+            rx_time: now_secs(),
+            payload_variant: Some(mesh_packet::PayloadVariant::Decoded(protobufs::Data {
+                portnum: PortNum::RoutingApp as i32,
+                payload: routing.encode_to_vec(),
+                request_id,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// Encrypt a packet of a client, with PKI for direct messages to nodes with a known key.
+    fn encrypt(&mut self, packet: &protobufs::MeshPacket) -> Result<MeshPacket, routing::Error> {
+        let Some(mesh_packet::PayloadVariant::Decoded(data)) = &packet.payload_variant else {
+            return Err(routing::Error::BadRequest);
+        };
+        let hop_limit = match packet.hop_limit {
+            0 => DEFAULT_HOP_LIMIT,
+            hop_limit => hop_limit.min(7) as u8,
+        };
+        let mut header = self.encoder.header();
+        if packet.id != 0 {
+            header.packet_id = packet.id;
+        }
+        header.dest = packet.to;
+        header.hop_limit = hop_limit;
+        header.hop_start = hop_limit;
+        header.want_ack = packet.want_ack;
+
+        let pki = self
+            .channels
+            .pki()
+            .filter(|pki| packet.to != BROADCAST && pki.public_key(packet.to).is_some());
+        match pki {
+            Some(pki) => pki
+                .encrypt(&mut header, data)
+                .map_err(|_| routing::Error::PkiFailed)?,
+            None if packet.pki_encrypted => return Err(routing::Error::PkiUnknownPubkey),
+            None => self
+                .channels
+                .channels()
+                .get(packet.channel as usize)
+                .ok_or(routing::Error::NoChannel)?
+                .encrypt(&mut header, data),
+        }
+        Ok(header)
+    }
+
+    fn on_to_radio(&mut self, router: &RouterHandle, client: u32, msg: protobufs::ToRadio) {
+        match msg.payload_variant {
+            Some(to_radio::PayloadVariant::WantConfigId(config_id)) => {
+                debug!("Meshtastic API: client {client} wants config {config_id}");
+                for payload in self.config_payloads(config_id) {
+                    self.send_to(client, payload);
+                }
+                if let Some(c) = self.clients.get_mut(&client) {
+                    c.configured = true;
+                }
+            }
+            Some(to_radio::PayloadVariant::Packet(packet)) => {
+                let result = self.encrypt(&packet).map(|encrypted| {
+                    let id = encrypted.packet_id;
+                    router.send(encrypted);
+                    id
+                });
+                let request_id = *result.as_ref().unwrap_or(&packet.id);
+                self.send_to(
+                    client,
+                    from_radio::PayloadVariant::QueueStatus(protobufs::QueueStatus {
+                        res: result.is_err() as i32,
+                        free: 16,
+                        maxlen: 16,
+                        mesh_packet_id: request_id,
+                    }),
+                );
+                if let Err(error) = result {
+                    warn!(
+                        "Meshtastic API: dropping packet {request_id:08x} of client {client}: {error:?}"
+                    );
+                    let reply = self.routing_reply(request_id, error);
+                    self.send_to(client, from_radio::PayloadVariant::Packet(reply));
+                }
+            }
+            Some(to_radio::PayloadVariant::Heartbeat(_)) => {}
+            Some(to_radio::PayloadVariant::Disconnect(_)) => {
+                self.clients.remove(&client);
+            }
+            other => debug!("Meshtastic API: ignoring {other:?} of client {client}"),
+        }
+    }
+
+    fn on_received(&mut self, packet: MeshPacket, snr: Option<f64>) {
+        let Some((index, data)) = self.channels.decrypt(&packet) else {
+            debug!(
+                "Meshtastic API: cannot decrypt packet {:08x}",
+                packet.packet_id
+            );
+            return;
+        };
+
+        let channel = match index {
+            Some(index) => self.channels.channels()[index].name(),
+            None => PKI_CHANNEL_NAME,
+        };
+        let mut event = MeshtasticEvent::new(&packet, channel, &data);
+        event.rx = Some(RxMetadata {
+            snr,
+            ..Default::default()
+        });
+        if self.channels.learn(&event) {
+            info!("learned public key of !{:08x}", event.from);
+            if let (Some(path), Some(keys)) = (&self.config.pki_path, self.channels.pki()) {
+                if let Err(e) = keys.save(path) {
+                    warn!("failed to save PKI keys: {e}");
+                }
+            }
+        }
+        self.node_db.update(&event);
+        self.save_node_db();
+
+        if packet.dest != BROADCAST && packet.dest != self.config.node_num {
+            return;
+        }
+        let public_key = match index {
+            Some(_) => Vec::new(),
+            None => self
+                .channels
+                .pki()
+                .and_then(|pki| pki.public_key(packet.sender))
+                .map(|key| key.to_vec())
+                .unwrap_or_default(),
+        };
+        let packet = protobufs::MeshPacket {
+            from: packet.sender,
+            to: packet.dest,
+            channel: index.unwrap_or(0) as u32,
+            id: packet.packet_id,
+            rx_time: now_secs(),
+            rx_snr: snr.unwrap_or_default() as f32,
+            hop_limit: packet.hop_limit as u32,
+            want_ack: packet.want_ack,
+            via_mqtt: packet.via_mqtt,
+            hop_start: packet.hop_start as u32,
+            pki_encrypted: index.is_none(),
+            public_key,
+            payload_variant: Some(mesh_packet::PayloadVariant::Decoded(data)),
+            ..Default::default()
+        };
+        self.broadcast(from_radio::PayloadVariant::Packet(packet));
+    }
+
+    fn save_node_db(&mut self) {
+        if let Err(e) = self.node_db.save_if_needed() {
+            warn!("failed to save node database: {e}");
+        }
+    }
+
+    fn on_router_event(&mut self, event: RouterEvent) {
+        let (packet_id, error) = match event {
+            RouterEvent::Received { packet, snr } => return self.on_received(packet, snr),
+            RouterEvent::ImplicitAck { packet_id } => (packet_id, routing::Error::None),
+            RouterEvent::NoAck { packet_id } => (packet_id, routing::Error::MaxRetransmit),
+            _ => return,
+        };
+        let reply = self.routing_reply(packet_id, error);
+        self.broadcast(from_radio::PayloadVariant::Packet(reply));
+    }
+
+    fn on_command(&mut self, router: &RouterHandle, command: ApiCommand) {
+        match command {
+            ApiCommand::Connected(client, sender) => {
+                self.clients.insert(
+                    client,
+                    ApiClient {
+                        sender,
+                        configured: false,
+                    },
+                );
+            }
+            ApiCommand::ToRadio(client, msg) => self.on_to_radio(router, client, msg),
+            ApiCommand::Disconnected(client) => {
+                self.clients.remove(&client);
+            }
+        }
+    }
+
+    /// Run the server until the router stopped.
+    pub async fn run(mut self, router: RouterHandle) -> std::io::Result<()> {
+        let listener = TcpListener::bind(self.config.addr).await?;
+        info!("Meshtastic API listening on {}", listener.local_addr()?);
+        let mut events = router.subscribe();
+        let (commands_tx, mut commands) = unbounded_channel();
+        let mut next_client = 0;
+        // flushes packet counters, changed nodes are saved as they are heard
+        let mut save_timer = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                conn = listener.accept() => match conn {
+                    Ok((stream, addr)) => {
+                        info!("Meshtastic API: client {next_client} connected from {addr}");
+                        tokio::spawn(serve_client(next_client, stream, commands_tx.clone()));
+                        next_client += 1;
+                    }
+                    Err(e) => warn!("Meshtastic API: accept failed: {e}"),
+                },
+                Some(command) = commands.recv() => self.on_command(&router, command),
+                event = events.recv() => match event {
+                    Some(event) => self.on_router_event(event),
+                    None => {
+                        warn!("Meshtastic API: router stopped");
+                        break;
+                    }
+                },
+                _ = save_timer.tick() => self.save_node_db(),
+            }
+        }
+        if let Err(e) = self.node_db.save() {
+            warn!("failed to save node database: {e}");
+        }
+        Ok(())
+    }
+}
+
+/// Frame the messages of one TCP connection until the client disconnects.
+async fn serve_client(client: u32, stream: TcpStream, commands: UnboundedSender<ApiCommand>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut from_radio) = unbounded_channel::<protobufs::FromRadio>();
+    let _ = commands.send(ApiCommand::Connected(client, sender));
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0u8; 1024];
+
+    'client: loop {
+        tokio::select! {
+            n = reader.read(&mut buf) => {
+                let n = match n {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                decoder.push(&buf[..n]);
+                while let Some(frame) = decoder.next_frame() {
+                    let msg = match protobufs::ToRadio::decode(&*frame) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            debug!("Meshtastic API: invalid ToRadio of client {client}: {e}");
+                            continue;
+                        }
+                    };
+                    let disconnect = matches!(
+                        msg.payload_variant,
+                        Some(to_radio::PayloadVariant::Disconnect(_))
+                    );
+                    if commands.send(ApiCommand::ToRadio(client, msg)).is_err() || disconnect {
+                        break 'client;
+                    }
+                }
+            }
+            msg = from_radio.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                if writer.write_all(&encode_frame(&msg.encode_to_vec())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    info!("Meshtastic API: client {client} disconnected");
+    let _ = commands.send(ApiCommand::Disconnected(client));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_decoder_resyncs_on_garbage() {
+        // wake-up sequence and debug output of the firmware
+        let mut stream = b"\x94\x94\x94\x94 INFO | boot\r\n\xc3".to_vec();
+        // start bytes with a length above MAX_FRAME_LEN are noise
+        stream.extend_from_slice(&[START1, START2, 0xff, 0xff]);
+        stream.extend_from_slice(&encode_frame(b"first"));
+        stream.extend_from_slice(&[START1, 0x00]);
+        stream.extend_from_slice(&encode_frame(b"second"));
+
+        let mut decoder = FrameDecoder::new();
+        let split = stream.len() - 3;
+        decoder.push(&stream[..split]);
+        assert_eq!(decoder.next_frame(), Some(b"first".to_vec()));
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&stream[split..]);
+        assert_eq!(decoder.next_frame(), Some(b"second".to_vec()));
+        assert_eq!(decoder.next_frame(), None);
+    }
+}
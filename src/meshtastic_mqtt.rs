@@ -0,0 +1,742 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use meshtastic::Message;
+use meshtastic::protobufs;
+use meshtastic::protobufs::mesh_packet;
+use rumqttc::AsyncClient;
+use rumqttc::Event;
+use rumqttc::LastWill;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::QoS;
+use tokio::runtime::Runtime;
+use tokio::time::Instant;
+use triggered::Listener;
+use triggered::Trigger;
+
+use futuresdr::channel::mpsc::Receiver;
+use futuresdr::channel::mpsc::Sender;
+use futuresdr::prelude::*;
+
+use crate::decoder::annotated_payload;
+use crate::meshtastic::BROADCAST;
+use crate::meshtastic::MeshPacket;
+use crate::meshtastic::MeshtasticChannel;
+use crate::meshtastic::MeshtasticChannels;
+use crate::meshtastic::RxMetadata;
+use crate::meshtastic_pki::PKI_CHANNEL_HASH;
+use crate::meshtastic_pki::PKI_CHANNEL_NAME;
+use crate::meshtastic_region::MeshtasticRegion;
+use crate::meshtastic_region::PRESETS;
+
+/// Connection settings and identity of a Meshtastic MQTT gateway.
+#[derive(Debug, Clone)]
+pub struct MeshtasticMqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+    /// node number of the gateway, the `gateway_id` of its envelopes
+    pub gateway_num: u32,
+    /// topics are `<root>/2/e/<channel>/<gateway id>`, `msh/<region>` like the firmware by default
+    pub root_topic: String,
+    /// also publish the decrypted packets on `<root>/2/c/<channel>/<gateway id>`
+    pub publish_decrypted: bool,
+    /// subscribe to the channels and hand envelopes of other gateways to the transmitter
+    pub downlink: bool,
+    /// name the unnamed channel is known by for downlinks, the preset of the transmitter
+    pub preset_name: String,
+    /// how long packet IDs are remembered to drop packets that came back over the other side, and nodes heard
+    /// over the air are considered local
+    pub history_ttl: Duration,
+    pub keep_alive: Duration,
+    /// delay before reconnecting after the connection failed, doubled on every further attempt
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+}
+
+impl MeshtasticMqttConfig {
+    pub fn new(host: &str, port: u16, region: MeshtasticRegion, gateway_num: u32) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            credentials: None,
+            gateway_num,
+            root_topic: format!("msh/{region}"),
+            publish_decrypted: false,
+            downlink: false,
+            preset_name: PRESETS[0].name.to_string(),
+            history_ttl: Duration::from_secs(600),
+            keep_alive: Duration::from_secs(30),
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+        }
+    }
+
+    fn gateway_id(&self) -> String {
+        format!("!{:08x}", self.gateway_num)
+    }
+
+    /// `<root>/2/<kind>/<channel>/<gateway id>`
+    fn topic(&self, kind: &str, channel_id: &str) -> String {
+        format!(
+            "{}/2/{}/{}/{}",
+            self.root_topic,
+            kind,
+            channel_id,
+            self.gateway_id()
+        )
+    }
+
+    fn stat_topic(&self) -> String {
+        format!("{}/2/stat/{}", self.root_topic, self.gateway_id())
+    }
+
+    /// Channel ID of an MQTT topic, i.e. the channel name or the preset name of the unnamed channel.
+    fn channel_id(&self, chan: &MeshtasticChannel) -> String {
+        if chan.name().is_empty() {
+            self.preset_name.clone()
+        } else {
+            chan.name().to_string()
+        }
+    }
+}
+
+/// Channel ID of a received packet: the channel name, or for the unnamed channel the preset name that gives
+/// the packet's channel hash. `None` if the hash does not belong to the channel.
+fn uplink_channel_id(chan: &MeshtasticChannel, channel_hash: u8) -> Option<String> {
+    if !chan.name().is_empty() {
+        return (chan.hash_value() == channel_hash).then(|| chan.name().to_string());
+    }
+    PRESETS
+        .iter()
+        .map(|preset| preset.name)
+        .find(|name| chan.hash_with(name) == channel_hash)
+        .map(str::to_string)
+}
+
+fn now_secs() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// `MeshPacket` protobuf of a received frame with the given payload.
+fn packet_protobuf(
+    packet: &MeshPacket,
+    rx: &RxMetadata,
+    channel: u32,
+    payload: mesh_packet::PayloadVariant,
+) -> protobufs::MeshPacket {
+    protobufs::MeshPacket {
+        from: packet.sender,
+        to: packet.dest,
+        channel,
+        id: packet.packet_id,
+        rx_time: now_secs(),
+        rx_snr: rx.snr.unwrap_or_default() as f32,
+        rx_rssi: rx.rssi.unwrap_or_default() as i32,
+        hop_limit: packet.hop_limit as u32,
+        want_ack: packet.want_ack,
+        via_mqtt: packet.via_mqtt,
+        hop_start: packet.hop_start as u32,
+        payload_variant: Some(payload),
+        ..Default::default()
+    }
+}
+
+/// Frame received over the air, on its way to the broker.
+struct Uplink {
+    packet: MeshPacket,
+    rx: RxMetadata,
+    /// channel ID, channel index and `Data` message, if the packet could be decrypted
+    decoded: Option<(String, usize, protobufs::Data)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MqttDownlinkError {
+    /// envelope without packet or with a packet that does not fit a frame
+    InvalidPacket,
+    /// envelope published by this gateway
+    OwnGateway,
+    /// packet sent by the gateway node
+    FromUs,
+    /// packet already heard over the air or sent before
+    Duplicate(u32, u32),
+    /// no channel with this ID
+    UnknownChannel(String),
+    /// direct message (sender, destination) neither for us nor between nodes heard over the air
+    PkiNotLocal(u32, u32),
+}
+
+impl Display for MqttDownlinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidPacket => write!(f, "envelope without valid packet"),
+            Self::OwnGateway => write!(f, "envelope of this gateway"),
+            Self::FromUs => write!(f, "packet of the gateway node"),
+            Self::Duplicate(sender, id) => {
+                write!(f, "packet {id:08x} of !{sender:08x} already seen")
+            }
+            Self::UnknownChannel(channel) => write!(f, "unknown channel {channel:?}"),
+            Self::PkiNotLocal(sender, dest) => {
+                write!(f, "direct message !{sender:08x} to !{dest:08x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MqttDownlinkError {}
+
+/// Loop avoidance and envelope conversion of the MQTT task.
+struct MqttBridge {
+    config: MeshtasticMqttConfig,
+    channels: Vec<MeshtasticChannel>,
+    /// (sender, packet id) of packets published or transmitted
+    history: HashMap<(u32, u32), Instant>,
+    /// nodes heard over the air, with the time they were last heard
+    local_nodes: HashMap<u32, Instant>,
+}
+
+impl MqttBridge {
+    /// Forget packets and local nodes not seen within the history TTL.
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.config.history_ttl;
+        self.history
+            .retain(|_, seen| now.duration_since(*seen) < ttl);
+        self.local_nodes
+            .retain(|_, heard| now.duration_since(*heard) < ttl);
+    }
+
+    /// Remember a packet, returns `false` if it was seen before.
+    fn remember(&mut self, sender: u32, packet_id: u32) -> bool {
+        self.history
+            .insert((sender, packet_id), Instant::now())
+            .is_none()
+    }
+
+    /// Topics and envelopes of a received frame, empty for frames that must not be published.
+    fn uplink(&mut self, uplink: Uplink) -> Vec<(String, Vec<u8>)> {
+        let Uplink {
+            packet,
+            rx,
+            decoded,
+        } = uplink;
+        let now = Instant::now();
+        self.expire(now);
+        if !packet.via_mqtt {
+            self.local_nodes.insert(packet.sender, now);
+        }
+        // packets heard over the air must not be transmitted again when they come back from the broker
+        let new = self.remember(packet.sender, packet.packet_id);
+        // the firmware never returns packets to MQTT that came from there
+        if packet.via_mqtt || !new {
+            return Vec::new();
+        }
+        let channel_id = match &decoded {
+            Some((channel_id, _, _)) => channel_id.clone(),
+            // direct messages of other nodes can only be forwarded encrypted
+            None if packet.channel_hash == PKI_CHANNEL_HASH && packet.dest != BROADCAST => {
+                PKI_CHANNEL_NAME.to_string()
+            }
+            None => return Vec::new(),
+        };
+
+        let envelope = |packet: protobufs::MeshPacket| protobufs::ServiceEnvelope {
+            packet: Some(packet),
+            channel_id: channel_id.clone(),
+            gateway_id: self.config.gateway_id(),
+        };
+        let mut encrypted = packet_protobuf(
+            &packet,
+            &rx,
+            packet.channel_hash as u32,
+            mesh_packet::PayloadVariant::Encrypted(packet.data.clone()),
+        );
+        encrypted.pki_encrypted = channel_id == PKI_CHANNEL_NAME;
+        let mut messages = vec![(
+            self.config.topic("e", &channel_id),
+            envelope(encrypted).encode_to_vec(),
+        )];
+        if let (true, Some((_, index, data))) = (self.config.publish_decrypted, decoded) {
+            let decrypted = packet_protobuf(
+                &packet,
+                &rx,
+                index as u32,
+                mesh_packet::PayloadVariant::Decoded(data),
+            );
+            messages.push((
+                self.config.topic("c", &channel_id),
+                envelope(decrypted).encode_to_vec(),
+            ));
+        }
+        messages
+    }
+
+    /// Channel of a downlink, the unnamed channel is known by the preset name.
+    fn downlink_channel(&self, channel_id: &str) -> Option<MeshtasticChannel> {
+        if let Some(chan) = self.channels.iter().find(|c| c.name() == channel_id) {
+            return Some(chan.clone());
+        }
+        let chan = self.channels.iter().find(|c| c.name().is_empty())?;
+        if channel_id != self.config.preset_name {
+            return None;
+        }
+        MeshtasticChannel::from_psk("", channel_id, chan.psk()).ok()
+    }
+
+    /// Frame to transmit for an envelope of another gateway.
+    fn downlink(
+        &mut self,
+        envelope: protobufs::ServiceEnvelope,
+    ) -> Result<Vec<u8>, MqttDownlinkError> {
+        if envelope.gateway_id == self.config.gateway_id() {
+            return Err(MqttDownlinkError::OwnGateway);
+        }
+        self.expire(Instant::now());
+        let packet = envelope.packet.ok_or(MqttDownlinkError::InvalidPacket)?;
+        if packet.from == self.config.gateway_num {
+            return Err(MqttDownlinkError::FromUs);
+        }
+        if packet.hop_limit > 7 || packet.hop_start > 7 {
+            return Err(MqttDownlinkError::InvalidPacket);
+        }
+
+        let mut frame = MeshPacket {
+            dest: packet.to,
+            sender: packet.from,
+            packet_id: packet.id,
+            hop_limit: packet.hop_limit as u8,
+            want_ack: packet.want_ack,
+            // marks the packet for all receivers, so that no gateway publishes it again
+            via_mqtt: true,
+            hop_start: packet.hop_start as u8,
+            channel_hash: 0,
+            next_hop: 0,
+            relay_node: 0,
+            data: Vec::new(),
+        };
+        match packet.payload_variant {
+            Some(mesh_packet::PayloadVariant::Encrypted(data)) => {
+                // like the firmware, only direct messages for us or between local nodes go on air
+                if envelope.channel_id == PKI_CHANNEL_NAME
+                    && packet.to != self.config.gateway_num
+                    && !(self.local_nodes.contains_key(&packet.from)
+                        && self.local_nodes.contains_key(&packet.to))
+                {
+                    return Err(MqttDownlinkError::PkiNotLocal(packet.from, packet.to));
+                }
+                if envelope.channel_id != PKI_CHANNEL_NAME
+                    && self.downlink_channel(&envelope.channel_id).is_none()
+                {
+                    return Err(MqttDownlinkError::UnknownChannel(envelope.channel_id));
+                }
+                frame.channel_hash = packet.channel as u8;
+                frame.data = data;
+            }
+            Some(mesh_packet::PayloadVariant::Decoded(data)) => {
+                let chan = self
+                    .downlink_channel(&envelope.channel_id)
+                    .ok_or(MqttDownlinkError::UnknownChannel(envelope.channel_id))?;
+                chan.encrypt(&mut frame, &data);
+            }
+            None => return Err(MqttDownlinkError::InvalidPacket),
+        }
+        if frame.data.is_empty() {
+            return Err(MqttDownlinkError::InvalidPacket);
+        }
+        if !self.remember(frame.sender, frame.packet_id) {
+            return Err(MqttDownlinkError::Duplicate(frame.sender, frame.packet_id));
+        }
+        Ok(frame.to_bytes())
+    }
+
+    /// Topics of the channels accepted for downlinks.
+    fn downlink_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self
+            .channels
+            .iter()
+            .map(|chan| {
+                format!(
+                    "{}/2/e/{}/+",
+                    self.config.root_topic,
+                    self.config.channel_id(chan)
+                )
+            })
+            .collect();
+        topics.push(format!(
+            "{}/2/e/{}/+",
+            self.config.root_topic, PKI_CHANNEL_NAME
+        ));
+        topics
+    }
+}
+
+/// Bridge a Meshtastic mesh to MQTT like the firmware's MQTT module.
+///
+/// Decoded frames (the `out_annotated` messages of [`Decoder`](crate::Decoder)s) are published as
+/// `ServiceEnvelope`s with the encrypted packet and, if enabled, the decrypted packet. Packets with the
+/// via_mqtt flag are not published. With downlinks enabled, envelopes of other gateways on the configured
+/// channels are turned into frames for a [`Transmitter`](crate::Transmitter), with the via_mqtt flag set and
+/// duplicates of packets already heard or sent dropped. Direct messages are only transmitted if they are
+/// addressed to the gateway node or both nodes were heard over the air within the history TTL.
+#[derive(Block)]
+#[message_inputs(r#in)]
+#[null_kernel]
+pub struct MeshtasticMqttGateway {
+    channels: MeshtasticChannels,
+    shutdown_trigger: Trigger,
+    to_mqtt: Sender<Uplink>,
+    downlink_receiver: Option<Receiver<Vec<u8>>>,
+    #[allow(dead_code)]
+    runtime: Runtime,
+}
+
+impl MeshtasticMqttGateway {
+    /// Gateway for the given channels, the first one is the primary channel.
+    pub fn new(
+        config: MeshtasticMqttConfig,
+        channels: Vec<MeshtasticChannel>,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(!channels.is_empty(), "MQTT gateway without channels");
+        let (to_mqtt, from_block) = mpsc::channel::<Uplink>(64);
+        let (downlink_sender, downlink_receiver) = mpsc::channel::<Vec<u8>>(16);
+        let (shutdown_trigger, shutdown_signal) = triggered::trigger();
+
+        let bridge = MqttBridge {
+            config,
+            channels: channels.clone(),
+            history: HashMap::new(),
+            local_nodes: HashMap::new(),
+        };
+        let runtime = Runtime::new().context("creating tokio runtime")?;
+        runtime.spawn(run_mqtt(
+            bridge,
+            from_block,
+            downlink_sender,
+            shutdown_signal,
+        ));
+
+        Ok(Self {
+            channels: MeshtasticChannels::from_channels(channels),
+            shutdown_trigger,
+            to_mqtt,
+            downlink_receiver: Some(downlink_receiver),
+            runtime,
+        })
+    }
+
+    /// Frames of downlink envelopes, to be sent as `Pmt::Blob` to the `msg` handler of a
+    /// [`Transmitter`](crate::Transmitter).
+    pub fn take_downlinks(&mut self) -> Option<Receiver<Vec<u8>>> {
+        self.downlink_receiver.take()
+    }
+
+    fn decode(&self, m: &HashMap<String, Pmt>) -> Option<Uplink> {
+        let payload = annotated_payload(m)?;
+        let packet = match MeshPacket::parse(&payload) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("not a Meshtastic frame: {}", e);
+                return None;
+            }
+        };
+        let decoded = self.channels.decrypt(&packet).and_then(|(index, data)| {
+            let index = index?;
+            let chan = &self.channels.channels()[index];
+            Some((uplink_channel_id(chan, packet.channel_hash)?, index, data))
+        });
+        Some(Uplink {
+            packet,
+            rx: RxMetadata::from_annotations(m),
+            decoded,
+        })
+    }
+
+    async fn r#in(
+        &mut self,
+        io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::Finished => {
+                self.shutdown_trigger.trigger();
+                io.finished = true;
+            }
+            Pmt::MapStrPmt(m) => match self.decode(&m) {
+                Some(uplink) => {
+                    if let Err(e) = self.to_mqtt.try_send(uplink) {
+                        warn!("dropping uplink: {}", e);
+                    }
+                }
+                None => return Ok(Pmt::InvalidValue),
+            },
+            _ => return Ok(Pmt::InvalidValue),
+        }
+        Ok(Pmt::Ok)
+    }
+}
+
+/// Drive the MQTT connection, publishing uplinks and handing downlinks to the transmitter.
+async fn run_mqtt(
+    mut bridge: MqttBridge,
+    mut from_block: Receiver<Uplink>,
+    mut downlink_sender: Sender<Vec<u8>>,
+    shutdown: Listener,
+) {
+    let config = bridge.config.clone();
+    let stat_topic = config.stat_topic();
+    let downlink_topics = bridge.downlink_topics();
+
+    let mut options = MqttOptions::new(
+        format!("futuresdr-meshtastic-{}", config.gateway_id()),
+        config.host.as_str(),
+        config.port,
+    );
+    options.set_keep_alive(config.keep_alive);
+    options.set_last_will(LastWill::new(
+        stat_topic.as_str(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some((user, password)) = &config.credentials {
+        options.set_credentials(user.as_str(), password.as_str());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+    let mut backoff = config.backoff_base;
+    loop {
+        tokio::select! {
+            _ = shutdown.clone() => {
+                let _ = client.try_publish(stat_topic.as_str(), QoS::AtLeastOnce, true, "offline");
+                let _ = client.try_disconnect();
+                return;
+            }
+            uplink = from_block.next() => {
+                let Some(uplink) = uplink else {
+                    return;
+                };
+                for (topic, envelope) in bridge.uplink(uplink) {
+                    if let Err(e) = client.try_publish(topic.as_str(), QoS::AtMostOnce, false, envelope) {
+                        warn!("dropping uplink: {}", e);
+                    }
+                }
+            }
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("connected to MQTT broker {}:{}", config.host, config.port);
+                    backoff = config.backoff_base;
+                    if config.downlink {
+                        for topic in &downlink_topics {
+                            if let Err(e) = client.try_subscribe(topic.as_str(), QoS::AtLeastOnce) {
+                                warn!("could not subscribe to {}: {}", topic, e);
+                            }
+                        }
+                    }
+                    let _ = client.try_publish(stat_topic.as_str(), QoS::AtLeastOnce, true, "online");
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let envelope = match protobufs::ServiceEnvelope::decode(&*publish.payload) {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            debug!("invalid envelope on {}: {}", publish.topic, e);
+                            continue;
+                        }
+                    };
+                    match bridge.downlink(envelope) {
+                        Ok(frame) => {
+                            if downlink_sender.try_send(frame).is_err() {
+                                warn!("no transmitter attached, dropping downlink");
+                            }
+                        }
+                        Err(e) => debug!("not transmitting envelope on {}: {}", publish.topic, e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // the event loop reconnects on the next poll
+                    warn!("MQTT connection failed: {}", e);
+                    tokio::select! {
+                        _ = shutdown.clone() => return,
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                    backoff = (backoff * 2).min(config.backoff_max);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAY: u32 = 0x0000_0001;
+    const NODE_A: u32 = 0x0000_000a;
+    const NODE_B: u32 = 0x0000_000b;
+
+    fn bridge() -> MqttBridge {
+        let mut config =
+            MeshtasticMqttConfig::new("localhost", 1883, MeshtasticRegion::EU_868, GATEWAY);
+        config.downlink = true;
+        MqttBridge {
+            config,
+            channels: vec![MeshtasticChannel::new("", "AQ==")],
+            history: HashMap::new(),
+            local_nodes: HashMap::new(),
+        }
+    }
+
+    fn text() -> protobufs::Data {
+        protobufs::Data {
+            portnum: protobufs::PortNum::TextMessageApp as i32,
+            payload: b"hi".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn received(sender: u32, packet_id: u32, via_mqtt: bool) -> Uplink {
+        let packet = MeshPacket {
+            dest: BROADCAST,
+            sender,
+            packet_id,
+            hop_limit: 3,
+            want_ack: false,
+            via_mqtt,
+            hop_start: 3,
+            channel_hash: 8,
+            next_hop: 0,
+            relay_node: 0,
+            data: vec![1, 2, 3],
+        };
+        Uplink {
+            packet,
+            rx: RxMetadata::default(),
+            decoded: Some((String::from("LongFast"), 0, text())),
+        }
+    }
+
+    fn envelope(
+        gateway: u32,
+        channel_id: &str,
+        packet: protobufs::MeshPacket,
+    ) -> protobufs::ServiceEnvelope {
+        protobufs::ServiceEnvelope {
+            packet: Some(packet),
+            channel_id: channel_id.to_string(),
+            gateway_id: format!("!{gateway:08x}"),
+        }
+    }
+
+    fn broadcast(gateway: u32, from: u32, id: u32) -> protobufs::ServiceEnvelope {
+        let packet = protobufs::MeshPacket {
+            from,
+            to: BROADCAST,
+            id,
+            hop_limit: 2,
+            hop_start: 3,
+            payload_variant: Some(mesh_packet::PayloadVariant::Decoded(text())),
+            ..Default::default()
+        };
+        envelope(gateway, "LongFast", packet)
+    }
+
+    fn direct(from: u32, to: u32, id: u32) -> protobufs::ServiceEnvelope {
+        let packet = protobufs::MeshPacket {
+            from,
+            to,
+            id,
+            hop_limit: 3,
+            hop_start: 3,
+            pki_encrypted: true,
+            payload_variant: Some(mesh_packet::PayloadVariant::Encrypted(vec![0; 20])),
+            ..Default::default()
+        };
+        envelope(2, PKI_CHANNEL_NAME, packet)
+    }
+
+    #[test]
+    fn uplink_published_once() {
+        let mut bridge = bridge();
+        let messages = bridge.uplink(received(NODE_A, 1, false));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "msh/EU_868/2/e/LongFast/!00000001");
+        let envelope = protobufs::ServiceEnvelope::decode(&*messages[0].1).unwrap();
+        assert_eq!(envelope.gateway_id, "!00000001");
+        assert_eq!(envelope.packet.unwrap().id, 1);
+
+        // another copy, e.g. rebroadcast by a neighbour
+        assert!(bridge.uplink(received(NODE_A, 1, false)).is_empty());
+        // packets that came from MQTT do not go back
+        assert!(bridge.uplink(received(NODE_B, 2, true)).is_empty());
+    }
+
+    #[test]
+    fn downlink_loop_avoidance() {
+        let mut bridge = bridge();
+        assert_eq!(
+            bridge.downlink(broadcast(GATEWAY, NODE_A, 1)),
+            Err(MqttDownlinkError::OwnGateway)
+        );
+        assert_eq!(
+            bridge.downlink(broadcast(2, GATEWAY, 1)),
+            Err(MqttDownlinkError::FromUs)
+        );
+
+        // heard over the air and published, then returned by another gateway
+        bridge.uplink(received(NODE_A, 1, false));
+        assert_eq!(
+            bridge.downlink(broadcast(2, NODE_A, 1)),
+            Err(MqttDownlinkError::Duplicate(NODE_A, 1))
+        );
+
+        let frame = bridge.downlink(broadcast(2, NODE_B, 2)).unwrap();
+        let frame = MeshPacket::parse(&frame).unwrap();
+        assert!(frame.via_mqtt);
+        assert_eq!(frame.hop_limit, 2);
+        let chan = bridge.downlink_channel("LongFast").unwrap();
+        assert_eq!(chan.decrypt(&frame), Some(text()));
+
+        // the same envelope from a third gateway, and the own transmission heard back
+        assert_eq!(
+            bridge.downlink(broadcast(3, NODE_B, 2)),
+            Err(MqttDownlinkError::Duplicate(NODE_B, 2))
+        );
+        let echo = Uplink {
+            packet: frame,
+            rx: RxMetadata::default(),
+            decoded: None,
+        };
+        assert!(bridge.uplink(echo).is_empty());
+    }
+
+    #[test]
+    fn direct_messages_only_between_local_nodes() {
+        let mut bridge = bridge();
+        assert_eq!(
+            bridge.downlink(direct(NODE_A, NODE_B, 1)),
+            Err(MqttDownlinkError::PkiNotLocal(NODE_A, NODE_B))
+        );
+        assert!(bridge.downlink(direct(NODE_A, GATEWAY, 2)).is_ok());
+
+        bridge.uplink(received(NODE_A, 3, false));
+        bridge.uplink(received(NODE_B, 4, false));
+        assert!(bridge.downlink(direct(NODE_A, NODE_B, 5)).is_ok());
+
+        // nodes not heard within the history TTL are no longer local
+        bridge.config.history_ttl = Duration::ZERO;
+        assert_eq!(
+            bridge.downlink(direct(NODE_A, NODE_B, 6)),
+            Err(MqttDownlinkError::PkiNotLocal(NODE_A, NODE_B))
+        );
+        assert!(bridge.local_nodes.is_empty());
+    }
+}
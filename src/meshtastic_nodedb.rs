@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use base64::prelude::*;
+use meshtastic::protobufs;
+use meshtastic::protobufs::HardwareModel;
+use meshtastic::protobufs::telemetry::Variant;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::meshtastic::MeshtasticEvent;
+use crate::meshtastic::MeshtasticPayload;
+
+/// How often packet counters and link metrics are written by [`NodeDb::save_if_needed`]
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NodePosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// meters above MSL
+    pub altitude: Option<i32>,
+    /// seconds since the Unix epoch, as reported by the node
+    pub time: u32,
+}
+
+/// What is known about one node of the mesh.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NodeEntry {
+    pub num: u32,
+    pub id: String,
+    pub long_name: Option<String>,
+    pub short_name: Option<String>,
+    pub hw_model: Option<String>,
+    /// base64-encoded PKI public key
+    pub public_key: Option<String>,
+    /// seconds since the Unix epoch
+    pub last_heard: u64,
+    pub last_snr: Option<f64>,
+    /// uncalibrated, see [`RxMetadata::rssi`](crate::meshtastic::RxMetadata::rssi)
+    pub last_rssi: Option<f64>,
+    /// hops of the last packet, if the sender reported its hop start
+    pub hops_away: Option<u8>,
+    pub position: Option<NodePosition>,
+    pub battery_level: Option<u32>,
+    pub voltage: Option<f32>,
+    pub packets: u64,
+}
+
+impl NodeEntry {
+    fn new(num: u32) -> Self {
+        Self {
+            num,
+            id: format!("!{num:08x}"),
+            ..Default::default()
+        }
+    }
+
+    fn update_user(&mut self, user: &protobufs::User) {
+        self.long_name = Some(user.long_name.clone());
+        self.short_name = Some(user.short_name.clone());
+        self.hw_model = HardwareModel::try_from(user.hw_model)
+            .ok()
+            .map(|m| m.as_str_name().to_string());
+        if !user.public_key.is_empty() {
+            self.public_key = Some(BASE64_STANDARD.encode(&user.public_key));
+        }
+    }
+
+    fn update_position(&mut self, position: &protobufs::Position) {
+        let (Some(latitude), Some(longitude)) = (position.latitude_i, position.longitude_i) else {
+            return;
+        };
+        self.position = Some(NodePosition {
+            latitude: latitude as f64 * 1e-7,
+            longitude: longitude as f64 * 1e-7,
+            altitude: position.altitude,
+            time: position.time,
+        });
+    }
+
+    fn update_telemetry(&mut self, telemetry: &protobufs::Telemetry) {
+        if let Some(Variant::DeviceMetrics(metrics)) = &telemetry.variant {
+            self.battery_level = metrics.battery_level.or(self.battery_level);
+            self.voltage = metrics.voltage.or(self.voltage);
+        }
+    }
+
+    /// `NodeInfo` as the firmware reports it to clients.
+    pub fn to_node_info(&self) -> protobufs::NodeInfo {
+        let user = self.long_name.as_ref().map(|long_name| protobufs::User {
+            id: self.id.clone(),
+            long_name: long_name.clone(),
+            short_name: self.short_name.clone().unwrap_or_default(),
+            hw_model: self
+                .hw_model
+                .as_deref()
+                .and_then(HardwareModel::from_str_name)
+                .map_or(0, |m| m as i32),
+            public_key: self
+                .public_key
+                .as_deref()
+                .and_then(|key| BASE64_STANDARD.decode(key).ok())
+                .unwrap_or_default(),
+            ..Default::default()
+        });
+        let position = self.position.as_ref().map(|p| protobufs::Position {
+            latitude_i: Some((p.latitude * 1e7).round() as i32),
+            longitude_i: Some((p.longitude * 1e7).round() as i32),
+            altitude: p.altitude,
+            time: p.time,
+            ..Default::default()
+        });
+        let device_metrics = (self.battery_level.is_some() || self.voltage.is_some()).then(|| {
+            protobufs::DeviceMetrics {
+                battery_level: self.battery_level,
+                voltage: self.voltage,
+                ..Default::default()
+            }
+        });
+        protobufs::NodeInfo {
+            num: self.num,
+            user,
+            position,
+            snr: self.last_snr.unwrap_or_default() as f32,
+            last_heard: self.last_heard as u32,
+            device_metrics,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DbFile {
+    nodes: Vec<NodeEntry>,
+}
+
+/// Nodes heard on the mesh, fed with decoded packets and persisted as JSON.
+#[derive(Debug, Default)]
+pub struct NodeDb {
+    nodes: BTreeMap<u32, NodeEntry>,
+    path: Option<PathBuf>,
+    /// updates not written to the file yet
+    dirty: bool,
+    /// new nodes, names, keys or positions not written to the file yet
+    changed: bool,
+    last_save: Option<Instant>,
+}
+
+impl NodeDb {
+    /// In-memory database.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Database backed by a JSON file, empty if the file does not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut db = if path.exists() {
+            Self::load(path)?
+        } else {
+            Self::new()
+        };
+        db.path = Some(path.to_path_buf());
+        Ok(db)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file: DbFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(Self {
+            nodes: file.nodes.into_iter().map(|n| (n.num, n)).collect(),
+            ..Self::default()
+        })
+    }
+
+    /// Write to the file the database was opened from, if any.
+    pub fn save(&self) -> anyhow::Result<()> {
+        match &self.path {
+            Some(path) => self.save_to(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Write new nodes, names, keys and positions right away, but packet counters and link metrics at most
+    /// every [`SAVE_INTERVAL`]. Returns whether the file was written.
+    pub fn save_if_needed(&mut self) -> anyhow::Result<bool> {
+        let due = self
+            .last_save
+            .is_none_or(|saved| saved.elapsed() >= SAVE_INTERVAL);
+        if self.path.is_none() || !(self.changed || self.dirty && due) {
+            return Ok(false);
+        }
+        self.save()?;
+        self.dirty = false;
+        self.changed = false;
+        self.last_save = Some(Instant::now());
+        Ok(true)
+    }
+
+    pub fn save_to(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let file = DbFile {
+            nodes: self.nodes.values().cloned().collect(),
+        };
+        // write and rename, so that readers never see a partial file
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&file)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Update the sender of a decoded packet.
+    pub fn update(&mut self, event: &MeshtasticEvent) -> &NodeEntry {
+        let rx = event.rx.as_ref();
+        let last_heard = rx
+            .and_then(|rx| rx.timestamp)
+            .map(|t| t / 1_000_000_000)
+            .unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+            });
+
+        let new = !self.nodes.contains_key(&event.from);
+        let node = self
+            .nodes
+            .entry(event.from)
+            .or_insert_with(|| NodeEntry::new(event.from));
+        node.packets += 1;
+        node.last_heard = node.last_heard.max(last_heard);
+        if let Some(rx) = rx {
+            node.last_snr = rx.snr.or(node.last_snr);
+            node.last_rssi = rx.rssi.or(node.last_rssi);
+        }
+        if event.hop_start != 0 {
+            node.hops_away = Some(event.hop_start.saturating_sub(event.hop_limit));
+        }
+        let before = node.clone();
+        match &event.payload {
+            MeshtasticPayload::NodeInfo(user) => node.update_user(user),
+            MeshtasticPayload::Position(position) => node.update_position(position),
+            MeshtasticPayload::Telemetry(telemetry) => node.update_telemetry(telemetry),
+            _ => {}
+        }
+        self.dirty = true;
+        self.changed |= new
+            || node.long_name != before.long_name
+            || node.short_name != before.short_name
+            || node.public_key != before.public_key
+            || node.position != before.position;
+        node
+    }
+
+    pub fn get(&self, num: u32) -> Option<&NodeEntry> {
+        self.nodes.get(&num)
+    }
+
+    /// Nodes ordered by node number.
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeEntry> {
+        self.nodes.values()
+    }
+
+    /// Nodes, most recently heard first.
+    pub fn by_last_heard(&self) -> Vec<&NodeEntry> {
+        let mut nodes: Vec<_> = self.nodes.values().collect();
+        nodes.sort_by(|a, b| b.last_heard.cmp(&a.last_heard));
+        nodes
+    }
+
+    /// Nodes heard within the last `seconds`.
+    pub fn heard_within(&self, seconds: u64) -> Vec<&NodeEntry> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.by_last_heard()
+            .into_iter()
+            .filter(|n| n.last_heard + seconds >= now)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(from: u32, payload: MeshtasticPayload) -> MeshtasticEvent {
+        MeshtasticEvent {
+            from,
+            to: 0xffff_ffff,
+            packet_id: 1,
+            hop_limit: 3,
+            hop_start: 3,
+            want_ack: false,
+            via_mqtt: false,
+            next_hop: 0,
+            relay_node: 0,
+            channel: String::from("LongFast"),
+            channel_hash: 8,
+            rx: None,
+            payload,
+        }
+    }
+
+    #[test]
+    fn saves_changes_but_throttles_counters() {
+        let path = std::env::temp_dir().join(format!("nodedb-{}.json", std::process::id()));
+        let mut db = NodeDb::open(&path).unwrap();
+        let text = || MeshtasticPayload::TextMessage {
+            text: String::from("hi"),
+        };
+        let user = protobufs::User {
+            long_name: String::from("Node"),
+            short_name: String::from("N"),
+            ..Default::default()
+        };
+
+        db.update(&event(1, text()));
+        assert!(db.save_if_needed().unwrap());
+        db.update(&event(1, text()));
+        assert!(!db.save_if_needed().unwrap());
+        db.update(&event(1, MeshtasticPayload::NodeInfo(user.clone())));
+        assert!(db.save_if_needed().unwrap());
+        db.update(&event(1, MeshtasticPayload::NodeInfo(user)));
+        assert!(!db.save_if_needed().unwrap());
+        db.update(&event(2, text()));
+        assert!(db.save_if_needed().unwrap());
+
+        let saved = NodeDb::load(&path).unwrap();
+        assert_eq!(saved.get(1).unwrap().long_name.as_deref(), Some("Node"));
+        assert_eq!(saved.get(1).unwrap().packets, 4);
+        assert_eq!(saved.len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use aes::Aes256;
+use base64::prelude::*;
+use ccm::Ccm;
+use ccm::aead::Aead;
+use ccm::aead::KeyInit;
+use ccm::consts::U8;
+use ccm::consts::U13;
+use futuresdr::tracing::warn;
+use meshtastic::Message;
+use meshtastic::protobufs;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use x25519_dalek::PublicKey;
+use x25519_dalek::StaticSecret;
+
+use crate::meshtastic::BROADCAST;
+use crate::meshtastic::MeshPacket;
+use crate::meshtastic::MeshtasticEncoder;
+use crate::meshtastic::MeshtasticEvent;
+use crate::meshtastic::MeshtasticPayload;
+
+/// Channel hash of PKI-encrypted packets in the radio header
+pub const PKI_CHANNEL_HASH: u8 = 0;
+/// Bytes added to the `Data` message: 8 byte authentication tag and 4 byte extra nonce
+pub const PKI_OVERHEAD: usize = 12;
+/// Channel name reported for decrypted direct messages
+pub const PKI_CHANNEL_NAME: &str = "PKI";
+
+const TAG_LEN: usize = 8;
+const EXTRA_NONCE_LEN: usize = 4;
+
+type Aes256Ccm = Ccm<Aes256, U8, U13>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PkiError {
+    /// key that is not 32 bytes long
+    InvalidKey(usize),
+    /// payload shorter than the PKI overhead
+    TooShort(usize),
+    /// broadcast packets are encrypted with the channel key
+    Broadcast,
+    /// packet addressed to another node
+    NotForUs(u32),
+    /// no public key learned for this node
+    UnknownNode(u32),
+    /// NodeInfo with another public key than the one learned for this node
+    KeyMismatch(u32),
+    /// authentication tag did not match
+    Decrypt,
+    /// decrypted payload is no `Data` message
+    InvalidData,
+}
+
+impl Display for PkiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidKey(len) => write!(f, "invalid key of {len} bytes (32 expected)"),
+            Self::TooShort(len) => write!(f, "PKI payload of {len} bytes too short"),
+            Self::Broadcast => write!(f, "broadcast packets can not be PKI-encrypted"),
+            Self::NotForUs(dest) => write!(f, "packet addressed to !{dest:08x}"),
+            Self::UnknownNode(node) => write!(f, "no public key of !{node:08x}"),
+            Self::KeyMismatch(node) => write!(f, "public key of !{node:08x} does not match"),
+            Self::Decrypt => write!(f, "authentication failed"),
+            Self::InvalidData => write!(f, "payload is no Data message"),
+        }
+    }
+}
+
+impl std::error::Error for PkiError {}
+
+fn key_from_slice(bytes: &[u8]) -> Result<[u8; 32], PkiError> {
+    bytes
+        .try_into()
+        .map_err(|_| PkiError::InvalidKey(bytes.len()))
+}
+
+/// Curve25519 key pair of a node.
+#[derive(Clone)]
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self::from_private(rand::random())
+    }
+
+    pub fn from_private(private_key: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(private_key);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn from_base64(private_key: &str) -> anyhow::Result<Self> {
+        let bytes = BASE64_STANDARD.decode(private_key.trim())?;
+        Ok(Self::from_private(key_from_slice(&bytes)?))
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    pub fn private_key(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// AES-256 key shared with a peer: SHA-256 of the X25519 secret, as in the firmware.
+    fn shared_key(&self, public_key: &[u8; 32]) -> [u8; 32] {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*public_key));
+        Sha256::digest(shared.as_bytes()).into()
+    }
+}
+
+impl std::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public", &BASE64_STANDARD.encode(self.public_key()))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Nonce of the firmware's `CryptoEngine::initNonce`, truncated to the 13 bytes CCM uses.
+fn nonce(packet_id: u32, sender: u32, extra_nonce: u32) -> [u8; 13] {
+    let mut nonce = [0u8; 13];
+    nonce[0..4].copy_from_slice(&packet_id.to_le_bytes());
+    nonce[4..8].copy_from_slice(&extra_nonce.to_le_bytes());
+    nonce[8..12].copy_from_slice(&sender.to_le_bytes());
+    nonce
+}
+
+/// On-disk format of [`PkiKeys`]
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    node_num: u32,
+    private_key: String,
+    #[serde(default)]
+    peers: HashMap<String, String>,
+}
+
+/// Own key pair and the public keys learned from other nodes.
+///
+/// Direct messages between nodes that know each other's public key are encrypted with AES-256-CCM instead
+/// of the channel key (firmware 2.5 and later). They are sent with channel hash [`PKI_CHANNEL_HASH`] and
+/// carry [`PKI_OVERHEAD`] extra bytes.
+#[derive(Debug, Clone)]
+pub struct PkiKeys {
+    node_num: u32,
+    key_pair: KeyPair,
+    peers: HashMap<u32, [u8; 32]>,
+}
+
+impl PkiKeys {
+    pub fn new(node_num: u32, key_pair: KeyPair) -> Self {
+        Self {
+            node_num,
+            key_pair,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Load keys from a JSON file or create it with a fresh key pair.
+    pub fn load_or_generate(path: impl AsRef<Path>, node_num: u32) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let keys = Self::load(path)?;
+            if keys.node_num != node_num {
+                anyhow::bail!(
+                    "key file {} belongs to !{:08x}, not !{:08x}",
+                    path.display(),
+                    keys.node_num,
+                    node_num
+                );
+            }
+            Ok(keys)
+        } else {
+            let keys = Self::new(node_num, KeyPair::generate());
+            keys.save(path)?;
+            Ok(keys)
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file: KeyFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let mut keys = Self::new(file.node_num, KeyPair::from_base64(&file.private_key)?);
+        for (node, key) in file.peers {
+            let node = crate::meshtastic::parse_node_num(&node)?;
+            let key = key_from_slice(&BASE64_STANDARD.decode(key)?)?;
+            keys.peers.insert(node, key);
+        }
+        Ok(keys)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let file = KeyFile {
+            node_num: self.node_num,
+            private_key: BASE64_STANDARD.encode(self.key_pair.private_key()),
+            peers: self
+                .peers
+                .iter()
+                .map(|(node, key)| (format!("!{node:08x}"), BASE64_STANDARD.encode(key)))
+                .collect(),
+        };
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // the file holds the private key
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut f = options.open(path)?;
+        f.write_all(serde_json::to_string_pretty(&file)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn node_num(&self) -> u32 {
+        self.node_num
+    }
+
+    pub fn key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    pub fn public_key(&self, node: u32) -> Option<&[u8; 32]> {
+        self.peers.get(&node)
+    }
+
+    /// Remember the public key of a node, returns if it was new.
+    ///
+    /// Known keys are pinned like in the firmware: a different key for the same node is refused, otherwise
+    /// anyone could take over the direct messages of a node by sending a NodeInfo in its name.
+    pub fn learn(&mut self, node: u32, public_key: &[u8]) -> Result<bool, PkiError> {
+        let key = key_from_slice(public_key)?;
+        if node == self.node_num {
+            return Ok(false);
+        }
+        match self.peers.get(&node) {
+            Some(known) if *known == key => Ok(false),
+            Some(_) => {
+                warn!("ignoring public key of !{node:08x} that does not match the known one");
+                Err(PkiError::KeyMismatch(node))
+            }
+            None => {
+                self.peers.insert(node, key);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Learn the public key of a decoded NodeInfo packet.
+    pub fn learn_from_event(&mut self, event: &MeshtasticEvent) -> bool {
+        let MeshtasticPayload::NodeInfo(user) = &event.payload else {
+            return false;
+        };
+        if user.public_key.is_empty() {
+            return false;
+        }
+        self.learn(event.from, &user.public_key).unwrap_or(false)
+    }
+
+    /// Packet that looks like a direct message to us that is encrypted with PKI.
+    pub fn is_pki_packet(&self, packet: &MeshPacket) -> bool {
+        packet.channel_hash == PKI_CHANNEL_HASH
+            && packet.dest == self.node_num
+            && packet.data.len() > PKI_OVERHEAD
+    }
+
+    /// Decrypt the `Data` message of a PKI-encrypted packet to us.
+    pub fn decrypt(&self, packet: &MeshPacket) -> Result<protobufs::Data, PkiError> {
+        if packet.dest != self.node_num {
+            return Err(PkiError::NotForUs(packet.dest));
+        }
+        if packet.data.len() <= PKI_OVERHEAD {
+            return Err(PkiError::TooShort(packet.data.len()));
+        }
+        let public_key = self
+            .peers
+            .get(&packet.sender)
+            .ok_or(PkiError::UnknownNode(packet.sender))?;
+
+        let (ciphertext, extra_nonce) = packet.data.split_at(packet.data.len() - EXTRA_NONCE_LEN);
+        let extra_nonce = u32::from_le_bytes(extra_nonce.try_into().unwrap());
+        let nonce = nonce(packet.packet_id, packet.sender, extra_nonce);
+
+        let cipher = Aes256Ccm::new(&self.key_pair.shared_key(public_key).into());
+        let plaintext = cipher
+            .decrypt(&nonce.into(), ciphertext)
+            .map_err(|_| PkiError::Decrypt)?;
+        protobufs::Data::decode(&*plaintext).map_err(|_| PkiError::InvalidData)
+    }
+
+    pub fn decode(&self, packet: &MeshPacket) -> Result<MeshtasticEvent, PkiError> {
+        let data = self.decrypt(packet)?;
+        Ok(MeshtasticEvent::new(packet, PKI_CHANNEL_NAME, &data))
+    }
+
+    /// Encrypt `data` as payload of a direct message from us.
+    pub fn encrypt(&self, packet: &mut MeshPacket, data: &protobufs::Data) -> Result<(), PkiError> {
+        if packet.dest == BROADCAST {
+            return Err(PkiError::Broadcast);
+        }
+        let public_key = self
+            .peers
+            .get(&packet.dest)
+            .ok_or(PkiError::UnknownNode(packet.dest))?;
+
+        let extra_nonce: u32 = rand::random();
+        let nonce = nonce(packet.packet_id, packet.sender, extra_nonce);
+        let cipher = Aes256Ccm::new(&self.key_pair.shared_key(public_key).into());
+        let mut bytes = cipher
+            .encrypt(&nonce.into(), &*data.encode_to_vec())
+            .expect("LoRa payloads fit the CCM length field");
+        debug_assert_eq!(bytes.len(), data.encoded_len() + TAG_LEN);
+        bytes.extend_from_slice(&extra_nonce.to_le_bytes());
+
+        packet.channel_hash = PKI_CHANNEL_HASH;
+        packet.data = bytes;
+        Ok(())
+    }
+
+    /// Next packet of `encoder`, encrypted for its destination.
+    pub fn encode(
+        &self,
+        encoder: &mut MeshtasticEncoder,
+        data: &protobufs::Data,
+    ) -> Result<MeshPacket, PkiError> {
+        let mut packet = encoder.header();
+        packet.sender = self.node_num;
+        self.encrypt(&mut packet, data)?;
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// `test_PKC_Decrypt` of the firmware's crypto tests
+    const FIRMWARE_PRIVATE_KEY: &str =
+        "a00330633e63522f8a4d81ec6d9d1e6617f6c8ffd3a4c698229537d44e522277";
+    const FIRMWARE_PEER_KEY: &str =
+        "db18fc50eea47f00251cb784819a3cf5fc361882597f589f0d7ff820e8064457";
+    const FIRMWARE_RADIO_BYTES: &str =
+        "8c646d7a2909000062d6b2136b00000040df24abfcc30a17a3d9046726099e796a1c036a792b";
+
+    fn firmware_keys() -> PkiKeys {
+        let private_key = hex(FIRMWARE_PRIVATE_KEY).try_into().unwrap();
+        let mut keys = PkiKeys::new(0x7a6d648c, KeyPair::from_private(private_key));
+        assert_eq!(keys.learn(0x929, &hex(FIRMWARE_PEER_KEY)), Ok(true));
+        keys
+    }
+
+    #[test]
+    fn decrypt_firmware_vector() {
+        let keys = firmware_keys();
+        let packet = MeshPacket::parse(&hex(FIRMWARE_RADIO_BYTES)).unwrap();
+        assert!(keys.is_pki_packet(&packet));
+        let data = keys.decrypt(&packet).unwrap();
+        assert_eq!(data.portnum, protobufs::PortNum::TextMessageApp as i32);
+        assert_eq!(data.payload, b"test");
+
+        let mut corrupted = packet.clone();
+        corrupted.data[0] ^= 1;
+        assert_eq!(keys.decrypt(&corrupted), Err(PkiError::Decrypt));
+        let mut other = packet;
+        other.dest = 0x1234;
+        assert_eq!(keys.decrypt(&other), Err(PkiError::NotForUs(0x1234)));
+    }
+
+    #[test]
+    fn encrypt_roundtrip() {
+        let private_key = hex(FIRMWARE_PRIVATE_KEY).try_into().unwrap();
+        let mut receiver = PkiKeys::new(0x7a6d648c, KeyPair::from_private(private_key));
+        let mut sender = PkiKeys::new(0x929, KeyPair::generate());
+        sender
+            .learn(receiver.node_num(), &receiver.key_pair().public_key())
+            .unwrap();
+        receiver
+            .learn(sender.node_num(), &sender.key_pair().public_key())
+            .unwrap();
+
+        let data = protobufs::Data {
+            portnum: protobufs::PortNum::TextMessageApp as i32,
+            payload: b"hello".to_vec(),
+            ..Default::default()
+        };
+        let mut packet = MeshPacket::parse(&hex(FIRMWARE_RADIO_BYTES)).unwrap();
+        packet.channel_hash = 8;
+        sender.encrypt(&mut packet, &data).unwrap();
+        assert_eq!(packet.channel_hash, PKI_CHANNEL_HASH);
+        assert_eq!(packet.data.len(), data.encoded_len() + PKI_OVERHEAD);
+        assert_eq!(receiver.decrypt(&packet), Ok(data));
+
+        packet.dest = BROADCAST;
+        assert_eq!(
+            sender.encrypt(&mut packet, &protobufs::Data::default()),
+            Err(PkiError::Broadcast)
+        );
+    }
+}
@@ -0,0 +1,445 @@
+//! Meshtastic regions and frequency slots, following the firmware's `RadioInterface`.
+//!
+//! A region's band is divided into slots of the preset bandwidth. The primary channel selects the slot with
+//! the djb2 hash of its name (the preset name for unnamed channels) or an explicit `channel_num`.
+use strum_macros::Display;
+use strum_macros::EnumIter;
+
+use crate::utils::Bandwidth;
+use crate::utils::Channel;
+use crate::utils::CodeRate;
+use crate::utils::SpreadingFactor;
+
+/// Band limits of a region in MHz, with the duty cycle in percent and the power limit in dBm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegionBand {
+    pub freq_start: f64,
+    pub freq_end: f64,
+    pub duty_cycle: u8,
+    pub spacing: f64,
+    pub power_limit: u8,
+    /// 2.4 GHz band with the wide LoRa bandwidths
+    pub wide_lora: bool,
+}
+
+const fn band(
+    freq_start: f64,
+    freq_end: f64,
+    duty_cycle: u8,
+    power_limit: u8,
+    wide_lora: bool,
+) -> RegionBand {
+    RegionBand {
+        freq_start,
+        freq_end,
+        duty_cycle,
+        spacing: 0.0,
+        power_limit,
+        wide_lora,
+    }
+}
+
+/// Region of the firmware's `Config.LoRaConfig.RegionCode`, with the protobuf values as discriminants.
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum, EnumIter, PartialEq, Eq, Hash, Display)]
+#[clap(rename_all = "SCREAMING_SNAKE_CASE")]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum MeshtasticRegion {
+    #[value(alias = "US_915")]
+    US = 1,
+    #[value(alias = "EU433")]
+    EU_433 = 2,
+    #[default]
+    #[value(aliases = ["EU", "EU868"])]
+    EU_868 = 3,
+    CN = 4,
+    JP = 5,
+    ANZ = 6,
+    KR = 7,
+    TW = 8,
+    RU = 9,
+    IN = 10,
+    NZ_865 = 11,
+    TH = 12,
+    LORA_24 = 13,
+    UA_433 = 14,
+    UA_868 = 15,
+    MY_433 = 16,
+    MY_919 = 17,
+    SG_923 = 18,
+    PH_433 = 19,
+    PH_868 = 20,
+    PH_915 = 21,
+    ANZ_433 = 22,
+    KZ_433 = 23,
+    KZ_863 = 24,
+    NP_865 = 25,
+    BR_902 = 26,
+}
+
+impl MeshtasticRegion {
+    pub fn band(&self) -> RegionBand {
+        match self {
+            Self::US => band(902.0, 928.0, 100, 30, false),
+            Self::EU_433 => band(433.0, 434.0, 10, 10, false),
+            Self::EU_868 => band(869.4, 869.65, 10, 27, false),
+            Self::CN => band(470.0, 510.0, 100, 19, false),
+            Self::JP => band(920.5, 923.5, 100, 13, false),
+            Self::ANZ => band(915.0, 928.0, 100, 30, false),
+            Self::ANZ_433 => band(433.05, 434.79, 100, 14, false),
+            Self::RU => band(868.7, 869.2, 100, 20, false),
+            Self::KR => band(920.0, 923.0, 100, 23, false),
+            Self::TW => band(920.0, 925.0, 100, 27, false),
+            Self::IN => band(865.0, 867.0, 100, 30, false),
+            Self::NZ_865 => band(864.0, 868.0, 100, 36, false),
+            Self::TH => band(920.0, 925.0, 100, 16, false),
+            Self::LORA_24 => band(2400.0, 2483.5, 100, 10, true),
+            Self::UA_433 => band(433.0, 434.7, 10, 10, false),
+            Self::UA_868 => band(868.0, 868.6, 1, 14, false),
+            Self::MY_433 => band(433.0, 435.0, 100, 20, false),
+            Self::MY_919 => band(919.0, 924.0, 100, 27, false),
+            Self::SG_923 => band(917.0, 925.0, 100, 20, false),
+            Self::PH_433 => band(433.0, 434.7, 100, 10, false),
+            Self::PH_868 => band(868.0, 869.4, 100, 14, false),
+            Self::PH_915 => band(915.0, 918.0, 100, 24, false),
+            Self::KZ_433 => band(433.075, 434.775, 100, 10, false),
+            Self::KZ_863 => band(863.0, 868.0, 100, 30, false),
+            Self::NP_865 => band(865.0, 868.0, 100, 30, false),
+            Self::BR_902 => band(902.0, 907.5, 100, 30, false),
+        }
+    }
+
+    /// Value of the `RegionCode` protobuf enum.
+    pub fn region_code(&self) -> i32 {
+        *self as i32
+    }
+
+    /// Region of a `RegionCode`, `None` for `UNSET` and unknown codes.
+    pub fn from_region_code(code: i32) -> Option<Self> {
+        use strum::IntoEnumIterator;
+        Self::iter().find(|r| r.region_code() == code)
+    }
+
+    /// Number of frequency slots for a bandwidth in kHz.
+    pub fn num_slots(&self, bandwidth_khz: f64) -> u32 {
+        let band = self.band();
+        ((band.freq_end - band.freq_start) / (band.spacing + bandwidth_khz / 1000.0)).floor() as u32
+    }
+
+    /// Slot of the primary channel, `channel_num` counts from 1 and 0 selects the slot by the channel name.
+    /// `None` if the bandwidth does not fit into the band.
+    pub fn slot(&self, bandwidth_khz: f64, channel_name: &str, channel_num: u32) -> Option<u32> {
+        let slots = self.num_slots(bandwidth_khz);
+        if slots == 0 {
+            return None;
+        }
+        let index = if channel_num > 0 {
+            channel_num - 1
+        } else {
+            djb2(channel_name)
+        };
+        Some(index % slots)
+    }
+
+    /// Center frequency of a slot in Hz.
+    pub fn slot_frequency(&self, bandwidth_khz: f64, slot: u32) -> u32 {
+        let band = self.band();
+        let freq = band.freq_start + bandwidth_khz / 2000.0 + slot as f64 * bandwidth_khz / 1000.0;
+        (freq * 1e6).round() as u32
+    }
+}
+
+/// Hash of the firmware's `RadioInterface::hash`, on 32-bit `unsigned long`.
+pub fn djb2(s: &str) -> u32 {
+    s.bytes().fold(5381u32, |hash, c| {
+        (hash << 5).wrapping_add(hash).wrapping_add(c as u32)
+    })
+}
+
+/// Modem settings of a preset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preset {
+    /// value of the `ModemPreset` protobuf enum
+    pub modem_preset: i32,
+    /// name shown by the firmware and hashed for unnamed channels
+    pub name: &'static str,
+    pub bandwidth_khz: f64,
+    /// bandwidth in the 2.4 GHz band
+    pub wide_bandwidth_khz: f64,
+    pub spreading_factor: SpreadingFactor,
+    pub code_rate: CodeRate,
+}
+
+const fn preset(
+    modem_preset: i32,
+    name: &'static str,
+    bandwidth_khz: f64,
+    wide_bandwidth_khz: f64,
+    spreading_factor: SpreadingFactor,
+    code_rate: CodeRate,
+) -> Preset {
+    Preset {
+        modem_preset,
+        name,
+        bandwidth_khz,
+        wide_bandwidth_khz,
+        spreading_factor,
+        code_rate,
+    }
+}
+
+pub static PRESETS: [Preset; 9] = [
+    preset(
+        0,
+        "LongFast",
+        250.0,
+        812.5,
+        SpreadingFactor::SF11,
+        CodeRate::CR_4_5,
+    ),
+    preset(
+        1,
+        "LongSlow",
+        125.0,
+        406.25,
+        SpreadingFactor::SF12,
+        CodeRate::CR_4_8,
+    ),
+    preset(
+        2,
+        "VLongSlow",
+        62.5,
+        203.125,
+        SpreadingFactor::SF12,
+        CodeRate::CR_4_8,
+    ),
+    preset(
+        3,
+        "MediumSlow",
+        250.0,
+        812.5,
+        SpreadingFactor::SF10,
+        CodeRate::CR_4_5,
+    ),
+    preset(
+        4,
+        "MediumFast",
+        250.0,
+        812.5,
+        SpreadingFactor::SF9,
+        CodeRate::CR_4_5,
+    ),
+    preset(
+        5,
+        "ShortSlow",
+        250.0,
+        812.5,
+        SpreadingFactor::SF8,
+        CodeRate::CR_4_5,
+    ),
+    preset(
+        6,
+        "ShortFast",
+        250.0,
+        812.5,
+        SpreadingFactor::SF7,
+        CodeRate::CR_4_5,
+    ),
+    preset(
+        7,
+        "LongMod",
+        125.0,
+        406.25,
+        SpreadingFactor::SF11,
+        CodeRate::CR_4_8,
+    ),
+    preset(
+        8,
+        "ShortTurbo",
+        500.0,
+        1625.0,
+        SpreadingFactor::SF7,
+        CodeRate::CR_4_5,
+    ),
+];
+
+impl Preset {
+    /// Preset of a `ModemPreset` value.
+    pub fn from_modem_preset(modem_preset: i32) -> Option<&'static Preset> {
+        PRESETS.iter().find(|p| p.modem_preset == modem_preset)
+    }
+
+    pub fn bandwidth_khz(&self, region: MeshtasticRegion) -> f64 {
+        if region.band().wide_lora {
+            self.wide_bandwidth_khz
+        } else {
+            self.bandwidth_khz
+        }
+    }
+
+    /// Bandwidth in the region, `None` for the wide bandwidths the receiver does not support.
+    pub fn bandwidth(&self, region: MeshtasticRegion) -> Option<Bandwidth> {
+        Bandwidth::try_from((self.bandwidth_khz(region) * 1000.0) as u32).ok()
+    }
+
+    /// Low data rate optimization, enabled by the radio for symbols longer than 16 ms.
+    pub fn ldro(&self) -> bool {
+        let symbol_ms = (1u32 << u8::from(self.spreading_factor)) as f64 / self.bandwidth_khz;
+        symbol_ms > 16.0
+    }
+}
+
+/// Frequency selection of a node: region, preset and the overrides of the LoRa config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrequencyPlan {
+    pub region: MeshtasticRegion,
+    pub preset: &'static Preset,
+    /// slot counting from 1, 0 to hash the primary channel name
+    pub channel_num: u32,
+    /// frequency in Hz used instead of the slot
+    pub override_frequency: Option<u32>,
+    /// offset in Hz added to the frequency
+    pub frequency_offset: i32,
+}
+
+impl FrequencyPlan {
+    pub fn new(region: MeshtasticRegion, preset: &'static Preset) -> Self {
+        Self {
+            region,
+            preset,
+            channel_num: 0,
+            override_frequency: None,
+            frequency_offset: 0,
+        }
+    }
+
+    /// Slot of the primary channel, an empty name stands for the unnamed channel.
+    pub fn slot(&self, channel_name: &str) -> Option<u32> {
+        let name = if channel_name.is_empty() {
+            self.preset.name
+        } else {
+            channel_name
+        };
+        self.region.slot(
+            self.preset.bandwidth_khz(self.region),
+            name,
+            self.channel_num,
+        )
+    }
+
+    /// If the channel is in the slot of the preset's unnamed default channel.
+    ///
+    /// Like the firmware, the default slot is always `djb2(preset name) % num_slots`, `channel_num` only
+    /// moves the channel.
+    pub fn uses_default_slot(&self, channel_name: &str) -> bool {
+        let default = self
+            .region
+            .slot(self.preset.bandwidth_khz(self.region), self.preset.name, 0);
+        default.is_some() && self.slot(channel_name) == default
+    }
+
+    /// Center frequency of the primary channel.
+    pub fn frequency(&self, channel_name: &str) -> Option<Channel> {
+        let freq = match self.override_frequency {
+            Some(freq) => freq,
+            None => self.region.slot_frequency(
+                self.preset.bandwidth_khz(self.region),
+                self.slot(channel_name)?,
+            ),
+        };
+        Some(Channel::Custom(
+            freq.checked_add_signed(self.frequency_offset)?,
+        ))
+    }
+
+    /// Bandwidth, spreading factor, code rate, frequency and LDRO like [`MeshtasticConfig::to_config`](crate::meshtastic::MeshtasticConfig::to_config).
+    pub fn to_config(
+        &self,
+        channel_name: &str,
+    ) -> Option<(Bandwidth, SpreadingFactor, CodeRate, Channel, bool)> {
+        Some((
+            self.preset.bandwidth(self.region)?,
+            self.preset.spreading_factor,
+            self.preset.code_rate,
+            self.frequency(channel_name)?,
+            self.preset.ldro(),
+        ))
+    }
+}
+
+/// Default channels of all presets in a region, grouped by bandwidth and frequency, e.g. to receive all of
+/// them at once.
+pub fn default_channels(
+    region: MeshtasticRegion,
+) -> Vec<(Bandwidth, Channel, Vec<(SpreadingFactor, bool, CodeRate)>)> {
+    let mut groups: Vec<(Bandwidth, Channel, Vec<(SpreadingFactor, bool, CodeRate)>)> = Vec::new();
+    for preset in PRESETS.iter() {
+        let Some((bandwidth, spreading_factor, code_rate, channel, ldro)) =
+            FrequencyPlan::new(region, preset).to_config("")
+        else {
+            continue;
+        };
+        let chain = (spreading_factor, ldro, code_rate);
+        match groups
+            .iter_mut()
+            .find(|(bw, chan, _)| *bw == bandwidth && *chan == channel)
+        {
+            Some((_, _, chains)) => {
+                if !chains.iter().any(|(sf, _, _)| *sf == spreading_factor) {
+                    chains.push(chain);
+                }
+            }
+            None => groups.push((bandwidth, channel, vec![chain])),
+        }
+    }
+    for (_, _, chains) in groups.iter_mut() {
+        chains.sort_by_key(|(sf, _, _)| u8::from(*sf));
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(name: &str) -> &'static Preset {
+        PRESETS.iter().find(|p| p.name == name).unwrap()
+    }
+
+    fn frequency(plan: &FrequencyPlan) -> u32 {
+        plan.frequency("").unwrap().into()
+    }
+
+    #[test]
+    fn djb2_hash() {
+        assert_eq!(djb2(""), 5381);
+        assert_eq!(djb2("LongFast") % 104, 19);
+    }
+
+    #[test]
+    fn default_slots() {
+        let plan = FrequencyPlan::new(MeshtasticRegion::US, preset("LongFast"));
+        assert_eq!(MeshtasticRegion::US.num_slots(250.0), 104);
+        assert_eq!(plan.slot(""), Some(19));
+        assert_eq!(frequency(&plan), 906_875_000);
+
+        let plan = FrequencyPlan::new(MeshtasticRegion::EU_868, preset("LongFast"));
+        assert_eq!(plan.slot(""), Some(0));
+        assert_eq!(frequency(&plan), 869_525_000);
+
+        let plan = FrequencyPlan::new(MeshtasticRegion::EU_868, preset("LongMod"));
+        assert_eq!(plan.slot(""), Some(1));
+        assert_eq!(frequency(&plan), 869_587_500);
+    }
+
+    #[test]
+    fn channel_num_and_default_slot() {
+        let mut plan = FrequencyPlan::new(MeshtasticRegion::US, preset("LongFast"));
+        assert!(plan.uses_default_slot(""));
+        plan.channel_num = 1;
+        assert_eq!(plan.slot(""), Some(0));
+        assert_eq!(frequency(&plan), 902_125_000);
+        assert!(!plan.uses_default_slot(""));
+        plan.channel_num = 20;
+        assert!(plan.uses_default_slot(""));
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futuresdr::tracing::debug;
+use futuresdr::tracing::info;
+use futuresdr::tracing::warn;
+use rand::Rng;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::Instant;
+
+use crate::mac::BROADCAST_ADDR;
+use crate::mac::MacHandle;
+use crate::mac::Priority;
+use crate::mac::RxFrame;
+use crate::mac::TxRequest;
+use crate::meshtastic::MeshPacket;
+use crate::utils::Bandwidth;
+use crate::utils::SpreadingFactor;
+
+/// SNR range mapped onto the contention window, as in the firmware
+const SNR_MIN: f64 = -20.0;
+const SNR_MAX: f64 = 10.0;
+/// contention window exponents
+const CW_MIN: u8 = 3;
+const CW_MAX: u8 = 8;
+
+#[derive(Debug, Clone)]
+pub struct RouterConfig {
+    /// own node number, the `sender` of own packets
+    pub node_num: u32,
+    /// router and repeater roles rebroadcast without the extra client delay
+    pub router_role: bool,
+    /// duration of one contention slot
+    pub slot_time: Duration,
+    /// how long (sender, packet id) pairs are remembered to drop duplicates
+    pub history_ttl: Duration,
+    /// retransmissions of own packets with want_ack that are not heard rebroadcast
+    pub max_retransmissions: u8,
+    /// time to wait for an implicit ACK before retransmitting
+    pub ack_timeout: Duration,
+}
+
+impl RouterConfig {
+    /// Slot time derived from the modem settings, covering channel activity detection, propagation and
+    /// processing like the firmware's `slotTimeMsec`.
+    pub fn new(node_num: u32, sf: SpreadingFactor, bw: Bandwidth) -> Self {
+        let symbol_time = (1u32 << u8::from(sf)) as f64 / Into::<f64>::into(bw);
+        let slot_time = Duration::from_secs_f64(2.5 * symbol_time + 0.0002 * 2.0 + 0.0045);
+        Self {
+            node_num,
+            router_role: true,
+            slot_time,
+            history_ttl: Duration::from_secs(600),
+            max_retransmissions: 3,
+            ack_timeout: slot_time * (2 << CW_MAX) + Duration::from_secs(5),
+        }
+    }
+
+    /// Contention window exponent of a reception, weak packets get short windows so that distant nodes
+    /// rebroadcast first.
+    fn cw_size(&self, snr: f64) -> u32 {
+        let snr = snr.clamp(SNR_MIN, SNR_MAX);
+        let cw = CW_MIN as f64 + (snr - SNR_MIN) / (SNR_MAX - SNR_MIN) * (CW_MAX - CW_MIN) as f64;
+        cw.round() as u32
+    }
+
+    /// Rebroadcast delay of a packet received with the given SNR.
+    fn rebroadcast_delay(&self, snr: f64) -> Duration {
+        let cw_size = self.cw_size(snr);
+        let mut rng = rand::rng();
+        if self.router_role {
+            self.slot_time * rng.random_range(0..=2 * cw_size)
+        } else {
+            self.slot_time * (2 * CW_MAX as u32)
+                + self.slot_time * rng.random_range(0..=1 << cw_size)
+        }
+    }
+}
+
+/// Events reported by a running [`MeshtasticRouter`].
+#[derive(Debug, Clone)]
+pub enum RouterEvent {
+    /// first copy of a packet from another node
+    Received {
+        packet: MeshPacket,
+        snr: Option<f64>,
+    },
+    /// packet scheduled for rebroadcast with the decremented hop limit
+    Rebroadcast {
+        sender: u32,
+        packet_id: u32,
+        delay: Duration,
+    },
+    /// another node rebroadcast the packet first, our copy was dropped
+    RebroadcastCancelled { sender: u32, packet_id: u32 },
+    /// own packet heard rebroadcast by another node
+    ImplicitAck { packet_id: u32 },
+    /// own packet with want_ack not heard rebroadcast after all retransmissions
+    NoAck { packet_id: u32 },
+}
+
+pub enum RouterCommand {
+    Send(MeshPacket),
+    Subscribe(UnboundedSender<RouterEvent>),
+}
+
+/// Cloneable handle to a running [`MeshtasticRouter`].
+#[derive(Clone)]
+pub struct RouterHandle {
+    commands: UnboundedSender<RouterCommand>,
+}
+
+impl RouterHandle {
+    /// Send an own packet, its `sender` should be the node number of the router.
+    pub fn send(&self, packet: MeshPacket) -> bool {
+        self.commands.send(RouterCommand::Send(packet)).is_ok()
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<RouterEvent> {
+        let (tx, rx) = unbounded_channel();
+        let _ = self.commands.send(RouterCommand::Subscribe(tx));
+        rx
+    }
+}
+
+/// Rebroadcast scheduled in the MAC queue
+struct PendingRebroadcast {
+    mac_id: u16,
+    not_before: Instant,
+}
+
+/// Own packet waiting for an implicit ACK
+struct PendingAck {
+    packet: MeshPacket,
+    retransmissions: u8,
+    deadline: Instant,
+}
+
+/// Managed-flood router like the firmware's `FloodingRouter`.
+///
+/// Packets from other nodes are rebroadcast once with the hop limit decremented, after a random delay in an
+/// SNR-weighted contention window. Hearing another node rebroadcast the packet before that cancels the own
+/// copy. Hearing an own packet rebroadcast counts as implicit ACK.
+pub struct MeshtasticRouter {
+    config: RouterConfig,
+    /// first reception of every (sender, packet id)
+    history: HashMap<(u32, u32), Instant>,
+    rebroadcasts: HashMap<(u32, u32), PendingRebroadcast>,
+    pending_acks: HashMap<u32, PendingAck>,
+    next_mac_id: u16,
+    subscribers: Vec<UnboundedSender<RouterEvent>>,
+}
+
+impl MeshtasticRouter {
+    pub fn new(config: RouterConfig) -> Self {
+        Self {
+            config,
+            history: HashMap::new(),
+            rebroadcasts: HashMap::new(),
+            pending_acks: HashMap::new(),
+            next_mac_id: 0,
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn channel() -> (RouterHandle, UnboundedReceiver<RouterCommand>) {
+        let (commands, rx) = unbounded_channel();
+        (RouterHandle { commands }, rx)
+    }
+
+    /// Run a router on the MAC of a started [`Node`](crate::Node).
+    pub fn spawn(config: RouterConfig, mac: MacHandle) -> RouterHandle {
+        let (handle, commands) = Self::channel();
+        tokio::spawn(Self::new(config).run(mac, commands));
+        handle
+    }
+
+    fn emit(&mut self, event: RouterEvent) {
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+
+    fn submit(&mut self, mac: &MacHandle, packet: &MeshPacket, not_before: Option<Instant>) -> u16 {
+        let id = self.next_mac_id;
+        self.next_mac_id = self.next_mac_id.wrapping_add(1);
+        mac.submit(TxRequest {
+            id,
+            payload: packet.to_bytes(),
+            priority: Priority::Normal,
+            dest: BROADCAST_ADDR,
+            want_ack: false,
+            not_before,
+        });
+        id
+    }
+
+    fn send(&mut self, mac: &MacHandle, packet: MeshPacket) {
+        let now = Instant::now();
+        self.history.insert((packet.sender, packet.packet_id), now);
+        self.submit(mac, &packet, None);
+        if packet.want_ack {
+            self.pending_acks.insert(
+                packet.packet_id,
+                PendingAck {
+                    packet,
+                    retransmissions: 0,
+                    deadline: now + self.config.ack_timeout,
+                },
+            );
+        }
+    }
+
+    fn on_rx(&mut self, mac: &MacHandle, frame: RxFrame) {
+        let packet = match MeshPacket::parse(&frame.payload) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("MeshtasticRouter: dropping frame: {}", e);
+                return;
+            }
+        };
+        let key = (packet.sender, packet.packet_id);
+        let now = Instant::now();
+
+        if packet.sender == self.config.node_num {
+            if self.pending_acks.remove(&packet.packet_id).is_some() {
+                self.emit(RouterEvent::ImplicitAck {
+                    packet_id: packet.packet_id,
+                });
+            }
+            return;
+        }
+
+        if self.history.contains_key(&key) {
+            // somebody else was faster, our rebroadcast would not reach new nodes
+            if let Some(pending) = self.rebroadcasts.remove(&key) {
+                if pending.not_before > now {
+                    mac.cancel(pending.mac_id);
+                    self.emit(RouterEvent::RebroadcastCancelled {
+                        sender: packet.sender,
+                        packet_id: packet.packet_id,
+                    });
+                }
+            }
+            return;
+        }
+        self.history.insert(key, now);
+        self.emit(RouterEvent::Received {
+            packet: packet.clone(),
+            snr: frame.snr,
+        });
+
+        if packet.dest == self.config.node_num || packet.hop_limit == 0 {
+            return;
+        }
+        let mut relayed = packet;
+        relayed.hop_limit -= 1;
+        relayed.relay_node = self.config.node_num as u8;
+        let delay = self.config.rebroadcast_delay(frame.snr.unwrap_or(SNR_MIN));
+        let not_before = now + delay;
+        let mac_id = self.submit(mac, &relayed, Some(not_before));
+        self.rebroadcasts
+            .insert(key, PendingRebroadcast { mac_id, not_before });
+        self.emit(RouterEvent::Rebroadcast {
+            sender: relayed.sender,
+            packet_id: relayed.packet_id,
+            delay,
+        });
+    }
+
+    fn check_timeouts(&mut self, mac: &MacHandle, now: Instant) {
+        let ttl = self.config.history_ttl;
+        self.history
+            .retain(|_, seen| now.duration_since(*seen) < ttl);
+        self.rebroadcasts.retain(|_, p| p.not_before > now);
+
+        let expired: Vec<u32> = self
+            .pending_acks
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for packet_id in expired {
+            let mut pending = self.pending_acks.remove(&packet_id).unwrap();
+            if pending.retransmissions >= self.config.max_retransmissions {
+                info!(
+                    "MeshtasticRouter: packet {:08x} not rebroadcast after {} retransmissions",
+                    packet_id, pending.retransmissions
+                );
+                self.emit(RouterEvent::NoAck { packet_id });
+                continue;
+            }
+            pending.retransmissions += 1;
+            pending.deadline = now + self.config.ack_timeout;
+            self.submit(mac, &pending.packet, None);
+            self.pending_acks.insert(packet_id, pending);
+        }
+    }
+
+    fn next_wakeup(&self) -> Instant {
+        let ack = self.pending_acks.values().map(|p| p.deadline).min();
+        let rebroadcast = self.rebroadcasts.values().map(|p| p.not_before).min();
+        let history = Instant::now() + self.config.history_ttl;
+        [ack, rebroadcast]
+            .into_iter()
+            .flatten()
+            .fold(history, Instant::min)
+    }
+
+    /// Run the router until the command channel is closed or the MAC stopped.
+    pub async fn run(mut self, mac: MacHandle, mut commands: UnboundedReceiver<RouterCommand>) {
+        let mut rx = mac.subscribe();
+        loop {
+            let wakeup = self.next_wakeup();
+            tokio::select! {
+                cmd = commands.recv() => match cmd {
+                    Some(RouterCommand::Send(packet)) => self.send(&mac, packet),
+                    Some(RouterCommand::Subscribe(sender)) => self.subscribers.push(sender),
+                    None => break,
+                },
+                frame = rx.recv() => match frame {
+                    Some(frame) => self.on_rx(&mac, frame),
+                    None => {
+                        warn!("MeshtasticRouter: MAC stopped");
+                        break;
+                    }
+                },
+                _ = tokio::time::sleep_until(wakeup) => self.check_timeouts(&mac, Instant::now()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mac::Mac;
+    use crate::mac::MacCommand;
+
+    const NODE: u32 = 0x1234_5678;
+
+    fn router() -> (MeshtasticRouter, UnboundedReceiver<RouterEvent>) {
+        let mut config = RouterConfig::new(NODE, SpreadingFactor::SF11, Bandwidth::BW250);
+        // clients wait at least 2 * CW_MAX slots, so the rebroadcast is still queued
+        config.router_role = false;
+        config.max_retransmissions = 1;
+        let mut router = MeshtasticRouter::new(config);
+        let (tx, rx) = unbounded_channel();
+        router.subscribers.push(tx);
+        (router, rx)
+    }
+
+    fn packet(sender: u32, packet_id: u32) -> MeshPacket {
+        MeshPacket {
+            dest: 0xffff_ffff,
+            sender,
+            packet_id,
+            hop_limit: 3,
+            want_ack: true,
+            via_mqtt: false,
+            hop_start: 3,
+            channel_hash: 8,
+            next_hop: 0,
+            relay_node: 0,
+            data: vec![1, 2, 3],
+        }
+    }
+
+    fn frame(packet: &MeshPacket) -> RxFrame {
+        RxFrame {
+            payload: packet.to_bytes(),
+            snr: Some(5.0),
+            annotations: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn rebroadcast_cancelled_by_other_relay() {
+        let (mut router, mut events) = router();
+        let (mac, mut commands) = Mac::channel();
+        let original = packet(0xaabb_ccdd, 1);
+
+        router.on_rx(&mac, frame(&original));
+        let Ok(MacCommand::Submit(request)) = commands.try_recv() else {
+            panic!("no rebroadcast queued");
+        };
+        assert!(request.not_before.unwrap() > Instant::now());
+        let relayed = MeshPacket::parse(&request.payload).unwrap();
+        assert_eq!((relayed.hop_limit, relayed.relay_node), (2, 0x78));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RouterEvent::Received { .. })
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RouterEvent::Rebroadcast { .. })
+        ));
+
+        // another node relays it first
+        let mut heard = original.clone();
+        heard.hop_limit = 2;
+        router.on_rx(&mac, frame(&heard));
+        assert!(matches!(commands.try_recv(), Ok(MacCommand::Cancel(id)) if id == request.id));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RouterEvent::RebroadcastCancelled {
+                sender: 0xaabb_ccdd,
+                packet_id: 1
+            })
+        ));
+
+        // later copies are dropped silently
+        router.on_rx(&mac, frame(&heard));
+        assert!(commands.try_recv().is_err());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn no_rebroadcast_without_hops() {
+        let (mut router, mut events) = router();
+        let (mac, mut commands) = Mac::channel();
+        let mut last_hop = packet(0xaabb_ccdd, 2);
+        last_hop.hop_limit = 0;
+        router.on_rx(&mac, frame(&last_hop));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RouterEvent::Received { .. })
+        ));
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[test]
+    fn implicit_ack() {
+        let (mut router, mut events) = router();
+        let (mac, mut commands) = Mac::channel();
+        let own = packet(NODE, 3);
+
+        router.send(&mac, own.clone());
+        assert!(matches!(commands.try_recv(), Ok(MacCommand::Submit(_))));
+        let mut rebroadcast = own;
+        rebroadcast.hop_limit = 2;
+        router.on_rx(&mac, frame(&rebroadcast));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RouterEvent::ImplicitAck { packet_id: 3 })
+        ));
+        assert!(router.pending_acks.is_empty());
+
+        // without a rebroadcast, the packet is retransmitted and finally reported
+        router.send(&mac, packet(NODE, 4));
+        assert!(matches!(commands.try_recv(), Ok(MacCommand::Submit(_))));
+        let mut now = Instant::now();
+        now += router.config.ack_timeout;
+        router.check_timeouts(&mac, now);
+        let Ok(MacCommand::Submit(request)) = commands.try_recv() else {
+            panic!("no retransmission");
+        };
+        assert_eq!(MeshPacket::parse(&request.payload).unwrap().packet_id, 4);
+        now += router.config.ack_timeout;
+        router.check_timeouts(&mac, now);
+        assert!(commands.try_recv().is_err());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RouterEvent::NoAck { packet_id: 4 })
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use base64::prelude::*;
+use meshtastic::Message;
+use meshtastic::protobufs;
+use meshtastic::protobufs::config::LoRaConfig;
+use meshtastic::protobufs::config::lo_ra_config::ModemPreset;
+use meshtastic::protobufs::config::lo_ra_config::RegionCode;
+
+use crate::meshtastic::ChannelKeyError;
+use crate::meshtastic::MeshtasticChannel;
+use crate::meshtastic::MeshtasticChannels;
+use crate::meshtastic::MeshtasticConfig;
+use crate::meshtastic_region::FrequencyPlan;
+use crate::meshtastic_region::MeshtasticRegion;
+use crate::meshtastic_region::Preset;
+use crate::utils::Bandwidth;
+use crate::utils::Channel;
+use crate::utils::CodeRate;
+use crate::utils::SpreadingFactor;
+
+/// Prefix of channel URLs shared by the apps
+pub const URL_PREFIX: &str = "https://meshtastic.org/e/";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelUrlError {
+    /// no `#` fragment with the channel set
+    NoChannelSet,
+    /// fragment is no valid base64url
+    InvalidBase64,
+    /// fragment is no `ChannelSet` protobuf
+    InvalidProtobuf,
+    /// channel set without channels
+    NoChannels,
+    InvalidKey(String, ChannelKeyError),
+}
+
+impl Display for ChannelUrlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoChannelSet => write!(f, "URL without channel set fragment"),
+            Self::InvalidBase64 => write!(f, "channel set is no valid base64url"),
+            Self::InvalidProtobuf => write!(f, "channel set is no valid protobuf"),
+            Self::NoChannels => write!(f, "channel set without channels"),
+            Self::InvalidKey(name, e) => write!(f, "channel {name:?}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ChannelUrlError {}
+
+/// Name the firmware shows for a modem preset and hashes unnamed channels with.
+pub fn preset_name(preset: ModemPreset) -> &'static str {
+    Preset::from_modem_preset(preset as i32).map_or("Invalid", |p| p.name)
+}
+
+impl MeshtasticConfig {
+    /// Config of a preset in a region, `None` for regions without a config.
+    pub fn from_preset(preset: ModemPreset, region: RegionCode) -> Option<Self> {
+        let config = match (region, preset) {
+            (RegionCode::Eu868, ModemPreset::ShortFast) => Self::ShortFastEu,
+            (RegionCode::Eu868, ModemPreset::ShortSlow) => Self::ShortSlowEu,
+            (RegionCode::Eu868, ModemPreset::MediumFast) => Self::MediumFastEu,
+            (RegionCode::Eu868, ModemPreset::MediumSlow) => Self::MediumSlowEu,
+            (RegionCode::Eu868, ModemPreset::LongFast) => Self::LongFastEu,
+            (RegionCode::Eu868, ModemPreset::LongModerate) => Self::LongModerateEu,
+            (RegionCode::Eu868, ModemPreset::LongSlow) => Self::LongSlowEu,
+            (RegionCode::Eu868, ModemPreset::VeryLongSlow) => Self::VeryLongSlowEu,
+            (RegionCode::Us, ModemPreset::ShortFast) => Self::ShortFastUs,
+            (RegionCode::Us, ModemPreset::ShortSlow) => Self::ShortSlowUs,
+            (RegionCode::Us, ModemPreset::MediumFast) => Self::MediumFastUs,
+            (RegionCode::Us, ModemPreset::MediumSlow) => Self::MediumSlowUs,
+            (RegionCode::Us, ModemPreset::LongFast) => Self::LongFastUs,
+            (RegionCode::Us, ModemPreset::LongModerate) => Self::LongModerateUs,
+            (RegionCode::Us, ModemPreset::LongSlow) => Self::LongSlowUs,
+            (RegionCode::Us, ModemPreset::VeryLongSlow) => Self::VeryLongSlowUs,
+            _ => return None,
+        };
+        Some(config)
+    }
+
+    pub fn preset(&self) -> ModemPreset {
+        match self {
+            Self::ShortFastEu | Self::ShortFastUs => ModemPreset::ShortFast,
+            Self::ShortSlowEu | Self::ShortSlowUs => ModemPreset::ShortSlow,
+            Self::MediumFastEu | Self::MediumFastUs => ModemPreset::MediumFast,
+            Self::MediumSlowEu | Self::MediumSlowUs => ModemPreset::MediumSlow,
+            Self::LongFastEu | Self::LongFastUs => ModemPreset::LongFast,
+            Self::LongModerateEu | Self::LongModerateUs => ModemPreset::LongModerate,
+            Self::LongSlowEu | Self::LongSlowUs => ModemPreset::LongSlow,
+            Self::VeryLongSlowEu | Self::VeryLongSlowUs => ModemPreset::VeryLongSlow,
+        }
+    }
+
+    pub fn region_code(&self) -> RegionCode {
+        match self {
+            Self::ShortFastEu
+            | Self::ShortSlowEu
+            | Self::MediumFastEu
+            | Self::MediumSlowEu
+            | Self::LongFastEu
+            | Self::LongModerateEu
+            | Self::LongSlowEu
+            | Self::VeryLongSlowEu => RegionCode::Eu868,
+            _ => RegionCode::Us,
+        }
+    }
+}
+
+/// Channels and LoRa settings of a `https://meshtastic.org/e/#…` URL.
+///
+/// The fragment is a base64url-encoded `ChannelSet` protobuf, the first channel is the primary channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelUrl {
+    pub channel_set: protobufs::ChannelSet,
+}
+
+impl ChannelUrl {
+    pub fn parse(url: &str) -> Result<Self, ChannelUrlError> {
+        let url = url.trim();
+        let fragment = match url.split_once('#') {
+            Some((_, fragment)) => fragment,
+            None if !url.contains('/') => url,
+            None => return Err(ChannelUrlError::NoChannelSet),
+        };
+        // the apps emit unpadded base64url, but accept padded and standard alphabet as well
+        let fragment: String = fragment
+            .trim_end_matches('=')
+            .chars()
+            .map(|c| match c {
+                '+' => '-',
+                '/' => '_',
+                c => c,
+            })
+            .collect();
+        let bytes = BASE64_URL_SAFE_NO_PAD
+            .decode(fragment)
+            .map_err(|_| ChannelUrlError::InvalidBase64)?;
+        let channel_set =
+            protobufs::ChannelSet::decode(&*bytes).map_err(|_| ChannelUrlError::InvalidProtobuf)?;
+        if channel_set.settings.is_empty() {
+            return Err(ChannelUrlError::NoChannels);
+        }
+        Ok(Self { channel_set })
+    }
+
+    /// URL of the given channels, the first one is the primary channel.
+    pub fn from_channels<'a>(
+        channels: impl IntoIterator<Item = &'a MeshtasticChannel>,
+        config: MeshtasticConfig,
+    ) -> Self {
+        let settings = channels
+            .into_iter()
+            .map(|chan| protobufs::ChannelSettings {
+                psk: chan.psk().to_vec(),
+                name: chan.name().to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let lora_config = LoRaConfig {
+            use_preset: true,
+            modem_preset: config.preset() as i32,
+            region: config.region_code() as i32,
+            hop_limit: crate::meshtastic::DEFAULT_HOP_LIMIT as u32,
+            tx_enabled: true,
+            ..Default::default()
+        };
+        Self {
+            channel_set: protobufs::ChannelSet {
+                settings,
+                lora_config: Some(lora_config),
+            },
+        }
+    }
+
+    pub fn to_url(&self) -> String {
+        format!(
+            "{URL_PREFIX}#{}",
+            BASE64_URL_SAFE_NO_PAD.encode(self.channel_set.encode_to_vec())
+        )
+    }
+
+    pub fn lora_config(&self) -> Option<&LoRaConfig> {
+        self.channel_set.lora_config.as_ref()
+    }
+
+    /// Modem preset, `None` for custom modem settings.
+    pub fn preset(&self) -> Option<ModemPreset> {
+        let lora = self.lora_config()?;
+        if !lora.use_preset {
+            return None;
+        }
+        ModemPreset::try_from(lora.modem_preset).ok()
+    }
+
+    pub fn region(&self) -> Option<RegionCode> {
+        RegionCode::try_from(self.lora_config()?.region).ok()
+    }
+
+    /// Frequency slot, 0 to derive it from the primary channel name.
+    pub fn channel_num(&self) -> u32 {
+        self.lora_config().map_or(0, |lora| lora.channel_num)
+    }
+
+    /// Override frequency in Hz, if set.
+    pub fn override_frequency(&self) -> Option<u32> {
+        let freq = self.lora_config()?.override_frequency;
+        (freq > 0.0).then(|| (freq as f64 * 1e6).round() as u32)
+    }
+
+    /// Name of the primary channel, empty if unnamed.
+    pub fn primary_name(&self) -> &str {
+        &self.channel_set.settings[0].name
+    }
+
+    /// Region, preset and slot of the LoRa config, `None` without config or with custom modem settings.
+    pub fn frequency_plan(&self) -> Option<FrequencyPlan> {
+        let lora = self.lora_config()?;
+        Some(FrequencyPlan {
+            region: MeshtasticRegion::from_region_code(lora.region)?,
+            preset: Preset::from_modem_preset(self.preset()? as i32)?,
+            channel_num: lora.channel_num,
+            override_frequency: self.override_frequency(),
+            frequency_offset: (lora.frequency_offset as f64 * 1e6).round() as i32,
+        })
+    }
+
+    /// Frequency of the primary channel.
+    pub fn frequency(&self) -> Option<Channel> {
+        self.frequency_plan()?.frequency(self.primary_name())
+    }
+
+    /// Modem settings and frequency like [`MeshtasticConfig::to_config`].
+    pub fn to_config(&self) -> Option<(Bandwidth, SpreadingFactor, CodeRate, Channel, bool)> {
+        self.frequency_plan()?.to_config(self.primary_name())
+    }
+
+    /// LoRa config of the URL's preset and region, if it has one.
+    pub fn meshtastic_config(&self) -> Option<MeshtasticConfig> {
+        MeshtasticConfig::from_preset(self.preset()?, self.region()?)
+    }
+
+    /// Channels of the set, unnamed channels hashed with the preset name like in the firmware.
+    pub fn channels(&self) -> Result<Vec<MeshtasticChannel>, ChannelUrlError> {
+        let default_name = match (self.lora_config(), self.preset()) {
+            (Some(lora), None) if !lora.use_preset => "Custom",
+            (_, preset) => preset_name(preset.unwrap_or(ModemPreset::LongFast)),
+        };
+        self.channel_set
+            .settings
+            .iter()
+            .map(|settings| {
+                let hash_name = if settings.name.is_empty() {
+                    default_name
+                } else {
+                    &settings.name
+                };
+                MeshtasticChannel::from_psk(&settings.name, hash_name, &settings.psk)
+                    .map_err(|e| ChannelUrlError::InvalidKey(settings.name.clone(), e))
+            })
+            .collect()
+    }
+
+    /// Channel set for decoding, with the primary channel as fallback.
+    pub fn meshtastic_channels(&self) -> Result<MeshtasticChannels, ChannelUrlError> {
+        Ok(MeshtasticChannels::from_channels(self.channels()?))
+    }
+}
+
+impl Display for ChannelUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_url())
+    }
+}
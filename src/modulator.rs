@@ -12,6 +12,7 @@ pub struct Modulator {
     preamble_len: usize,
     pad_front: usize,
     pad_tail: usize,
+    invert_iq: bool,
 }
 
 impl Modulator {
@@ -21,6 +22,7 @@ impl Modulator {
         sync_words: Vec<usize>,
         preamble_len: usize,
         pad: usize,
+        invert_iq: bool,
     ) -> Self {
         if preamble_len < 5 {
             warn!("Preamble length should be at least 5!"); // TODO
@@ -50,15 +52,19 @@ impl Modulator {
             preamble_len,
             pad_front: pad,
             pad_tail: pad,
+            invert_iq,
         }
     }
 
     fn samples_from_phase_diff(&self, phase_increments: &[f32]) -> Vec<Complex32> {
+        // inverted IQ is the complex conjugate, i.e., the phase runs backwards
+        let sign = if self.invert_iq { -1.0 } else { 1.0 };
         let mut last_phase = 0.0;
         phase_increments
             .iter()
             .map(|p_i| {
-                let tmp = Complex32::new(1.0, 0.0) * Complex32::from_polar(1., last_phase + *p_i);
+                let tmp = Complex32::new(1.0, 0.0)
+                    * Complex32::from_polar(1., sign * (last_phase + *p_i));
                 last_phase += *p_i;
                 tmp
             })
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use anyhow::anyhow;
+use futuredsp::firdes::remez;
+use futuresdr::blocks::MessageAnnotator;
+use futuresdr::blocks::NullSink;
+use futuresdr::blocks::PfbArbResampler;
+use futuresdr::blocks::PfbChannelizer;
+use futuresdr::prelude::*;
+
+use crate::Decoder;
+use crate::Deinterleaver;
+use crate::FftDemod;
+use crate::FrameSync;
+use crate::GrayMapping;
+use crate::HammingDecoder;
+use crate::HeaderDecoder;
+use crate::HeaderMode;
+use crate::default_values::ldro;
+use crate::gateway_config::GatewayChannel;
+use crate::utils::Channel;
+use crate::utils::SpreadingFactor;
+
+/// Channel spacing of the common LoRaWAN plans.
+pub const DEFAULT_CHANNEL_SPACING: usize = 200_000;
+
+/// Forwards the messages of several inputs to one output, finishing once all inputs finished.
+#[derive(Block)]
+#[message_inputs(r#in)]
+#[message_outputs(out)]
+#[null_kernel]
+pub struct FrameMerger {
+    inputs: usize,
+    finished: usize,
+}
+
+impl FrameMerger {
+    pub fn new(inputs: usize) -> Self {
+        Self {
+            inputs,
+            finished: 0,
+        }
+    }
+
+    async fn r#in(
+        &mut self,
+        io: &mut WorkIo,
+        mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::Finished => {
+                self.finished += 1;
+                if self.finished >= self.inputs {
+                    io.finished = true;
+                }
+            }
+            p => mio.post("out", p).await?,
+        }
+        Ok(Pmt::Ok)
+    }
+}
+
+/// Channelizer layout covering all channels of a gateway on a common grid.
+#[derive(Debug, Clone)]
+pub struct ChannelizerPlan {
+    pub center_freq: f64,
+    pub sample_rate: f64,
+    pub channel_spacing: usize,
+    /// channel connected to each channelizer output, None for outputs without channel
+    pub ports: Vec<Option<GatewayChannel>>,
+}
+
+impl ChannelizerPlan {
+    /// Plan with the smallest band covering all channels, with an odd number of outputs so that the
+    /// center channel sits at DC.
+    pub fn new(channels: Vec<GatewayChannel>, channel_spacing: usize) -> Result<Self> {
+        let channels = Self::supported(channels, channel_spacing)?;
+        let min = channels
+            .iter()
+            .map(|c| Into::<u32>::into(c.channel) as usize)
+            .min()
+            .unwrap_or_default();
+        let max = channels
+            .iter()
+            .map(|c| Into::<u32>::into(c.channel) as usize)
+            .max()
+            .unwrap_or_default();
+        if let Some(c) = channels
+            .iter()
+            .find(|c| (Into::<u32>::into(c.channel) as usize - min) % channel_spacing != 0)
+        {
+            return Err(anyhow!(
+                "channel {} is not on the {}kHz grid",
+                c.channel,
+                channel_spacing / 1000
+            ));
+        }
+        let num_channels = ((max - min) / channel_spacing + 1) | 1;
+        let center_freq = (min + num_channels / 2 * channel_spacing) as f64;
+        Self::with_center(
+            channels,
+            channel_spacing,
+            center_freq,
+            (num_channels * channel_spacing) as f64,
+        )
+    }
+
+    /// Plan for a given center frequency and sample rate, which has to be a multiple of the channel spacing.
+    pub fn with_center(
+        channels: Vec<GatewayChannel>,
+        channel_spacing: usize,
+        center_freq: f64,
+        sample_rate: f64,
+    ) -> Result<Self> {
+        let channels = Self::supported(channels, channel_spacing)?;
+        let num_channels = (sample_rate / channel_spacing as f64).round() as usize;
+        if num_channels == 0 || num_channels * channel_spacing != sample_rate as usize {
+            return Err(anyhow!(
+                "sample rate {} is not a multiple of the {}kHz channel spacing",
+                sample_rate,
+                channel_spacing / 1000
+            ));
+        }
+        let mut plan = Self {
+            center_freq,
+            sample_rate,
+            channel_spacing,
+            ports: vec![None; num_channels],
+        };
+        for channel in channels {
+            let port = plan.port(channel.channel.into()).ok_or_else(|| {
+                anyhow!(
+                    "channel {} is not on the {}kHz grid around {:.3}MHz",
+                    channel.channel,
+                    channel_spacing / 1000,
+                    center_freq / 1e6
+                )
+            })?;
+            plan.ports[port] = Some(channel);
+        }
+        Ok(plan)
+    }
+
+    /// Channelizer output of a frequency, numbered from the center and wrapping around.
+    pub fn port(&self, freq: f64) -> Option<usize> {
+        let n = self.ports.len() as i64;
+        let offset = (freq - self.center_freq) / self.channel_spacing as f64;
+        if offset.fract() != 0.0 {
+            return None;
+        }
+        let offset = offset as i64;
+        let in_band = if n % 2 == 1 {
+            offset.abs() <= n / 2
+        } else {
+            (-n / 2..n / 2).contains(&offset)
+        };
+        in_band.then_some(offset.rem_euclid(n) as usize)
+    }
+
+    /// Drop channels not narrower than the channel spacing, their edges would alias into the neighbours.
+    fn supported(
+        channels: Vec<GatewayChannel>,
+        channel_spacing: usize,
+    ) -> Result<Vec<GatewayChannel>> {
+        let channels: Vec<GatewayChannel> = channels
+            .into_iter()
+            .filter(|c| {
+                let supported = Into::<usize>::into(c.bandwidth) < channel_spacing;
+                if !supported {
+                    warn!(
+                        "skipping {:.1}MHz channel: not narrower than the {}kHz channel spacing",
+                        Into::<f32>::into(c.channel) / 1.0e6,
+                        channel_spacing / 1000
+                    );
+                }
+                supported
+            })
+            .collect();
+        if channels.is_empty() {
+            return Err(anyhow!("no supported channels configured"));
+        }
+        Ok(channels)
+    }
+}
+
+/// Decode chain of one channel and spreading factor.
+pub struct GatewayChain {
+    pub channel: Channel,
+    pub spreading_factor: SpreadingFactor,
+    pub frame_sync: BlockRef<FrameSync>,
+    pub header_decoder: BlockRef<HeaderDecoder>,
+    pub decoder: BlockRef<Decoder>,
+}
+
+/// Blocks of a [`MultiChannelGateway`] added to a flowgraph.
+pub struct GatewayBlocks {
+    pub plan: ChannelizerPlan,
+    /// input of the wideband samples at `plan.center_freq` and `plan.sample_rate`
+    pub channelizer: BlockRef<PfbChannelizer>,
+    /// `out` of the merger carries the annotated frames of all chains, tagged with `sf`, `bw` and `freq`
+    pub merger: BlockRef<FrameMerger>,
+    pub chains: Vec<GatewayChain>,
+}
+
+/// Builder of a wideband receiver, decoding several channels with several spreading factors each.
+///
+/// A polyphase channelizer splits the band into channels on a common grid, each channel is resampled to
+/// the oversampling of the decoders and fed to one decode chain per spreading factor.
+pub struct MultiChannelGateway {
+    channels: Vec<GatewayChannel>,
+    channel_spacing: usize,
+    band: Option<(f64, f64)>,
+    oversampling: usize,
+    sync_words: Vec<Vec<usize>>,
+    stream_start_time: Option<SystemTime>,
+}
+
+impl MultiChannelGateway {
+    pub fn new(channels: Vec<GatewayChannel>) -> Self {
+        Self {
+            channels,
+            channel_spacing: DEFAULT_CHANNEL_SPACING,
+            band: None,
+            oversampling: 4,
+            sync_words: vec![vec![0x12], vec![0x34]],
+            stream_start_time: None,
+        }
+    }
+
+    pub fn channel_spacing(mut self, channel_spacing: usize) -> Self {
+        self.channel_spacing = channel_spacing;
+        self
+    }
+
+    /// Fix center frequency and sample rate, instead of covering just the channels.
+    pub fn band(mut self, center_freq: f64, sample_rate: f64) -> Self {
+        self.band = Some((center_freq, sample_rate));
+        self
+    }
+
+    pub fn oversampling(mut self, oversampling: usize) -> Self {
+        self.oversampling = oversampling;
+        self
+    }
+
+    pub fn sync_words(mut self, sync_words: Vec<Vec<usize>>) -> Self {
+        self.sync_words = sync_words;
+        self
+    }
+
+    /// Reference for the timestamps of received frames.
+    pub fn stream_start_time(mut self, stream_start_time: SystemTime) -> Self {
+        self.stream_start_time = Some(stream_start_time);
+        self
+    }
+
+    pub fn plan(&self) -> Result<ChannelizerPlan> {
+        match self.band {
+            Some((center_freq, sample_rate)) => ChannelizerPlan::with_center(
+                self.channels.clone(),
+                self.channel_spacing,
+                center_freq,
+                sample_rate,
+            ),
+            None => ChannelizerPlan::new(self.channels.clone(), self.channel_spacing),
+        }
+    }
+
+    /// Add channelizer, resamplers and decode chains to the flowgraph.
+    pub fn build(self, fg: &mut Flowgraph) -> Result<GatewayBlocks> {
+        let plan = self.plan()?;
+        let num_ports = plan.ports.len();
+        let spacing = self.channel_spacing as f64;
+
+        let transition_bw = plan
+            .ports
+            .iter()
+            .flatten()
+            .map(|c| (spacing - Into::<f64>::into(c.bandwidth)) / spacing)
+            .fold(f64::MAX, f64::min);
+        let channelizer_taps: Vec<f32> = remez::low_pass(
+            1.,
+            num_ports,
+            0.5 - transition_bw / 2.,
+            0.5 + transition_bw / 2.,
+            0.1,
+            100.,
+            None,
+        )
+        .into_iter()
+        .map(|x| x as f32)
+        .collect();
+        let channelizer = fg.add_block(PfbChannelizer::new(num_ports, &channelizer_taps, 1.0));
+
+        let num_chains = plan
+            .ports
+            .iter()
+            .flatten()
+            .map(|c| c.spreading_factors.len())
+            .sum();
+        let merger = fg.add_block(FrameMerger::new(num_chains));
+        let mut chains = Vec::new();
+
+        for (n_out, gateway_channel) in plan.ports.iter().enumerate() {
+            let Some(gateway_channel) = gateway_channel else {
+                // map unused channelizer outputs to null-sink
+                let null_sink = fg.add_block(NullSink::<Complex32>::new());
+                fg.connect_dyn(&channelizer, format!("out{n_out}"), null_sink, "in")?;
+                continue;
+            };
+            let channel = gateway_channel.channel;
+            let bandwidth = gateway_channel.bandwidth;
+            let bw = Into::<f64>::into(bandwidth);
+            let resampler_taps: Vec<f32> = remez::low_pass(
+                1.,
+                5,
+                bw / (2.0 * spacing),
+                (bw / 2.0 + (spacing - bw)) / spacing,
+                0.1,
+                100.,
+                None,
+            )
+            .into_iter()
+            .map(|x| x as f32)
+            .collect();
+            let rate = bw * self.oversampling as f64 / spacing;
+            let resampler = fg.add_block(PfbArbResampler::new(rate as f32, &resampler_taps, 5));
+            fg.connect_dyn(&channelizer, format!("out{n_out}"), &resampler, "in")?;
+            info!(
+                "connecting {:.3}MHz chain to channelizer output {}",
+                Into::<f64>::into(channel) / 1.0e6,
+                n_out
+            );
+
+            for sf in gateway_channel.spreading_factors.iter().copied() {
+                let frame_sync: FrameSync = FrameSync::new(
+                    channel,
+                    bandwidth,
+                    sf,
+                    false,
+                    self.sync_words.clone(),
+                    self.oversampling,
+                    None,
+                    None,
+                    false,
+                    self.stream_start_time,
+                    false,
+                );
+                let fft_demod: FftDemod = FftDemod::new(sf, ldro(sf));
+                let gray_mapping: GrayMapping = GrayMapping::new();
+                let deinterleaver: Deinterleaver = Deinterleaver::new(ldro(sf), sf);
+                let hamming_dec: HammingDecoder = HammingDecoder::new();
+                let header_decoder = HeaderDecoder::new(HeaderMode::Explicit, ldro(sf));
+                let decoder = Decoder::new();
+                let tags: HashMap<String, Pmt> = HashMap::from([
+                    (String::from("sf"), Pmt::U32(sf.into())),
+                    (
+                        String::from("bw"),
+                        Pmt::U32(Into::<u32>::into(bandwidth) / 1000),
+                    ),
+                    (String::from("freq"), Pmt::F64(Into::<f64>::into(channel))),
+                ]);
+                let metadata_tagger = MessageAnnotator::new(tags, None);
+                let resampler = resampler.clone();
+                let merger = merger.clone();
+                connect!(fg,
+                    resampler > frame_sync > fft_demod > gray_mapping > deinterleaver > hamming_dec > header_decoder;
+                    header_decoder.frame_info | frame_info.frame_sync;
+                    header_decoder | decoder;
+                    decoder.out_annotated | metadata_tagger;
+                    metadata_tagger | merger;
+                );
+                chains.push(GatewayChain {
+                    channel,
+                    spreading_factor: sf,
+                    frame_sync,
+                    header_decoder,
+                    decoder,
+                });
+            }
+        }
+
+        Ok(GatewayBlocks {
+            plan,
+            channelizer,
+            merger,
+            chains,
+        })
+    }
+}
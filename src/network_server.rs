@@ -0,0 +1,702 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::anyhow;
+use base64::prelude::*;
+use futuresdr::tracing::info;
+use futuresdr::tracing::warn;
+use serde_json::Value;
+use serde_json::json;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::Instant;
+
+use crate::adr::AdrConfig;
+use crate::adr::AdrDecision;
+use crate::adr::AdrEngine;
+use crate::lorawan::FCtrl;
+use crate::lorawan::JoinAccept;
+use crate::lorawan::MHdr;
+use crate::lorawan::MType;
+use crate::lorawan::MacPayload;
+use crate::lorawan::PhyPayload;
+use crate::lorawan_keys::DecryptedFrame;
+use crate::lorawan_keys::KeyStore;
+use crate::lorawan_keys::MicContext;
+use crate::lorawan_keys::SecurityEvent;
+use crate::lorawan_keys::SessionKeys;
+use crate::region::Region;
+use crate::utils::Bandwidth;
+use crate::utils::SpreadingFactor;
+
+const PROTOCOL_VERSION: u8 = 2;
+const PUSH_DATA: u8 = 0x00;
+const PUSH_ACK: u8 = 0x01;
+const PULL_DATA: u8 = 0x02;
+const PULL_RESP: u8 = 0x03;
+const PULL_ACK: u8 = 0x04;
+const TX_ACK: u8 = 0x05;
+
+#[derive(Debug, Clone)]
+pub struct NetworkServerConfig {
+    pub bind: SocketAddr,
+    /// regional plan of the RX1 channel and data rate
+    pub region: Region,
+    pub net_id: u32,
+    /// first DevAddr handed out to joining devices
+    pub dev_addr_base: u32,
+    /// time to collect copies of an uplink from further gateways
+    pub dedup_window: Duration,
+    pub rx1_delay: Duration,
+    pub join_accept_delay: Duration,
+    /// TX power of downlinks in dBm
+    pub tx_power: i64,
+    /// adaptive data rate for devices setting the ADR bit, `None` to disable
+    pub adr: Option<AdrConfig>,
+}
+
+impl Default for NetworkServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 1700)),
+            region: Region::EU868,
+            net_id: 0,
+            dev_addr_base: 0x2600_0000,
+            dedup_window: Duration::from_millis(200),
+            rx1_delay: Duration::from_secs(1),
+            join_accept_delay: Duration::from_secs(5),
+            tx_power: 14,
+            adr: Some(AdrConfig::default()),
+        }
+    }
+}
+
+/// Events reported by the [`NetworkServer`].
+#[derive(Debug, Clone)]
+pub enum NsEvent {
+    Joined {
+        device: String,
+        dev_eui: u64,
+        dev_addr: u32,
+    },
+    Uplink {
+        device: String,
+        dev_addr: u32,
+        fcnt: u32,
+        fport: Option<u8>,
+        payload: Vec<u8>,
+        confirmed: bool,
+        /// number of gateways that received the frame
+        gateways: usize,
+    },
+    TxAck {
+        gateway: u64,
+        error: Option<String>,
+    },
+    /// LinkADRReq sent to the device
+    Adr {
+        dev_addr: u32,
+        decision: AdrDecision,
+    },
+}
+
+/// Queue application downlinks of a running [`NetworkServer`].
+#[derive(Clone)]
+pub struct NetworkServerHandle {
+    commands: UnboundedSender<(u32, u8, Vec<u8>)>,
+}
+
+impl NetworkServerHandle {
+    /// Send `payload` in the next RX1 window of the device.
+    pub fn queue_downlink(&self, dev_addr: u32, fport: u8, payload: Vec<u8>) -> anyhow::Result<()> {
+        self.commands
+            .send((dev_addr, fport, payload))
+            .map_err(|_| anyhow!("network server stopped"))
+    }
+}
+
+/// copy of an uplink received by one gateway
+#[derive(Debug, Clone)]
+struct Reception {
+    gateway: u64,
+    rxpk: Value,
+    snr: f64,
+}
+
+struct PendingUplink {
+    deadline: Instant,
+    receptions: Vec<Reception>,
+}
+
+/// Minimal LoRaWAN 1.0.x network server speaking the Semtech UDP protocol (GWMP).
+///
+/// It deduplicates uplinks received by several gateways, answers OTAA joins with a Join-Accept,
+/// acknowledges confirmed uplinks and sends ADR requests through the gateway with the best SNR.
+/// Meant for local end-to-end tests of the [`PacketForwarderClient`](crate::PacketForwarderClient).
+pub struct NetworkServer {
+    config: NetworkServerConfig,
+    socket: UdpSocket,
+    keys: KeyStore,
+    /// address of the last PULL_DATA per gateway, used for downlinks
+    gateways: HashMap<u64, SocketAddr>,
+    pending: HashMap<Vec<u8>, PendingUplink>,
+    fcnt_down: HashMap<u32, u32>,
+    queue: HashMap<u32, VecDeque<(u8, Vec<u8>)>>,
+    adr: Option<AdrEngine>,
+    next_dev_addr: u32,
+    join_nonce: u32,
+    /// DevNonces of the accepted Join-Requests per DevEUI, a repeated one is a replay
+    dev_nonces: HashMap<u64, HashSet<u16>>,
+    events: Vec<UnboundedSender<NsEvent>>,
+    commands: UnboundedReceiver<(u32, u8, Vec<u8>)>,
+    command_sender: UnboundedSender<(u32, u8, Vec<u8>)>,
+}
+
+impl NetworkServer {
+    pub async fn bind(config: NetworkServerConfig, keys: KeyStore) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(config.bind)
+            .await
+            .with_context(|| format!("binding {}", config.bind))?;
+        let (command_sender, commands) = unbounded_channel();
+        Ok(Self {
+            next_dev_addr: config.dev_addr_base,
+            adr: config.adr.clone().map(AdrEngine::new),
+            config,
+            socket,
+            keys,
+            gateways: HashMap::new(),
+            pending: HashMap::new(),
+            fcnt_down: HashMap::new(),
+            queue: HashMap::new(),
+            join_nonce: 0,
+            dev_nonces: HashMap::new(),
+            events: Vec::new(),
+            commands,
+            command_sender,
+        })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn handle(&self) -> NetworkServerHandle {
+        NetworkServerHandle {
+            commands: self.command_sender.clone(),
+        }
+    }
+
+    pub fn subscribe(&mut self) -> UnboundedReceiver<NsEvent> {
+        let (tx, rx) = unbounded_channel();
+        self.events.push(tx);
+        rx
+    }
+
+    fn emit(&mut self, event: NsEvent) {
+        self.events.retain(|s| s.send(event.clone()).is_ok());
+    }
+
+    /// Serve gateways until the socket fails.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let next_deadline = self.pending.values().map(|p| p.deadline).min();
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => {
+                    let (n, addr) = received?;
+                    if let Err(e) = self.handle_packet(&buf[..n], addr).await {
+                        warn!("NetworkServer: invalid packet from {}: {}", addr, e);
+                    }
+                }
+                Some((dev_addr, fport, payload)) = self.commands.recv() => {
+                    self.queue.entry(dev_addr).or_default().push_back((fport, payload));
+                }
+                _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                    let now = Instant::now();
+                    let due: Vec<Vec<u8>> = self
+                        .pending
+                        .iter()
+                        .filter(|(_, p)| p.deadline <= now)
+                        .map(|(k, _)| k.clone())
+                        .collect();
+                    for raw in due {
+                        let pending = self.pending.remove(&raw).unwrap();
+                        if let Err(e) = self.process_uplink(&raw, pending.receptions).await {
+                            info!("NetworkServer: dropping uplink: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: &[u8], addr: SocketAddr) -> anyhow::Result<()> {
+        if packet.len() < 4 || packet[0] != PROTOCOL_VERSION {
+            return Err(anyhow!("unsupported protocol version"));
+        }
+        let token = [packet[1], packet[2]];
+        let identifier = packet[3];
+        let gateway = packet
+            .get(4..12)
+            .map(|g| u64::from_be_bytes(g.try_into().unwrap()));
+        match (identifier, gateway) {
+            (PUSH_DATA, Some(gateway)) => {
+                self.socket
+                    .send_to(&[PROTOCOL_VERSION, token[0], token[1], PUSH_ACK], addr)
+                    .await?;
+                let data: Value = serde_json::from_slice(&packet[12..])?;
+                for rxpk in data
+                    .get("rxpk")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                {
+                    if let Err(e) = self.receive(gateway, rxpk.clone()) {
+                        warn!("NetworkServer: invalid rxpk from {:016x}: {}", gateway, e);
+                        continue;
+                    }
+                }
+            }
+            (PULL_DATA, Some(gateway)) => {
+                self.socket
+                    .send_to(&[PROTOCOL_VERSION, token[0], token[1], PULL_ACK], addr)
+                    .await?;
+                if self.gateways.insert(gateway, addr).is_none() {
+                    info!(
+                        "NetworkServer: gateway {:016x} connected from {}",
+                        gateway, addr
+                    );
+                }
+            }
+            (TX_ACK, Some(gateway)) => {
+                let error = serde_json::from_slice::<Value>(&packet[12..])
+                    .ok()
+                    .and_then(|v| {
+                        v.pointer("/txpk_ack/error")
+                            .and_then(Value::as_str)
+                            .map(String::from)
+                    })
+                    .filter(|e| e != "NONE");
+                self.emit(NsEvent::TxAck { gateway, error });
+            }
+            (identifier, _) => {
+                return Err(anyhow!("unexpected packet type {identifier:#04x}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect a reception, the uplink is processed when its deduplication window closes.
+    fn receive(&mut self, gateway: u64, rxpk: Value) -> anyhow::Result<()> {
+        let raw = rxpk
+            .get("data")
+            .and_then(Value::as_str)
+            .and_then(|d| BASE64_STANDARD.decode(d).ok())
+            .ok_or_else(|| anyhow!("rxpk without data"))?;
+        let snr = rxpk.get("lsnr").and_then(Value::as_f64).unwrap_or(f64::MIN);
+        let deadline = Instant::now() + self.config.dedup_window;
+        self.pending
+            .entry(raw)
+            .or_insert_with(|| PendingUplink {
+                deadline,
+                receptions: Vec::new(),
+            })
+            .receptions
+            .push(Reception { gateway, rxpk, snr });
+        Ok(())
+    }
+
+    async fn process_uplink(
+        &mut self,
+        raw: &[u8],
+        receptions: Vec<Reception>,
+    ) -> anyhow::Result<()> {
+        let phy = PhyPayload::parse(raw)?;
+        let best = receptions
+            .iter()
+            .max_by(|a, b| a.snr.total_cmp(&b.snr))
+            .cloned()
+            .ok_or_else(|| anyhow!("uplink without reception"))?;
+
+        match self.keys.process(raw, &phy)? {
+            SecurityEvent::JoinRequest { device, dev_nonce } => {
+                let MacPayload::JoinRequest(request) = &phy.payload else {
+                    unreachable!()
+                };
+                let otaa = self
+                    .keys
+                    .otaa_device(request.dev_eui)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("unknown device"))?;
+                if !self
+                    .dev_nonces
+                    .entry(request.dev_eui)
+                    .or_default()
+                    .insert(dev_nonce)
+                {
+                    return Err(anyhow!("{} reused DevNonce {}", device, dev_nonce));
+                }
+                let dev_addr = self.next_dev_addr;
+                self.next_dev_addr = self.next_dev_addr.wrapping_add(1);
+                self.join_nonce = (self.join_nonce + 1) & 0xff_ffff;
+                let join_accept = JoinAccept {
+                    join_nonce: self.join_nonce,
+                    net_id: self.config.net_id,
+                    dev_addr,
+                    opt_neg: false,
+                    rx1_dr_offset: 0,
+                    rx2_data_rate: self.config.region.rx2().1,
+                    rx_delay: self.config.rx1_delay.as_secs() as u8,
+                    cf_list: None,
+                };
+                let keys = SessionKeys::derive(
+                    &otaa.app_key,
+                    otaa.nwk_key.as_ref(),
+                    &join_accept,
+                    request.join_eui,
+                    dev_nonce,
+                );
+                self.keys.add_session(device.clone(), dev_addr, keys);
+                self.fcnt_down.insert(dev_addr, 0);
+                info!("NetworkServer: {} joined as {:08X}", device, dev_addr);
+                self.emit(NsEvent::Joined {
+                    device,
+                    dev_eui: request.dev_eui,
+                    dev_addr,
+                });
+                let frame = otaa.seal_join_accept(&join_accept);
+                self.send_downlink(&best, self.config.join_accept_delay, frame)
+                    .await?;
+            }
+            SecurityEvent::Data(frame) => {
+                let confirmed = phy.mhdr.mtype.is_confirmed();
+                let MacPayload::Data(data) = &phy.payload else {
+                    unreachable!()
+                };
+                if frame.retransmission && !confirmed {
+                    return Ok(());
+                }
+                // a repeated confirmed uplink lost its ACK, acknowledge it again without delivering it
+                let (fopts, queued) = if frame.retransmission {
+                    (Vec::new(), None)
+                } else {
+                    let fopts = self.adr(&frame, data.fctrl, &best);
+                    self.emit(NsEvent::Uplink {
+                        device: frame.device,
+                        dev_addr: frame.dev_addr,
+                        fcnt: frame.fcnt,
+                        fport: frame.fport,
+                        payload: frame.payload,
+                        confirmed,
+                        gateways: receptions.len(),
+                    });
+                    let queued = self
+                        .queue
+                        .get_mut(&frame.dev_addr)
+                        .and_then(|q| q.pop_front());
+                    (fopts, queued)
+                };
+                // ADRACKReq asks for any downlink to confirm the link
+                if !confirmed && queued.is_none() && fopts.is_empty() && !data.fctrl.adr_ack_req {
+                    return Ok(());
+                }
+                let keys = self
+                    .keys
+                    .session_keys(frame.dev_addr)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("no session"))?;
+                let fcnt = self.fcnt_down.entry(frame.dev_addr).or_insert(0);
+                let (fport, payload) = match &queued {
+                    Some((fport, payload)) => (Some(*fport), payload.as_slice()),
+                    None => (None, &[][..]),
+                };
+                let downlink = keys.seal_data(
+                    MHdr {
+                        mtype: MType::UnconfirmedDataDown,
+                        major: 0,
+                    },
+                    frame.dev_addr,
+                    FCtrl {
+                        ack: confirmed,
+                        ..FCtrl::default()
+                    },
+                    *fcnt,
+                    &fopts,
+                    fport,
+                    payload,
+                    MicContext {
+                        conf_fcnt: frame.fcnt,
+                        ..MicContext::default()
+                    },
+                );
+                *fcnt += 1;
+                self.send_downlink(&best, self.config.rx1_delay, downlink)
+                    .await?;
+            }
+            SecurityEvent::Joined { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Run ADR on an uplink, returns the FOpts of the answer.
+    fn adr(&mut self, frame: &DecryptedFrame, fctrl: FCtrl, best: &Reception) -> Vec<u8> {
+        let Some(adr) = self.adr.as_mut() else {
+            return Vec::new();
+        };
+        for cmd in &frame.mac_commands {
+            adr.acknowledge(frame.dev_addr, cmd);
+        }
+        let Some(data_rate) = self.data_rate(best) else {
+            return Vec::new();
+        };
+        adr.record(frame.dev_addr, frame.fcnt, best.snr, data_rate);
+        if !fctrl.adr {
+            return Vec::new();
+        }
+        let Some(decision) = adr.decide(frame.dev_addr) else {
+            return Vec::new();
+        };
+        let fopts = decision.link_adr_req(adr.config().ch_mask).to_bytes();
+        self.emit(NsEvent::Adr {
+            dev_addr: frame.dev_addr,
+            decision,
+        });
+        fopts
+    }
+
+    /// Data rate index of an uplink, from the `datr` of the rxpk.
+    fn data_rate(&self, reception: &Reception) -> Option<u8> {
+        let (sf, bw) = reception
+            .rxpk
+            .get("datr")
+            .and_then(Value::as_str)?
+            .strip_prefix("SF")?
+            .split_once("BW")?;
+        let sf = SpreadingFactor::try_from(sf.parse::<u8>().ok()?).ok()?;
+        let bw = Bandwidth::try_from(bw.parse::<u32>().ok()? * 1000).ok()?;
+        self.config.region.find_data_rate(sf, bw)
+    }
+
+    /// Frequency and data rate of RX1, the uplink ones if the region does not know the uplink.
+    fn rx1(&self, reception: &Reception) -> (Value, Value) {
+        let region = self.config.region;
+        let uplink_freq = reception.rxpk.get("freq").cloned().unwrap_or_default();
+        let uplink_datr = reception.rxpk.get("datr").cloned().unwrap_or_default();
+        let freq = uplink_freq
+            .as_f64()
+            .and_then(|f| region.rx1_channel((f * 1e6).round() as u32))
+            .map(|c| json!(Into::<f64>::into(c) / 1e6))
+            .unwrap_or(uplink_freq);
+        let datr = self
+            .data_rate(reception)
+            .and_then(|dr| region.rx1_data_rate(dr, 0, false))
+            .and_then(|dr| region.data_rate(dr)?.datr())
+            .map(Value::from)
+            .unwrap_or(uplink_datr);
+        (freq, datr)
+    }
+
+    /// Answer in RX1: frequency and data rate from [`Self::rx1`], delayed from the uplink's `tmst`.
+    async fn send_downlink(
+        &mut self,
+        reception: &Reception,
+        delay: Duration,
+        frame: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let addr = *self
+            .gateways
+            .get(&reception.gateway)
+            .ok_or_else(|| anyhow!("gateway {:016x} did not send PULL_DATA", reception.gateway))?;
+        let tmst = reception
+            .rxpk
+            .get("tmst")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("rxpk without tmst"))? as u32;
+        let (freq, datr) = self.rx1(reception);
+        let txpk = json!({
+            "txpk": {
+                "imme": false,
+                "tmst": tmst.wrapping_add(delay.as_micros() as u32),
+                "freq": freq,
+                "rfch": 0,
+                "powe": self.config.tx_power,
+                "modu": "LORA",
+                "datr": datr,
+                "codr": "4/5",
+                "ipol": true,
+                "size": frame.len(),
+                "data": BASE64_STANDARD.encode(&frame),
+                "ncrc": true,
+            }
+        });
+        let token: u16 = rand::random();
+        let mut packet = vec![PROTOCOL_VERSION];
+        packet.extend_from_slice(&token.to_be_bytes());
+        packet.push(PULL_RESP);
+        packet.extend_from_slice(txpk.to_string().as_bytes());
+        self.socket.send_to(&packet, addr).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lorawan_keys::OtaaDevice;
+    use crate::lorawan_keys::parse_key;
+
+    const DEV_ADDR: u32 = 0x49BE7DF1;
+
+    /// GWMP gateway talking to the server under test
+    struct Gateway {
+        eui: u64,
+        socket: UdpSocket,
+    }
+
+    impl Gateway {
+        async fn connect(eui: u64, server: SocketAddr) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(server).await.unwrap();
+            let gateway = Self { eui, socket };
+            gateway.send(PULL_DATA, &[]).await;
+            assert_eq!(gateway.recv().await.unwrap()[3], PULL_ACK);
+            gateway
+        }
+
+        async fn send(&self, identifier: u8, body: &[u8]) {
+            let mut packet = vec![PROTOCOL_VERSION, 0x12, 0x34, identifier];
+            packet.extend_from_slice(&self.eui.to_be_bytes());
+            packet.extend_from_slice(body);
+            self.socket.send(&packet).await.unwrap();
+        }
+
+        async fn push(&self, frame: &[u8], lsnr: f64) {
+            let data = json!({
+                "rxpk": [{
+                    "tmst": 1_000_000,
+                    "freq": 868.1,
+                    "datr": "SF7BW125",
+                    "codr": "4/5",
+                    "lsnr": lsnr,
+                    "rssi": -80,
+                    "size": frame.len(),
+                    "data": BASE64_STANDARD.encode(frame),
+                }]
+            });
+            self.send(PUSH_DATA, data.to_string().as_bytes()).await;
+            assert_eq!(self.recv().await.unwrap()[3], PUSH_ACK);
+        }
+
+        /// next packet from the server, `None` if there is none within half a second
+        async fn recv(&self) -> Option<Vec<u8>> {
+            let mut buf = vec![0u8; 2048];
+            let len = tokio::time::timeout(Duration::from_millis(500), self.socket.recv(&mut buf))
+                .await
+                .ok()?
+                .unwrap();
+            buf.truncate(len);
+            Some(buf)
+        }
+    }
+
+    async fn start(keys: KeyStore) -> (SocketAddr, UnboundedReceiver<NsEvent>) {
+        let config = NetworkServerConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+            dedup_window: Duration::from_millis(50),
+            adr: None,
+            ..NetworkServerConfig::default()
+        };
+        let mut server = NetworkServer::bind(config, keys).await.unwrap();
+        let events = server.subscribe();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        (addr, events)
+    }
+
+    #[tokio::test]
+    async fn dev_nonce_replay() {
+        let device = OtaaDevice {
+            name: String::from("otaa"),
+            dev_eui: 0x0004_a30b_001c_0530,
+            join_eui: 0x70b3_d57e_d000_0001,
+            app_key: parse_key("b6b53f4a168a7a88bdf7ea135ce9cfca").unwrap(),
+            nwk_key: None,
+        };
+        let mut keys = KeyStore::new();
+        keys.add_otaa(device.clone());
+        let (server, mut events) = start(keys).await;
+        let gateway = Gateway::connect(1, server).await;
+
+        gateway.push(&device.seal_join_request(1), 5.0).await;
+        let Some(NsEvent::Joined { dev_addr, .. }) = events.recv().await else {
+            panic!("join not accepted");
+        };
+        assert_eq!(dev_addr, 0x2600_0000);
+        assert_eq!(gateway.recv().await.unwrap()[3], PULL_RESP);
+
+        // a replayed Join-Request is not answered
+        gateway.push(&device.seal_join_request(1), 5.0).await;
+        assert!(gateway.recv().await.is_none());
+        assert!(events.try_recv().is_err());
+
+        gateway.push(&device.seal_join_request(2), 5.0).await;
+        let Some(NsEvent::Joined { dev_addr, .. }) = events.recv().await else {
+            panic!("join with a new DevNonce not accepted");
+        };
+        assert_eq!(dev_addr, 0x2600_0001);
+    }
+
+    #[tokio::test]
+    async fn dedup_answers_through_best_gateway() {
+        let nwk_s_key = parse_key("44024241ed4ce9a68c6a8bc055233fd3").unwrap();
+        let app_s_key = parse_key("ec925802ae430ca77fd3dd73cb2cc588").unwrap();
+        let mut keys = KeyStore::new();
+        keys.add_abp(String::from("abp"), DEV_ADDR, nwk_s_key, app_s_key);
+        let (server, mut events) = start(keys).await;
+        let weak = Gateway::connect(1, server).await;
+        let strong = Gateway::connect(2, server).await;
+
+        let frame = SessionKeys::from_abp(nwk_s_key, app_s_key).seal_data(
+            MHdr::from(0x80),
+            DEV_ADDR,
+            FCtrl::default(),
+            2,
+            &[],
+            Some(1),
+            b"test",
+            MicContext::default(),
+        );
+        weak.push(&frame, -5.0).await;
+        strong.push(&frame, 3.0).await;
+        let Some(NsEvent::Uplink {
+            fcnt,
+            payload,
+            confirmed,
+            gateways,
+            ..
+        }) = events.recv().await
+        else {
+            panic!("uplink not delivered");
+        };
+        assert_eq!((fcnt, confirmed, gateways), (2, true, 2));
+        assert_eq!(payload, b"test");
+
+        // the ACK goes out once, through the gateway with the best SNR
+        let pull_resp = strong.recv().await.unwrap();
+        assert_eq!(pull_resp[3], PULL_RESP);
+        let txpk: Value = serde_json::from_slice(&pull_resp[4..]).unwrap();
+        assert_eq!(txpk["txpk"]["tmst"], 2_000_000);
+        assert!(weak.recv().await.is_none());
+
+        // a retransmission is acknowledged again but not delivered twice
+        weak.push(&frame, -5.0).await;
+        assert_eq!(weak.recv().await.unwrap()[3], PULL_RESP);
+        assert!(events.try_recv().is_err());
+    }
+}
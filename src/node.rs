@@ -3,12 +3,19 @@ use std::sync::Arc;
 
 use crossbeam_channel::{Receiver, Sender};
 use futuredsp::firdes;
-use futuresdr::{async_io::Timer, blocks::{BlobToUdp, XlatingFir}, macros::connect, prelude::{Complex32, DefaultCpuReader, DefaultCpuWriter}, runtime::{BlockId, BlockRef, Flowgraph, FlowgraphHandle, Pmt, WrappedKernel, scheduler::SmolScheduler}, tracing::Instrument};
+use futuresdr::{async_io::Timer, blocks::{BlobToUdp, MessagePipe, XlatingFir}, channel::mpsc, macros::connect, prelude::{Complex32, DefaultCpuReader, DefaultCpuWriter}, runtime::{BlockId, BlockRef, Flowgraph, FlowgraphHandle, Pmt, WrappedKernel, scheduler::SmolScheduler}, tracing::Instrument};
 use futuresdr::runtime::Runtime;
 use anyhow::Result;
-use tokio::{net::UdpSocket, task::JoinHandle};
+use anyhow::anyhow;
+use std::collections::HashMap;
+use tokio::{net::UdpSocket, sync::mpsc::{UnboundedReceiver, unbounded_channel}, task::JoinHandle};
 
-use crate::{AddAWGN, ChannelPublisher, ChannelSubscriber, Decoder, Deinterleaver, FftDemod, FrameSync, GrayMapping, HammingDecoder, HeaderDecoder, HeaderMode, IqFrame, Transmitter, frame_sync, utils::{
+use crate::adr::AdrDecision;
+use crate::region::Region;
+use crate::end_device::{EndDevice, EndDeviceConfig, EndDeviceHandle};
+use crate::meshtastic_api::{ApiConfig, MeshtasticApiServer};
+use crate::meshtastic_router::{MeshtasticRouter, RouterConfig, RouterHandle};
+use crate::{AddAWGN, ChannelPublisher, ChannelSubscriber, Decoder, Deinterleaver, FftDemod, FrameSync, GrayMapping, HammingDecoder, HeaderDecoder, HeaderMode, IqFrame, Transmitter, frame_sync, kiss_driver::{create_cmd, descape, kiss}, mac::{BROADCAST_ADDR, Mac, MacConfig, MacEvent, MacHandle, Priority, RxFrame, TxRequest}, utils::{
     Bandwidth, Channel, CodeRate, SpreadingFactor
 }};
 
@@ -21,15 +28,24 @@ pub struct Node {
     sf : SpreadingFactor,
     sync_word : u8, 
     oversampling : usize,
+    interpolation: usize,
     sigma : f32,
     
     //DSP interface
     transmitter: BlockRef<Transmitter>,
+    frame_syncs: Vec<(BlockRef<FrameSync>, SpreadingFactor)>,
+    awgn: BlockRef<AddAWGN<DefaultCpuReader<Complex32>, DefaultCpuWriter<Complex32>>>,
+    rx_pipe: BlockRef<MessagePipe>,
     pub fg : Option<Flowgraph>,
+    handle: Option<FlowgraphHandle>,
 
     //aka MAC interface
     remote_port: u16, // remote port
     local_port : u16, // node rcv port
+    mac_config: MacConfig,
+    mac: Option<MacHandle>,
+    tx_done: Option<mpsc::Receiver<Pmt>>,
+    rx_frames: Option<mpsc::Receiver<Pmt>>,
 }
 
 impl Node {
@@ -49,6 +65,8 @@ impl Node {
         local_port: u16,
         remote_port: u16,
 
+        mac_config: MacConfig,
+
         // rt : Runtime<'_, SmolScheduler>,
     ) -> Result<Self> {
         //TODO: coderate setup
@@ -87,12 +105,13 @@ impl Node {
             bw,
             sf,
             implicit_header,
-            vec![vec![16, 88]],
+            vec![vec![sync_word as usize]],
             interpolation,
             None,
             Some("header_crc_ok"),
             false,
             None,
+            false,
         );
 
         let fft_demod: FftDemod = FftDemod::new(sf, ldro);
@@ -118,14 +137,21 @@ impl Node {
             ldro,
             implicit_header,
             interpolation,
-            vec![16, 88],
+            vec![sync_word as usize],
             8,
             10000,
+            false,
         );
         let publisher = ChannelPublisher
         ::<DefaultCpuReader<Complex32>>
         ::new(sender);
 
+        //mac feedback
+        let (tx_done_tx, tx_done) = mpsc::channel::<Pmt>(16);
+        let tx_done_pipe = MessagePipe::new(tx_done_tx);
+        let (rx_frames_tx, rx_frames) = mpsc::channel::<Pmt>(64);
+        let rx_pipe = MessagePipe::new(rx_frames_tx);
+
         //flowgraph connection
         let mut fg = Flowgraph::new();
         
@@ -139,10 +165,15 @@ impl Node {
             header_decoder            | decoder;
             header_decoder.kiss       | udp_data;
             decoder.crc_check         | payload_crc_result.frame_sync;
-            decoder.kiss              | udp_data;
+            decoder.out_annotated     | rx_pipe;
             // tx graph
             transmitter > publisher;
-        );    
+            transmitter.tx_done       | tx_done_pipe;
+        );
+        if !mac_config.framing {
+            // without framing payloads are passed to the client unchanged, otherwise the MAC strips the header first
+            connect!(fg, decoder.kiss | udp_data);
+        }
 
         
         println!("flowgraph started");
@@ -153,15 +184,67 @@ impl Node {
             sf,
             sync_word,
             oversampling,
+            interpolation,
             sigma,
             fg: Some(fg),
             transmitter: transmitter,
+            frame_syncs: vec![(frame_sync, sf)],
+            awgn,
+            rx_pipe,
+            handle: None,
             remote_port,
             local_port,
+            mac_config,
+            mac: None,
+            tx_done: Some(tx_done),
+            rx_frames: Some(rx_frames),
         })
     }
 
-    async fn server_task_body(mut handle: FlowgraphHandle, tx_id : BlockId, local_port: u16) {
+    /// Parse a datagram of the UDP client into a transmit request.
+    ///
+    /// `FEND CMD_TX_REQ <prio> <flags> <dest> <data..> FEND` selects priority, receiver and ACK, anything else is broadcast as is with normal priority.
+    fn parse_tx_request(datagram: &[u8], id: u16) -> (TxRequest, bool) {
+        if datagram.len() >= 6
+            && datagram[0] == kiss::FEND
+            && datagram[1] == kiss::CMD_TX_REQ
+            && datagram[datagram.len() - 1] == kiss::FEND
+        {
+            let body = descape(&datagram[2..datagram.len() - 1]);
+            if body.len() >= 3 {
+                return (
+                    TxRequest {
+                        id,
+                        payload: body[3..].to_vec(),
+                        priority: Priority::from(body[0]).min(Priority::High),
+                        dest: body[2],
+                        want_ack: body[1] & kiss::TX_FLAG_ACK != 0,
+                        not_before: None,
+                    },
+                    false,
+                );
+            }
+        }
+        (
+            TxRequest {
+                id,
+                payload: datagram.to_vec(),
+                priority: Priority::Normal,
+                dest: BROADCAST_ADDR,
+                want_ack: false,
+                not_before: None,
+            },
+            true,
+        )
+    }
+
+    async fn server_task_body(
+        mac: MacHandle,
+        mut events: UnboundedReceiver<MacEvent>,
+        mut rx: Option<UnboundedReceiver<RxFrame>>,
+        local_port: u16,
+        remote_port: u16,
+    ) {
         let src = format!("127.0.0.1:{}", local_port);
         let socket= match UdpSocket::bind(src).await {
             Ok(s) => s,
@@ -170,36 +253,125 @@ impl Node {
                 return;
             }
         };
-       
+        let remote = format!("127.0.0.1:{}", remote_port);
+
         let mut buf = vec![0u8; 1500];
         let resp = [0xC0, 0x0F, 0x00, 0xC0];
+        let mut peer = None;
+        let mut next_id: u16 = 0;
         println!("thread running, listen port: {}", local_port);
         loop {
-            match socket.recv_from(&mut buf).await {
-                Ok((n, _peer)) => {
-                    let payload = buf[..n].to_vec();
-                    if let Err(e) = handle.call(tx_id, "msg", Pmt::Blob(payload)).await {
-                        eprintln!("flowgraph call error: {}", e);
+            tokio::select! {
+                res = socket.recv_from(&mut buf) => match res {
+                    Ok((n, _peer)) => {
+                        peer = Some(_peer);
+                        let (request, legacy) = Self::parse_tx_request(&buf[..n], next_id);
+                        next_id = next_id.wrapping_add(1);
+                        if !mac.submit(request) {
+                            eprintln!("mac task stopped");
+                            return;
+                        }
+                        if legacy {
+                            if let Err(e) = socket.send_to(&resp, _peer).await {
+                                eprintln!("error sending response: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("socket recv error: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    }
+                },
+                Some(event) = events.recv() => {
+                    if let Some(p) = peer {
+                        if let Err(e) = socket.send_to(&event.to_kiss(), p).await {
+                            eprintln!("error sending tx report: {}", e);
+                        }
                     }
-                    if let Err(e) = socket.send_to(&resp, _peer).await {
-                        eprintln!("error sending response: {}", e);
+                },
+                Some(frame) = async { match rx.as_mut() { Some(rx) => rx.recv().await, None => std::future::pending().await } } => {
+                    let data = create_cmd(kiss::CMD_DATA, &frame.payload);
+                    let ready = create_cmd(kiss::CMD_READY, &[0]);
+                    for msg in [data, ready] {
+                        if let Err(e) = socket.send_to(&msg, &remote).await {
+                            eprintln!("error forwarding frame: {}", e);
+                        }
                     }
-                }
-                Err(e) => {
-                    eprintln!("socket recv error: {}", e);
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                }   
-            }            
+                },
+            }
         }
     }
 
-    pub fn server_task_create(&self, handle: FlowgraphHandle) {
+    pub fn server_task_create(&mut self, handle: FlowgraphHandle) {
         let tx_id = self.transmitter.clone().into();
-        let local_port = self.local_port;
+        let (tx_done, rx_frames) = match (self.tx_done.take(), self.rx_frames.take()) {
+            (Some(t), Some(r)) => (t, r),
+            _ => {
+                eprintln!("server task already created");
+                return;
+            }
+        };
 
+        let (event_tx, events) = unbounded_channel();
+        let (mac_handle, commands) = Mac::channel();
+        let mac = Mac::new(self.mac_config.clone(), self.sf, self.bw, event_tx);
+        // with framing the MAC strips the header and forwards payloads to the client
+        let rx = self.mac_config.framing.then(|| mac_handle.subscribe());
+
+        tokio::spawn(mac.run(handle, tx_id, commands, tx_done, rx_frames));
         tokio::spawn(
-            Self::server_task_body(handle, tx_id, local_port)
+            Self::server_task_body(mac_handle.clone(), events, rx, self.local_port, self.remote_port)
         );
+        self.mac = Some(mac_handle);
+    }
+
+    /// Run a LoRaWAN class A end device on the node instead of the UDP server and MAC.
+    pub fn end_device_create(
+        &mut self,
+        handle: FlowgraphHandle,
+        config: EndDeviceConfig,
+    ) -> Option<EndDeviceHandle> {
+        let (Some(tx_done), Some(rx_frames)) = (self.tx_done.take(), self.rx_frames.take()) else {
+            eprintln!("server task already created");
+            return None;
+        };
+        let (device_handle, commands) = EndDevice::channel();
+        let rx_chains: Vec<(BlockId, SpreadingFactor)> = self.frame_syncs.iter().map(|(f, sf)| (f.clone().into(), *sf)).collect();
+        let device = EndDevice::new(config, self.channel.into(), self.bw, rx_chains);
+        tokio::spawn(device.run(
+            handle,
+            self.transmitter.clone().into(),
+            commands,
+            tx_done,
+            rx_frames,
+        ));
+        Some(device_handle)
+    }
+
+    /// Handle to the MAC layer, available once the node has been started with the server enabled.
+    pub fn mac_handle(&self) -> Option<MacHandle> {
+        self.mac.clone()
+    }
+
+    /// Relay Meshtastic packets over the MAC of a started node.
+    pub fn meshtastic_router_create(&self, config: RouterConfig) -> Option<RouterHandle> {
+        let mac = self.mac.clone()?;
+        Some(MeshtasticRouter::spawn(config, mac))
+    }
+
+    /// Serve the Meshtastic client API on TCP, sending and receiving through a router on the MAC of a started node.
+    pub fn meshtastic_api_create(&self, router: RouterConfig, api: ApiConfig) -> Result<RouterHandle> {
+        let router = self
+            .meshtastic_router_create(router)
+            .ok_or_else(|| anyhow!("node not started"))?;
+        let server = MeshtasticApiServer::new(api)?;
+        let handle = router.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.run(handle).await {
+                eprintln!("Meshtastic API server failed: {}", e);
+            }
+        });
+        Ok(router)
     }
 
     pub fn start(
@@ -211,6 +383,7 @@ impl Node {
         let fg = self.fg.take().expect("Flowgraph already started");
 
         let (_fg, handle) = rt.start_sync(fg)?;
+        self.handle = Some(handle.clone());
 
         if enabled {
             self.server_task_create(handle);
@@ -220,6 +393,80 @@ impl Node {
     }
     
 
+    /// Add a decode chain for a further spreading factor, e.g. to follow ADR. Only possible before the node is started.
+    pub fn add_rx_chain(&mut self, sf: SpreadingFactor, ldro: bool) -> Result<()> {
+        let fg = self.fg.as_mut().ok_or_else(|| anyhow!("flowgraph already started"))?;
+        let frame_sync: FrameSync = FrameSync::new(
+            self.channel,
+            self.bw,
+            sf,
+            false,
+            vec![vec![self.sync_word as usize]],
+            self.interpolation,
+            None,
+            Some("header_crc_ok"),
+            false,
+            None,
+            false,
+        );
+        let fft_demod: FftDemod = FftDemod::new(sf, ldro);
+        let gray_mapping: GrayMapping = GrayMapping::new();
+        let deinterleaver: Deinterleaver = Deinterleaver::new(ldro, sf);
+        let hamming_dec: HammingDecoder = HammingDecoder::new();
+        let header_decoder: HeaderDecoder = HeaderDecoder::new(HeaderMode::Explicit, ldro);
+        let decoder: Decoder = Decoder::new();
+        let awgn = self.awgn.clone();
+        let rx_pipe = self.rx_pipe.clone();
+        connect!(fg,
+            awgn > frame_sync > fft_demod > gray_mapping > deinterleaver > hamming_dec > header_decoder;
+            header_decoder.frame_info | frame_info.frame_sync;
+            header_decoder            | decoder;
+            decoder.crc_check         | payload_crc_result.frame_sync;
+            decoder.out_annotated     | rx_pipe;
+        );
+        self.frame_syncs.push((frame_sync, sf));
+        Ok(())
+    }
+
+    /// Change spreading factor and gain of frames sent without explicit settings, e.g. by the MAC.
+    pub async fn set_tx_settings(&mut self, sf: SpreadingFactor, gain_db: f64) -> Result<()> {
+        let mut handle = self.handle.clone().ok_or_else(|| anyhow!("node not started"))?;
+        // symbols longer than 16 ms need the low data rate optimization
+        let ldro = (1u32 << u8::from(sf)) as f64 / Into::<f64>::into(self.bw) > 0.016;
+        let settings = HashMap::from([
+            (String::from("sf"), Pmt::Usize(sf.into())),
+            (String::from("ldro"), Pmt::Bool(ldro)),
+            (String::from("gain_db"), Pmt::F64(gain_db)),
+        ]);
+        handle
+            .call(self.transmitter.clone().into(), "config", Pmt::MapStrPmt(settings))
+            .await
+            .map_err(|e| anyhow!("{e}"))?;
+        self.sf = sf;
+        Ok(())
+    }
+
+    /// Apply the TX settings of an ADR decision.
+    pub async fn apply_adr(&mut self, decision: &AdrDecision, region: Region) -> Result<()> {
+        let sf = decision
+            .spreading_factor(region)
+            .ok_or_else(|| anyhow!("invalid data rate {}", decision.data_rate))?;
+        self.set_tx_settings(sf, decision.gain_db()).await
+    }
+
+    /// Start the flowgraph with a LoRaWAN end device instead of the UDP server.
+    pub fn start_end_device(
+        &mut self,
+        rt: &mut Runtime<'_, SmolScheduler>,
+        config: EndDeviceConfig,
+    ) -> Result<EndDeviceHandle, Box<dyn std::error::Error>> {
+        let fg = self.fg.take().expect("Flowgraph already started");
+        let (_fg, handle) = rt.start_sync(fg)?;
+        self.handle = Some(handle.clone());
+        self.end_device_create(handle, config)
+            .ok_or_else(|| "end device already created".into())
+    }
+
     pub fn get_sample_rate(self) -> u32 {
         if matches!(self.bw, Bandwidth::BW62) {
             return 62500*self.oversampling as u32;
@@ -1,8 +1,18 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 use std::time::SystemTime;
 
+use anyhow::Context;
+use anyhow::anyhow;
 use chrono::prelude::DateTime;
 use chrono::prelude::Utc;
 use semtech_udp::Bandwidth;
@@ -10,88 +20,527 @@ use semtech_udp::CodingRate;
 use semtech_udp::DataRate;
 use semtech_udp::MacAddress;
 use semtech_udp::SpreadingFactor;
+use semtech_udp::client_runtime::DownlinkRequest;
 use semtech_udp::client_runtime::Event;
 use semtech_udp::client_runtime::UdpRuntime;
 use semtech_udp::push_data::CRC;
+use semtech_udp::push_data::Data;
 use semtech_udp::push_data::Packet;
 use semtech_udp::push_data::RSig;
 use semtech_udp::push_data::RxPk;
 use semtech_udp::push_data::RxPkV2;
+use semtech_udp::tx_ack;
+use serde_json::json;
+use tokio::net::UdpSocket;
 use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use triggered::Listener;
 use triggered::Trigger;
 
+use futuresdr::channel::mpsc::Receiver;
 use futuresdr::channel::mpsc::Sender;
 use futuresdr::prelude::*;
 
+use crate::decoder::annotated_payload;
+use crate::downlink::Downlink;
+use crate::downlink::DownlinkError;
+use crate::downlink::DownlinkScheduler;
+use crate::downlink::ScheduledDownlink;
+use crate::downlink::reception_remainder;
+use crate::downlink::tmst_now;
+use crate::gateway_config::GatewayConfig;
+
+impl From<DownlinkError> for tx_ack::Error {
+    fn from(e: DownlinkError) -> Self {
+        match e {
+            DownlinkError::TooLate => tx_ack::Error::TooLate,
+            DownlinkError::TooEarly => tx_ack::Error::TooEarly,
+            DownlinkError::Collision => tx_ack::Error::CollisionPacket,
+            DownlinkError::TxFreq => tx_ack::Error::TxFreq,
+            DownlinkError::GpsUnlocked => tx_ack::Error::GpsUnlocked,
+            DownlinkError::Unsupported(_) => tx_ack::Error::SendFail,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ForwarderConfig {
+    /// number of uplinks buffered while the server connection is slow, further frames are dropped
+    pub uplink_queue_len: usize,
+    /// recreate the UDP runtime (new socket, DNS lookup) if the server stays unreachable this long
+    pub reconnect_after: Duration,
+    /// delay before recreating a failed UDP runtime, doubled on every further attempt
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// interval of the `stat` reports, `None` to disable them
+    pub stat_interval: Option<Duration>,
+    /// static gateway position reported in `stat`
+    pub position: Option<GpsPosition>,
+    /// frequencies (Hz) of the TX chain, downlinks outside are rejected with `TX_FREQ`
+    pub tx_freq_range: Option<RangeInclusive<u32>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// meters above sea level
+    pub altitude: i32,
+}
+
+impl Default for ForwarderConfig {
+    fn default() -> Self {
+        Self {
+            uplink_queue_len: 64,
+            reconnect_after: Duration::from_secs(60),
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+            stat_interval: Some(Duration::from_secs(30)),
+            position: None,
+            tx_freq_range: None,
+        }
+    }
+}
+
+/// Counters of the forwarder, shared with the background tasks.
+#[derive(Debug, Default)]
+pub struct ForwarderStats {
+    pub uplinks_forwarded: AtomicU64,
+    /// frames dropped because of missing or invalid annotations
+    pub uplinks_invalid: AtomicU64,
+    /// frames dropped because the uplink queue was full or the connection failed
+    pub uplinks_dropped: AtomicU64,
+    pub downlinks_accepted: AtomicU64,
+    pub downlinks_rejected: AtomicU64,
+    pub connection_lost: AtomicU64,
+    pub runtime_restarts: AtomicU64,
+    /// PUSH_DATA datagrams sent to the server and the PUSH_ACKs received for them
+    pub push_data_sent: AtomicU64,
+    pub push_acks: AtomicU64,
+    /// preambles detected by the [`FrameSync`](crate::FrameSync) blocks, and the ones without a frame
+    pub detections: AtomicU64,
+    pub detections_failed: AtomicU64,
+    pub headers_ok: AtomicU64,
+    pub header_errors: AtomicU64,
+    pub crc_ok: AtomicU64,
+    pub crc_errors: AtomicU64,
+    /// downlinks the [`Transmitter`](crate::Transmitter) handed to the SDR
+    pub downlinks_sent: AtomicU64,
+}
+
+impl ForwarderStats {
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+/// Counter values at the last `stat` report, the report covers the difference.
+#[derive(Debug, Default, Clone, Copy)]
+struct StatSnapshot {
+    rxnb: u64,
+    rxok: u64,
+    rxfw: u64,
+    dwnb: u64,
+    txnb: u64,
+    push_data_sent: u64,
+    push_acks: u64,
+}
+
+impl StatSnapshot {
+    fn take(stats: &ForwarderStats) -> Self {
+        let headers_ok = ForwarderStats::get(&stats.headers_ok);
+        Self {
+            // every frame with a valid header counts as received, payload CRC errors make it not ok
+            rxnb: headers_ok,
+            rxok: headers_ok.saturating_sub(ForwarderStats::get(&stats.crc_errors)),
+            rxfw: ForwarderStats::get(&stats.uplinks_forwarded),
+            dwnb: ForwarderStats::get(&stats.downlinks_accepted)
+                + ForwarderStats::get(&stats.downlinks_rejected),
+            txnb: ForwarderStats::get(&stats.downlinks_sent),
+            push_data_sent: ForwarderStats::get(&stats.push_data_sent),
+            push_acks: ForwarderStats::get(&stats.push_acks),
+        }
+    }
+
+    /// GWMP `stat` object for the interval since `last`.
+    fn to_data(self, last: &Self, position: Option<GpsPosition>) -> anyhow::Result<Data> {
+        // like the packet forwarder, 0% if nothing was sent
+        let sent = self.push_data_sent - last.push_data_sent;
+        let ackr = if sent == 0 {
+            0.0
+        } else {
+            100.0 * (self.push_acks - last.push_acks) as f64 / sent as f64
+        };
+        let mut stat = json!({
+            "time": Utc::now().format("%Y-%m-%d %H:%M:%S GMT").to_string(),
+            "rxnb": self.rxnb - last.rxnb,
+            "rxok": self.rxok - last.rxok,
+            "rxfw": self.rxfw - last.rxfw,
+            "ackr": (ackr * 10.0).round() / 10.0,
+            "dwnb": self.dwnb - last.dwnb,
+            "txnb": self.txnb - last.txnb,
+        });
+        if let Some(position) = position {
+            stat["lati"] = json!(position.latitude);
+            stat["long"] = json!(position.longitude);
+            stat["alti"] = json!(position.altitude);
+        }
+        Ok(serde_json::from_value(json!({ "stat": stat }))?)
+    }
+}
+
+/// GWMP identifiers of the datagrams counted for `ackr`
+const PUSH_DATA: u8 = 0x00;
+const PUSH_ACK: u8 = 0x01;
+/// PUSH_DATA tokens remembered to match late PUSH_ACKs
+const PUSH_TOKENS: usize = 64;
+
+/// Relay between the UDP runtime and the server that counts PUSH_DATA datagrams and their PUSH_ACKs,
+/// which the runtime handles internally. Returns the address to connect the runtime to.
+async fn ack_relay(
+    server: SocketAddr,
+    stats: Arc<ForwarderStats>,
+) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let local = UdpSocket::bind("127.0.0.1:0").await?;
+    let unspecified: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let upstream = UdpSocket::bind(unspecified).await?;
+    upstream.connect(server).await?;
+    let addr = local.local_addr()?;
+    let task = tokio::spawn(async move {
+        let mut runtime_addr = None;
+        let mut tokens = VecDeque::with_capacity(PUSH_TOKENS);
+        let mut up = vec![0u8; 65536];
+        let mut down = vec![0u8; 65536];
+        loop {
+            tokio::select! {
+                received = local.recv_from(&mut up) => {
+                    let Ok((len, from)) = received else {
+                        continue;
+                    };
+                    runtime_addr = Some(from);
+                    if len >= 4 && up[3] == PUSH_DATA {
+                        ForwarderStats::inc(&stats.push_data_sent);
+                        if tokens.len() == PUSH_TOKENS {
+                            tokens.pop_front();
+                        }
+                        tokens.push_back([up[1], up[2]]);
+                    }
+                    if let Err(e) = upstream.send(&up[..len]).await {
+                        debug!("could not relay datagram to the server: {}", e);
+                    }
+                }
+                received = upstream.recv(&mut down) => {
+                    // errors are ICMP responses of an unreachable server, the runtime notices the missing ACKs
+                    let Ok(len) = received else {
+                        continue;
+                    };
+                    if len >= 4 && down[3] == PUSH_ACK {
+                        if let Some(i) = tokens.iter().position(|t| *t == [down[1], down[2]]) {
+                            tokens.remove(i);
+                            ForwarderStats::inc(&stats.push_acks);
+                        }
+                    }
+                    if let Some(runtime_addr) = runtime_addr {
+                        let _ = local.send_to(&down[..len], runtime_addr).await;
+                    }
+                }
+            }
+        }
+    });
+    Ok((addr, task))
+}
+
+fn get<'a>(m: &'a HashMap<String, Pmt>, key: &str) -> anyhow::Result<&'a Pmt> {
+    m.get(key).ok_or_else(|| anyhow!("missing {key}"))
+}
+
+/// Build an `rxpk` from the annotated output of the [`Decoder`](crate::Decoder), extended by `sf`, `bw` (kHz) and `freq` (Hz).
+fn rxpk_from_annotations(m: &HashMap<String, Pmt>) -> anyhow::Result<RxPk> {
+    let payload = annotated_payload(m).ok_or_else(|| anyhow!("missing or invalid payload"))?;
+    let codr = match get(m, "code_rate")? {
+        Pmt::Usize(1) => CodingRate::_4_5,
+        Pmt::Usize(2) => CodingRate::_4_6,
+        Pmt::Usize(3) => CodingRate::_4_7,
+        Pmt::Usize(4) => CodingRate::_4_8,
+        p => return Err(anyhow!("invalid code_rate {p:?}")),
+    };
+    let sf = match get(m, "sf")? {
+        Pmt::U32(sf) => *sf as usize,
+        Pmt::Usize(sf) => *sf,
+        p => return Err(anyhow!("invalid sf {p:?}")),
+    };
+    let sf = match sf {
+        5 => SpreadingFactor::_5,
+        6 => SpreadingFactor::_6,
+        7 => SpreadingFactor::_7,
+        8 => SpreadingFactor::_8,
+        9 => SpreadingFactor::_9,
+        10 => SpreadingFactor::_10,
+        11 => SpreadingFactor::_11,
+        12 => SpreadingFactor::_12,
+        sf => return Err(anyhow!("unsupported spreading factor {sf}")),
+    };
+    let bw = match get(m, "bw")? {
+        Pmt::U32(125) => Bandwidth::_125KHz,
+        Pmt::U32(250) => Bandwidth::_250KHz,
+        Pmt::U32(500) => Bandwidth::_500KHz,
+        p => return Err(anyhow!("unsupported bandwidth {p:?}")),
+    };
+    let freq = match get(m, "freq")? {
+        Pmt::F64(f) if f.is_finite() && *f > 0.0 => *f,
+        p => return Err(anyhow!("invalid freq {p:?}")),
+    };
+    let stat = match get(m, "has_crc")? {
+        Pmt::Bool(true) => CRC::OK,
+        Pmt::Bool(false) => CRC::Disabled,
+        p => return Err(anyhow!("invalid has_crc {p:?}")),
+    };
+    let snr = match get(m, "snr")? {
+        Pmt::F64(snr) if snr.is_finite() => *snr,
+        p => return Err(anyhow!("invalid snr {p:?}")),
+    };
+    let timestamp_header_start_unix_time_nanos = match get(m, "timestamp")? {
+        Pmt::U64(t) => *t,
+        p => return Err(anyhow!("invalid timestamp {p:?}")),
+    };
+
+    let mut cfo: f32 = 0.0;
+    if let Some(Pmt::Isize(cfo_int)) = m.get("cfo_int") {
+        cfo += *cfo_int as f32 * (bw.hz() as f32 / (1 << Into::<u32>::into(sf)) as f32);
+    }
+    if let Some(Pmt::F64(cfo_frac)) = m.get("cfo_frac") {
+        cfo += *cfo_frac as f32;
+    }
+    let received_signal_info = RSig {
+        ant: 0,
+        chan: 0,
+        rssic: 0,
+        rssis: None,
+        lsnr: snr as f32,
+        etime: None, // TODO how 'encrypted'?
+        foff: Some(cfo as i64),
+        ftstat: None,
+        ftver: None,
+        ftdelta: None,
+    };
+    let timestamp = Duration::from_nanos(timestamp_header_start_unix_time_nanos);
+    let timestamp = SystemTime::UNIX_EPOCH
+        .checked_add(timestamp)
+        .ok_or_else(|| anyhow!("timestamp out of range"))?;
+    // concentrators latch tmst when the reception ends, RX1 downlinks are scheduled relative to it
+    let rx_finished_unix_time_nanos = timestamp_header_start_unix_time_nanos
+        + reception_remainder(m, payload.len())
+            .unwrap_or_default()
+            .as_nanos() as u64;
+    let tmst = (rx_finished_unix_time_nanos / 1000) as u32; // overflowing 32bit counter with microsecond resolution, only meaningful w.r.t. this receiver instance / gateway
+    let utc_time: DateTime<Utc> = timestamp.into();
+    let time = Some(utc_time.format("%Y%m%dT%H%M%S%.6fZ").to_string());
+    let gps_time_reference = chrono::NaiveDate::from_ymd_opt(1980, 1, 6)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|d| d.and_utc())
+        .ok_or_else(|| anyhow!("invalid GPS epoch"))?;
+    let tmms = Some(
+        utc_time
+            .signed_duration_since(gps_time_reference)
+            .num_milliseconds() as u64,
+    );
+    Ok(RxPk::V2(RxPkV2 {
+        aesk: 0,
+        brd: 0,
+        codr: Some(codr),
+        data: payload.clone(),
+        datr: DataRate::new(sf, bw),
+        freq: freq / 1e6,
+        jver: 2,
+        modu: "LORA".to_owned(),
+        rsig: vec![received_signal_info],
+        size: payload.len() as u64,
+        stat,
+        tmst,
+        delayed: None,
+        tmms,
+        time,
+    }))
+}
+
 /// Forward messages.
 #[derive(Block)]
-#[message_inputs(r#in)]
+#[message_inputs(r#in, frame_detected, detection_failed, header, crc, tx_done)]
 #[null_kernel]
 pub struct PacketForwarderClient {
     mac_addr: MacAddress,
     shutdown_trigger: Trigger,
     uplink_sender: Sender<Packet>,
+    downlink_receiver: Option<Receiver<ScheduledDownlink>>,
+    /// set once the downlink receiver was taken, downlinks are rejected before
+    downlinks_taken: Arc<AtomicBool>,
+    stats: Arc<ForwarderStats>,
     #[allow(dead_code)]
     udp_client_runtime: Runtime,
 }
 
 impl PacketForwarderClient {
-    pub fn new(mac_addr: &str, server_addr: &str) -> Self {
-        let mac_address = MacAddress::from_str(mac_addr).unwrap();
-        let (to_forwarder_sender, mut to_forwarder_receiver) = mpsc::channel::<Packet>(1);
-        let host = SocketAddr::from_str(server_addr).unwrap();
-        let server_addr = server_addr.to_owned();
+    pub fn new(mac_addr: &str, server_addr: &str, config: ForwarderConfig) -> anyhow::Result<Self> {
+        let mac_address = MacAddress::from_str(mac_addr)
+            .map_err(|e| anyhow!("invalid gateway MAC {mac_addr}: {e}"))?;
+        // fail early on unresolvable addresses, the runtime resolves again on every reconnect
+        server_addr
+            .to_socket_addrs()
+            .with_context(|| format!("invalid server address {server_addr}"))?
+            .next()
+            .ok_or_else(|| anyhow!("could not resolve {server_addr}"))?;
+        let (to_forwarder_sender, to_forwarder_receiver) =
+            mpsc::channel::<Packet>(config.uplink_queue_len);
+        let (downlink_sender, downlink_receiver) = mpsc::channel::<ScheduledDownlink>(16);
+        let stats = Arc::new(ForwarderStats::default());
+        let downlinks_taken = Arc::new(AtomicBool::new(false));
 
         let (shutdown_trigger, shutdown_signal) = triggered::trigger();
-        let shutdown_trigger_tmp = shutdown_trigger.clone();
 
-        let rt_tokio = Runtime::new().unwrap();
-        let handle = rt_tokio.handle();
+        let rt_tokio = Runtime::new().context("creating tokio runtime")?;
+        rt_tokio.spawn(supervise(
+            mac_address,
+            server_addr.to_owned(),
+            config,
+            to_forwarder_receiver,
+            downlink_sender,
+            downlinks_taken.clone(),
+            stats.clone(),
+            shutdown_signal,
+        ));
 
-        rt_tokio.block_on(async move {
-            let (uplink_sender, mut downlink_request_receiver, udp_runtime) =
-                UdpRuntime::new(mac_address, host).await.unwrap();
-            println!("Connecting to server {server_addr}");
-            let udp_runtime_task = handle.spawn(udp_runtime.run(shutdown_signal));
+        Ok(Self {
+            mac_addr: mac_address,
+            shutdown_trigger,
+            uplink_sender: to_forwarder_sender,
+            downlink_receiver: Some(downlink_receiver),
+            downlinks_taken,
+            stats,
+            udp_client_runtime: rt_tokio,
+        })
+    }
 
-            // send received frames
-            handle.spawn(async move {
-                while let Some(received_frame) = to_forwarder_receiver.next().await {
-                    uplink_sender.send(received_frame).await.unwrap();
-                }
+    /// Connect to the network server given by the `gateway_conf` section of a Semtech configuration.
+    ///
+    /// `stat_interval`, the reference position and the TX frequency range of the configuration take precedence
+    /// over `forwarder`.
+    pub fn from_config(
+        config: &GatewayConfig,
+        mut forwarder: ForwarderConfig,
+    ) -> anyhow::Result<Self> {
+        let mac_addr = config.gateway_mac()?;
+        let server_addr = config.server_addr()?;
+        if let Some(interval) = config.gateway.stat_interval {
+            forwarder.stat_interval = (interval > 0).then(|| Duration::from_secs(interval));
+        }
+        if let (Some(latitude), Some(longitude)) =
+            (config.gateway.ref_latitude, config.gateway.ref_longitude)
+        {
+            forwarder.position = Some(GpsPosition {
+                latitude,
+                longitude,
+                altitude: config.gateway.ref_altitude.unwrap_or(0.0) as i32,
             });
+        }
+        if let Some(range) = config.tx_freq_range() {
+            forwarder.tx_freq_range = Some(range);
+        }
+        Self::new(&mac_addr, &server_addr.to_string(), forwarder)
+    }
 
-            // report problems with the server connection and handle downlink request acks
-            handle.spawn(async move {
-                while let Some(event) = downlink_request_receiver.recv().await {
-                    match event {
-                        Event::LostConnection => {
-                            println!("Lost connection to GWMP client");
-                        }
-                        Event::Reconnected => {
-                            println!("Reconnected to GWMP client");
-                        }
-                        Event::DownlinkRequest(downlink_request) => {
-                            downlink_request.ack().await.unwrap()
-                        }
-                        Event::UnableToParseUdpFrame(parse_error, _buffer) => {
-                            println!("Error parsing UDP frame {parse_error}")
-                        }
-                    }
-                }
-                shutdown_trigger.trigger();
-                if let Err(e) = udp_runtime_task.await {
-                    println!("UdpRunTime return error {e}");
-                }
-            });
-        });
+    pub fn stats(&self) -> Arc<ForwarderStats> {
+        self.stats.clone()
+    }
 
-        Self {
-            mac_addr: mac_address,
-            shutdown_trigger: shutdown_trigger_tmp,
-            uplink_sender: to_forwarder_sender,
-            udp_client_runtime: rt_tokio,
+    /// Downlinks accepted from the network server, to be handed to [`transmit_downlinks`](crate::downlink::transmit_downlinks).
+    ///
+    /// Until this is taken, and after the receiver was dropped, downlink requests are rejected.
+    pub fn take_downlinks(&mut self) -> Option<Receiver<ScheduledDownlink>> {
+        let receiver = self.downlink_receiver.take();
+        if receiver.is_some() {
+            self.downlinks_taken.store(true, Ordering::Relaxed);
+        }
+        receiver
+    }
+
+    /// detection reported by a [`FrameSync`](crate::FrameSync)
+    async fn frame_detected(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        ForwarderStats::inc(&self.stats.detections);
+        Ok(Pmt::Ok)
+    }
+
+    /// preamble of a [`FrameSync`](crate::FrameSync) that did not lead to a frame
+    async fn detection_failed(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        ForwarderStats::inc(&self.stats.detections_failed);
+        Ok(Pmt::Ok)
+    }
+
+    /// `frame_info` of a [`HeaderDecoder`](crate::HeaderDecoder)
+    async fn header(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::MapStrPmt(m) => match m.get("err") {
+                Some(Pmt::Bool(true)) => ForwarderStats::inc(&self.stats.header_errors),
+                Some(Pmt::Bool(false)) => ForwarderStats::inc(&self.stats.headers_ok),
+                _ => return Ok(Pmt::InvalidValue),
+            },
+            _ => return Ok(Pmt::InvalidValue),
         }
+        Ok(Pmt::Ok)
+    }
+
+    /// payload CRC result of a [`Decoder`](crate::Decoder)
+    async fn crc(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        match p {
+            Pmt::Bool(true) => ForwarderStats::inc(&self.stats.crc_ok),
+            Pmt::Bool(false) => ForwarderStats::inc(&self.stats.crc_errors),
+            _ => return Ok(Pmt::InvalidValue),
+        }
+        Ok(Pmt::Ok)
+    }
+
+    /// burst completion of the downlink [`Transmitter`](crate::Transmitter)
+    async fn tx_done(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        _p: Pmt,
+    ) -> Result<Pmt> {
+        ForwarderStats::inc(&self.stats.downlinks_sent);
+        Ok(Pmt::Ok)
     }
 
     async fn r#in(
@@ -106,126 +555,248 @@ impl PacketForwarderClient {
                 self.shutdown_trigger.trigger();
                 io.finished = true;
             }
-            Pmt::MapStrPmt(m) => {
-                if let Pmt::Blob(payload) = m.get("payload").unwrap() {
-                    let codr: CodingRate = match m.get("code_rate").unwrap() {
-                        Pmt::Usize(1) => CodingRate::_4_5,
-                        Pmt::Usize(2) => CodingRate::_4_6,
-                        Pmt::Usize(3) => CodingRate::_4_7,
-                        Pmt::Usize(4) => CodingRate::_4_8,
-                        _ => {
+            Pmt::MapStrPmt(m) => match rxpk_from_annotations(&m) {
+                Ok(rxpk) => {
+                    let packet = Packet::from_rxpk(self.mac_addr, rxpk);
+                    if let Err(e) = self.uplink_sender.try_send(packet) {
+                        ForwarderStats::inc(&self.stats.uplinks_dropped);
+                        warn!("dropping uplink: {}", e);
+                    }
+                }
+                Err(e) => {
+                    ForwarderStats::inc(&self.stats.uplinks_invalid);
+                    warn!("dropping invalid frame: {}", e);
+                    return Ok(Pmt::InvalidValue);
+                }
+            },
+            _ => {
+                ForwarderStats::inc(&self.stats.uplinks_invalid);
+                warn!("invalid message type to in port. Expected Pmt::MapStrPmt, got {p}");
+                return Ok(Pmt::InvalidValue);
+            }
+        }
+        Ok(Pmt::Ok)
+    }
+}
+
+async fn handle_downlink(
+    downlink_request: DownlinkRequest,
+    scheduler: &mut DownlinkScheduler,
+    downlink_sender: &mut Sender<ScheduledDownlink>,
+    downlinks_taken: &AtomicBool,
+    stats: &ForwarderStats,
+) {
+    let no_transmitter = || DownlinkError::Unsupported("no transmitter attached".to_string());
+    let attached = downlinks_taken.load(Ordering::Relaxed) && !downlink_sender.is_closed();
+    let scheduled = serde_json::to_value(downlink_request.txpk())
+        .map_err(|e| DownlinkError::Unsupported(e.to_string()))
+        .and_then(|txpk| {
+            if attached {
+                Ok(txpk)
+            } else {
+                Err(no_transmitter())
+            }
+        })
+        .and_then(|txpk| Downlink::from_txpk(&txpk))
+        .and_then(|downlink| scheduler.schedule(downlink, tmst_now()))
+        .and_then(|scheduled| {
+            downlink_sender.try_send(scheduled).map_err(|e| {
+                if e.is_full() {
+                    DownlinkError::Unsupported("downlink queue full".to_string())
+                } else {
+                    no_transmitter()
+                }
+            })
+        });
+    let result = match scheduled {
+        Ok(()) => {
+            ForwarderStats::inc(&stats.downlinks_accepted);
+            downlink_request.ack().await
+        }
+        Err(e) => {
+            ForwarderStats::inc(&stats.downlinks_rejected);
+            warn!("rejecting downlink: {}", e);
+            downlink_request.nack(e.into()).await
+        }
+    };
+    if let Err(e) = result {
+        warn!("Error sending TX_ACK {e}");
+    }
+}
+
+/// Run the UDP runtime, recreating it with backoff when it fails or the server stays unreachable.
+async fn supervise(
+    mac_address: MacAddress,
+    server_addr: String,
+    config: ForwarderConfig,
+    mut uplinks: Receiver<Packet>,
+    mut downlink_sender: Sender<ScheduledDownlink>,
+    downlinks_taken: Arc<AtomicBool>,
+    stats: Arc<ForwarderStats>,
+    shutdown: Listener,
+) {
+    let mut scheduler = DownlinkScheduler::with_freq_range(config.tx_freq_range.clone());
+    let mut backoff = config.backoff_base;
+    let mut last_stat = StatSnapshot::default();
+    let mut stat_timer = config.stat_interval.map(|interval| {
+        let mut timer = tokio::time::interval_at(Instant::now() + interval, interval);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        timer
+    });
+    loop {
+        let host = tokio::net::lookup_host(server_addr.as_str())
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next());
+        let relay = match host {
+            Some(host) => ack_relay(host, stats.clone())
+                .await
+                .map_err(|e| anyhow!("could not start relay: {e}")),
+            None => Err(anyhow!("could not resolve {server_addr}")),
+        };
+        let runtime = match relay {
+            Ok((relay_addr, relay)) => match UdpRuntime::new(mac_address, relay_addr).await {
+                Ok(runtime) => Ok((runtime, relay)),
+                Err(e) => {
+                    relay.abort();
+                    Err(anyhow!("{e}"))
+                }
+            },
+            Err(e) => Err(e),
+        };
+        match runtime {
+            Ok(((uplink_sender, mut events, udp_runtime), relay)) => {
+                info!("Connecting to server {server_addr}");
+                let udp_runtime_task = tokio::spawn(udp_runtime.run(shutdown.clone()));
+                let mut lost_since: Option<Instant> = None;
+                loop {
+                    tokio::select! {
+                        _ = shutdown.clone() => {
+                            let _ = udp_runtime_task.await;
+                            relay.abort();
+                            return;
+                        }
+                        uplink = uplinks.next() => {
+                            let Some(uplink) = uplink else {
+                                udp_runtime_task.abort();
+                                relay.abort();
+                                return;
+                            };
+                            match uplink_sender.send(uplink).await {
+                                Ok(_) => ForwarderStats::inc(&stats.uplinks_forwarded),
+                                Err(e) => {
+                                    ForwarderStats::inc(&stats.uplinks_dropped);
+                                    warn!("could not forward uplink: {}", e);
+                                }
+                            }
+                        }
+                        _ = async { stat_timer.as_mut().unwrap().tick().await }, if stat_timer.is_some() => {
+                            let snapshot = StatSnapshot::take(&stats);
+                            match snapshot.to_data(&last_stat, config.position) {
+                                Ok(data) => {
+                                    let packet = Packet {
+                                        random_token: rand::random(),
+                                        gateway_mac: mac_address,
+                                        data,
+                                    };
+                                    if let Err(e) = uplink_sender.send(packet).await {
+                                        warn!("could not send stat report: {}", e);
+                                    }
+                                }
+                                Err(e) => warn!("could not create stat report: {}", e),
+                            }
+                            last_stat = snapshot;
+                        }
+                        event = events.recv() => match event {
+                            Some(Event::LostConnection) => {
+                                ForwarderStats::inc(&stats.connection_lost);
+                                warn!("Lost connection to GWMP server");
+                                lost_since.get_or_insert(Instant::now());
+                            }
+                            Some(Event::Reconnected) => {
+                                info!("Reconnected to GWMP server");
+                                lost_since = None;
+                                backoff = config.backoff_base;
+                            }
+                            Some(Event::DownlinkRequest(downlink_request)) => {
+                                handle_downlink(
+                                    downlink_request,
+                                    &mut scheduler,
+                                    &mut downlink_sender,
+                                    &downlinks_taken,
+                                    &stats,
+                                )
+                                .await;
+                            }
+                            Some(Event::UnableToParseUdpFrame(parse_error, _buffer)) => {
+                                warn!("Error parsing UDP frame {parse_error}");
+                            }
+                            None => {
+                                warn!("UDP runtime terminated");
+                                break;
+                            }
+                        },
+                        _ = tokio::time::sleep_until(
+                            lost_since.unwrap_or_else(Instant::now) + config.reconnect_after
+                        ), if lost_since.is_some() => {
                             warn!(
-                                "invalid Coding Rate in received msg: {:?}, {:?}",
-                                m.get("code_rate"),
-                                m
+                                "server unreachable for {:?}, recreating UDP runtime",
+                                config.reconnect_after
                             );
-                            return Ok(Pmt::Ok);
+                            break;
                         }
-                    };
-                    let sf: SpreadingFactor = match m.get("sf").unwrap() {
-                        Pmt::U32(5) => SpreadingFactor::_5,
-                        Pmt::U32(6) => SpreadingFactor::_6,
-                        Pmt::U32(7) => SpreadingFactor::_7,
-                        Pmt::U32(8) => SpreadingFactor::_8,
-                        Pmt::U32(9) => SpreadingFactor::_9,
-                        Pmt::U32(10) => SpreadingFactor::_10,
-                        Pmt::U32(11) => SpreadingFactor::_11,
-                        Pmt::U32(12) => SpreadingFactor::_12,
-                        _ => panic!(
-                            "invalid Spreading Factor in received msg: {:?}",
-                            m.get("sf")
-                        ),
-                    };
-                    let bw: Bandwidth = match m.get("bw").unwrap() {
-                        Pmt::U32(125) => Bandwidth::_125KHz,
-                        Pmt::U32(250) => Bandwidth::_250KHz,
-                        Pmt::U32(500) => Bandwidth::_500KHz,
-                        _ => panic!("invalid Bandwidth in received msg: {:?}", m.get("bw")),
-                    };
-                    let freq: f64 = match m.get("freq").unwrap() {
-                        Pmt::F64(f) => *f,
-                        _ => panic!("invalid Frequency in received msg: {:?}", m.get("freq")),
-                    };
-                    let stat = match m.get("has_crc").unwrap() {
-                        Pmt::Bool(true) => CRC::OK,
-                        Pmt::Bool(false) => CRC::Disabled,
-                        _ => panic!("invalid has_crc in received msg: {:?}", m.get("has_crc")),
-                    };
-                    let mut cfo: f32 = 0.0;
-                    if let Some(Pmt::Isize(cfo_int)) = m.get("cfo_int") {
-                        cfo += *cfo_int as f32
-                            * (bw.hz() as f32 / (1 << Into::<u32>::into(sf)) as f32);
                     }
-                    if let Some(Pmt::F64(cfo_frac)) = m.get("cfo_frac") {
-                        cfo += *cfo_frac as f32;
-                    }
-                    let received_signal_info = match m.get("snr").unwrap() {
-                        Pmt::F64(snr) => RSig {
-                            ant: 0,
-                            chan: 0,
-                            rssic: 0,
-                            rssis: None,
-                            lsnr: *snr as f32,
-                            etime: None, // TODO how 'encrypted'?
-                            foff: Some(cfo as i64),
-                            ftstat: None,
-                            ftver: None,
-                            ftdelta: None,
-                        },
-                        _ => panic!("invalid SNR in received msg: {:?}", m.get("snr")),
-                    };
-                    let timestamp_header_start_unix_time_nanos =
-                        if let Some(Pmt::U64(tmp)) = m.get("timestamp") {
-                            *tmp
-                        } else {
-                            panic!(
-                                "invalid timestamp in received msg: {:?}",
-                                m.get("timestamp")
-                            )
-                        };
-                    let timestamp = Duration::from_nanos(timestamp_header_start_unix_time_nanos);
-                    let timestamp = SystemTime::UNIX_EPOCH.checked_add(timestamp).unwrap();
-                    let tmst = (timestamp_header_start_unix_time_nanos / 1000) as u32; // overflowing 32bit counter with microsecond resolution, only meaningful w.r.t. this receiver instance / gateway
-                    let utc_time: DateTime<Utc> = timestamp.into();
-                    let time = Some(utc_time.format("%Y%m%dT%H%M%S%.6fZ").to_string());
-                    let gps_time_reference = chrono::NaiveDate::from_ymd_opt(1980, 1, 6)
-                        .unwrap()
-                        .and_hms_opt(0, 0, 0)
-                        .unwrap()
-                        .and_utc();
-                    let tmms = Some(
-                        utc_time
-                            .signed_duration_since(gps_time_reference)
-                            .num_milliseconds() as u64,
-                    );
-                    let rxpk = RxPk::V2(RxPkV2 {
-                        aesk: 0,
-                        brd: 0,
-                        codr: Some(codr),
-                        data: payload.clone(),
-                        datr: DataRate::new(sf, bw),
-                        freq,
-                        jver: 2,
-                        modu: "LORA".to_owned(),
-                        rsig: vec![received_signal_info],
-                        size: payload.len() as u64,
-                        stat,
-                        tmst,
-                        delayed: None,
-                        tmms,
-                        time,
-                    });
-                    let packet = Packet::from_rxpk(self.mac_addr, rxpk);
-                    self.uplink_sender.send(packet).await.unwrap();
-                } else {
-                    panic!(
-                        "invalid payload in received msg. Expected Pmt::Blob, got {:?}",
-                        m.get("payload")
-                    )
                 }
+                udp_runtime_task.abort();
+                relay.abort();
             }
-            _ => panic!("invalid message type to msg in port. Expected Pmt::MapStrPmt, got {p}"),
+            Err(e) => warn!("could not start UDP runtime: {}", e),
         }
-        Ok(Pmt::Ok)
+
+        ForwarderStats::inc(&stats.runtime_restarts);
+        tokio::select! {
+            _ = shutdown.clone() => return,
+            _ = tokio::time::sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(config.backoff_max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn relay_counts_push_acks() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stats = Arc::new(ForwarderStats::default());
+        let (relay_addr, relay) = ack_relay(server.local_addr().unwrap(), stats.clone())
+            .await
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 64];
+
+        client
+            .send_to(&[2, 0x12, 0x34, PUSH_DATA, 1], relay_addr)
+            .await
+            .unwrap();
+        let (len, relay_upstream) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], [2, 0x12, 0x34, PUSH_DATA, 1]);
+        // an ACK for an unknown token does not count, but is passed on
+        for token in [[0x56, 0x78], [0x12, 0x34]] {
+            server
+                .send_to(&[2, token[0], token[1], PUSH_ACK], relay_upstream)
+                .await
+                .unwrap();
+            let len = client.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], [2, token[0], token[1], PUSH_ACK]);
+        }
+        relay.abort();
+
+        let snapshot = StatSnapshot::take(&stats);
+        assert_eq!((snapshot.push_data_sent, snapshot.push_acks), (1, 1));
+        let data = snapshot.to_data(&StatSnapshot::default(), None).unwrap();
+        let stat = serde_json::to_value(data).unwrap();
+        assert_eq!(stat["stat"]["ackr"], 100.0);
     }
 }
@@ -0,0 +1,563 @@
+//! LoRaWAN regional parameters, following RP002.
+//!
+//! Only the parts needed by the gateway, the network server and the simulated devices are covered: channel
+//! plans, data rates, payload limits, RX window settings, duty-cycle and dwell-time rules.
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use strum_macros::Display;
+use strum_macros::EnumIter;
+
+use crate::gateway_config::GatewayChannel;
+use crate::utils::Bandwidth;
+use crate::utils::Channel;
+use crate::utils::SpreadingFactor;
+use crate::utils::SpreadingFactor::SF7;
+use crate::utils::SpreadingFactor::SF8;
+use crate::utils::SpreadingFactor::SF9;
+use crate::utils::SpreadingFactor::SF10;
+use crate::utils::SpreadingFactor::SF11;
+use crate::utils::SpreadingFactor::SF12;
+
+/// Uplink dwell time limit of regions with FCC-like rules.
+pub const DWELL_TIME: Duration = Duration::from_millis(400);
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum, EnumIter, PartialEq, Eq, Hash, Display)]
+#[clap(rename_all = "SCREAMING_SNAKE_CASE")]
+#[allow(non_camel_case_types)]
+pub enum Region {
+    #[default]
+    EU868,
+    US915,
+    AU915,
+    AS923_1,
+    AS923_2,
+    AS923_3,
+    AS923_4,
+    IN865,
+    KR920,
+    /// 96 channel plan of LoRaWAN 1.0.2
+    CN470,
+    EU433,
+}
+
+/// Modulation of a data rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataRate {
+    Lora {
+        spreading_factor: SpreadingFactor,
+        bandwidth: Bandwidth,
+    },
+    /// 50 kbps GFSK
+    Fsk,
+    LrFhss,
+}
+
+impl DataRate {
+    const fn lora(spreading_factor: SpreadingFactor, bandwidth: Bandwidth) -> Self {
+        DataRate::Lora {
+            spreading_factor,
+            bandwidth,
+        }
+    }
+
+    pub fn spreading_factor(&self) -> Option<SpreadingFactor> {
+        match self {
+            DataRate::Lora {
+                spreading_factor, ..
+            } => Some(*spreading_factor),
+            _ => None,
+        }
+    }
+
+    pub fn bandwidth(&self) -> Option<Bandwidth> {
+        match self {
+            DataRate::Lora { bandwidth, .. } => Some(*bandwidth),
+            _ => None,
+        }
+    }
+
+    /// Data rate in the `SF7BW125` notation of the Packet Forwarder protocol.
+    pub fn datr(&self) -> Option<String> {
+        match self {
+            DataRate::Lora {
+                spreading_factor,
+                bandwidth,
+            } => Some(format!(
+                "SF{}BW{}",
+                u8::from(*spreading_factor),
+                Into::<u32>::into(*bandwidth) / 1000
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Uplink channel of a regional plan.
+#[derive(Debug, Clone, Copy)]
+pub struct UplinkChannel {
+    pub channel: Channel,
+    pub bandwidth: Bandwidth,
+    pub min_data_rate: u8,
+    pub max_data_rate: u8,
+}
+
+/// DR0 to DR7 of EU868 and the plans derived from it
+static DATA_RATES_EU: [Option<DataRate>; 8] = [
+    Some(DataRate::lora(SF12, Bandwidth::BW125)),
+    Some(DataRate::lora(SF11, Bandwidth::BW125)),
+    Some(DataRate::lora(SF10, Bandwidth::BW125)),
+    Some(DataRate::lora(SF9, Bandwidth::BW125)),
+    Some(DataRate::lora(SF8, Bandwidth::BW125)),
+    Some(DataRate::lora(SF7, Bandwidth::BW125)),
+    Some(DataRate::lora(SF7, Bandwidth::BW250)),
+    Some(DataRate::Fsk),
+];
+
+/// IN865 has no DR6
+static DATA_RATES_IN865: [Option<DataRate>; 8] = [
+    Some(DataRate::lora(SF12, Bandwidth::BW125)),
+    Some(DataRate::lora(SF11, Bandwidth::BW125)),
+    Some(DataRate::lora(SF10, Bandwidth::BW125)),
+    Some(DataRate::lora(SF9, Bandwidth::BW125)),
+    Some(DataRate::lora(SF8, Bandwidth::BW125)),
+    Some(DataRate::lora(SF7, Bandwidth::BW125)),
+    None,
+    Some(DataRate::Fsk),
+];
+
+static DATA_RATES_US915: [Option<DataRate>; 14] = [
+    Some(DataRate::lora(SF10, Bandwidth::BW125)),
+    Some(DataRate::lora(SF9, Bandwidth::BW125)),
+    Some(DataRate::lora(SF8, Bandwidth::BW125)),
+    Some(DataRate::lora(SF7, Bandwidth::BW125)),
+    Some(DataRate::lora(SF8, Bandwidth::BW500)),
+    Some(DataRate::LrFhss),
+    Some(DataRate::LrFhss),
+    None,
+    Some(DataRate::lora(SF12, Bandwidth::BW500)),
+    Some(DataRate::lora(SF11, Bandwidth::BW500)),
+    Some(DataRate::lora(SF10, Bandwidth::BW500)),
+    Some(DataRate::lora(SF9, Bandwidth::BW500)),
+    Some(DataRate::lora(SF8, Bandwidth::BW500)),
+    Some(DataRate::lora(SF7, Bandwidth::BW500)),
+];
+
+static DATA_RATES_AU915: [Option<DataRate>; 14] = [
+    Some(DataRate::lora(SF12, Bandwidth::BW125)),
+    Some(DataRate::lora(SF11, Bandwidth::BW125)),
+    Some(DataRate::lora(SF10, Bandwidth::BW125)),
+    Some(DataRate::lora(SF9, Bandwidth::BW125)),
+    Some(DataRate::lora(SF8, Bandwidth::BW125)),
+    Some(DataRate::lora(SF7, Bandwidth::BW125)),
+    Some(DataRate::lora(SF8, Bandwidth::BW500)),
+    Some(DataRate::LrFhss),
+    Some(DataRate::lora(SF12, Bandwidth::BW500)),
+    Some(DataRate::lora(SF11, Bandwidth::BW500)),
+    Some(DataRate::lora(SF10, Bandwidth::BW500)),
+    Some(DataRate::lora(SF9, Bandwidth::BW500)),
+    Some(DataRate::lora(SF8, Bandwidth::BW500)),
+    Some(DataRate::lora(SF7, Bandwidth::BW500)),
+];
+
+/// Maximum MACPayload size M per data rate, `None` where the data rate is not allowed.
+static MAX_PAYLOAD_EU: [Option<usize>; 8] = [
+    Some(59),
+    Some(59),
+    Some(59),
+    Some(123),
+    Some(250),
+    Some(250),
+    Some(250),
+    Some(250),
+];
+
+static MAX_PAYLOAD_US915: [Option<usize>; 14] = [
+    Some(19),
+    Some(61),
+    Some(133),
+    Some(250),
+    Some(250),
+    Some(58),
+    Some(133),
+    None,
+    Some(61),
+    Some(137),
+    Some(250),
+    Some(250),
+    Some(250),
+    Some(250),
+];
+
+static MAX_PAYLOAD_AU915: [Option<usize>; 14] = [
+    Some(59),
+    Some(59),
+    Some(59),
+    Some(123),
+    Some(250),
+    Some(250),
+    Some(250),
+    Some(58),
+    Some(61),
+    Some(137),
+    Some(250),
+    Some(250),
+    Some(250),
+    Some(250),
+];
+
+/// AS923 and AU915 with uplink dwell time limit
+static MAX_PAYLOAD_DWELL: [Option<usize>; 8] = [
+    None,
+    None,
+    Some(19),
+    Some(61),
+    Some(133),
+    Some(250),
+    Some(250),
+    Some(125),
+];
+
+impl Region {
+    fn data_rates(&self) -> &'static [Option<DataRate>] {
+        match self {
+            Region::US915 => &DATA_RATES_US915,
+            Region::AU915 => &DATA_RATES_AU915,
+            Region::KR920 | Region::CN470 => &DATA_RATES_EU[..6],
+            Region::IN865 => &DATA_RATES_IN865,
+            _ => &DATA_RATES_EU,
+        }
+    }
+
+    /// Modulation of a data rate index.
+    pub fn data_rate(&self, dr: u8) -> Option<DataRate> {
+        self.data_rates().get(dr as usize).copied().flatten()
+    }
+
+    /// Lowest data rate index using a LoRa modulation, uplink data rates are preferred.
+    pub fn find_data_rate(&self, sf: SpreadingFactor, bandwidth: Bandwidth) -> Option<u8> {
+        let wanted = DataRate::lora(sf, bandwidth);
+        self.data_rates()
+            .iter()
+            .position(|dr| *dr == Some(wanted))
+            .map(|dr| dr as u8)
+    }
+
+    /// Highest data rate of the 125 kHz uplink channels, e.g. the upper bound for ADR.
+    pub fn max_uplink_data_rate(&self) -> u8 {
+        match self {
+            Region::US915 => 3,
+            _ => 5,
+        }
+    }
+
+    /// Lowest uplink data rate, which is raised by the dwell time limit in AS923 and AU915.
+    pub fn min_uplink_data_rate(&self, dwell_time_limited: bool) -> u8 {
+        match self {
+            Region::AS923_1
+            | Region::AS923_2
+            | Region::AS923_3
+            | Region::AS923_4
+            | Region::AU915
+                if dwell_time_limited =>
+            {
+                2
+            }
+            _ => 0,
+        }
+    }
+
+    /// Maximum MACPayload size M of a data rate, the FRMPayload without FOpts is 8 bytes shorter.
+    pub fn max_payload(&self, dr: u8, dwell_time_limited: bool) -> Option<usize> {
+        let dwell_table = dwell_time_limited && self.dwell_time().is_some();
+        let table: &[Option<usize>] = match self {
+            Region::US915 => &MAX_PAYLOAD_US915,
+            Region::AU915 if dwell_table && dr < 8 => &MAX_PAYLOAD_DWELL,
+            Region::AU915 => &MAX_PAYLOAD_AU915,
+            Region::AS923_1 | Region::AS923_2 | Region::AS923_3 | Region::AS923_4
+                if dwell_table =>
+            {
+                &MAX_PAYLOAD_DWELL
+            }
+            _ => &MAX_PAYLOAD_EU,
+        };
+        self.data_rate(dr)?;
+        table.get(dr as usize).copied().flatten()
+    }
+
+    /// Uplink dwell time limit, if the regulations of the region may impose one.
+    ///
+    /// It always applies in US915, in AS923 and AU915 it is set by TxParamSetupReq.
+    pub fn dwell_time(&self) -> Option<Duration> {
+        match self {
+            Region::US915
+            | Region::AU915
+            | Region::AS923_1
+            | Region::AS923_2
+            | Region::AS923_3
+            | Region::AS923_4 => Some(DWELL_TIME),
+            _ => None,
+        }
+    }
+
+    /// Transmit duty cycle allowed on a frequency, `None` if the region has no duty-cycle rule.
+    ///
+    /// Frequencies outside the bands of the region get a duty cycle of 0.
+    pub fn duty_cycle(&self, freq: u32) -> Option<f64> {
+        match self {
+            // ETSI EN 300 220 sub-bands
+            Region::EU868 => match freq {
+                863_000_000..868_000_000 => Some(0.01),
+                868_000_000..868_600_000 => Some(0.01),
+                868_700_000..869_200_000 => Some(0.001),
+                869_400_000..869_650_000 => Some(0.1),
+                869_700_000..870_000_000 => Some(0.01),
+                _ => Some(0.0),
+            },
+            Region::EU433 => match freq {
+                433_050_000..434_790_000 => Some(0.01),
+                _ => Some(0.0),
+            },
+            _ => None,
+        }
+    }
+
+    /// Frequency band (Hz) of the region.
+    pub fn frequency_range(&self) -> RangeInclusive<u32> {
+        match self {
+            Region::EU868 => 863_000_000..=870_000_000,
+            Region::US915 => 902_000_000..=928_000_000,
+            Region::AU915 | Region::AS923_1 | Region::AS923_2 | Region::AS923_3 => {
+                915_000_000..=928_000_000
+            }
+            Region::AS923_4 => 917_000_000..=920_000_000,
+            Region::IN865 => 865_000_000..=867_000_000,
+            Region::KR920 => 920_900_000..=923_300_000,
+            Region::CN470 => 470_000_000..=510_000_000,
+            Region::EU433 => 433_050_000..=434_790_000,
+        }
+    }
+
+    /// Maximum EIRP in dBm, TXPower index 0.
+    pub fn max_eirp_dbm(&self) -> f64 {
+        match self {
+            Region::US915 | Region::AU915 | Region::IN865 => 30.0,
+            Region::KR920 => 14.0,
+            Region::CN470 => 19.15,
+            Region::EU433 => 12.15,
+            _ => 16.0,
+        }
+    }
+
+    /// Highest TXPower index, each step lowers the power by 2 dB.
+    pub fn max_tx_power(&self) -> u8 {
+        match self {
+            Region::US915 | Region::AU915 => 14,
+            Region::IN865 => 10,
+            Region::EU433 => 5,
+            _ => 7,
+        }
+    }
+
+    /// All uplink channels of the region.
+    ///
+    /// Regions with channels defined by the network only list their default channels.
+    pub fn uplink_channels(&self) -> Vec<UplinkChannel> {
+        let channel =
+            |freq: u32, bandwidth: Bandwidth, min_data_rate: u8, max_data_rate: u8| UplinkChannel {
+                channel: Channel::from(freq),
+                bandwidth,
+                min_data_rate,
+                max_data_rate,
+            };
+        let grid = |start: u32, step: u32, count: u32, bandwidth: Bandwidth, min: u8, max: u8| {
+            (0..count)
+                .map(|n| channel(start + n * step, bandwidth, min, max))
+                .collect::<Vec<_>>()
+        };
+        let default = |freqs: &[u32]| {
+            freqs
+                .iter()
+                .map(|f| channel(*f, Bandwidth::BW125, 0, 5))
+                .collect::<Vec<_>>()
+        };
+        match self {
+            Region::EU868 => default(&[868_100_000, 868_300_000, 868_500_000]),
+            Region::US915 => {
+                let mut channels = grid(902_300_000, 200_000, 64, Bandwidth::BW125, 0, 3);
+                channels.extend(grid(903_000_000, 1_600_000, 8, Bandwidth::BW500, 4, 4));
+                channels
+            }
+            Region::AU915 => {
+                let mut channels = grid(915_200_000, 200_000, 64, Bandwidth::BW125, 0, 5);
+                channels.extend(grid(915_900_000, 1_600_000, 8, Bandwidth::BW500, 6, 6));
+                channels
+            }
+            Region::AS923_1 => default(&[923_200_000, 923_400_000]),
+            Region::AS923_2 => default(&[921_400_000, 921_600_000]),
+            Region::AS923_3 => default(&[916_600_000, 916_800_000]),
+            Region::AS923_4 => default(&[917_300_000, 917_500_000]),
+            Region::IN865 => default(&[865_062_500, 865_402_500, 865_985_000]),
+            Region::KR920 => default(&[922_100_000, 922_300_000, 922_500_000]),
+            Region::CN470 => grid(470_300_000, 200_000, 96, Bandwidth::BW125, 0, 5),
+            Region::EU433 => default(&[433_175_000, 433_375_000, 433_575_000]),
+        }
+    }
+
+    /// Number of sub-bands of 8 channels, for regions with fixed channel plans.
+    pub fn sub_bands(&self) -> u8 {
+        match self {
+            Region::US915 | Region::AU915 => 8,
+            Region::CN470 => 12,
+            _ => 0,
+        }
+    }
+
+    /// Uplink channels of a sub-band, counted from 1, the channels a typical 8 channel gateway listens on.
+    ///
+    /// The sub-band also includes its 500 kHz channel. Regions without sub-bands return the common
+    /// gateway channels: the default channels and, in EU868, the five channels of the TTN plan.
+    pub fn sub_band_channels(&self, sub_band: u8) -> Vec<UplinkChannel> {
+        let channels = self.uplink_channels();
+        match self {
+            Region::US915 | Region::AU915 | Region::CN470 => {
+                if sub_band == 0 || sub_band > self.sub_bands() {
+                    return Vec::new();
+                }
+                let first = (sub_band as usize - 1) * 8;
+                let mut sub: Vec<UplinkChannel> = channels[first..first + 8].to_vec();
+                if *self != Region::CN470 {
+                    sub.push(channels[64 + sub_band as usize - 1]);
+                }
+                sub
+            }
+            Region::EU868 => {
+                let mut channels = channels;
+                channels.extend((0..5).map(|n| UplinkChannel {
+                    channel: Channel::from(867_100_000 + n * 200_000),
+                    bandwidth: Bandwidth::BW125,
+                    min_data_rate: 0,
+                    max_data_rate: 5,
+                }));
+                channels
+            }
+            _ => channels,
+        }
+    }
+
+    /// Receive channels of a gateway, with the spreading factors of their data rates.
+    pub fn gateway_channels(&self, sub_band: Option<u8>) -> Vec<GatewayChannel> {
+        let channels = match sub_band {
+            Some(sub_band) => self.sub_band_channels(sub_band),
+            None => self.uplink_channels(),
+        };
+        channels
+            .into_iter()
+            .map(|c| GatewayChannel {
+                channel: c.channel,
+                bandwidth: c.bandwidth,
+                spreading_factors: (c.min_data_rate..=c.max_data_rate)
+                    .filter_map(|dr| self.data_rate(dr)?.spreading_factor())
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Downlink channel of RX1 for an uplink frequency.
+    pub fn rx1_channel(&self, uplink_freq: u32) -> Option<Channel> {
+        // index of the uplink channel on a grid
+        let index = |start: u32, step: u32, count: u32| {
+            let offset = uplink_freq.checked_sub(start)?;
+            (offset % step == 0 && offset / step < count).then_some(offset / step)
+        };
+        match self {
+            Region::US915 | Region::AU915 => {
+                let (narrow, wide) = match self {
+                    Region::US915 => (902_300_000, 903_000_000),
+                    _ => (915_200_000, 915_900_000),
+                };
+                let n = index(narrow, 200_000, 64).or_else(|| index(wide, 1_600_000, 8))?;
+                Some(Channel::from(923_300_000 + (n % 8) * 600_000))
+            }
+            Region::CN470 => {
+                let n = index(470_300_000, 200_000, 96)?;
+                Some(Channel::from(500_300_000 + (n % 48) * 200_000))
+            }
+            _ => Some(Channel::from(uplink_freq)),
+        }
+    }
+
+    /// Downlink data rate of RX1 for an uplink data rate and the RX1DROffset of the device.
+    pub fn rx1_data_rate(&self, uplink_dr: u8, offset: u8, dwell_time_limited: bool) -> Option<u8> {
+        self.data_rate(uplink_dr)?;
+        let dr = uplink_dr as i16;
+        let downlink = match self {
+            // the LR-FHSS rates answer like DR0 and DR1 (RP002 2.5.7 and 2.7.7)
+            Region::US915 => {
+                let dr = match dr {
+                    5 => 0,
+                    6 => 1,
+                    dr => dr.min(4),
+                };
+                (10 + dr - offset.min(3) as i16).clamp(8, 13)
+            }
+            Region::AU915 => {
+                let dr = if dr == 7 { 1 } else { dr.min(6) };
+                (8 + dr - offset.min(5) as i16).clamp(8, 13)
+            }
+            Region::AS923_1
+            | Region::AS923_2
+            | Region::AS923_3
+            | Region::AS923_4
+            | Region::IN865 => {
+                // offsets 6 and 7 raise the data rate
+                let offset = match offset.min(7) {
+                    6 => -1,
+                    7 => -2,
+                    o => o as i16,
+                };
+                let min = self.min_uplink_data_rate(dwell_time_limited) as i16;
+                (dr - offset).clamp(min, 5)
+            }
+            _ => (dr - offset.min(5) as i16).max(0),
+        };
+        Some(downlink as u8)
+    }
+
+    /// Default RX2 channel and data rate.
+    pub fn rx2(&self) -> (Channel, u8) {
+        match self {
+            Region::EU868 => (Channel::EU868_Down, 0),
+            Region::US915 | Region::AU915 => (Channel::from(923_300_000), 8),
+            Region::AS923_1 => (Channel::from(923_200_000), 2),
+            Region::AS923_2 => (Channel::from(921_400_000), 2),
+            Region::AS923_3 => (Channel::from(916_600_000), 2),
+            Region::AS923_4 => (Channel::from(917_300_000), 2),
+            Region::IN865 => (Channel::from(866_550_000), 2),
+            Region::KR920 => (Channel::from(921_900_000), 0),
+            Region::CN470 => (Channel::from(505_300_000), 0),
+            Region::EU433 => (Channel::from(434_665_000), 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rx1_data_rate_tables() {
+        let table = |region: Region, uplink_dr: u8, offsets: u8| {
+            (0..offsets)
+                .map(|offset| region.rx1_data_rate(uplink_dr, offset, false).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(table(Region::US915, 0, 4), [10, 9, 8, 8]);
+        assert_eq!(table(Region::US915, 4, 4), [13, 13, 12, 11]);
+        assert_eq!(table(Region::US915, 5, 4), [10, 9, 8, 8]);
+        assert_eq!(table(Region::US915, 6, 4), [11, 10, 9, 8]);
+        assert_eq!(table(Region::AU915, 6, 6), [13, 13, 12, 11, 10, 9]);
+        assert_eq!(table(Region::AU915, 7, 6), [9, 8, 8, 8, 8, 8]);
+        assert_eq!(Region::US915.rx1_data_rate(7, 0, false), None);
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use futuresdr::prelude::*;
+use std::collections::HashMap;
 use std::collections::VecDeque;
 
 use crate::Encoder;
@@ -7,20 +8,45 @@ use crate::Modulator;
 use crate::utils::CodeRate;
 use crate::utils::SpreadingFactor;
 
+/// Modulation parameters of a single frame.
+#[derive(Debug, Clone, Copy)]
+struct FrameSettings {
+    code_rate: CodeRate,
+    has_crc: bool,
+    spreading_factor: SpreadingFactor,
+    low_data_rate: bool,
+    implicit_header: bool,
+    oversampling: usize,
+    preamble_len: usize,
+    invert_iq: bool,
+    /// amplitude scaling in dB, e.g. to simulate TX power
+    gain_db: f64,
+}
+
+struct TxFrame {
+    payload: Vec<u8>,
+    /// `None` to use the settings the block was created with
+    settings: Option<FrameSettings>,
+}
+
 #[derive(Block)]
-#[message_inputs(msg)]
+#[message_inputs(msg, config)]
+#[message_outputs(tx_done)]
 pub struct Transmitter<O = DefaultCpuWriter<Complex32>>
 where
     O: CpuBufferWriter<Item = Complex32>,
 {
     #[output]
     output: O,
-    frames: VecDeque<Vec<u8>>,
+    frames: VecDeque<TxFrame>,
     current_frame: Vec<Complex32>,
     current_offset: usize,
     finished: bool,
     encoder: Encoder,
     modulator: Modulator,
+    settings: FrameSettings,
+    sync_words: Vec<usize>,
+    pad: usize,
     tag_pending: Option<Tag>,
 }
 
@@ -39,6 +65,7 @@ where
         sync_words: Vec<usize>,
         preamble_len: usize,
         pad: usize,
+        invert_iq: bool,
     ) -> Self {
         Self {
             output: O::default(),
@@ -56,14 +83,120 @@ where
             modulator: Modulator::new(
                 spreading_factor,
                 oversampling,
-                sync_words,
+                sync_words.clone(),
                 preamble_len,
                 pad,
+                invert_iq,
             ),
+            settings: FrameSettings {
+                code_rate,
+                has_crc,
+                spreading_factor,
+                low_data_rate,
+                implicit_header,
+                oversampling,
+                preamble_len,
+                invert_iq,
+                gain_db: 0.0,
+            },
+            sync_words,
+            pad,
             tag_pending: None,
         }
     }
 
+    /// Frame settings from a parameter map, falling back to the block's defaults for missing keys.
+    fn parse_settings(&self, m: &HashMap<String, Pmt>) -> Option<FrameSettings> {
+        let mut s = self.settings;
+        for (k, v) in m.iter() {
+            match (k.as_str(), v) {
+                ("payload", _) => {}
+                ("sf", Pmt::Usize(sf)) => {
+                    s.spreading_factor = SpreadingFactor::try_from(*sf as u8).ok()?
+                }
+                ("code_rate", Pmt::Usize(cr)) => {
+                    s.code_rate = CodeRate::try_from(*cr as u8).ok()?
+                }
+                ("has_crc", Pmt::Bool(b)) => s.has_crc = *b,
+                ("ldro", Pmt::Bool(b)) => s.low_data_rate = *b,
+                ("implicit_header", Pmt::Bool(b)) => s.implicit_header = *b,
+                ("invert_iq", Pmt::Bool(b)) => s.invert_iq = *b,
+                ("oversampling", Pmt::Usize(os)) if *os > 0 => s.oversampling = *os,
+                ("preamble_len", Pmt::Usize(p)) => s.preamble_len = *p,
+                ("gain_db", Pmt::F64(g)) => s.gain_db = *g,
+                _ => {
+                    warn!("Transmitter: invalid frame parameter {}: {:?}", k, v);
+                    return None;
+                }
+            }
+        }
+        Some(s)
+    }
+
+    fn modulate(&self, frame: TxFrame) -> Vec<Complex32> {
+        let settings = frame.settings.unwrap_or(self.settings);
+        let mut samples = match frame.settings {
+            None => self.modulator.modulate(self.encoder.encode(frame.payload)),
+            Some(s) => {
+                let encoder = Encoder::new(
+                    s.code_rate,
+                    s.spreading_factor,
+                    s.has_crc,
+                    s.low_data_rate,
+                    s.implicit_header,
+                );
+                let modulator = Modulator::new(
+                    s.spreading_factor,
+                    s.oversampling,
+                    self.sync_words.clone(),
+                    s.preamble_len,
+                    self.pad,
+                    s.invert_iq,
+                );
+                modulator.modulate(encoder.encode(frame.payload))
+            }
+        };
+        if settings.gain_db != 0.0 {
+            let gain = 10f32.powf(settings.gain_db as f32 / 20.0);
+            samples.iter_mut().for_each(|s| *s *= gain);
+        }
+        samples
+    }
+
+    /// Change the default settings of frames sent as plain `Blob` or `String`.
+    async fn config(
+        &mut self,
+        _io: &mut WorkIo,
+        _mio: &mut MessageOutputs,
+        _meta: &mut BlockMeta,
+        p: Pmt,
+    ) -> Result<Pmt> {
+        let Pmt::MapStrPmt(m) = p else {
+            warn!("Transmitter: config was not a parameter map");
+            return Ok(Pmt::InvalidValue);
+        };
+        let Some(s) = self.parse_settings(&m) else {
+            return Ok(Pmt::InvalidValue);
+        };
+        self.encoder = Encoder::new(
+            s.code_rate,
+            s.spreading_factor,
+            s.has_crc,
+            s.low_data_rate,
+            s.implicit_header,
+        );
+        self.modulator = Modulator::new(
+            s.spreading_factor,
+            s.oversampling,
+            self.sync_words.clone(),
+            s.preamble_len,
+            self.pad,
+            s.invert_iq,
+        );
+        self.settings = s;
+        Ok(Pmt::Ok)
+    }
+
     async fn msg(
         &mut self,
         _io: &mut WorkIo,
@@ -72,11 +205,30 @@ where
         p: Pmt,
     ) -> Result<Pmt> {
         match p {
-            Pmt::Blob(payload) => self.frames.push_back(payload),
-            Pmt::String(payload) => self.frames.push_back(payload.as_bytes().into()),
+            Pmt::Blob(payload) => self.frames.push_back(TxFrame {
+                payload,
+                settings: None,
+            }),
+            Pmt::String(payload) => self.frames.push_back(TxFrame {
+                payload: payload.as_bytes().into(),
+                settings: None,
+            }),
+            Pmt::MapStrPmt(m) => {
+                let Some(Pmt::Blob(payload)) = m.get("payload") else {
+                    warn!("Transmitter: parameter map without Blob payload");
+                    return Ok(Pmt::InvalidValue);
+                };
+                let Some(settings) = self.parse_settings(&m) else {
+                    return Ok(Pmt::InvalidValue);
+                };
+                self.frames.push_back(TxFrame {
+                    payload: payload.clone(),
+                    settings: Some(settings),
+                });
+            }
             Pmt::Finished => self.finished = true,
             _ => {
-                warn!("Transmitter: Payload was neither String nor Blob");
+                warn!("Transmitter: Payload was neither String, Blob nor parameter map");
                 return Ok(Pmt::InvalidValue);
             }
         }
@@ -91,14 +243,14 @@ where
     async fn work(
         &mut self,
         io: &mut WorkIo,
-        _m: &mut MessageOutputs,
+        mio: &mut MessageOutputs,
         _b: &mut BlockMeta,
     ) -> Result<()> {
         let (out, mut out_tags) = self.output.slice_with_tags();
 
         if self.current_offset == self.current_frame.len() {
             if let Some(frame) = self.frames.pop_front() {
-                self.current_frame = self.modulator.modulate(self.encoder.encode(frame));
+                self.current_frame = self.modulate(frame);
                 self.current_offset = 0;
                 self.tag_pending = Some(Tag::NamedUsize(
                     "burst_start".to_string(),
@@ -137,6 +289,12 @@ where
         self.current_offset += n;
         self.output.produce(n);
 
+        if n > 0 && self.current_offset == self.current_frame.len() {
+            // whole burst handed to the output buffer
+            mio.post("tx_done", Pmt::Usize(self.current_frame.len()))
+                .await?;
+        }
+
         Ok(())
     }
 }
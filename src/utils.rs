@@ -250,7 +250,7 @@ impl From<Channel> for f64 {
     }
 }
 
-#[derive(Debug, Clone, clap::ValueEnum, Copy, Default)]
+#[derive(Debug, Clone, clap::ValueEnum, Copy, Default, PartialEq, Eq)]
 #[clap(rename_all = "SCREAMING_SNAKE_CASE")]
 #[allow(non_camel_case_types)]
 pub enum Bandwidth {